@@ -1,15 +1,14 @@
 //! Benchmark tests for TegDB engine operations using Criterion.
 
 use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
-use std::path::PathBuf;
-use tegdb::Engine;
+use tegdb::{Engine, EngineOptions};
 use tokio::runtime::Runtime;
 use rand::Rng;
 use rand::distr::Alphanumeric;
 
 fn engine_benchmark(c: &mut Criterion) {
     let rt = Runtime::new().unwrap();
-    let engine = Engine::new(PathBuf::from("test.db"));
+    let engine = Engine::open_temporary().unwrap();
     let key = b"key";
     let value = b"value";
 
@@ -69,7 +68,7 @@ fn engine_benchmark(c: &mut Criterion) {
 
 /// Sequential benchmark tests using keys with varying value sizes.
 async fn engine_seq_benchmark(c: &mut Criterion, value_size: usize) {
-    let engine = Engine::new(PathBuf::from("test.db"));
+    let engine = Engine::open_temporary().unwrap();
     let value = vec![0; value_size];
 
     let mut group = c.benchmark_group(format!("engine_seq_{}", value_size));
@@ -152,13 +151,11 @@ fn engine_concurrency_benchmark(c: &mut Criterion) {
     group.sample_size(100);
     group.throughput(Throughput::Elements(4));
 
-    // Remove concurrent.db once before running the benchmarks.
-    std::fs::remove_file("concurrent.db").ok();
+    // The benchmarks share one database, so that reads find the keys written before.
+    let engine = Engine::open_temporary().unwrap();
 
     // Concurrent benchmark for set.
     group.bench_function("set", |b| {
-        // Create engine once outside the timed iteration.
-        let engine = Engine::new(PathBuf::from("concurrent.db"));
         b.iter(|| {
             rt.block_on(async {
                 let mut tasks = Vec::new();
@@ -183,7 +180,6 @@ fn engine_concurrency_benchmark(c: &mut Criterion) {
 
     // Concurrent benchmark for get.
     group.bench_function("get", |b| {
-        let engine = Engine::new(PathBuf::from("concurrent.db"));
         b.iter(|| {
             rt.block_on(async {
                 let mut tasks = Vec::new();
@@ -207,7 +203,6 @@ fn engine_concurrency_benchmark(c: &mut Criterion) {
 
     // Concurrent benchmark for scan.
     group.bench_function("scan", |b| {
-        let engine = Engine::new(PathBuf::from("concurrent.db"));
         b.iter(|| {
             rt.block_on(async {
                 let mut tasks = Vec::new();
@@ -230,7 +225,6 @@ fn engine_concurrency_benchmark(c: &mut Criterion) {
 
     // Concurrent benchmark for delete.
     group.bench_function("del", |b| {
-        let engine = Engine::new(PathBuf::from("concurrent.db"));
         b.iter(|| {
             rt.block_on(async {
                 let mut tasks = Vec::new();
@@ -255,11 +249,48 @@ fn engine_concurrency_benchmark(c: &mut Criterion) {
     group.finish();
 }
 
+/// Benchmark opening a database, which replays its whole log.
+fn engine_startup_benchmark(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("startup.db");
+    // Small segments spread the log over several of them, which are replayed in parallel.
+    let options = EngineOptions {
+        segment_size: 4 * 1024 * 1024,
+        background_compaction: false,
+        ..EngineOptions::default()
+    };
+    let entries = 200_000;
+
+    // Write the log once, outside the timed iterations.
+    let engine = Engine::open_with_options(&path, options.clone()).unwrap();
+    rt.block_on(async {
+        for i in 0..entries {
+            let key = format!("key{:08}", i);
+            engine.set(key.as_bytes(), vec![0; 100]).await.unwrap();
+        }
+        engine.close().await.unwrap();
+    });
+    drop(engine);
+
+    let mut group = c.benchmark_group("engine_startup");
+    group.sample_size(20);
+    group.throughput(Throughput::Elements(entries));
+    group.bench_function("open", |b| {
+        b.iter(|| {
+            let engine = Engine::open_with_options(black_box(&path), options.clone()).unwrap();
+            rt.block_on(engine.close()).unwrap();
+        })
+    });
+    group.finish();
+}
+
 criterion_group!(
     benches,
     engine_benchmark,
     engine_short_benchmark,
     engine_long_benchmark,
-    engine_concurrency_benchmark
+    engine_concurrency_benchmark,
+    engine_startup_benchmark
 );
 criterion_main!(benches);
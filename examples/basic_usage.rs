@@ -6,7 +6,7 @@ use tegdb::Engine;
 async fn main() {
     // Initialize the database engine using a file-based store.
     let path = PathBuf::from("test.db");
-    let engine = Engine::new(path.clone());
+    let engine = Engine::open(path.clone()).expect("Failed to open database");
 
     // Store a key-value pair.
     let key = b"key";
@@ -17,8 +17,9 @@ async fn main() {
 
     // Retrieve and print the value for the provided key.
     match engine.get(key).await {
-        Some(get_value) => println!("Got value: {}", String::from_utf8_lossy(&get_value)),
-        None => println!("Key not found"),
+        Ok(Some(get_value)) => println!("Got value: {}", String::from_utf8_lossy(&get_value)),
+        Ok(None) => println!("Key not found"),
+        Err(e) => eprintln!("Error getting value: {}", e),
     }
 
     // Delete the key-value pair.
@@ -20,8 +20,8 @@ fn main() {
 
     // Initialize the engine and remove any pre-existing data file.
     let path = PathBuf::from("test_concurrent.db");
-    let _ = fs::remove_file(&path);
-    let engine = Engine::new(path);
+    let _ = fs::remove_dir_all(&path);
+    let engine = Engine::open(path).expect("Failed to open database");
 
     // Shared metrics for tracking set() and get() call counts.
     let set_metrics = Arc::new(Mutex::new(Vec::<usize>::new()));
@@ -0,0 +1,12 @@
+//! Feeds arbitrary bytes to the log parser, which must never panic on them. Run with
+//! `cargo +nightly fuzz run parse_records` from the repository root.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut records = tegdb::parse_records(data);
+    for _ in records.by_ref() {}
+    assert_eq!(records.offset(), data.len() as u64);
+});
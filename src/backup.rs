@@ -0,0 +1,211 @@
+//! Incremental backups.
+//!
+//! A backup directory holds copies of the log's segment files along with a `BACKUP` file listing
+//! them in replay order, each with the number of bytes backed up and a CRC-32 of those bytes, and
+//! naming the comparator of the database, if any, which the restored database records again.
+//! Segments are only ever appended to, so a later backup into the same directory copies just
+//! the bytes appended since the previous one, plus any segments written by compaction, and
+//! removes the copies of segments compaction has since replaced.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::engine::Engine;
+use crate::error::{Error, Result};
+use crate::log;
+
+/// Name of the file listing the backed up segments.
+const BACKUP: &str = "BACKUP";
+// First line of the backup file, identifying its format.
+const BACKUP_HEADER: &str = "tegdb backup 1";
+// Prefix of the backup file line naming the comparator the keys are ordered with, if any.
+const COMPARATOR_PREFIX: &str = "comparator ";
+
+/// Summary of a backup written by [`Backup::create`].
+#[derive(Clone, Copy, Debug)]
+pub struct Backup {
+    /// Number of log bytes copied by this backup.
+    pub bytes_copied: u64,
+    /// Number of log bytes the backup holds in total.
+    pub total_bytes: u64,
+}
+
+// A segment copy listed in the backup file.
+#[derive(Clone, Copy)]
+struct BackedUpSegment {
+    id: u64,
+    len: u64,
+    crc: u32,
+}
+
+impl Backup {
+    /// Backs up the database of `engine` into the directory `dir`, creating it if needed.
+    /// If `dir` already holds a backup of the same database, only the log bytes written since
+    /// then are copied. The backup reflects a single point in time and writes continue while
+    /// it is taken.
+    pub fn create<P: AsRef<Path>>(engine: &Engine, dir: P) -> Result<Backup> {
+        let dir = dir.as_ref();
+        let inner = &engine.engine;
+        std::fs::create_dir_all(dir)?;
+        let (previous, _) = read_backup_file(dir)?;
+        let previous: HashMap<u64, BackedUpSegment> = previous.into_iter().map(|s| (s.id, s)).collect();
+
+        // Compaction must not remove segments while they are copied.
+        let _compacting = inner.compaction_lock.lock().unwrap();
+        let segments = {
+            let _guard = inner.write_lock.lock().unwrap();
+            inner.log.flush_and_wait();
+            inner.log.segments()
+        };
+        let mut bytes_copied = 0;
+        let mut backed_up = Vec::with_capacity(segments.len());
+        for segment in &segments {
+            let mut source = File::open(inner.log.segment_path(segment.id))?;
+            let mut copy = File::options()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(log::segment_path(dir, segment.id))?;
+            let copied = copy.metadata()?.len();
+            let (offset, crc) = match previous.get(&segment.id) {
+                Some(prev) if prev.len <= segment.len && prev.len <= copied => (prev.len, prev.crc),
+                _ => (0, 0),
+            };
+            // Drops anything left behind by an interrupted backup.
+            copy.set_len(offset)?;
+            copy.seek(SeekFrom::End(0))?;
+            source.seek(SeekFrom::Start(offset))?;
+            let crc = copy_bytes(&mut source, &mut copy, segment.len - offset, crc)?;
+            copy.sync_all()?;
+            bytes_copied += segment.len - offset;
+            backed_up.push(BackedUpSegment {
+                id: segment.id,
+                len: segment.len,
+                crc,
+            });
+        }
+        let comparator = inner.options.comparator.map(|comparator| comparator.name);
+        write_backup_file(dir, &backed_up, comparator)?;
+        for id in previous.keys() {
+            if !backed_up.iter().any(|s| s.id == *id) {
+                std::fs::remove_file(log::segment_path(dir, *id))?;
+            }
+        }
+        Ok(Backup {
+            bytes_copied,
+            total_bytes: backed_up.iter().map(|s| s.len).sum(),
+        })
+    }
+
+    /// Restores the backup in `dir` into a new database at `path`, which must not exist yet.
+    /// Every segment is verified against its checksum first; a mismatch is reported as
+    /// [`Error::Corrupted`] and nothing is restored.
+    pub fn restore<P: AsRef<Path>, Q: AsRef<Path>>(dir: P, path: Q) -> Result<()> {
+        let (dir, path) = (dir.as_ref(), path.as_ref());
+        if path.exists() {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("restore destination {} already exists", path.display()),
+            )));
+        }
+        let (segments, comparator) = read_backup_file(dir)?;
+        if segments.is_empty() {
+            return Err(Error::Corrupted(format!("no backup found in {}", dir.display())));
+        }
+        for segment in &segments {
+            let mut copy = File::open(log::segment_path(dir, segment.id))?;
+            let crc = copy_bytes(&mut copy, &mut std::io::sink(), segment.len, 0)?;
+            if crc != segment.crc {
+                return Err(Error::Corrupted(format!(
+                    "backed up segment {} does not match its checksum",
+                    segment.id
+                )));
+            }
+        }
+        std::fs::create_dir_all(path)?;
+        for segment in &segments {
+            let mut copy = File::open(log::segment_path(dir, segment.id))?;
+            let mut restored = File::create(log::segment_path(path, segment.id))?;
+            copy_bytes(&mut copy, &mut restored, segment.len, 0)?;
+            restored.sync_all()?;
+        }
+        let ids: Vec<u64> = segments.iter().map(|s| s.id).collect();
+        log::write_manifest(path, &ids, 0, comparator.as_deref())?;
+        Ok(())
+    }
+}
+
+// Copies exactly `len` bytes from `from` to `to`, returning the CRC-32 of the bytes copied
+// continued from `crc`.
+fn copy_bytes(from: &mut File, to: &mut impl Write, len: u64, mut crc: u32) -> Result<u32> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = remaining.min(buf.len() as u64) as usize;
+        from.read_exact(&mut buf[..n]).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                Error::Corrupted("segment is shorter than expected".to_string())
+            } else {
+                e.into()
+            }
+        })?;
+        to.write_all(&buf[..n])?;
+        crc = log::crc32(crc, &buf[..n]);
+        remaining -= n as u64;
+    }
+    Ok(crc)
+}
+
+// Returns the segments listed in the backup file along with the name of the comparator it records.
+fn read_backup_file(dir: &Path) -> Result<(Vec<BackedUpSegment>, Option<String>)> {
+    let contents = match std::fs::read_to_string(dir.join(BACKUP)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), None)),
+        Err(e) => return Err(e.into()),
+    };
+    let mut lines = contents.lines().peekable();
+    if lines.next() != Some(BACKUP_HEADER) {
+        return Err(Error::Corrupted("unrecognized backup header".to_string()));
+    }
+    let comparator = lines
+        .next_if(|line| line.starts_with(COMPARATOR_PREFIX))
+        .map(|line| line[COMPARATOR_PREFIX.len()..].to_string());
+    let segments = lines
+        .map(|line| {
+            let invalid = || Error::Corrupted(format!("invalid segment in backup: {}", line));
+            let mut fields = line.split(' ').map(|field| field.parse::<u64>().map_err(|_| invalid()));
+            let (Some(id), Some(len), Some(crc), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid());
+            };
+            Ok(BackedUpSegment {
+                id: id?,
+                len: len?,
+                crc: u32::try_from(crc?).map_err(|_| invalid())?,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok((segments, comparator))
+}
+
+// Replaces the backup file atomically, so an interrupted backup leaves the previous one intact.
+fn write_backup_file(dir: &Path, segments: &[BackedUpSegment], comparator: Option<&str>) -> Result<()> {
+    let mut contents = String::from(BACKUP_HEADER);
+    if let Some(name) = comparator {
+        contents.push_str(&format!("\n{}{}", COMPARATOR_PREFIX, name));
+    }
+    for segment in segments {
+        contents.push_str(&format!("\n{} {} {}", segment.id, segment.len, segment.crc));
+    }
+    contents.push('\n');
+    let tmp_path = dir.join(format!("{}.tmp", BACKUP));
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(contents.as_bytes())?;
+    tmp.sync_all()?;
+    std::fs::rename(tmp_path, dir.join(BACKUP))?;
+    Ok(())
+}
+
@@ -0,0 +1,242 @@
+//! Inspects and edits a tegdb database from the command line.
+//!
+//! Usage: `tegdb-cli PATH [--tree NAME] COMMAND [ARGS]`. Keys and values are taken from the
+//! arguments as given, and printed with non-printable bytes escaped. Commands that only read
+//! open the database read-only, so they can run while another process has it open read-only
+//! too; the others need exclusive access.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use tegdb::blocking::{Engine, Tree};
+use tegdb::{EngineOptions, Verification};
+
+const USAGE: &str = "Usage: tegdb-cli PATH [--tree NAME] COMMAND [ARGS]
+
+Commands:
+    get KEY                          Print the value of a key
+    set KEY VALUE [--ttl MILLIS]     Set a key, optionally expiring it
+    del KEY                          Delete a key
+    scan [START [END]] [--prefix PREFIX] [--limit N]
+                                     Print the pairs within a range or with a prefix
+    stats                            Print the number of keys and the size of the database
+    compact                          Compact the log
+    dump FILE                        Write every tree to FILE in the dump format, or to - for stdout
+    restore FILE                     Write every pair of a dump in FILE into the database
+    verify                           Read back every value of every tree
+    fsck [--repair] [--report FILE]  Check the log of a database that is not open for writing,
+                                     removing corrupt entries if --repair is given. Keys that
+                                     were lost are listed as TREE<tab>KEY, with an empty TREE
+                                     for the default tree, to FILE or else to stdout";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let (path, mut args) = match args {
+        [path, args @ ..] if !path.starts_with('-') => (path, args),
+        _ => return Err(USAGE.to_string()),
+    };
+    let mut tree_name = None;
+    if let [flag, name, rest @ ..] = args {
+        if flag == "--tree" {
+            tree_name = Some(name.as_str());
+            args = rest;
+        }
+    }
+    let Some((command, args)) = args.split_first() else {
+        return Err(USAGE.to_string());
+    };
+    if command == "fsck" {
+        return fsck(path, args);
+    }
+    let read_only = matches!(command.as_str(), "get" | "scan" | "stats" | "dump" | "verify");
+    let options = EngineOptions {
+        read_only,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path, options)
+        .map_err(|e| format!("failed to open database {}: {}", path, e))?;
+    let tree = match tree_name {
+        // Trees cannot be created by commands that only read.
+        Some(name) if read_only && !engine.tree_names().iter().any(|n| n == name) => {
+            return Err(format!("no tree named {:?}", name));
+        }
+        Some(name) => engine.open_tree(name).map_err(|e| e.to_string())?,
+        None => (*engine).clone(),
+    };
+    let result = execute(&engine, &tree, command, args);
+    // Waits for the writes to be durable before the process exits.
+    let closed = engine.close().map_err(|e| format!("failed to close database {}: {}", path, e));
+    result.and(closed)
+}
+
+fn execute(engine: &Engine, tree: &Tree, command: &str, args: &[String]) -> Result<(), String> {
+    let mut stdout = io::stdout().lock();
+    let result = match (command, args) {
+        ("get", [key]) => match tree.get(key.as_bytes()).map_err(|e| e.to_string())? {
+            Some(value) => writeln!(stdout, "{}", value.escape_ascii()),
+            None => return Err(format!("key not found: {}", key)),
+        },
+        ("set", [key, value, rest @ ..]) => {
+            let result = match rest {
+                [] => tree.set(key.as_bytes(), value.as_bytes().to_vec()),
+                [flag, ttl] if flag == "--ttl" => {
+                    let ttl = ttl.parse().map_err(|_| format!("invalid ttl: {}", ttl))?;
+                    let ttl = Duration::from_millis(ttl);
+                    tree.set_with_ttl(key.as_bytes(), value.as_bytes().to_vec(), ttl)
+                }
+                _ => return Err(USAGE.to_string()),
+            };
+            return result.map_err(|e| e.to_string());
+        }
+        ("del", [key]) => return tree.del(key.as_bytes()).map_err(|e| e.to_string()),
+        ("scan", args) => return scan(tree, args, &mut stdout),
+        ("stats", []) => stats(engine, &mut stdout),
+        ("compact", []) => {
+            let reclaimed = engine.compact().map_err(|e| e.to_string())?;
+            writeln!(stdout, "Reclaimed {} bytes", reclaimed)
+        }
+        ("dump", [file]) => {
+            let count = if file == "-" {
+                engine.export(&mut stdout)
+            } else {
+                let file = File::create(file).map_err(|e| format!("failed to create {}: {}", file, e))?;
+                engine.export(file)
+            };
+            let count = count.map_err(|e| e.to_string())?;
+            eprintln!("Dumped {} pairs", count);
+            Ok(())
+        }
+        ("restore", [file]) => {
+            let file = File::open(file).map_err(|e| format!("failed to open {}: {}", file, e))?;
+            let count = engine.import(file).map_err(|e| e.to_string())?;
+            writeln!(stdout, "Restored {} pairs", count)
+        }
+        ("verify", []) => {
+            let count = verify(engine)?;
+            writeln!(stdout, "Read {} values", count)
+        }
+        _ => return Err(USAGE.to_string()),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+fn scan(tree: &Tree, args: &[String], stdout: &mut impl Write) -> Result<(), String> {
+    let mut bounds = Vec::new();
+    let mut prefix = None;
+    let mut limit = usize::MAX;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--prefix" => prefix = Some(args.next().ok_or(USAGE)?.as_bytes()),
+            "--limit" => {
+                let value = args.next().ok_or(USAGE)?;
+                limit = value.parse().map_err(|_| format!("invalid limit: {}", value))?;
+            }
+            _ if bounds.len() < 2 => bounds.push(arg.as_bytes().to_vec()),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let pairs = match (prefix, bounds.as_slice()) {
+        (Some(prefix), []) => tree.scan_prefix(prefix),
+        (None, []) => tree.scan(..),
+        (None, [start]) => tree.scan(start.clone()..),
+        (None, [start, end]) if start < end => tree.scan(start.clone()..end.clone()),
+        (None, [_, _]) => return Ok(()),
+        _ => return Err(USAGE.to_string()),
+    };
+    for (key, value) in pairs.map_err(|e| e.to_string())?.take(limit) {
+        writeln!(stdout, "{}\t{}", key.escape_ascii(), value.escape_ascii()).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn stats(engine: &Engine, stdout: &mut impl Write) -> io::Result<()> {
+    let size = engine.size_on_disk().map_err(io::Error::other)?;
+    let stats = engine.stats();
+    writeln!(stdout, "Size on disk: {} bytes", size)?;
+    writeln!(stdout, "Log size: {} bytes, {} of them live", stats.log_bytes, stats.live_bytes)?;
+    writeln!(stdout, "Last sequence: {}", engine.last_sequence())?;
+    writeln!(stdout, "Keys in the default tree: {}", engine.len())?;
+    for name in engine.tree_names() {
+        let tree = engine.open_tree(&name).map_err(io::Error::other)?;
+        writeln!(stdout, "Keys in tree {:?}: {}", name, tree.len())?;
+    }
+    Ok(())
+}
+
+// Reads every value of every tree, returning how many were read.
+fn verify(engine: &Engine) -> Result<u64, String> {
+    let mut trees = vec![("default".to_string(), (**engine).clone())];
+    for name in engine.tree_names() {
+        let tree = engine.open_tree(&name).map_err(|e| e.to_string())?;
+        trees.push((name, tree));
+    }
+    let mut count = 0;
+    for (name, tree) in trees {
+        let keys = tree.keys(..).map_err(|e| e.to_string())?;
+        for key in keys {
+            tree.get(&key).map_err(|e| format!("tree {:?}, key {}: {}", name, key.escape_ascii(), e))?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// Checks and optionally repairs the log without opening the database, which fails on corrupt
+// logs. Corruption left in place is reported as an error.
+fn fsck(path: &str, args: &[String]) -> Result<(), String> {
+    let mut repair = false;
+    let mut report = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--repair" => repair = true,
+            "--report" => report = Some(args.next().ok_or(USAGE)?),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let verification = if repair { Engine::repair(path) } else { Engine::verify(path) };
+    let verification = verification.map_err(|e| format!("failed to check {}: {}", path, e))?;
+    print_verification(&verification, report).map_err(|e| e.to_string())?;
+    if verification.is_ok() || verification.repaired {
+        Ok(())
+    } else {
+        let count = verification.corruptions.len();
+        Err(format!("found {} corrupt ranges; run fsck with --repair to remove them", count))
+    }
+}
+
+fn print_verification(verification: &Verification, report: Option<&String>) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    let (segments, entries) = (verification.segments, verification.entries);
+    writeln!(stdout, "Checked {} segments holding {} intact entries", segments, entries)?;
+    for c in &verification.corruptions {
+        let (segment, offset, len) = (c.segment, c.offset, c.len);
+        writeln!(stdout, "Segment {}: {} corrupt bytes at offset {}: {}", segment, len, offset, c.reason)?;
+    }
+    let mut lost = String::new();
+    for lost_key in &verification.lost_keys {
+        let tree = lost_key.tree.as_deref().unwrap_or_default();
+        lost.push_str(&format!("{}\t{}\n", tree.escape_default(), lost_key.key.escape_ascii()));
+    }
+    match report {
+        Some(file) => std::fs::write(file, lost)?,
+        None => stdout.write_all(lost.as_bytes())?,
+    }
+    if verification.repaired {
+        writeln!(stdout, "Repaired the log")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,241 @@
+//! An HTTP API over the database, served with axum.
+//!
+//! Keys appear percent-encoded in paths and query strings:
+//!
+//! - `GET /keys/KEY` returns the value of a key, or 404 if it does not exist.
+//! - `PUT /keys/KEY` sets a key to the request body, expiring after `?ttl=MILLIS` if given.
+//! - `DELETE /keys/KEY` deletes a key.
+//! - `GET /scan` streams the pairs within `?start=KEY&end=KEY`, with the end excluded and both
+//!   optional, or the pairs whose key starts with `?prefix=KEY`, at most `?limit=N` of them. The
+//!   response holds a line `KEY=VALUE` per pair, both percent-encoded, and is sent as it is read.
+//! - `POST /batch` applies the lines `set KEY VALUE` and `del KEY` of the request body in order,
+//!   with keys and values percent-encoded. The lines are all checked before any is applied, but
+//!   they are not applied atomically.
+//! - `GET /metrics` returns the engine's statistics in the Prometheus text format.
+//!
+//! Errors are reported with a status code and a plain-text message.
+
+use std::future::ready;
+use std::io;
+use std::net::TcpListener;
+use std::ops::Bound;
+use std::time::Duration;
+
+use axum::body::{Body, Bytes};
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::{header, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{any, get, post};
+use axum::Router;
+use futures_util::StreamExt;
+use tegdb::{Engine, Error};
+
+// Guards allocations against oversized requests.
+const MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+
+// An engine error, answered with the status matching its cause.
+struct Failure(Error);
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Self {
+        Self(e)
+    }
+}
+
+impl IntoResponse for Failure {
+    fn into_response(self) -> Response {
+        let status = match &self.0 {
+            Error::KeyTooLarge { .. } => StatusCode::BAD_REQUEST,
+            Error::Io(e) if e.kind() == io::ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+            Error::ValueTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::ReadOnly => StatusCode::FORBIDDEN,
+            Error::Rejected(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::NoSpace | Error::IndexMemoryLimit { .. } => StatusCode::INSUFFICIENT_STORAGE,
+            Error::Closed => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        error(status, self.0.to_string())
+    }
+}
+
+/// Answers the requests sent over the connections accepted by `listener`, on an async runtime
+/// of its own, until accepting fails.
+pub(crate) fn serve(engine: Engine, listener: TcpListener) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    runtime.block_on(async {
+        let app = Router::new()
+            .route("/keys/{*key}", any(item))
+            .route("/scan", get(scan))
+            .route("/batch", post(batch))
+            .route("/metrics", get(metrics))
+            .fallback(|| async { error(StatusCode::NOT_FOUND, "no such endpoint") })
+            .layer(DefaultBodyLimit::max(MAX_BODY_LEN))
+            .with_state(engine);
+        axum::serve(tokio::net::TcpListener::from_std(listener)?, app).await
+    })
+}
+
+async fn item(
+    State(engine): State<Engine>,
+    method: Method,
+    uri: Uri,
+    body: Bytes,
+) -> Result<Response, Failure> {
+    let key = uri.path().strip_prefix("/keys/").unwrap_or_default();
+    let Some(key) = percent_decode(key.as_bytes()) else {
+        return Ok(error(StatusCode::BAD_REQUEST, "invalid percent-encoding in key"));
+    };
+    let Some(query) = parse_query(&uri) else {
+        return Ok(error(StatusCode::BAD_REQUEST, "invalid query string"));
+    };
+    match method {
+        Method::GET => match engine.get(&key).await? {
+            Some(value) => {
+                Ok(([(header::CONTENT_TYPE, "application/octet-stream")], value).into_response())
+            }
+            None => Ok(error(StatusCode::NOT_FOUND, "no such key")),
+        },
+        Method::PUT => {
+            let value = body.to_vec();
+            match query_param(&query, "ttl").map(number) {
+                None => engine.set(&key, value).await?,
+                Some(Some(ttl)) => {
+                    engine.set_with_ttl(&key, value, Duration::from_millis(ttl)).await?
+                }
+                Some(None) => return Ok(error(StatusCode::BAD_REQUEST, "invalid ttl")),
+            }
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        Method::DELETE => {
+            engine.del(&key).await?;
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        _ => Ok(error(StatusCode::METHOD_NOT_ALLOWED, "use GET, PUT or DELETE")),
+    }
+}
+
+// Streams the requested pairs as they are read, or answers with an error if the request is
+// invalid. A read failing once the status has been sent cuts the response short.
+async fn scan(State(engine): State<Engine>, uri: Uri) -> Response {
+    let Some(query) = parse_query(&uri) else {
+        return error(StatusCode::BAD_REQUEST, "invalid query string");
+    };
+    let limit = match query_param(&query, "limit").map(number) {
+        None => u64::MAX,
+        Some(Some(limit)) => limit,
+        Some(None) => return error(StatusCode::BAD_REQUEST, "invalid limit"),
+    };
+    let prefix = query_param(&query, "prefix").unwrap_or_default().to_vec();
+    let start = query_param(&query, "start").map(<[u8]>::to_vec);
+    let end = query_param(&query, "end").map(<[u8]>::to_vec);
+    if !prefix.is_empty() && (start.is_some() || end.is_some()) {
+        return error(StatusCode::BAD_REQUEST, "prefix cannot be combined with start or end");
+    }
+    let start = start.unwrap_or_else(|| prefix.clone());
+    let content_type = [(header::CONTENT_TYPE, "text/plain")];
+    if end.as_ref().is_some_and(|end| &start >= end) {
+        return (content_type, Body::empty()).into_response();
+    }
+    let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+    let lines = engine
+        .scan_stream((Bound::Included(start), end))
+        .take_while(move |pair| {
+            ready(pair.as_ref().map_or(true, |(key, _)| key.starts_with(&prefix)))
+        })
+        .take(usize::try_from(limit).unwrap_or(usize::MAX))
+        .map(|pair| {
+            pair.map(|(key, value)| {
+                let mut line = percent_encode(&key);
+                line.push(b'=');
+                line.extend(percent_encode(&value));
+                line.push(b'\n');
+                line
+            })
+        });
+    (content_type, Body::from_stream(lines)).into_response()
+}
+
+async fn batch(State(engine): State<Engine>, body: Bytes) -> Result<Response, Failure> {
+    let mut ops = Vec::new();
+    for line in body.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        let words: Option<Vec<Vec<u8>>> = line.split(|&b| b == b' ').map(percent_decode).collect();
+        match words.as_deref() {
+            Some([op, key, value]) if op == b"set" => ops.push((key.clone(), Some(value.clone()))),
+            Some([op, key]) if op == b"del" => ops.push((key.clone(), None)),
+            _ => {
+                let line = String::from_utf8_lossy(line);
+                return Ok(error(StatusCode::BAD_REQUEST, format!("invalid batch line: {}", line)));
+            }
+        }
+    }
+    for (key, value) in ops {
+        match value {
+            Some(value) => engine.set(&key, value).await?,
+            None => engine.del(&key).await?,
+        }
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn metrics(State(engine): State<Engine>) -> Response {
+    ([(header::CONTENT_TYPE, "text/plain")], engine.stats().to_prometheus()).into_response()
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> Response {
+    let mut body = message.into();
+    body.push('\n');
+    (status, [(header::CONTENT_TYPE, "text/plain")], body).into_response()
+}
+
+// Decodes the query string of `uri`, or returns `None` if it is not validly percent-encoded.
+fn parse_query(uri: &Uri) -> Option<Vec<(String, Vec<u8>)>> {
+    uri.query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            Some((name.to_string(), percent_decode(value.as_bytes())?))
+        })
+        .collect()
+}
+
+fn query_param<'a>(query: &'a [(String, Vec<u8>)], name: &str) -> Option<&'a [u8]> {
+    query.iter().find(|(param, _)| param == name).map(|(_, value)| value.as_slice())
+}
+
+fn number(digits: &[u8]) -> Option<u64> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+fn percent_decode(encoded: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [*bytes.next()?, *bytes.next()?];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    Some(decoded)
+}
+
+// Encodes every byte other than ASCII letters, digits and `-._~`.
+fn percent_encode(bytes: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(bytes.len());
+    for &byte in bytes {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte);
+        } else {
+            encoded.extend(format!("%{:02X}", byte).into_bytes());
+        }
+    }
+    encoded
+}
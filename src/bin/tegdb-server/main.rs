@@ -0,0 +1,107 @@
+//! Serves a tegdb database over the network, so that it can be shared by services written in
+//! any language.
+//!
+//! Usage: `tegdb-server [--addr ADDR] [--http ADDR] PATH`
+//!
+//! The Redis protocol is served on `--addr`, `127.0.0.1:6379` by default, and an HTTP API is
+//! served on `--http` if it is given. Each Redis connection is served by its own thread, and the
+//! HTTP API by an async runtime.
+
+mod http;
+mod resp;
+
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::process::ExitCode;
+use std::thread;
+
+use tegdb::blocking::Engine;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:6379";
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let mut addr = DEFAULT_ADDR.to_string();
+    let mut http_addr = None;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--addr" => match args.next() {
+                Some(value) => addr = value,
+                None => return usage(),
+            },
+            "--http" => match args.next() {
+                Some(value) => http_addr = Some(value),
+                None => return usage(),
+            },
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => return usage(),
+        }
+    }
+    let Some(path) = path else {
+        return usage();
+    };
+    let engine = match Engine::open(&path) {
+        Ok(engine) => engine,
+        Err(e) => {
+            eprintln!("Failed to open database {}: {}", path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(listener) = listen(&addr, "") else {
+        return ExitCode::FAILURE;
+    };
+    if let Some(http_addr) = http_addr {
+        let Some(http_listener) = listen(&http_addr, " for HTTP") else {
+            return ExitCode::FAILURE;
+        };
+        let engine = engine.as_async().clone();
+        thread::spawn(move || {
+            if let Err(e) = http::serve(engine, http_listener) {
+                eprintln!("HTTP server failed: {}", e);
+            }
+        });
+    }
+    accept(&engine, listener, resp::serve);
+    ExitCode::SUCCESS
+}
+
+fn usage() -> ExitCode {
+    eprintln!("Usage: tegdb-server [--addr ADDR] [--http ADDR] PATH");
+    ExitCode::FAILURE
+}
+
+// Binds `addr` and reports the address actually listened on.
+fn listen(addr: &str, protocol: &str) -> Option<TcpListener> {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to listen{} on {}: {}", protocol, addr, e);
+            return None;
+        }
+    };
+    match listener.local_addr() {
+        Ok(local_addr) => println!("Listening{} on {}", protocol, local_addr),
+        Err(_) => println!("Listening{} on {}", protocol, addr),
+    }
+    Some(listener)
+}
+
+// Serves every connection accepted by `listener` with `serve` on a thread of its own.
+fn accept(engine: &Engine, listener: TcpListener, serve: fn(&Engine, TcpStream) -> io::Result<()>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let engine = engine.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(&engine, stream) {
+                eprintln!("Connection failed: {}", e);
+            }
+        });
+    }
+}
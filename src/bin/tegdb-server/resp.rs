@@ -0,0 +1,257 @@
+//! The Redis protocol (RESP), so that any Redis client can use the database.
+//!
+//! The supported commands are `GET`, `SET` (with `EX` or `PX`), `DEL`, `EXPIRE`, `SCAN` (with
+//! `MATCH` and `COUNT`), `PING` and `QUIT`. Setting an empty value deletes the key, as it does
+//! in tegdb. `SCAN` cursors count the keys visited so far, and `MATCH` patterns support `*`,
+//! `?` and `\` escapes.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use tegdb::blocking::Engine;
+
+
+// Guards allocations against oversized requests; no key or value is this large.
+const MAX_BULK_LEN: usize = 512 * 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Answers the commands sent over `stream` until the client disconnects or quits.
+pub(crate) fn serve(engine: &Engine, stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                Reply::Error(format!("Protocol error: {}", e)).write_to(&mut writer)?;
+                return writer.flush();
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let reply = if quit { Reply::Simple("OK") } else { execute(engine, &args) };
+        reply.write_to(&mut writer)?;
+        // Replies to pipelined commands are sent together.
+        if quit || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", s),
+            Reply::Error(e) => write!(writer, "-ERR {}\r\n", e.replace(['\r', '\n'], " ")),
+            Reply::Integer(i) => write!(writer, ":{}\r\n", i),
+            Reply::Bulk(None) => write!(writer, "$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")
+            }
+            Reply::Array(items) => {
+                write!(writer, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write_to(writer))
+            }
+        }
+    }
+}
+
+fn execute(engine: &Engine, args: &[Vec<u8>]) -> Reply {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    let result = match name.as_str() {
+        "ping" => match args {
+            [_] => Ok(Reply::Simple("PONG")),
+            [_, message] => Ok(Reply::Bulk(Some(message.clone()))),
+            _ => Err(wrong_arity(&name)),
+        },
+        "get" => match args {
+            [_, key] => get(engine, key).map(|value| Reply::Bulk(value.map(|value| value.to_vec()))),
+            _ => Err(wrong_arity(&name)),
+        },
+        "set" if args.len() >= 3 => set(engine, &args[1], &args[2], &args[3..]),
+        "del" if args.len() >= 2 => del(engine, &args[1..]),
+        "expire" => match args {
+            [_, key, seconds] => expire(engine, key, seconds),
+            _ => Err(wrong_arity(&name)),
+        },
+        "scan" if args.len() >= 2 => scan(engine, &args[1], &args[2..]),
+        "set" | "del" | "scan" => Err(wrong_arity(&name)),
+        // Sent by some clients on connecting; no command documentation is provided.
+        "command" => Ok(Reply::Array(Vec::new())),
+        _ => Err(format!("unknown command '{}'", name)),
+    };
+    result.unwrap_or_else(Reply::Error)
+}
+
+fn get(engine: &Engine, key: &[u8]) -> Result<Option<tegdb::Bytes>, String> {
+    engine.get(key).map_err(|e| e.to_string())
+}
+
+fn set(engine: &Engine, key: &[u8], value: &[u8], options: &[Vec<u8>]) -> Result<Reply, String> {
+    let ttl = match options {
+        [] => None,
+        [unit, amount] if unit.eq_ignore_ascii_case(b"EX") => Some(Duration::from_secs(positive(amount)?)),
+        [unit, amount] if unit.eq_ignore_ascii_case(b"PX") => Some(Duration::from_millis(positive(amount)?)),
+        _ => return Err("syntax error".to_string()),
+    };
+    let result = match ttl {
+        Some(ttl) => engine.set_with_ttl(key, value.to_vec(), ttl),
+        None => engine.set(key, value.to_vec()),
+    };
+    result.map_err(|e| e.to_string())?;
+    Ok(Reply::Simple("OK"))
+}
+
+fn del(engine: &Engine, keys: &[Vec<u8>]) -> Result<Reply, String> {
+    let keys: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+    let existing = engine.get_many(&keys).map_err(|e| e.to_string())?;
+    engine.del_many(&keys).map_err(|e| e.to_string())?;
+    Ok(Reply::Integer(existing.iter().filter(|value| value.is_some()).count() as i64))
+}
+
+fn expire(engine: &Engine, key: &[u8], seconds: &[u8]) -> Result<Reply, String> {
+    let seconds = integer(seconds)?;
+    let Some(value) = get(engine, key)? else {
+        return Ok(Reply::Integer(0));
+    };
+    let result = if seconds <= 0 {
+        engine.del(key)
+    } else {
+        engine.set_with_ttl(key, value.to_vec(), Duration::from_secs(seconds as u64))
+    };
+    result.map_err(|e| e.to_string())?;
+    Ok(Reply::Integer(1))
+}
+
+fn scan(engine: &Engine, cursor: &[u8], options: &[Vec<u8>]) -> Result<Reply, String> {
+    let cursor = usize::try_from(integer(cursor)?).map_err(|_| "invalid cursor".to_string())?;
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in options.chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case(b"MATCH") => pattern = Some(value.as_slice()),
+            [name, value] if name.eq_ignore_ascii_case(b"COUNT") => {
+                count = usize::try_from(positive(value)?).map_err(|_| "invalid count".to_string())?
+            }
+            _ => return Err("syntax error".to_string()),
+        }
+    }
+    let keys: Vec<tegdb::Bytes> = engine.keys(..)
+        .map_err(|e| e.to_string())?
+        .skip(cursor)
+        .take(count)
+        .collect();
+    let next = if keys.len() < count { 0 } else { cursor + count };
+    let keys = keys
+        .into_iter()
+        .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+        .map(|key| Reply::Bulk(Some(key.to_vec())))
+        .collect();
+    Ok(Reply::Array(vec![Reply::Bulk(Some(next.to_string().into_bytes())), Reply::Array(keys)]))
+}
+
+fn wrong_arity(name: &str) -> String {
+    format!("wrong number of arguments for '{}' command", name)
+}
+
+fn integer(arg: &[u8]) -> Result<i64, String> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| "value is not an integer or out of range".to_string())
+}
+
+fn positive(arg: &[u8]) -> Result<u64, String> {
+    match integer(arg)? {
+        i if i > 0 => Ok(i as u64),
+        _ => Err("value is out of range, must be positive".to_string()),
+    }
+}
+
+/// Returns true if `key` matches the glob `pattern`, where `*` matches any run of bytes, `?`
+/// matches any single byte and `\` matches the byte after it literally.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => (0..=key.len()).any(|skip| glob_match(rest, &key[skip..])),
+        Some((b'?', rest)) => !key.is_empty() && glob_match(rest, &key[1..]),
+        Some((b'\\', [escaped, rest @ ..])) => {
+            key.first() == Some(escaped) && glob_match(rest, &key[1..])
+        }
+        Some((byte, rest)) => key.first() == Some(byte) && glob_match(rest, &key[1..]),
+    }
+}
+
+/// Reads the next command, either as an array of bulk strings or as an inline command made of
+/// whitespace-separated words. Returns `None` once the client has disconnected.
+fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        let words = line.split(u8::is_ascii_whitespace).filter(|word| !word.is_empty());
+        return Ok(Some(words.map(<[u8]>::to_vec).collect()));
+    };
+    let count = length(count)?;
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| invalid("unexpected end of command"))?;
+        let len = length(line.strip_prefix(b"$").ok_or_else(|| invalid("expected a bulk string"))?)?;
+        if len > MAX_BULK_LEN {
+            return Err(invalid("bulk string is too long"));
+        }
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid("bulk string is not terminated"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+// Reads a line terminated by `\r\n` or `\n`, without its terminator.
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.take(MAX_BULK_LEN as u64).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid("line is too long or not terminated"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn length(digits: &[u8]) -> io::Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid("invalid length"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
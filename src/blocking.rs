@@ -0,0 +1,539 @@
+//! A synchronous API mirroring the asynchronous one.
+//!
+//! Most of the engine's async operations do their work on the thread polling them, reading
+//! and writing the disk and waiting for the engine's locks there, so they block that thread
+//! until they complete on first poll. Those that wait on the log writer thread or run for
+//! long, such as [`crate::Engine::sync`] and [`crate::Engine::compact`], are completed from
+//! other threads instead. The types in this module run either kind to completion on the
+//! calling thread, parking it until the future is woken, so command-line tools and
+//! applications without an async runtime can use the engine directly. Both APIs share the
+//! same engine: [`Engine::as_async`] and `From` convert between them without reopening the
+//! database.
+
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::io::{Read, Write};
+use std::ops::{Deref, RangeBounds};
+use std::path::Path;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_core::Stream;
+
+use crate::changes::Change;
+use crate::error::Result;
+use crate::health::HealthReport;
+use crate::history::{At, Version};
+use crate::options::EngineOptions;
+use crate::scan::{Iter, Keys, Page, Pairs};
+use crate::stats::{SpaceStats, Stats, TreeStats};
+use crate::tree::Entry;
+use crate::verify::Verification;
+use crate::watch::{Event, Filter};
+
+/// A synchronous handle to an engine; see [`crate::Engine`]. It dereferences to its default
+/// [`Tree`].
+#[derive(Clone)]
+pub struct Engine {
+    engine: crate::Engine,
+    tree: Tree,
+}
+
+impl Engine {
+    /// Opens the database stored in the directory at `path`; see [`crate::Engine::open`].
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(crate::Engine::open(path)?.into())
+    }
+
+    /// Opens the database stored at `path` with the given options.
+    pub fn open_with_options<P: AsRef<Path>>(path: P, options: EngineOptions) -> Result<Self> {
+        Ok(crate::Engine::open_with_options(path, options)?.into())
+    }
+
+    /// Creates a database that is deleted once closed or dropped; see
+    /// [`crate::Engine::open_temporary`].
+    pub fn open_temporary() -> Result<Self> {
+        Ok(crate::Engine::open_temporary()?.into())
+    }
+
+    /// Creates a temporary database with the given options.
+    pub fn open_temporary_with_options(options: EngineOptions) -> Result<Self> {
+        Ok(crate::Engine::open_temporary_with_options(options)?.into())
+    }
+
+    /// Returns the directory the database is stored in.
+    pub fn path(&self) -> &Path {
+        self.engine.path()
+    }
+
+    /// Returns the asynchronous handle to the same engine.
+    pub fn as_async(&self) -> &crate::Engine {
+        &self.engine
+    }
+
+    /// Opens the tree called `name`, creating it if it does not exist yet; see
+    /// [`crate::Engine::open_tree`].
+    pub fn open_tree(&self, name: &str) -> Result<Tree> {
+        Ok(self.engine.open_tree(name)?.into())
+    }
+
+    /// Deletes the tree called `name` along with all of its keys, returning whether it existed.
+    pub fn drop_tree(&self, name: &str) -> Result<bool> {
+        self.engine.drop_tree(name)
+    }
+
+    /// Returns the names of the trees created with [`Engine::open_tree`], in order.
+    pub fn tree_names(&self) -> Vec<String> {
+        self.engine.tree_names()
+    }
+
+    /// Returns every tree created with [`Engine::open_tree`] along with its name; see
+    /// [`crate::Engine::trees`].
+    pub fn trees(&self) -> Result<Vec<(String, Tree)>> {
+        let trees = self.engine.trees()?;
+        Ok(trees.into_iter().map(|(name, tree)| (name, tree.into())).collect())
+    }
+
+    /// Returns the number of bytes the database's files occupy on disk.
+    pub fn size_on_disk(&self) -> Result<u64> {
+        self.engine.size_on_disk()
+    }
+
+    /// Returns the sequence number of the latest write to any tree.
+    pub fn last_sequence(&self) -> u64 {
+        self.engine.last_sequence()
+    }
+
+    /// Records that `consumer` has processed every write up to `sequence`; see
+    /// [`crate::Engine::acknowledge`].
+    pub fn acknowledge(&self, consumer: &str, sequence: u64) -> Result<()> {
+        block_on(self.engine.acknowledge(consumer, sequence))
+    }
+
+    /// Stops `consumer` from holding back compaction; see [`crate::Engine::remove_consumer`].
+    pub fn remove_consumer(&self, consumer: &str) -> Result<bool> {
+        block_on(self.engine.remove_consumer(consumer))
+    }
+
+    /// Returns the consumers holding back compaction along with the sequence number each
+    /// acknowledged.
+    pub fn consumers(&self) -> Vec<(String, u64)> {
+        self.engine.consumers()
+    }
+
+    /// Returns the sequence number up to which compaction may drop overwritten values and
+    /// deletions; see [`crate::Engine::gc_horizon`].
+    pub fn gc_horizon(&self) -> u64 {
+        self.engine.gc_horizon()
+    }
+
+    /// Returns statistics about the contents of the database and about its activity.
+    pub fn stats(&self) -> Stats {
+        self.engine.stats()
+    }
+
+    /// Returns how much of the log compaction could reclaim; see [`crate::Engine::space_stats`].
+    pub fn space_stats(&self) -> SpaceStats {
+        self.engine.space_stats()
+    }
+
+    /// Returns the state of the engine; see [`crate::Engine::health`].
+    pub fn health(&self) -> HealthReport {
+        self.engine.health()
+    }
+
+    /// Checks the sealed segments of the log while the database stays open; see
+    /// [`crate::Engine::scrub`].
+    pub fn scrub(&self) -> Result<Verification> {
+        block_on(self.engine.scrub())
+    }
+
+    /// Waits until every write made so far has been handed to the operating system; see
+    /// [`crate::Engine::flush`].
+    pub fn flush(&self) -> Result<()> {
+        block_on(self.engine.flush())
+    }
+
+    /// Waits until every write made so far has been fsynced to disk; see
+    /// [`crate::Engine::sync`].
+    pub fn sync(&self) -> Result<()> {
+        block_on(self.engine.sync())
+    }
+
+    /// Closes the database, waiting for every write to be fsynced and releasing its lock; see
+    /// [`crate::Engine::close`].
+    pub fn close(&self) -> Result<()> {
+        block_on(self.engine.close())
+    }
+
+    /// Rewrites the log so it only contains live entries; see [`crate::Engine::compact`].
+    pub fn compact(&self) -> Result<u64> {
+        block_on(self.engine.compact())
+    }
+
+    /// Writes a compacted copy of the database to a new directory at `path`; see
+    /// [`crate::Engine::checkpoint`].
+    pub fn checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        block_on(self.engine.checkpoint(path))
+    }
+
+    /// Writes a copy of the database to a new directory at `path` that shares its sealed
+    /// segments; see [`crate::Engine::snapshot_dir`].
+    pub fn snapshot_dir<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        block_on(self.engine.snapshot_dir(path))
+    }
+
+    /// Writes every key-value pair of every tree to `writer` in the dump format; see
+    /// [`crate::Engine::export`].
+    pub fn export<W: Write>(&self, writer: W) -> Result<u64> {
+        self.engine.export(writer)
+    }
+
+    /// Writes every key-value pair of a dump into the database; see [`crate::Engine::import`].
+    pub fn import<R: Read>(&self, reader: R) -> Result<u64> {
+        self.engine.import(reader)
+    }
+
+    /// Merges the dump in the file at `path` into the database as new log segments; see
+    /// [`crate::Engine::ingest_file`].
+    pub fn ingest_file<P: AsRef<Path>>(&self, path: P) -> Result<u64> {
+        block_on(self.engine.ingest_file(path))
+    }
+
+    /// Checks the log of the database at `path`; see [`crate::Engine::verify`].
+    pub fn verify<P: AsRef<Path>>(path: P) -> Result<Verification> {
+        crate::Engine::verify(path)
+    }
+
+    /// Removes the corrupt ranges of the log of the database at `path`; see
+    /// [`crate::Engine::repair`].
+    pub fn repair<P: AsRef<Path>>(path: P) -> Result<Verification> {
+        crate::Engine::repair(path)
+    }
+}
+
+impl From<crate::Engine> for Engine {
+    fn from(engine: crate::Engine) -> Self {
+        let tree = (*engine).clone().into();
+        Self { engine, tree }
+    }
+}
+
+impl From<Engine> for crate::Engine {
+    fn from(engine: Engine) -> Self {
+        engine.engine
+    }
+}
+
+impl fmt::Debug for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.engine.fmt(f)
+    }
+}
+
+/// Iterates over the default tree; see [`Tree::iter`].
+impl IntoIterator for &Engine {
+    type Item = Result<(Bytes, Bytes)>;
+    type IntoIter = Iter;
+
+    fn into_iter(self) -> Iter {
+        self.tree.iter()
+    }
+}
+
+impl Deref for Engine {
+    type Target = Tree;
+
+    fn deref(&self) -> &Tree {
+        &self.tree
+    }
+}
+
+/// A synchronous handle to a tree; see [`crate::Tree`].
+#[derive(Clone)]
+pub struct Tree {
+    tree: crate::Tree,
+}
+
+impl Tree {
+    /// Returns the asynchronous handle to the same tree.
+    pub fn as_async(&self) -> &crate::Tree {
+        &self.tree
+    }
+
+    /// Retrieves the value associated with the given key.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        block_on(self.tree.get(key))
+    }
+
+    /// Returns true if the tree holds `key`, without reading its value from the log; see
+    /// [`crate::Tree::contains_key`].
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        block_on(self.tree.contains_key(key))
+    }
+
+    /// Retrieves the values of several keys at once, in the order the keys are given.
+    pub fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Bytes>>> {
+        block_on(self.tree.get_many(keys))
+    }
+
+    /// Inserts or updates the value for the given key, removing the key if the value is empty.
+    pub fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        block_on(self.tree.set(key, value))
+    }
+
+    /// Inserts or updates the value for the given key, waiting until the write is fsynced.
+    pub fn set_durable(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        block_on(self.tree.set_durable(key, value))
+    }
+
+    /// Inserts or updates the value for the given key so that it expires after `ttl`.
+    pub fn set_with_ttl(&self, key: &[u8], value: Vec<u8>, ttl: Duration) -> Result<()> {
+        block_on(self.tree.set_with_ttl(key, value, ttl))
+    }
+
+    /// Deletes a key-value pair from the store.
+    pub fn del(&self, key: &[u8]) -> Result<()> {
+        block_on(self.tree.del(key))
+    }
+
+    /// Deletes a key-value pair from the store, waiting until the deletion is fsynced.
+    pub fn del_durable(&self, key: &[u8]) -> Result<()> {
+        block_on(self.tree.del_durable(key))
+    }
+
+    /// Loads `pairs`, in ascending key order, into the empty tree and returns the number of
+    /// keys loaded; see [`crate::Tree::bulk_load`].
+    pub fn bulk_load(&self, pairs: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<u64> {
+        block_on(self.tree.bulk_load(pairs))
+    }
+
+    /// Returns a pipeline buffering writes to the tree and submitting them in groups; see
+    /// [`crate::Tree::pipeline`].
+    pub fn pipeline(&self) -> Pipeline {
+        Pipeline(self.tree.pipeline())
+    }
+
+    /// Deletes several keys at once.
+    pub fn del_many(&self, keys: &[&[u8]]) -> Result<()> {
+        block_on(self.tree.del_many(keys))
+    }
+
+    /// Deletes every key in the tree with a single log entry.
+    pub fn clear(&self) -> Result<()> {
+        block_on(self.tree.clear())
+    }
+
+    /// Deletes every key within the specified range with a single log entry.
+    pub fn delete_range(&self, range: impl RangeBounds<Vec<u8>>) -> Result<()> {
+        block_on(self.tree.delete_range(range))
+    }
+
+    /// Atomically replaces the value of `key` with `new` if its current value is `expected`;
+    /// see [`crate::Tree::compare_and_swap`].
+    pub fn compare_and_swap(&self, key: &[u8], expected: Option<&[u8]>, new: Option<Vec<u8>>) -> Result<bool> {
+        block_on(self.tree.compare_and_swap(key, expected, new))
+    }
+
+    /// Returns the value of `key` along with its version; see [`crate::Tree::get_versioned`].
+    pub fn get_versioned(&self, key: &[u8]) -> Result<Option<(Bytes, u64)>> {
+        block_on(self.tree.get_versioned(key))
+    }
+
+    /// Returns the value of `key` along with its timestamps and version; see
+    /// [`crate::Tree::get_entry`].
+    pub fn get_entry(&self, key: &[u8]) -> Result<Option<Entry>> {
+        block_on(self.tree.get_entry(key))
+    }
+
+    /// Returns the value `key` had at `at`; see [`crate::Tree::get_at`].
+    pub fn get_at(&self, key: &[u8], at: At) -> Result<Option<Bytes>> {
+        block_on(self.tree.get_at(key, at))
+    }
+
+    /// Returns the versions kept of `key`; see [`crate::Tree::history`].
+    pub fn history(&self, key: &[u8]) -> Result<Vec<Version>> {
+        block_on(self.tree.history(key))
+    }
+
+    /// Atomically sets `key` to `value` if its version is still `version`; see
+    /// [`crate::Tree::set_if_version`].
+    pub fn set_if_version(&self, key: &[u8], value: Vec<u8>, version: Option<u64>) -> Result<bool> {
+        block_on(self.tree.set_if_version(key, value, version))
+    }
+
+    /// Returns an iterator over key-value pairs within the specified range as of the write with
+    /// sequence number `sequence`; see [`crate::Tree::scan_at`].
+    pub fn scan_at(&self, range: impl RangeBounds<Vec<u8>>, sequence: u64) -> Result<Pairs> {
+        block_on(self.tree.scan_at(range, sequence))
+    }
+
+    /// Returns an iterator over key-value pairs within the specified range, in key order.
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Pairs> {
+        block_on(self.tree.scan(range))
+    }
+
+    /// Returns an iterator over every key-value pair whose key starts with `prefix`, in key order.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Pairs> {
+        block_on(self.tree.scan_prefix(prefix))
+    }
+
+    /// Returns a page of at most `limit` pairs within the specified range, in key order, and a
+    /// continuation token for the next page if pairs are left.
+    pub fn scan_page(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+        continuation: Option<Vec<u8>>,
+    ) -> Result<Page> {
+        block_on(self.tree.scan_page(range, limit, continuation))
+    }
+
+    /// Returns an iterator over the keys within the specified range, in key order.
+    pub fn keys(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Keys> {
+        block_on(self.tree.keys(range))
+    }
+
+    /// Returns an iterator over key-value pairs within the specified range, in key order, that
+    /// fetches them lazily like [`crate::Tree::scan_stream`].
+    pub fn scan_iter(&self, range: impl RangeBounds<Vec<u8>>) -> Iter {
+        Iter::new(self.tree.clone(), &range)
+    }
+
+    /// Returns an iterator over every key-value pair of the tree, in key order, that fetches
+    /// them lazily; see [`crate::Tree::iter`].
+    pub fn iter(&self) -> Iter {
+        self.tree.iter()
+    }
+
+    /// Returns an iterator over the changes accepted by `filter` from now on; see
+    /// [`crate::Tree::watch`]. Each call to `next` blocks until such a change is made, and the
+    /// iterator ends once the tree is dropped.
+    pub fn watch(&self, filter: Filter) -> impl Iterator<Item = Event> + Send + 'static {
+        blocking_iter(self.tree.watch(filter))
+    }
+
+    /// Returns an iterator over the changes made to keys starting with `prefix` from now on;
+    /// see [`crate::Tree::watch_prefix`]. Each call to `next` blocks until a change is made,
+    /// and the iterator ends once the tree is dropped.
+    pub fn watch_prefix(&self, prefix: &[u8]) -> impl Iterator<Item = Event> + Send + 'static {
+        blocking_iter(self.tree.watch_prefix(prefix))
+    }
+
+    /// Returns the changes made to the tree after the write numbered `sequence`; see
+    /// [`crate::Tree::changes_since`].
+    pub fn changes_since(&self, sequence: u64) -> Result<Vec<Change>> {
+        block_on(self.tree.changes_since(sequence))
+    }
+
+    /// Returns the number of keys in the tree.
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Returns true if the tree holds no keys.
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Returns statistics about the keys of the tree; see [`crate::Tree::stats`].
+    pub fn stats(&self) -> TreeStats {
+        self.tree.stats()
+    }
+}
+
+impl fmt::Debug for Tree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.tree.fmt(f)
+    }
+}
+
+impl IntoIterator for &Tree {
+    type Item = Result<(Bytes, Bytes)>;
+    type IntoIter = Iter;
+
+    fn into_iter(self) -> Iter {
+        self.iter()
+    }
+}
+
+impl From<crate::Tree> for Tree {
+    fn from(tree: crate::Tree) -> Self {
+        Self { tree }
+    }
+}
+
+impl From<Tree> for crate::Tree {
+    fn from(tree: Tree) -> Self {
+        tree.tree
+    }
+}
+
+/// A synchronous pipeline of writes to a tree; see [`crate::Pipeline`].
+#[derive(Debug)]
+pub struct Pipeline(crate::Pipeline);
+
+impl Pipeline {
+    /// Sets the number of buffered key and value bytes after which the writes are submitted.
+    pub fn max_bytes(self, max_bytes: usize) -> Self {
+        Self(self.0.max_bytes(max_bytes))
+    }
+
+    /// Sets how long the oldest buffered write may wait before the writes are submitted.
+    pub fn max_delay(self, max_delay: Duration) -> Self {
+        Self(self.0.max_delay(max_delay))
+    }
+
+    /// Buffers a write of `value` to `key` and submits the buffered writes if they are due.
+    pub fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        block_on(self.0.set(key, value))
+    }
+
+    /// Submits the buffered writes and waits until they have been handed to the operating
+    /// system.
+    pub fn flush(&mut self) -> Result<()> {
+        block_on(self.0.flush())
+    }
+
+    /// Returns the number of buffered writes not submitted yet.
+    pub fn pending(&self) -> usize {
+        self.0.pending()
+    }
+
+    /// Drops the buffered writes not submitted yet.
+    pub fn clear(&mut self) {
+        self.0.clear()
+    }
+}
+
+// Wakes the thread blocked in `block_on`.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs `future` to completion on the current thread, parking it until the future is woken.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+// Turns `stream` into an iterator whose `next` blocks until the stream yields.
+fn blocking_iter<S: Stream + Send + 'static>(stream: S) -> impl Iterator<Item = S::Item> + Send + 'static {
+    let mut stream = Box::pin(stream);
+    std::iter::from_fn(move || block_on(poll_fn(|cx| stream.as_mut().poll_next(cx))))
+}
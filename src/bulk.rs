@@ -0,0 +1,217 @@
+//! Bulk loads written straight to new log segments.
+//!
+//! Rather than handing entries to the log writer one at a time, a [`Loader`] encodes them into
+//! fresh segments. Once every entry is written, the segments are added to the log right before
+//! the active segment with a single manifest update, and only then do the entries reach the key
+//! maps, so a load either happens as a whole or not at all. Writes are held back meanwhile,
+//! which lets the loaded entries take the sequence numbers following the latest write.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::changes::Change;
+use crate::chunks;
+use crate::compaction::Output;
+use crate::engine::{self, Entry, Inner, ENTRY_OVERHEAD};
+use crate::error::{Error, Result};
+use crate::log;
+use crate::tree::{Keyspace, META_TREE};
+use crate::watch::{Event, Op};
+
+/// Writes the entries of a bulk load; see [`load`].
+pub(crate) struct Loader<'a> {
+    engine: &'a Inner,
+    output: Output<'a>,
+    now: u64,
+    // Sequence number of the latest entry written.
+    sequence: u64,
+    // Memory the key maps will take once the loaded entries are added to them.
+    index_bytes: u64,
+    // Entries written so far by tree, in key order.
+    trees: HashMap<u32, Vec<(Bytes, Entry)>>,
+    // Trees created by the load, by name.
+    created: BTreeMap<String, u32>,
+    // Writes to report to subscribers along with their tree, in the order they were made.
+    published: Vec<(u32, Change)>,
+}
+
+/// Runs `f` to write the entries of a bulk load with the loader it is given, then adds them to
+/// the log and the key maps and returns what `f` returned. Nothing is loaded if `f` fails. The
+/// caller must hold the compaction lock and the write lock.
+pub(crate) fn load<T>(engine: &Inner, f: impl FnOnce(&mut Loader) -> Result<T>) -> Result<T> {
+    // The loaded entries replay after everything written so far.
+    engine.log.seal()?;
+    let mut loader = Loader {
+        engine,
+        output: Output::new(&engine.log),
+        now: engine.now(),
+        sequence: engine.log.last_sequence(),
+        index_bytes: engine.counters.index_bytes(),
+        trees: HashMap::new(),
+        created: BTreeMap::new(),
+        published: Vec::new(),
+    };
+    let written = f(&mut loader).and_then(|result| {
+        let meta = engine.keyspace(META_TREE);
+        for (name, id) in std::mem::take(&mut loader.created) {
+            loader.set(&meta, name.into_bytes(), id.to_be_bytes().to_vec(), None)?;
+        }
+        Ok(result)
+    });
+    // The segments written are deleted along with the loader if the load failed.
+    let result = written?;
+    loader.commit()?;
+    Ok(result)
+}
+
+impl Loader<'_> {
+    /// Returns the tree called `name`, which is created along with the load if it does not
+    /// exist yet.
+    pub(crate) fn tree(&mut self, name: &str) -> Result<Arc<Keyspace>> {
+        let engine = self.engine;
+        let meta = engine.keyspace(META_TREE);
+        let id = match engine.get(&meta, name.as_bytes())? {
+            Some(id) => engine::tree_id(name, &id)?,
+            None => match self.created.get(name) {
+                Some(&id) => id,
+                None => {
+                    engine.check_limits(name.as_bytes(), &[])?;
+                    let id = engine.next_tree_id();
+                    self.created.insert(name.to_string(), id);
+                    id
+                }
+            },
+        };
+        Ok(engine.keyspace(id))
+    }
+
+    /// Writes an entry setting `key` of the tree `ks` to `value` until `expires_at`, returning
+    /// whether it was written. Keys must come in ascending order within each tree. Empty values
+    /// and values that have already expired are skipped.
+    pub(crate) fn set(
+        &mut self,
+        ks: &Keyspace,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<bool> {
+        let engine = self.engine;
+        engine.check_limits(&key, &value)?;
+        let key_map = ks.key_map.read().unwrap();
+        let last = self.trees.get(&ks.id).and_then(|entries| entries.last());
+        if last.is_some_and(|(last, _)| key_map.compare(last, &key).is_ge()) {
+            return Err(Error::BulkLoad("keys are not in ascending order".to_string()));
+        }
+        if value.is_empty() || expires_at.is_some_and(|t| t <= self.now) {
+            return Ok(false);
+        }
+        let old = key_map.get(&key).map_or(0, |old| old.footprint(key.len()));
+        drop(key_map);
+        if let Some(limit) = engine.options.index_memory_limit {
+            let needed = ENTRY_OVERHEAD + (key.len() + value.len()) as u64;
+            if engine.keeps_values() && (self.index_bytes + needed).saturating_sub(old) > limit {
+                engine.spill_values();
+                for entries in self.trees.values_mut() {
+                    for (_, entry) in entries {
+                        self.index_bytes -= entry.value.take().map_or(0, |value| value.len() as u64);
+                    }
+                }
+            }
+            if (self.index_bytes + ENTRY_OVERHEAD + key.len() as u64).saturating_sub(old) > limit {
+                return Err(Error::IndexMemoryLimit { limit });
+            }
+        }
+
+        let timestamps = engine.options.record_timestamps.then(|| engine.timestamps(ks, &key));
+        self.sequence += 1;
+        let sequence = self.sequence;
+        let (buffer, value_len, codec) =
+            engine.log.encode_entry(ks.id, &key, &value, expires_at, timestamps, sequence)?;
+        let location = self.output.write(&buffer)?;
+        let (key, value) = (Bytes::from(key), Bytes::from(value));
+        if engine.feed.is_subscribed() || ks.watchers.is_watched(&key) {
+            let (key, value) = (key.clone(), value.clone());
+            self.published.push((ks.id, Change::Set { sequence, key, value, expires_at }));
+        }
+        let entry = Entry {
+            location,
+            value_len,
+            ticket: 0,
+            value: engine.keeps_values().then_some(value),
+            expires_at,
+            codec,
+            sequence,
+            checksum: true,
+            timestamps,
+            recent: AtomicBool::new(false),
+        };
+        self.index_bytes = (self.index_bytes + entry.footprint(key.len())).saturating_sub(old);
+        self.trees.entry(ks.id).or_default().push((key, entry));
+        Ok(true)
+    }
+
+    // Adds the written segments to the log and their entries to the key maps, replacing the
+    // entries of keys that already exist.
+    fn commit(self) -> Result<()> {
+        let engine = self.engine;
+        let mut written = self.output.finish()?;
+        // Each key is loaded at most once, so every entry written is live.
+        for segment in &mut written {
+            segment.live = segment.len;
+        }
+        engine.log.insert_segments(written, self.sequence)?;
+
+        let mut replaced = HashMap::new();
+        for (tree, entries) in self.trees {
+            let ks = engine.keyspace(tree);
+            let (bytes, value_bytes) = entries.iter().fold((0, 0), |(bytes, value_bytes), (key, entry)| {
+                (bytes + entry.footprint(key.len()), value_bytes + entry.value_bytes())
+            });
+            engine.counters.index_grew(bytes, value_bytes);
+            let expiring: Vec<(u64, Bytes)> =
+                entries.iter().filter_map(|(key, entry)| Some((entry.expires_at?, key.clone()))).collect();
+            let mut key_map = ks.key_map.write().unwrap();
+            if key_map.is_empty() {
+                key_map.fill(entries);
+            } else {
+                for (key, entry) in entries {
+                    if let Some(old) = key_map.insert(key.clone(), entry) {
+                        engine.forget(&ks, &key, &old);
+                        replaced.insert((ks.id, key), old);
+                    }
+                }
+            }
+            drop(key_map);
+            ks.expirations.lock().unwrap().extend(expiring);
+        }
+        engine.demote_cold_values();
+
+        for (tree, change) in self.published {
+            engine.publish(tree, || change.clone());
+            let ks = engine.keyspace(tree);
+            let Change::Set { key, value, .. } = change else {
+                continue;
+            };
+            if ks.watchers.is_watched(&key) {
+                let old = replaced.get(&(tree, key.clone()));
+                ks.watchers.notify(Event {
+                    old_value: engine.old_value(&ks, &key, old)?,
+                    key,
+                    new_value: Some(value),
+                    op: Op::Set,
+                });
+            }
+        }
+        // The chunks of replaced values are only deleted once they are no longer needed.
+        for ((tree, key), old) in replaced {
+            engine.cache.remove(tree, &key);
+            if old.codec == log::Codec::Chunked {
+                chunks::delete(engine, tree, &key, None)?;
+            }
+        }
+        Ok(())
+    }
+}
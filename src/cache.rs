@@ -0,0 +1,112 @@
+//! A size-bounded LRU cache for values read back from the log.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+/// Caches recently used values, evicting the least recently used ones once
+/// the total size of cached values exceeds the configured capacity.
+pub(crate) struct ValueCache {
+    capacity: u64,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    // Cached values by tree and key, with the tick of their most recent use.
+    entries: HashMap<u32, HashMap<Bytes, (Bytes, u64)>>,
+    // Tree and key of each cached value, ordered by last use, oldest first.
+    recency: BTreeMap<u64, (u32, Bytes)>,
+    size: u64,
+    tick: u64,
+}
+
+impl ValueCache {
+    /// Creates a cache holding at most `capacity` bytes of values. A capacity of zero disables caching.
+    pub(crate) fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(CacheState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn get(&self, tree: u32, key: &[u8]) -> Option<Bytes> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+        let Some((value, last_used)) = state.entries.get_mut(&tree).and_then(|t| t.get_mut(key)) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        let value = value.clone();
+        let previous = std::mem::replace(last_used, tick);
+        let entry = state.recency.remove(&previous).unwrap();
+        state.recency.insert(tick, entry);
+        Some(value)
+    }
+
+    pub(crate) fn insert(&self, tree: u32, key: Bytes, value: Bytes) {
+        let size = (key.len() + value.len()) as u64;
+        if size > self.capacity {
+            self.remove(tree, &key);
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.remove(tree, &key);
+        state.tick += 1;
+        let tick = state.tick;
+        state.recency.insert(tick, (tree, key.clone()));
+        state.entries.entry(tree).or_default().insert(key, (value, tick));
+        state.size += size;
+        while state.size > self.capacity {
+            let Some((_, (tree, oldest))) = state.recency.pop_first() else {
+                break;
+            };
+            if let Some((value, _)) = state.entries.get_mut(&tree).and_then(|t| t.remove(&oldest)) {
+                state.size -= (oldest.len() + value.len()) as u64;
+            }
+        }
+    }
+
+    /// Returns the total size of the cached keys and values.
+    pub(crate) fn size(&self) -> u64 {
+        if self.capacity == 0 {
+            return 0;
+        }
+        self.state.lock().unwrap().size
+    }
+
+    /// Returns the number of lookups that found their value and the number that did not.
+    pub(crate) fn hits_and_misses(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn remove(&self, tree: u32, key: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.state.lock().unwrap().remove(tree, key);
+    }
+}
+
+impl CacheState {
+    fn remove(&mut self, tree: u32, key: &[u8]) {
+        let Some(entries) = self.entries.get_mut(&tree) else {
+            return;
+        };
+        if let Some((value, last_used)) = entries.remove(key) {
+            self.recency.remove(&last_used);
+            self.size -= (key.len() + value.len()) as u64;
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! Changefeed: replaying committed writes from the log by sequence number.
+//!
+//! Every entry appended to the log is numbered from a counter shared by all trees and persisted
+//! in the entries themselves, so a consumer can remember the last sequence number it has seen
+//! and pick up the changes made after it, even across restarts. Changes can also be followed
+//! as they are committed by subscribing to the engine's feed. Replaying the log up to a sequence
+//! number also reads a tree as it was right after that write, as long as compaction has not
+//! dropped the writes it depends on.
+
+use std::ops::RangeBounds;
+use std::sync::mpsc::SyncSender;
+#[cfg(feature = "replication")]
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+use crate::chunks;
+use crate::engine::{self, Inner};
+use crate::error::{Error, Result};
+use crate::log::{self, Codec, Record, SegmentReader};
+use crate::order::{self, OrderedMap};
+use crate::scan::Pairs;
+use crate::tree::{CHUNK_TREE, HISTORY_TREE};
+
+/// A committed write read back from the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// `key` was set to `value`, expiring at `expires_at` milliseconds since the Unix epoch.
+    Set {
+        sequence: u64,
+        key: Bytes,
+        value: Bytes,
+        expires_at: Option<u64>,
+    },
+    /// `key` was deleted.
+    Del { sequence: u64, key: Bytes },
+    /// Every key from `start` up to but excluding `end` was deleted, or every key from `start`
+    /// on if `end` is `None`.
+    DelRange {
+        sequence: u64,
+        start: Bytes,
+        end: Option<Bytes>,
+    },
+}
+
+impl Change {
+    /// Returns the sequence number of the write.
+    pub fn sequence(&self) -> u64 {
+        match self {
+            Change::Set { sequence, .. }
+            | Change::Del { sequence, .. }
+            | Change::DelRange { sequence, .. } => *sequence,
+        }
+    }
+}
+
+/// Number of changes a subscriber may leave unreceived before it is dropped.
+#[cfg(feature = "replication")]
+const FEED_CAPACITY: usize = 4096;
+
+/// Subscribers to the changes committed to every tree of an engine.
+#[derive(Default)]
+pub(crate) struct Feed {
+    // Dropped receivers are pruned on the next change, along with those that fell behind.
+    subscribers: Mutex<Vec<SyncSender<(u32, Change)>>>,
+}
+
+impl Feed {
+    /// Returns a receiver for every change committed from now on, along with its tree. Once
+    /// it falls `FEED_CAPACITY` changes behind, it is dropped rather than holding back
+    /// writes or buffering without bound: it disconnects after the changes it still holds,
+    /// and its owner catches up from the log instead.
+    #[cfg(feature = "replication")]
+    pub(crate) fn subscribe(&self) -> Receiver<(u32, Change)> {
+        let (sender, receiver) = mpsc::sync_channel(FEED_CAPACITY);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Returns true if anything subscribed to the changes.
+    pub(crate) fn is_subscribed(&self) -> bool {
+        !self.subscribers.lock().unwrap().is_empty()
+    }
+
+    /// Delivers the change produced by `change` to every subscriber, only producing it if
+    /// there are any. The caller must hold the write lock so changes arrive in order.
+    pub(crate) fn publish(&self, tree: u32, change: impl FnOnce() -> Change) {
+        // Chunks are published as part of the values they belong to, and versions as part of
+        // the writes they record.
+        if tree == CHUNK_TREE || tree == HISTORY_TREE {
+            return;
+        }
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let change = change();
+        // Subscribers whose channel is full have fallen behind and are dropped as well.
+        subscribers.retain(|subscriber| subscriber.try_send((tree, change.clone())).is_ok());
+    }
+}
+
+/// Returns the changes with a sequence number greater than `sequence` along with their tree, in
+/// the order they were committed. Only the changes to `tree` are returned if it is given.
+pub(crate) fn changes_since(
+    engine: &Inner,
+    tree: Option<u32>,
+    sequence: u64,
+) -> Result<Vec<(u32, Change)>> {
+    engine.replayed()?;
+    // Compaction would remove segments from under the readers.
+    let _compacting = engine.compaction_lock.lock().unwrap();
+    let segments = {
+        let _guard = engine.write_lock.lock().unwrap();
+        // Entries must be fully written before they can be read back.
+        engine.log.flush_and_wait();
+        engine.log.segments()
+    };
+    let mut changes = Vec::new();
+    for segment in segments {
+        let reader = SegmentReader::open(&engine.log.segment_path(segment.id), segment.id)?;
+        // Writes made since the segments were listed are left out, as they may be incomplete.
+        for record in reader.limit(segment.len) {
+            let (_, record) = record?;
+            // Chunks are read along with the values they belong to, and versions are left out.
+            let internal = record.tree == CHUNK_TREE || record.tree == HISTORY_TREE;
+            let skipped = internal || tree.is_some_and(|tree| tree != record.tree);
+            if skipped || record.sequence <= sequence {
+                continue;
+            }
+            let key = Bytes::from(record.key);
+            let change = if record.deletes_range {
+                Change::DelRange {
+                    sequence: record.sequence,
+                    start: key,
+                    end: (!record.value.is_empty()).then(|| Bytes::from(record.value)),
+                }
+            } else if record.value.is_empty() {
+                Change::Del {
+                    sequence: record.sequence,
+                    key,
+                }
+            } else {
+                let value = match record.codec {
+                    // Values whose chunks are gone have been overwritten or deleted since.
+                    Codec::Chunked => match chunks::read(engine, record.tree, &key, &record.value)? {
+                        Some(value) => value,
+                        None => continue,
+                    },
+                    codec => log::decompress(codec, record.value)?,
+                };
+                Change::Set {
+                    sequence: record.sequence,
+                    key,
+                    value: Bytes::from(value),
+                    expires_at: record.expires_at,
+                }
+            };
+            changes.push((record.tree, change));
+        }
+    }
+    Ok(changes)
+}
+
+/// Returns the pairs of `tree` within `range` as they were right after the write with sequence
+/// number `sequence`, by replaying the log up to it. Fails with [`Error::Compacted`] if
+/// compaction may have dropped writes that the state then depends on. Keys that have expired
+/// since are left out.
+pub(crate) fn scan_at(
+    engine: &Inner,
+    tree: u32,
+    range: &impl RangeBounds<Vec<u8>>,
+    sequence: u64,
+) -> Result<Pairs> {
+    engine.replayed()?;
+    // Compaction would remove segments from under the readers.
+    let _compacting = engine.compaction_lock.lock().unwrap();
+    let segments = {
+        let _guard = engine.write_lock.lock().unwrap();
+        // Entries must be fully written before they can be read back.
+        engine.log.flush_and_wait();
+        engine.log.segments()
+    };
+    let earliest = engine.log.view_start();
+    if sequence < earliest {
+        return Err(Error::Compacted { sequence, earliest });
+    }
+    // The keys of the tree within the range and the chunks of the tree, as of `sequence`.
+    let order = order::of_tree(engine.options.comparator, tree);
+    let bounds = engine::as_slices(range);
+    let mut view = OrderedMap::new(order);
+    let mut chunk_view = OrderedMap::new(None);
+    let chunk_prefix = tree.to_be_bytes();
+    'segments: for segment in segments {
+        let reader = SegmentReader::open(&engine.log.segment_path(segment.id), segment.id)?;
+        for record in reader.limit(segment.len) {
+            let (_, record) = record?;
+            // Sequence numbers grow along the log, so every write from here on is later.
+            if record.sequence > sequence {
+                break 'segments;
+            }
+            if record.tree == tree && (record.deletes_range || order::contains(order, bounds, &record.key)) {
+                apply(&mut view, record);
+            } else if record.tree == CHUNK_TREE && record.key.starts_with(&chunk_prefix) {
+                apply(&mut chunk_view, record);
+            }
+        }
+    }
+    let now = engine.now();
+    let mut pairs = Vec::with_capacity(view.len());
+    for (key, record) in view {
+        if record.is_deletion(now) {
+            continue;
+        }
+        let value = match record.codec {
+            Codec::Chunked => {
+                let get = |chunk_key: &[u8]| match chunk_view.get(chunk_key) {
+                    Some(chunk) if !chunk.is_deletion(now) => {
+                        Ok(Some(Bytes::from(log::decompress(chunk.codec, chunk.value.clone())?)))
+                    }
+                    _ => Ok(None),
+                };
+                chunks::assemble(tree, &key, &record.value, get)?
+                    .ok_or_else(|| Error::Corrupted("chunked value is missing chunks".to_string()))?
+            }
+            codec => log::decompress(codec, record.value)?,
+        };
+        pairs.push((key, Bytes::from(value)));
+    }
+    Ok(Pairs::new(pairs))
+}
+
+// Applies a write read back from the log to `view`, the keys it sets mapped to their records.
+fn apply(view: &mut OrderedMap<Record>, record: Record) {
+    if record.deletes_range {
+        view.remove_range(&record.key, &record.value);
+    } else if record.value.is_empty() {
+        view.remove(&record.key);
+    } else {
+        view.insert(Bytes::copy_from_slice(&record.key), record);
+    }
+}
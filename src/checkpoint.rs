@@ -0,0 +1,137 @@
+//! Checkpoints: compacted point-in-time copies of a database.
+//!
+//! The index of every tree is captured while writes are held back, which only takes as long as
+//! cloning the key maps. The values are then copied from the log while writes continue; they
+//! stay readable at the captured locations because compaction is held off until the copy is done.
+
+use std::path::Path;
+
+use bytes::Bytes;
+
+use crate::chunks;
+use crate::engine::Inner;
+use crate::error::{Error, Result};
+use crate::log::{self, Log, Pinned};
+use crate::sink::SinkOptions;
+use crate::tree::CHUNK_TREE;
+
+/// An entry of the captured index, along with the value if it is kept in memory.
+pub(crate) struct Captured {
+    pub(crate) key: Bytes,
+    location: log::Location,
+    value_len: u32,
+    value: Option<Bytes>,
+    pub(crate) expires_at: Option<u64>,
+    codec: log::Codec,
+    timestamps: Option<log::Timestamps>,
+    // Length of the entry in the log, if it is checked when read back.
+    checked_len: Option<u64>,
+}
+
+impl Captured {
+    /// Returns the value of the entry of `tree`, reading it back from the log if needed. The
+    /// caller must either hold the compaction lock taken before the entry was captured, or
+    /// pass the segments pinned while the entry was captured. Returns `None` if the entry's
+    /// value was chunked and has been overwritten since.
+    pub(crate) fn value(&self, engine: &Inner, tree: u32, pinned: Option<&Pinned>) -> Result<Option<Vec<u8>>> {
+        let value = match (&self.value, pinned) {
+            (Some(value), _) => value.to_vec(),
+            (None, Some(pinned)) => engine.log.read_pinned_value(
+                pinned,
+                self.location,
+                tree,
+                self.key.len(),
+                self.value_len,
+                self.expires_at,
+                self.codec,
+                self.checked_len,
+            )?,
+            (None, None) => engine.log.read_value(
+                self.location,
+                tree,
+                self.key.len(),
+                self.value_len,
+                self.expires_at,
+                self.codec,
+                self.checked_len,
+            )?,
+        };
+        match self.codec {
+            log::Codec::Chunked => chunks::read(engine, tree, &self.key, &value),
+            _ => Ok(Some(value)),
+        }
+    }
+}
+
+/// Writes every live key of every tree in `engine` to a new database at `path`.
+pub(crate) fn checkpoint(engine: &Inner, path: &Path) -> Result<()> {
+    if path.exists() {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("checkpoint destination {} already exists", path.display()),
+        )));
+    }
+    engine.replayed()?;
+    let _compacting = engine.compaction_lock.lock().unwrap();
+    let trees = {
+        let _guard = engine.write_lock.lock().unwrap();
+        capture(engine)
+    };
+    // Entries must be fully written before they can be read back.
+    engine.log.flush_and_wait();
+
+    let options = &engine.options;
+    let output = Log::open(
+        path.to_path_buf(),
+        options.segment_size,
+        false,
+        options.compression,
+        options.write_queue_capacity,
+        SinkOptions::new(options),
+        options.comparator.map(|comparator| comparator.name),
+    )?;
+    let copied = copy(engine, &output, trees).and_then(|()| output.sync_and_wait());
+    output.shutdown();
+    copied
+}
+
+/// Captures the unexpired entries of every tree. The caller must hold the write lock, and the
+/// compaction lock for as long as values are read from the captured entries. Chunks are left
+/// out, as they are read along with the entries they belong to.
+pub(crate) fn capture(engine: &Inner) -> Vec<(u32, Vec<Captured>)> {
+    let now = engine.now();
+    engine
+        .keyspaces()
+        .iter()
+        .filter(|ks| ks.id != CHUNK_TREE)
+        .map(|ks| {
+            let key_map = ks.key_map.read().unwrap();
+            let entries = key_map
+                .iter()
+                .filter(|(_, entry)| !entry.is_expired(now))
+                .map(|(key, entry)| Captured {
+                    key: key.clone(),
+                    location: entry.location,
+                    value_len: entry.value_len,
+                    value: entry.value.clone(),
+                    expires_at: entry.expires_at,
+                    codec: entry.codec,
+                    timestamps: entry.timestamps,
+                    checked_len: engine.options.paranoid_checks.then(|| entry.log_size(ks.id, key.len())),
+                })
+                .collect();
+            (ks.id, entries)
+        })
+        .collect()
+}
+
+fn copy(engine: &Inner, output: &Log, trees: Vec<(u32, Vec<Captured>)>) -> Result<()> {
+    for (tree, entries) in trees {
+        for entry in entries {
+            if let Some(value) = entry.value(engine, tree, None)? {
+                output.write_entry(tree, &entry.key, &value, entry.expires_at, entry.timestamps)?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,160 @@
+//! Chunking of values too large for a single log entry.
+//!
+//! With [`EngineOptions::chunk_large_values`](crate::EngineOptions::chunk_large_values) set, a
+//! value longer than the largest value an entry may hold is split into chunks of that size,
+//! which are stored in an internal tree. The key itself gets an entry holding a manifest of the
+//! chunks, and reads reassemble the value from them.
+//!
+//! Chunk keys are made of the tree id, the key encoded so that keys keep their order, the
+//! generation of the value and the index of the chunk, so the chunks of a range of keys form a
+//! range of their own. Every chunked write starts a new generation: its chunks are written before
+//! its manifest, so a crash in between leaves the previous value intact, and the chunks of earlier
+//! generations are deleted once the manifest is in place.
+
+use std::ops::Bound;
+
+use bytes::Bytes;
+
+use crate::engine::Inner;
+use crate::error::{Error, Result};
+use crate::keyencoding::Key;
+use crate::tree::{self, CHUNK_TREE};
+
+/// Where to find the chunks of a value, stored as the value of its key.
+pub(crate) struct Manifest {
+    pub(crate) generation: u64,
+    chunks: u32,
+    len: u64,
+}
+
+impl Manifest {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(20);
+        bytes.extend_from_slice(&self.generation.to_be_bytes());
+        bytes.extend_from_slice(&self.chunks.to_be_bytes());
+        bytes.extend_from_slice(&self.len.to_be_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let invalid = || Error::Corrupted("invalid manifest of a chunked value".to_string());
+        let bytes = <[u8; 20]>::try_from(bytes).map_err(|_| invalid())?;
+        Ok(Self {
+            generation: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            chunks: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+            len: u64::from_be_bytes(bytes[12..].try_into().unwrap()),
+        })
+    }
+}
+
+/// Writes `value` for `key` of `tree` as chunks of `chunk_size` bytes expiring along with the
+/// key, and returns the manifest to store for the key. The caller must hold the write lock.
+pub(crate) fn write(
+    engine: &Inner,
+    tree: u32,
+    key: &[u8],
+    value: &[u8],
+    chunk_size: usize,
+    expires_at: Option<u64>,
+) -> Result<Manifest> {
+    let chunks = engine.keyspace(CHUNK_TREE);
+    // Sequence numbers only grow, so the generation is above that of every earlier write.
+    let generation = engine.log.last_sequence() + 1;
+    let mut count = 0;
+    for chunk in value.chunks(chunk_size) {
+        let chunk_key = chunk_key(tree, key, generation, count);
+        engine.set(&chunks, &chunk_key, chunk.to_vec(), expires_at)?;
+        count += 1;
+    }
+    Ok(Manifest {
+        generation,
+        chunks: count,
+        len: value.len() as u64,
+    })
+}
+
+/// Reassembles the value of `key` of `tree` from the chunks listed in `manifest`, or returns
+/// `None` if one of them is missing because the value has since been overwritten or deleted.
+pub(crate) fn read(engine: &Inner, tree: u32, key: &[u8], manifest: &[u8]) -> Result<Option<Vec<u8>>> {
+    let chunks = engine.keyspace(CHUNK_TREE);
+    assemble(tree, key, manifest, |chunk_key| engine.get(&chunks, chunk_key))
+}
+
+/// Reassembles the value of `key` of `tree` like [`read`], fetching each chunk by its key with
+/// `get`.
+pub(crate) fn assemble(
+    tree: u32,
+    key: &[u8],
+    manifest: &[u8],
+    mut get: impl FnMut(&[u8]) -> Result<Option<Bytes>>,
+) -> Result<Option<Vec<u8>>> {
+    let manifest = Manifest::decode(manifest)?;
+    let mut value = Vec::with_capacity(manifest.len as usize);
+    for index in 0..manifest.chunks {
+        match get(&chunk_key(tree, key, manifest.generation, index))? {
+            Some(chunk) => value.extend_from_slice(&chunk),
+            None => return Ok(None),
+        }
+    }
+    if value.len() as u64 != manifest.len {
+        return Err(Error::Corrupted(format!(
+            "chunked value of {} bytes reassembled to {} bytes",
+            manifest.len,
+            value.len()
+        )));
+    }
+    Ok(Some(value))
+}
+
+/// Deletes the chunks of `key` of `tree` from generations before `generation`, or from every
+/// generation if it is `None`. The caller must hold the write lock.
+pub(crate) fn delete(engine: &Inner, tree: u32, key: &[u8], generation: Option<u64>) -> Result<()> {
+    let start = key_prefix(tree, key);
+    let end = match generation {
+        Some(generation) => [start.as_slice(), &generation.to_be_bytes()].concat(),
+        None => tree::prefix_end(&start).unwrap_or_default(),
+    };
+    delete_chunks(engine, &start, &end)
+}
+
+/// Deletes the chunks of every key of `tree` from `start` up to but excluding `end`, or of every
+/// key from `start` on if `end` is empty. The caller must hold the write lock.
+pub(crate) fn delete_range(engine: &Inner, tree: u32, start: &[u8], end: &[u8]) -> Result<()> {
+    let start = key_prefix(tree, start);
+    let end = if end.is_empty() {
+        tree::prefix_end(&tree.to_be_bytes()).unwrap_or_default()
+    } else {
+        key_prefix(tree, end)
+    };
+    delete_chunks(engine, &start, &end)
+}
+
+// Deletes the chunk keys from `start` up to but excluding `end`, where an empty `end` means
+// unbounded, unless there are none.
+fn delete_chunks(engine: &Inner, start: &[u8], end: &[u8]) -> Result<()> {
+    let chunks = engine.keyspace(CHUNK_TREE);
+    let end_bound = if end.is_empty() {
+        Bound::Unbounded
+    } else {
+        Bound::Excluded(end)
+    };
+    let bounds = (Bound::Included(start), end_bound);
+    if chunks.key_map.read().unwrap().range(bounds).next().is_none() {
+        return Ok(());
+    }
+    engine.delete_range(&chunks, start, end)
+}
+
+// Returns the prefix shared by the chunk keys of `key` of `tree`, which sorts like the key.
+fn key_prefix(tree: u32, key: &[u8]) -> Vec<u8> {
+    let mut prefix = tree.to_be_bytes().to_vec();
+    key.encode_into(&mut prefix);
+    prefix
+}
+
+fn chunk_key(tree: u32, key: &[u8], generation: u64, index: u32) -> Vec<u8> {
+    let mut chunk_key = key_prefix(tree, key);
+    chunk_key.extend_from_slice(&generation.to_be_bytes());
+    chunk_key.extend_from_slice(&index.to_be_bytes());
+    chunk_key
+}
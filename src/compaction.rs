@@ -0,0 +1,537 @@
+//! Log compaction.
+//!
+//! Compaction rewrites sealed segments so they only contain live entries. Sealed segments are
+//! immutable, so the copy runs while reads and writes continue against the key map and the
+//! active segment; writes are only held back while relocated entries are re-pointed at the
+//! rewritten segments and the manifest is swapped. The rewritten segments only become part of
+//! the log once the manifest swap is durable, so a crash at any point leaves either the old or
+//! the new segments in effect. The copy can be throttled, so that it leaves disk bandwidth to
+//! reads and writes.
+//!
+//! A background thread removes expired keys and watches the log's size and garbage ratio,
+//! compacting it once the configured thresholds are exceeded, or whenever the configured
+//! [`CompactionStrategy`](crate::CompactionStrategy) finds it due. Expired entries are dropped
+//! from the rewritten segments, or reduced to tombstones where older segments still need
+//! to be shadowed. The same thread writes the periodic index snapshots, scrubs the log and
+//! compacts it once the disk is full, if configured.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::engine::{self, Inner};
+use crate::error::{Error, Result};
+use crate::index;
+use crate::log::{self, Location, Log, SegmentInfo, SegmentReader};
+use crate::health::DiskFullPolicy;
+use crate::options::EngineOptions;
+use crate::stats::SegmentSpace;
+use crate::verify;
+
+/// Spawns the compactor thread, unless the engine is read-only or none of background
+/// compaction, index snapshots, scrubbing and compaction once the disk is full are enabled. It stops once the returned sender or the engine
+/// is dropped, or once the engine is closed. Under a simulation, the compactor's work is left
+/// to [`Simulation::advance`](crate::Simulation::advance) instead.
+pub(crate) fn spawn(engine: Weak<Inner>, options: &EngineOptions) -> Option<Sender<()>> {
+    let (mut chores, interval) = Chores::new(options)?;
+    #[cfg(feature = "sim")]
+    if let Some(simulation) = &options.simulation {
+        simulation.schedule(engine, chores, interval);
+        return None;
+    }
+    let (stop, stopped) = mpsc::channel::<()>();
+    thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            let Some(engine) = engine.upgrade().filter(|engine| !engine.log.is_closed()) else {
+                break;
+            };
+            chores.run(&engine);
+        }
+    });
+    Some(stop)
+}
+
+/// The periodic work of the compactor.
+pub(crate) struct Chores {
+    compacting: bool,
+    snapshots: Option<Duration>,
+    // When the latest snapshot was written, in milliseconds since the Unix epoch.
+    last_snapshot: u64,
+    // The sequence number and segments covered by the latest snapshot, so that no snapshot
+    // is written while the log is unchanged.
+    covered: Option<(u64, Vec<u64>)>,
+    scrubs: Option<Duration>,
+    // When the log was last scrubbed, in milliseconds since the Unix epoch.
+    last_scrub: u64,
+    // Whether the log is compacted once the disk is full.
+    reclaiming: bool,
+    // The retention horizon as of the latest compaction, if it left the log in need of another
+    // one, which would reclaim nothing more until the horizon moves.
+    held_back: Option<u64>,
+}
+
+impl Chores {
+    // Returns the work enabled by `options` and how often it is due, or `None` if there is none.
+    fn new(options: &EngineOptions) -> Option<(Self, Duration)> {
+        if options.read_only {
+            return None;
+        }
+        let compacting = options.background_compaction;
+        let (snapshots, scrubs) = (options.index_snapshot_interval, options.scrub_interval);
+        let reclaiming = options.disk_full_policy == DiskFullPolicy::Compact && options.min_free_space.is_some();
+        let compactions = (compacting || reclaiming).then_some(options.compaction_interval);
+        let interval = [compactions, snapshots, scrubs].into_iter().flatten().min()?;
+        let now = engine::clock_millis(options);
+        let chores = Self {
+            compacting,
+            snapshots,
+            last_snapshot: now,
+            covered: None,
+            scrubs,
+            last_scrub: now,
+            reclaiming,
+            held_back: None,
+        };
+        Some((chores, interval))
+    }
+
+    /// Compacts the log to reclaim space if the disk is full, removes expired keys and compacts
+    /// the log if needed, and writes an index snapshot or scrubs the log if either is due.
+    pub(crate) fn run(&mut self, engine: &Inner) {
+        if self.reclaiming && engine.disk.take_compaction_due() {
+            *engine.compaction_error.lock().unwrap() = compact(engine, true).err();
+        }
+        if self.compacting {
+            engine.remove_expired();
+            let horizon = engine.horizon.sequence(engine);
+            if engine.needs_compaction() && self.held_back != Some(horizon) {
+                *engine.compaction_error.lock().unwrap() = compact(engine, false).err();
+                self.held_back = (horizon < u64::MAX && engine.needs_compaction()).then_some(horizon);
+            }
+        }
+        let now = engine.now();
+        let elapsed = Duration::from_millis(now.saturating_sub(self.last_snapshot));
+        if self.snapshots.is_some_and(|snapshots| elapsed >= snapshots) {
+            let ids: Vec<u64> = engine.log.segments().iter().map(|s| s.id).collect();
+            let current = Some((engine.log.last_sequence(), ids));
+            if current != self.covered {
+                self.last_snapshot = now;
+                let written = index::write(engine);
+                #[cfg(feature = "tracing")]
+                if let Err(e) = &written {
+                    tracing::warn!(error = %e, "writing the index snapshot failed");
+                }
+                if written.is_ok() {
+                    self.covered = current;
+                }
+                *engine.snapshot_error.lock().unwrap() = written.err();
+            }
+        }
+        let elapsed = Duration::from_millis(now.saturating_sub(self.last_scrub));
+        if self.scrubs.is_some_and(|scrubs| elapsed >= scrubs) {
+            self.last_scrub = now;
+            let scrubbed = verify::scrub(engine);
+            #[cfg(feature = "tracing")]
+            if let Err(e) = &scrubbed {
+                tracing::warn!(error = %e, "scrubbing the log failed");
+            }
+            *engine.scrub_error.lock().unwrap() = scrubbed.err();
+        }
+    }
+}
+
+/// Seals the active segment and rewrites sealed segments, returning the number of bytes reclaimed.
+///
+/// A `full` compaction rewrites every sealed segment. Otherwise the configured strategy picks
+/// the segments, or failing one, only segments whose own garbage ratio exceeds the configured
+/// threshold are rewritten, falling back to every segment holding garbage when it is spread
+/// too thinly for any single one to qualify.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(full = full), err))]
+pub(crate) fn compact(engine: &Inner, full: bool) -> Result<u64> {
+    if engine.options.read_only || engine.disk.is_read_only(engine) {
+        return Err(Error::ReadOnly);
+    }
+    engine.replayed()?;
+    let _compacting = engine.compaction_lock.lock().unwrap();
+    let started = Instant::now();
+    {
+        let _guard = engine.write_lock.lock().unwrap();
+        engine.log.seal()?;
+    }
+    let segments = engine.log.segments();
+    let sealed = &segments[..segments.len() - 1];
+    if sealed.iter().all(|s| s.live >= s.len) {
+        return Ok(0);
+    }
+    let ratio = engine.options.compaction_garbage_ratio;
+    let selected: Vec<SegmentInfo> = match &engine.options.compaction_strategy {
+        _ if full => sealed.to_vec(),
+        Some(strategy) => {
+            let space: Vec<SegmentSpace> = sealed.iter().map(SegmentInfo::space).collect();
+            let ids = strategy.select(&space, engine.now());
+            sealed.iter().filter(|s| ids.contains(&s.id)).copied().collect()
+        }
+        None => {
+            let selected: Vec<SegmentInfo> = sealed
+                .iter()
+                .filter(|s| (s.len - s.live.min(s.len)) as f64 >= s.len as f64 * ratio)
+                .copied()
+                .collect();
+            if selected.is_empty() {
+                sealed.iter().filter(|s| s.live < s.len).copied().collect()
+            } else {
+                selected
+            }
+        }
+    };
+    if selected.is_empty() {
+        return Ok(0);
+    }
+    // The rewritten segments take the place of the newest one, so the selection is widened to a
+    // contiguous run; otherwise a range tombstone could move past entries it must not delete.
+    let first = sealed.iter().position(|s| s.id == selected[0].id).unwrap();
+    let last = sealed.iter().position(|s| s.id == selected[selected.len() - 1].id).unwrap();
+    let selected = &sealed[first..=last];
+    // Tombstones can only be dropped when every older segment is rewritten as well,
+    // otherwise an older value for the key would be resurrected on replay.
+    let prefix = segments
+        .iter()
+        .take_while(|s| selected.iter().any(|sel| sel.id == s.id))
+        .count();
+
+    let bytes = selected.iter().map(|s| s.len).sum();
+    notify(engine, CompactionEvent::Started {
+        segments: selected.len(),
+        bytes,
+    });
+    let reclaimed = rewrite(engine, &segments[..prefix], selected, started);
+    notify(engine, match &reclaimed {
+        Ok(reclaimed) => CompactionEvent::Finished {
+            bytes,
+            reclaimed: *reclaimed,
+            elapsed: started.elapsed(),
+        },
+        Err(e) => CompactionEvent::Failed { error: e.to_string() },
+    });
+    reclaimed
+}
+
+// Rewrites the `selected` segments, dropping deletions from those that are part of `prefix`,
+// the oldest segments of the log, and swaps the rewritten segments in.
+fn rewrite(engine: &Inner, prefix: &[SegmentInfo], selected: &[SegmentInfo], started: Instant) -> Result<u64> {
+    // Segments must be fully written before their entries can be read back.
+    engine.log.flush_and_wait();
+    let now = engine.now();
+    let total = selected.iter().map(|s| s.len).sum();
+    let progress = engine.counters.compaction_started(total);
+    let mut done = 0;
+    let mut throttle = Throttle::new(engine.options.compaction_bytes_per_second);
+    let mut output = Output::new(&engine.log);
+    // The rewritten segments keep their place in time, for strategies grouping segments by it.
+    if let Some(sealed_at) = selected.iter().filter_map(|s| s.sealed_at).max() {
+        output.keep_sealed_at(sealed_at);
+    }
+    let mut relocations = Vec::new();
+    let mut expired = Vec::new();
+    // Writes made after this are kept as they are.
+    let horizon = engine.horizon.sequence(engine);
+    // Sequence number of the latest deletion left out of the rewritten segments.
+    let mut dropped = 0;
+    // Sequence number from which the state of the database no longer depends on any write left
+    // out of the rewritten segments.
+    let mut view_start = 0;
+    for segment in selected {
+        let droppable = prefix.iter().any(|s| s.id == segment.id);
+        for record in SegmentReader::open(&engine.log.segment_path(segment.id), segment.id)? {
+            let (location, record) = record?;
+            let size = log::entry_size(&record);
+            progress.advance(size);
+            throttle.consume(size);
+            if record.deletes_range {
+                if droppable && record.sequence <= horizon {
+                    dropped = dropped.max(record.sequence);
+                } else {
+                    output.write(&log::encode_record(&record))?;
+                }
+                continue;
+            }
+            // Writes left out are shadowed by the key's live entry, or by a deletion made so far,
+            // which includes dropping the key's tree.
+            let current = engine.existing_keyspace(record.tree).and_then(|keyspace| {
+                let key_map = keyspace.key_map.read().unwrap();
+                key_map.get(record.key.as_slice()).map(|entry| (entry.location, entry.sequence))
+            });
+            let (current, shadowed_until) = match current {
+                Some((location, sequence)) => (Some(location), sequence),
+                None => (None, engine.log.last_sequence()),
+            };
+            if record.is_deletion(now) {
+                if current == Some(location) {
+                    expired.push((record.tree, record.key.clone(), location));
+                }
+                // A newer entry for the key already shadows older segments.
+                if droppable || current.is_some_and(|current| current != location) {
+                    if record.sequence > horizon {
+                        output.write(&log::encode_record(&record))?;
+                        continue;
+                    }
+                    if record.value.is_empty() {
+                        dropped = dropped.max(record.sequence);
+                    }
+                    if current.is_some() {
+                        view_start = view_start.max(shadowed_until);
+                    }
+                    continue;
+                }
+                // Expired values only turn into tombstones once the horizon has passed them.
+                if record.sequence > horizon {
+                    output.write(&log::encode_record(&record))?;
+                    continue;
+                }
+                let tombstone = log::Record {
+                    value: Vec::new(),
+                    expires_at: None,
+                    codec: log::Codec::None,
+                    timestamps: None,
+                    ..record
+                };
+                output.write(&log::encode_record(&tombstone))?;
+            } else if current == Some(location) {
+                let new_location = output.write(&log::encode_record(&record))?;
+                relocations.push((record.tree, record.key, location, new_location, size));
+            } else if record.sequence > horizon {
+                output.write(&log::encode_record(&record))?;
+            } else {
+                view_start = view_start.max(shadowed_until);
+            }
+        }
+        done += segment.len;
+        notify(engine, CompactionEvent::Progress { done, total });
+    }
+    let mut written = output.finish()?;
+
+    let _guard = engine.write_lock.lock().unwrap();
+    // Writes are held back from here on, which subscribers can tell from the finishing event.
+    #[cfg(feature = "tracing")]
+    let locked = Instant::now();
+    // Entries overwritten while the segments were being copied keep their newer location, and
+    // those of trees dropped meanwhile are gone.
+    relocations.retain(|(tree, key, old, _, _)| {
+        let Some(keyspace) = engine.existing_keyspace(*tree) else {
+            return false;
+        };
+        let key_map = keyspace.key_map.read().unwrap();
+        key_map.get(key.as_slice()).is_some_and(|entry| entry.location == *old)
+    });
+    let mut live: HashMap<u64, u64> = HashMap::new();
+    for (_, _, _, new, size) in &relocations {
+        *live.entry(new.segment).or_default() += size;
+    }
+    for segment in &mut written {
+        segment.live = live.get(&segment.id).copied().unwrap_or(0);
+    }
+    let before: u64 = selected.iter().map(|s| s.len).sum();
+    let after: u64 = written.iter().map(|s| s.len).sum();
+    let ids: Vec<u64> = selected.iter().map(|s| s.id).collect();
+    // The key maps keep pointing at the old segments unless the manifest lists the new ones.
+    // Should the swap fail, the new segments are removed as unlisted when the log is next opened,
+    // as the manifest may still have been replaced on disk.
+    engine.log.replace_segments(&ids, &written)?;
+    for (tree, key, _, new, _) in relocations {
+        let Some(keyspace) = engine.existing_keyspace(tree) else {
+            continue;
+        };
+        let mut key_map = keyspace.key_map.write().unwrap();
+        if let Some(entry) = key_map.get_mut(key.as_slice()) {
+            entry.location = new;
+        }
+    }
+    for (tree, key, location) in expired {
+        if let Some(keyspace) = engine.existing_keyspace(tree) {
+            engine.expire(&keyspace, &key, location);
+        }
+    }
+    engine.log.remove_segments(&ids)?;
+    engine.log.truncate_history(dropped);
+    engine.log.truncate_views(view_start);
+    engine.counters.compacted(started.elapsed());
+    #[cfg(feature = "tracing")]
+    tracing::info!(
+        segments = selected.len(),
+        reclaimed = before.saturating_sub(after),
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        writes_held_ms = locked.elapsed().as_millis() as u64,
+        "compaction finished"
+    );
+    Ok(before.saturating_sub(after))
+}
+
+/// Progress of a compaction, reported to [`EngineOptions::on_compaction`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompactionEvent {
+    /// A compaction started rewriting `segments` sealed segments holding `bytes` bytes.
+    Started { segments: usize, bytes: u64 },
+    /// The compaction has copied the live entries of `done` of the `total` bytes it rewrites,
+    /// reported after each segment.
+    Progress { done: u64, total: u64 },
+    /// The compaction finished rewriting `bytes` bytes, reclaiming `reclaimed` of them.
+    Finished { bytes: u64, reclaimed: u64, elapsed: Duration },
+    /// The compaction failed and left the log as it was.
+    Failed { error: String },
+}
+
+/// A function called with every [`CompactionEvent`]; see [`EngineOptions::on_compaction`].
+#[derive(Clone)]
+pub struct CompactionHook(Arc<dyn Fn(&CompactionEvent) + Send + Sync>);
+
+impl CompactionHook {
+    /// Wraps `f`, which may be called from several threads.
+    pub fn new(f: impl Fn(&CompactionEvent) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    /// Calls the wrapped function with `event`.
+    pub fn call(&self, event: &CompactionEvent) {
+        (self.0)(event)
+    }
+}
+
+impl fmt::Debug for CompactionHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CompactionHook")
+    }
+}
+
+fn notify(engine: &Inner, event: CompactionEvent) {
+    if let Some(hook) = &engine.options.on_compaction {
+        hook.call(&event);
+    }
+    for observer in &engine.options.observers {
+        observer.on_compact(&event);
+    }
+}
+
+/// Paces a compaction so that it copies at most `rate` bytes per second, if a rate is set.
+/// It sleeps on the thread running the compaction, which is never an executor's: the compactor
+/// thread, the one [`Engine::compact`](crate::Engine::compact) hands the rewrite to, or the
+/// one opening the engine.
+struct Throttle {
+    rate: Option<u64>,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Records that `bytes` more have been copied, sleeping until they are due at the set rate.
+    fn consume(&mut self, bytes: u64) {
+        let Some(rate) = self.rate else {
+            return;
+        };
+        self.bytes += bytes;
+        let due = Duration::from_secs_f64(self.bytes as f64 / rate.max(1) as f64);
+        let elapsed = self.started.elapsed();
+        // Sleeps shorter than this would mostly cost wakeups.
+        if due > elapsed + Duration::from_millis(10) {
+            thread::sleep(due - elapsed);
+        }
+    }
+}
+
+/// Writes entries into fresh segments, starting a new one whenever the size limit is hit.
+pub(crate) struct Output<'a> {
+    log: &'a Log,
+    current: Option<(SegmentInfo, BufWriter<File>)>,
+    finished: Vec<SegmentInfo>,
+    // When the segments written count as sealed, if not when they are finished.
+    sealed_at: Option<u64>,
+}
+
+impl<'a> Output<'a> {
+    pub(crate) fn new(log: &'a Log) -> Self {
+        Self {
+            log,
+            current: None,
+            finished: Vec::new(),
+            sealed_at: None,
+        }
+    }
+
+    /// Makes the segments written count as sealed at `sealed_at`, in milliseconds since the
+    /// Unix epoch, which is also recorded as the time their files were last modified.
+    pub(crate) fn keep_sealed_at(&mut self, sealed_at: u64) {
+        self.sealed_at = Some(sealed_at);
+    }
+
+    pub(crate) fn write(&mut self, buffer: &[u8]) -> Result<Location> {
+        let full = self.current.as_ref().is_some_and(|(segment, _)| {
+            segment.len + buffer.len() as u64 > self.log.segment_size()
+        });
+        if full {
+            self.close_current()?;
+        }
+        if self.current.is_none() {
+            let id = self.log.allocate_segment_id();
+            let file = File::create(self.log.segment_path(id))?;
+            let segment = SegmentInfo {
+                id,
+                len: 0,
+                live: 0,
+                sealed_at: None,
+            };
+            self.current = Some((segment, BufWriter::new(file)));
+        }
+        let (segment, writer) = self.current.as_mut().unwrap();
+        writer.write_all(buffer)?;
+        let location = Location {
+            segment: segment.id,
+            offset: segment.len,
+        };
+        segment.len += buffer.len() as u64;
+        Ok(location)
+    }
+
+    fn close_current(&mut self) -> Result<()> {
+        if let Some((mut segment, writer)) = self.current.take() {
+            segment.sealed_at = Some(self.sealed_at.unwrap_or_else(|| self.log.now()));
+            // The segment is listed first so that it is discarded if it cannot be closed.
+            self.finished.push(segment);
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            if let Some(sealed_at) = self.sealed_at {
+                file.set_modified(UNIX_EPOCH + Duration::from_millis(sealed_at))?;
+            }
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Closes the segment being written and returns every segment written, which are deleted
+    /// if that fails.
+    pub(crate) fn finish(mut self) -> Result<Vec<SegmentInfo>> {
+        self.close_current()?;
+        Ok(std::mem::take(&mut self.finished))
+    }
+}
+
+// The segments of an output dropped before it is finished, such as when writing it failed, are
+// not part of the log and are deleted.
+impl Drop for Output<'_> {
+    fn drop(&mut self) {
+        let current = self.current.take().map(|(segment, _)| segment);
+        for segment in self.finished.iter().chain(&current) {
+            let _ = std::fs::remove_file(self.log.segment_path(segment.id));
+        }
+    }
+}
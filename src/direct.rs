@@ -0,0 +1,92 @@
+//! Direct I/O for the log on Linux.
+//!
+//! With [`EngineOptions::direct_io`](crate::EngineOptions::direct_io), segments are written
+//! with `O_DIRECT` and `O_DSYNC`, so writes bypass the page cache and are durable once they
+//! return. Direct writes must cover whole blocks at aligned offsets from an aligned buffer,
+//! while entries end anywhere. The whole blocks of each batch are therefore written directly,
+//! and the partial block at the end through a second, ordinary descriptor opened with
+//! `O_DSYNC`, so the file never holds padding. The partial block is kept in memory and written
+//! again directly once it has been filled. File systems that do not support `O_DIRECT`, such
+//! as tmpfs, are written with `O_DSYNC` alone.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+
+// Alignment of direct writes, which covers the logical block sizes of common devices.
+const ALIGN: usize = 4096;
+
+/// A segment file written with direct I/O.
+pub(crate) struct DirectFile {
+    // Opened with O_DIRECT, unless the file system does not support it.
+    direct: Option<File>,
+    // Opened with O_DSYNC alone, for the partial block at the end of the file.
+    file: File,
+    // Offset of the first byte not yet written directly, which is always block aligned when
+    // direct I/O is used.
+    offset: u64,
+    // Bytes from `offset` on, which only ever hold part of a block between writes.
+    pending: Vec<u8>,
+    // Scratch space out of which an aligned buffer is carved for each direct write.
+    aligned: Vec<u8>,
+}
+
+impl DirectFile {
+    /// Opens the segment file at `path` for appending, creating it if needed.
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .custom_flags(libc::O_DSYNC)
+            .open(path)?;
+        let direct = match File::options().write(true).custom_flags(libc::O_DIRECT | libc::O_DSYNC).open(path) {
+            Ok(direct) => Some(direct),
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => None,
+            Err(e) => return Err(e),
+        };
+        let len = file.metadata()?.len();
+        let offset = match direct {
+            Some(_) => len - len % ALIGN as u64,
+            None => len,
+        };
+        let mut pending = vec![0; (len - offset) as usize];
+        file.read_exact_at(&mut pending, offset)?;
+        Ok(Self {
+            direct,
+            file,
+            offset,
+            pending,
+            aligned: Vec::new(),
+        })
+    }
+
+    /// Writes the entries in `batch`, leaving it empty.
+    pub(crate) fn write(&mut self, batch: &mut Vec<u8>) -> io::Result<()> {
+        let Some(direct) = &self.direct else {
+            self.file.write_all_at(batch, self.offset)?;
+            self.offset += batch.len() as u64;
+            batch.clear();
+            return Ok(());
+        };
+        self.pending.append(batch);
+        let blocks = self.pending.len() - self.pending.len() % ALIGN;
+        if blocks > 0 {
+            if self.aligned.len() < blocks + ALIGN {
+                self.aligned.resize(blocks + ALIGN, 0);
+            }
+            let start = self.aligned.as_ptr().align_offset(ALIGN);
+            let aligned = &mut self.aligned[start..start + blocks];
+            aligned.copy_from_slice(&self.pending[..blocks]);
+            direct.write_all_at(aligned, self.offset)?;
+            self.pending.drain(..blocks);
+            self.offset += blocks as u64;
+        }
+        if !self.pending.is_empty() {
+            self.file.write_all_at(&self.pending, self.offset)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Portable dumps of a database's contents.
+//!
+//! A dump is independent of the log format and of the machine that wrote it, so it can carry
+//! data between tegdb versions. Its format is described on [`Engine::export`].
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+use bytes::Bytes;
+
+use crate::bulk;
+use crate::engine::{self, Engine, Inner};
+use crate::error::{Error, Result};
+use crate::intercept::{self, Mutation};
+use crate::tree::{Keyspace, Tree, DEFAULT_TREE, META_TREE};
+
+const DUMP_HEADER: &[u8] = b"tegdb dump 1\n";
+const TREE_TAG: u8 = 1;
+const ENTRY_TAG: u8 = 2;
+
+/// Writes every unexpired key of every tree to `writer`, returning the number of entries written.
+pub(crate) fn export(engine: &Engine, writer: impl Write) -> Result<u64> {
+    let inner = &engine.engine;
+    let mut writer = BufWriter::new(writer);
+    writer.write_all(DUMP_HEADER)?;
+    let mut count = write_entries(&mut writer, inner, &inner.keyspace(DEFAULT_TREE))?;
+    let meta = inner.keyspace(META_TREE);
+    for (name, id) in inner.scan(&meta, &(..))? {
+        let id = engine::tree_id(&String::from_utf8_lossy(&name), &id)?;
+        writer.write_all(&[TREE_TAG])?;
+        write_bytes(&mut writer, &name)?;
+        count += write_entries(&mut writer, inner, &inner.keyspace(id))?;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+fn write_entries(writer: &mut impl Write, inner: &Inner, ks: &Keyspace) -> Result<u64> {
+    let now = inner.now();
+    let keys: Vec<(Bytes, Option<u64>)> = ks
+        .key_map
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, entry)| !entry.is_expired(now))
+        .map(|(key, entry)| (key.clone(), entry.expires_at))
+        .collect();
+    let mut count = 0;
+    for (key, expires_at) in keys {
+        // Keys deleted since they were collected are skipped.
+        let Some(value) = inner.get(ks, &key)? else {
+            continue;
+        };
+        writer.write_all(&[ENTRY_TAG])?;
+        write_bytes(writer, &key)?;
+        write_bytes(writer, &value)?;
+        writer.write_all(&expires_at.unwrap_or(0).to_be_bytes())?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Writes every entry of the dump read from `reader` into `engine`, returning the number of
+/// entries read. Entries that have expired by the time they are read are skipped.
+pub(crate) fn import(engine: &Engine, reader: impl Read) -> Result<u64> {
+    let mut tree: Tree = (**engine).clone();
+    let mut count = 0;
+    for record in DumpReader::new(reader, tree.engine.limits())? {
+        match record? {
+            DumpRecord::Tree(name) => tree = engine.open_tree(&name)?,
+            DumpRecord::Entry { key, value, expires_at } => {
+                count += 1;
+                if expires_at.is_some_and(|t| t <= tree.engine.now()) {
+                    continue;
+                }
+                let inner = &tree.engine;
+                inner.check_limits(&key, &value)?;
+                let mutation = Mutation::Set { key, value, expires_at };
+                inner.write(|| intercept::apply(inner, &tree.keyspace, mutation))?;
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Merges the dump in the file at `path`, whose keys must be in ascending order within each
+/// tree, into `engine` as new log segments, returning the number of entries read. Expired
+/// entries are skipped, and nothing is merged if the file is invalid.
+pub(crate) fn ingest(engine: &Engine, path: &Path) -> Result<u64> {
+    let inner = &engine.engine;
+    let records = DumpReader::new(File::open(path)?, inner.limits())?;
+    let _compacting = inner.compaction_lock.lock().unwrap();
+    inner.write(|| {
+        bulk::load(inner, |loader| {
+            let (mut name, mut tree) = (String::new(), inner.keyspace(DEFAULT_TREE));
+            let mut count = 0;
+            for record in records {
+                match record? {
+                    DumpRecord::Tree(next) => {
+                        tree = loader.tree(&next)?;
+                        name = next;
+                    }
+                    DumpRecord::Entry { key, value, expires_at } => {
+                        let (key, value, expires_at) = intercept::loaded(inner, &name, key, value, expires_at)?;
+                        loader.set(&tree, key, value, expires_at)?;
+                        count += 1;
+                    }
+                }
+            }
+            Ok(count)
+        })
+    })
+}
+
+// A record of a dump.
+enum DumpRecord {
+    // Starts the entries of the named tree.
+    Tree(String),
+    Entry {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    },
+}
+
+// Reads the records of a dump in order.
+struct DumpReader<R> {
+    reader: BufReader<R>,
+    // Longest key and value accepted, which guard allocations against corrupted lengths.
+    limits: (usize, usize),
+}
+
+impl<R: Read> DumpReader<R> {
+    // Checks the header of the dump read from `reader`.
+    fn new(reader: R, limits: (usize, usize)) -> Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let mut header = [0; DUMP_HEADER.len()];
+        read_exact(&mut reader, &mut header)?;
+        if header != DUMP_HEADER {
+            return Err(Error::Corrupted("unrecognized dump header".to_string()));
+        }
+        Ok(Self { reader, limits })
+    }
+
+    fn read_record(&mut self) -> Result<Option<DumpRecord>> {
+        let (key_limit, value_limit) = self.limits;
+        let reader = &mut self.reader;
+        let mut tag = [0; 1];
+        match reader.read_exact(&mut tag) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        match tag[0] {
+            TREE_TAG => {
+                let name = String::from_utf8(read_bytes(reader, key_limit)?)
+                    .map_err(|_| Error::Corrupted("tree name in dump is not UTF-8".to_string()))?;
+                Ok(Some(DumpRecord::Tree(name)))
+            }
+            ENTRY_TAG => {
+                let key = read_bytes(reader, key_limit)?;
+                let value = read_bytes(reader, value_limit)?;
+                let mut expires_at = [0; 8];
+                read_exact(reader, &mut expires_at)?;
+                let expires_at = Some(u64::from_be_bytes(expires_at)).filter(|&t| t != 0);
+                Ok(Some(DumpRecord::Entry { key, value, expires_at }))
+            }
+            tag => Err(Error::Corrupted(format!("unknown record tag in dump: {}", tag))),
+        }
+    }
+}
+
+impl<R: Read> Iterator for DumpReader<R> {
+    type Item = Result<DumpRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_bytes(reader: &mut impl Read, limit: usize) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    read_exact(reader, &mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    // Guards the allocation against a corrupted length; no acceptable key or value is this large.
+    if len > limit {
+        return Err(Error::Corrupted(format!("length {} in dump exceeds the limit of {}", len, limit)));
+    }
+    let mut bytes = vec![0; len];
+    read_exact(reader, &mut bytes)?;
+    Ok(bytes)
+}
+
+// Reads exactly `buf.len()` bytes, reporting a dump that ends mid-record as corrupted.
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            Error::Corrupted("dump ends in the middle of a record".to_string())
+        } else {
+            e.into()
+        }
+    })
+}
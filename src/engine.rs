@@ -1,139 +1,1519 @@
 //! Tegdb Engine: A persistent key-value store with an append-only log and automatic compaction.
 //! This module implements CRUD operations and log rebuilding to maintain data integrity.
 
+use crate::cache::ValueCache;
+use crate::changes::{Change, Feed};
+use crate::checkpoint;
+use crate::chunks;
+use crate::compaction;
+use crate::dump;
+use crate::error::{Error, Result};
+use crate::health::{self, DiskWatch, HealthReport};
+use crate::horizon::Horizon;
+use crate::history;
+use crate::index;
 use crate::log;
+use crate::observer::{self, TreeNames};
+use crate::options::EngineOptions;
+use crate::order::{self, OrderedMap};
+use crate::scan::{Iter, Pairs};
+use crate::sink::SinkOptions;
+use crate::snapshot;
+use crate::stats::{Counters, SpaceStats, Stats};
+use crate::task;
+use crate::tree::{Keyspace, Tree, DEFAULT_TREE, HISTORY_TREE, META_TREE};
+use crate::verify::{self, Corruption, Verification};
+use crate::watch::{Event, Op};
 
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::ops::Range;
-use dashmap::DashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io::{Read, Write};
+use std::ops::{Bound, Deref, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+
+/// Ordered index of live keys, so range queries only visit the keys they return.
+pub(crate) type KeyMap = RwLock<OrderedMap<Entry>>;
+
+/// Index entry pointing at the log entry that holds a key's live value.
+pub(crate) struct Entry {
+    pub(crate) location: log::Location,
+    pub(crate) value_len: u32,
+    // Write ticket used to tell whether the entry has reached its segment file yet.
+    pub(crate) ticket: u64,
+    // The value itself, unless values are only kept on disk.
+    pub(crate) value: Option<Bytes>,
+    // Milliseconds since the Unix epoch after which the key no longer exists.
+    pub(crate) expires_at: Option<u64>,
+    // How the value is stored in the log; `value_len` is its stored length.
+    pub(crate) codec: log::Codec,
+    // Sequence number of the write, or 0 if it predates sequence numbers.
+    pub(crate) sequence: u64,
+    // Whether the entry ends with a checksum in the log.
+    pub(crate) checksum: bool,
+    // When the key was created and last modified, if the entry records it.
+    pub(crate) timestamps: Option<log::Timestamps>,
+    // Set when the value is read, so that it stays in memory over colder values.
+    pub(crate) recent: AtomicBool,
+}
+
+// Approximate memory taken by each key map entry besides its key and value: the handles to
+// them, the entry itself and its share of the map's nodes.
+pub(crate) const ENTRY_OVERHEAD: u64 = (std::mem::size_of::<Bytes>() * 2 + std::mem::size_of::<Entry>()) as u64;
+
+impl Entry {
+    /// Returns the approximate memory taken by the entry and its key of `key_len` bytes in a
+    /// key map.
+    pub(crate) fn footprint(&self, key_len: usize) -> u64 {
+        ENTRY_OVERHEAD + key_len as u64 + self.value_bytes()
+    }
+
+    /// Returns the length of the value kept in memory, if any.
+    pub(crate) fn value_bytes(&self) -> u64 {
+        self.value.as_ref().map_or(0, |v| v.len() as u64)
+    }
+
+    /// Returns the number of bytes the entry occupies in the log, for a key of `key_len` bytes
+    /// of `tree`.
+    pub(crate) fn log_size(&self, tree: u32, key_len: usize) -> u64 {
+        let (value_len, expires_at, codec) = (self.value_len, self.expires_at, self.codec);
+        let (sequence, timestamps, checksum) = (self.sequence, self.timestamps.is_some(), self.checksum);
+        log::entry_size_of(tree, key_len, value_len, expires_at, codec, sequence, timestamps, checksum)
+    }
+
+    /// Records that the value was read.
+    fn touch(&self) {
+        if !self.recent.load(Ordering::Relaxed) {
+            self.recent.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+}
 
 /// Core storage engine that provides CRUD operations with log compaction.
+/// The engine dereferences to its default [`Tree`]; further trees are opened with
+/// [`Engine::open_tree`].
+///
+/// An engine is `Send` and `Sync` and every operation takes `&self`, so a single handle can be
+/// shared by reference between threads and tasks. Cloning it only copies a reference to the
+/// same database, for tasks that need to own their handle. The database stays open until every
+/// clone and every tree opened from it have been dropped.
+///
+/// Reads and writes do their disk I/O on the thread polling them, blocking it for as long as
+/// the disk takes, and with [`EngineOptions::sync_writes`] for as long as each fsync takes, so
+/// their futures complete on first poll. Operations that wait for the log writer thread or do
+/// long-running work, such as [`Engine::sync`], [`Engine::compact`] and [`Engine::checkpoint`],
+/// leave the thread polling them free instead. [`Engine::export`] and [`Engine::import`] are
+/// plain blocking methods.
 #[derive(Clone)]
 pub struct Engine {
-    log: Arc<log::Log>,
-    key_map: Arc<DashMap<Vec<u8>, Vec<u8>>>,
+    tree: Tree,
+}
+
+/// State shared by every clone of an [`Engine`] and by its background compactor.
+pub(crate) struct Inner {
+    pub(crate) log: log::Log,
+    // Index of every tree by id.
+    trees: RwLock<HashMap<u32, Arc<Keyspace>>>,
+    pub(crate) options: EngineOptions,
+    // Serializes log appends with key map updates so both observe writes in the same order.
+    pub(crate) write_lock: Mutex<()>,
+    // Ensures only one compaction runs at a time.
+    pub(crate) compaction_lock: Mutex<()>,
+    // Recently read values when values are only kept on disk.
+    pub(crate) cache: ValueCache,
+    // Subscribers to every committed change, such as replication connections.
+    pub(crate) feed: Feed,
+    // Activity reported by `Engine::stats`.
+    pub(crate) counters: Counters,
+    // Set once values have been dropped from memory to respect `index_memory_limit`.
+    values_spilled: AtomicBool,
+    // Tree and key from which the next values are dropped from memory under
+    // `value_memory_budget`.
+    demotion_cursor: Mutex<(u32, Bytes)>,
+    // Progress of replaying the log, which reads and writes wait for.
+    replay: Replay,
+    // First corrupt range found by scrubbing the log, which `Engine::health` reports.
+    pub(crate) corruption: Mutex<Option<Corruption>>,
+    // Error the latest background compaction failed with, which `Engine::health` reports
+    // until one succeeds.
+    pub(crate) compaction_error: Mutex<Option<Error>>,
+    // Error writing the latest periodic index snapshot failed with, which `Engine::health`
+    // reports until one is written.
+    pub(crate) snapshot_error: Mutex<Option<Error>>,
+    // Error the latest periodic scrub failed with, which `Engine::health` reports until one
+    // runs through.
+    pub(crate) scrub_error: Mutex<Option<Error>>,
+    // Whether the disk holding the database is full.
+    pub(crate) disk: DiskWatch,
+    // How far back compaction keeps superseded writes.
+    pub(crate) horizon: Horizon,
+    // Names of the trees reported to observers and interceptors.
+    pub(crate) tree_names: TreeNames,
+    // Whether the database directory is deleted once the engine is closed or dropped.
+    temporary: bool,
+    // Dropping this sender stops the background compactor.
+    _compactor: Option<Sender<()>>,
 }
 
 impl Engine {
-    /// Creates a new Engine instance.
+    /// Opens the database stored in the directory at `path`, creating it if it does not exist.
     /// Initializes the underlying log, reconstructs the in-memory key map from the log,
-    /// and performs an immediate compaction to optimize storage.
+    /// and compacts it if the configured thresholds are already exceeded.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_options(path, EngineOptions::default())
+    }
+
+    /// Opens the database stored at `path` with the given options.
+    pub fn open_with_options<P: AsRef<Path>>(path: P, options: EngineOptions) -> Result<Self> {
+        Self::open_in(path.as_ref(), options, false)
+    }
+
+    /// Creates an empty database in a new directory under the system's temporary directory,
+    /// which is deleted once the engine is closed or its last handle dropped. Meant for tests
+    /// and state that need not outlive the process.
+    pub fn open_temporary() -> Result<Self> {
+        Self::open_temporary_with_options(EngineOptions::default())
+    }
+
+    /// Creates a temporary database with the given options; see [`Engine::open_temporary`].
+    pub fn open_temporary_with_options(options: EngineOptions) -> Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let path = loop {
+            let id = NEXT.fetch_add(1, Ordering::Relaxed);
+            let path = std::env::temp_dir().join(format!("tegdb-{}-{}-{}", std::process::id(), nanos, id));
+            match std::fs::create_dir(&path) {
+                Ok(()) => break path,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        Self::open_in(&path, options, true).inspect_err(|_| {
+            let _ = std::fs::remove_dir_all(&path);
+        })
+    }
+
+    fn open_in(path: &Path, options: EngineOptions, temporary: bool) -> Result<Self> {
+        let log = log::Log::open(
+            path.to_path_buf(),
+            options.segment_size,
+            options.read_only,
+            options.compression,
+            options.write_queue_capacity,
+            SinkOptions::new(&options),
+            options.comparator.map(|comparator| comparator.name),
+        )?;
+        let horizon = Horizon::open(path, clock_millis(&options), log.last_sequence())?;
+        let mut trees = HashMap::new();
+        for id in [DEFAULT_TREE, META_TREE] {
+            let key_map = KeyMap::new(OrderedMap::new(order::of_tree(options.comparator, id)));
+            trees.insert(id, Arc::new(Keyspace::new(id, key_map, BTreeSet::new())));
+        }
+        let replay = if options.background_replay {
+            Replay::pending()
+        } else {
+            let now = clock_millis(&options);
+            let built_trees = log.build_key_map(options.keep_values_in_memory, now, options.comparator)?;
+            for keyspace in index(built_trees) {
+                trees.insert(keyspace.id, Arc::new(keyspace));
+            }
+            Replay::finished()
+        };
+        // Values dropped from memory under the index memory limit are cached like others.
+        let cache_size = if options.keep_values_in_memory && options.index_memory_limit.is_none() {
+            0
+        } else {
+            options.value_cache_size
+        };
+        let inner = Arc::new_cyclic(|weak| Inner {
+            log,
+            trees: RwLock::new(trees),
+            _compactor: compaction::spawn(weak.clone(), &options),
+            options,
+            write_lock: Mutex::new(()),
+            compaction_lock: Mutex::new(()),
+            cache: ValueCache::new(cache_size),
+            feed: Feed::default(),
+            counters: Counters::new(),
+            values_spilled: AtomicBool::new(false),
+            demotion_cursor: Mutex::new((DEFAULT_TREE, Bytes::new())),
+            corruption: Mutex::new(None),
+            compaction_error: Mutex::new(None),
+            snapshot_error: Mutex::new(None),
+            scrub_error: Mutex::new(None),
+            disk: DiskWatch::default(),
+            horizon,
+            tree_names: TreeNames::default(),
+            replay,
+            temporary,
+        });
+        if inner.options.background_replay {
+            let inner = inner.clone();
+            thread::spawn(move || inner.replay_in_background());
+        } else {
+            inner.count_index();
+            if !inner.options.read_only && inner.needs_compaction() {
+                compaction::compact(&inner, false)?;
+            }
+        }
+        let keyspace = inner.keyspace(DEFAULT_TREE);
+        Ok(Self {
+            tree: Tree {
+                engine: inner,
+                keyspace,
+            },
+        })
+    }
+
+    /// Creates a new Engine instance, panicking if the database cannot be opened.
+    #[deprecated(note = "use `Engine::open`, which returns an error instead of panicking")]
     pub fn new(path: PathBuf) -> Self {
-        let log = Arc::new(log::Log::new(path));
-        let built_map = log.build_key_map();
-        let key_map = Arc::new(DashMap::new());
-        for (k, v) in built_map {
-            key_map.insert(k, v);
-        }
-        let mut s = Self { log, key_map };
-        s.compact().expect("Failed to compact log");
-        s
-    }
-
-    /// Retrieves the value associated with the given key asynchronously.
-    pub async fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.key_map.get(key).map(|entry| entry.value().clone())
-    }
-
-    /// Inserts or updates the value for the given key.
-    /// If an empty value is provided, the key is removed.
-    /// Returns an error if the key or value exceeds predefined size limits.
-    pub async fn set(&self, key: &[u8], value: Vec<u8>) -> Result<(), std::io::Error> {
-        if key.len() > 1024 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "Key length exceeds 1k",
-            ));
-        }
-        if value.len() > 256 * 1024 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "Value length exceeds 256k",
-            ));
+        Self::open(path).expect("Failed to open database")
+    }
+
+    /// Opens the tree called `name`, creating it if it does not exist yet.
+    /// Trees are isolated keyspaces: their keys never show up in the default tree or in any
+    /// other tree, while all of them share the engine's log.
+    pub fn open_tree(&self, name: &str) -> Result<Tree> {
+        let inner = &self.tree.engine;
+        let meta = inner.keyspace(META_TREE);
+        // Existing trees are looked up without the write lock, so they open read-only too.
+        if let Some(id) = inner.get(&meta, name.as_bytes())? {
+            let id = tree_id(name, &id)?;
+            return Ok(Tree {
+                engine: inner.clone(),
+                keyspace: inner.keyspace(id),
+            });
+        }
+        let id = inner.write(|| match inner.get(&meta, name.as_bytes())? {
+            Some(id) => tree_id(name, &id),
+            None => {
+                inner.check_limits(name.as_bytes(), &[])?;
+                let id = inner.next_tree_id();
+                inner.set(&meta, name.as_bytes(), id.to_be_bytes().to_vec(), None)?;
+                Ok(id)
+            }
+        })?;
+        Ok(Tree {
+            engine: inner.clone(),
+            keyspace: inner.keyspace(id),
+        })
+    }
+
+    /// Deletes the tree called `name` along with all of its keys, returning whether it existed.
+    /// The keys are deleted with a single log entry. Handles to the tree must not be used
+    /// once it has been dropped.
+    pub fn drop_tree(&self, name: &str) -> Result<bool> {
+        let inner = &self.tree.engine;
+        let meta = inner.keyspace(META_TREE);
+        inner.write(|| {
+            let Some(id) = inner.get(&meta, name.as_bytes())? else {
+                return Ok(false);
+            };
+            let id = tree_id(name, &id)?;
+            inner.delete_range(&inner.keyspace(id), &[], &[])?;
+            history::delete_tree(inner, id)?;
+            inner.del(&meta, name.as_bytes())?;
+            inner.trees.write().unwrap().remove(&id);
+            Ok(true)
+        })
+    }
+
+    /// Returns the names of the trees created with [`Engine::open_tree`], in order.
+    pub fn tree_names(&self) -> Vec<String> {
+        let inner = &self.tree.engine;
+        // No tree is listed if the replay failed.
+        let _ = inner.replayed();
+        let names = inner.keys_in(&inner.keyspace(META_TREE), &(..));
+        names.iter().map(|name| String::from_utf8_lossy(name).into_owned()).collect()
+    }
+
+    /// Returns every tree created with [`Engine::open_tree`] along with its name, in order of
+    /// their names. Trees created or dropped meanwhile may or may not be included.
+    pub fn trees(&self) -> Result<Vec<(String, Tree)>> {
+        let inner = &self.tree.engine;
+        let mut trees = Vec::new();
+        for (name, id) in inner.scan(&inner.keyspace(META_TREE), &(..))? {
+            let name = String::from_utf8_lossy(&name).into_owned();
+            let id = tree_id(&name, &id)?;
+            let tree = Tree {
+                engine: inner.clone(),
+                keyspace: inner.keyspace(id),
+            };
+            trees.push((name, tree));
+        }
+        Ok(trees)
+    }
+
+    /// Returns the number of bytes the database's files occupy on disk.
+    pub fn size_on_disk(&self) -> Result<u64> {
+        let mut size = 0;
+        for entry in std::fs::read_dir(&self.tree.engine.log.dir)? {
+            size += entry?.metadata()?.len();
+        }
+        Ok(size)
+    }
+
+    /// Returns the sequence number of the latest write to any tree, or 0 if nothing has been
+    /// written. See [`Tree::changes_since`].
+    pub fn last_sequence(&self) -> u64 {
+        self.tree.engine.log.last_sequence()
+    }
+
+    /// Records that `consumer`, such as a changefeed reader, has processed every write up to
+    /// the one numbered `sequence`. Until it is removed with [`Engine::remove_consumer`],
+    /// compaction keeps the writes made after the lowest sequence number acknowledged by any
+    /// consumer, even once they are overwritten or deleted, so that [`Tree::changes_since`]
+    /// still returns them. Acknowledgements survive restarts, and an
+    /// acknowledgement below an earlier one of the same consumer is ignored. Consumer names
+    /// must be a single line.
+    pub async fn acknowledge(&self, consumer: &str, sequence: u64) -> Result<()> {
+        let inner = &self.tree.engine;
+        if inner.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+        inner.horizon.acknowledge(inner, consumer, sequence)
+    }
+
+    /// Stops `consumer` from holding back compaction, returning whether it had acknowledged
+    /// anything.
+    pub async fn remove_consumer(&self, consumer: &str) -> Result<bool> {
+        let inner = &self.tree.engine;
+        if inner.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+        inner.horizon.remove(inner, consumer)
+    }
+
+    /// Returns the consumers holding back compaction along with the sequence number each
+    /// acknowledged, ordered by name.
+    pub fn consumers(&self) -> Vec<(String, u64)> {
+        self.tree.engine.horizon.consumers()
+    }
+
+    /// Returns the sequence number up to which compaction may drop overwritten values and
+    /// deletions, as set by [`EngineOptions::retention`] and the consumers, or `u64::MAX` if
+    /// nothing holds them back. The writes made after it are kept.
+    pub fn gc_horizon(&self) -> u64 {
+        let inner = &self.tree.engine;
+        inner.horizon.sequence(inner)
+    }
+
+    /// Returns the directory the database is stored in.
+    pub fn path(&self) -> &Path {
+        &self.tree.engine.log.dir
+    }
+
+    /// Returns true once the log has been replayed. This is only false right after opening
+    /// with [`EngineOptions::background_replay`], while operations wait for the replay.
+    pub fn is_replayed(&self) -> bool {
+        self.tree.engine.replay.is_finished()
+    }
+
+    /// Returns statistics about the contents of the database and about its activity since it
+    /// was opened.
+    pub fn stats(&self) -> Stats {
+        let inner = &self.tree.engine;
+        let mut stats = Stats {
+            live_keys: inner.live_keys(),
+            ..Stats::default()
+        };
+        inner.log.fill_stats(&mut stats);
+        (stats.cache_hits, stats.cache_misses) = inner.cache.hits_and_misses();
+        stats.cache_bytes = inner.cache.size();
+        stats.values_spilled = inner.values_spilled.load(Ordering::Relaxed);
+        inner.counters.fill(&mut stats);
+        stats
+    }
+
+    /// Returns how much of the log is taken up by live entries and how much compaction could
+    /// reclaim, overall and for each segment. The figures are kept up to date as entries are
+    /// written, so this does not read the log.
+    pub fn space_stats(&self) -> SpaceStats {
+        self.tree.engine.log.space_stats()
+    }
+
+    /// Returns the state of the engine: whether writes fail, how much disk space is left, how
+    /// far the log writer thread lags behind, what corruption has been found and whether
+    /// background compaction, index snapshots or scrubbing fail, for example to serve a
+    /// readiness probe. Apart from asking the file system for the available space, this only
+    /// reads what the engine keeps in memory.
+    pub fn health(&self) -> HealthReport {
+        let engine = &self.tree.engine;
+        let mut report = HealthReport {
+            corruption: engine.corruption.lock().unwrap().clone(),
+            compaction_error: engine.compaction_error.lock().unwrap().as_ref().map(Error::duplicate),
+            snapshot_error: engine.snapshot_error.lock().unwrap().as_ref().map(Error::duplicate),
+            scrub_error: engine.scrub_error.lock().unwrap().as_ref().map(Error::duplicate),
+            disk_full: engine.disk.is_full(),
+            ..HealthReport::default()
+        };
+        engine.log.fill_health(&mut report);
+        report
+    }
+
+    /// Checks every entry of the sealed log segments like [`Engine::verify`], while the
+    /// database stays open, and reports the corrupt ranges found, the first of which the
+    /// [`HealthReport`] of [`Engine::health`] holds from then on. The active segment is checked once sealed.
+    /// [`EngineOptions::scrub_interval`] runs this in the background.
+    pub async fn scrub(&self) -> Result<Verification> {
+        let inner = self.tree.engine.clone();
+        task::unblock(move || verify::scrub(&inner)).await
+    }
+
+    /// Waits until every write made so far has been handed to the operating system, failing
+    /// if the log writer thread could not write one. The writes survive the process crashing
+    /// but not the machine losing power; see [`Engine::sync`].
+    pub async fn flush(&self) -> Result<()> {
+        self.tree.engine.log.flush().await
+    }
+
+    /// Waits until every write made so far has been fsynced to disk, so that it survives the
+    /// machine losing power. This makes a durability barrier at chosen points without paying
+    /// for [`EngineOptions::sync_writes`] on every write; concurrent calls share one fsync.
+    pub async fn sync(&self) -> Result<()> {
+        self.tree.engine.log.sync().await
+    }
+
+    /// Closes the database: waits for every write made so far to be written and fsynced,
+    /// stops the log writer thread and releases the lock on the directory, so the database can
+    /// be opened again right away, including by another process. Dropping the last handle
+    /// flushes the writes but does not wait for the writer thread or fsync them.
+    ///
+    /// The engine, its clones and the trees opened from it stay usable for reads, while their
+    /// writes fail with [`Error::Closed`]. Closing a database that is already closed does
+    /// nothing.
+    ///
+    /// If [`EngineOptions::index_snapshot_interval`] is set, a snapshot of the index is written
+    /// first, so that the next open does not need to replay the log. The work runs on a thread
+    /// of its own, as it waits for any compaction in progress.
+    pub async fn close(&self) -> Result<()> {
+        let inner = self.tree.engine.clone();
+        task::unblock(move || inner.close()).await
+    }
+
+    /// Rewrites the log so it only contains live entries and returns the number of bytes reclaimed.
+    /// Reads and writes proceed concurrently; writes are held back only while the rewritten
+    /// segments are swapped in. The rewrite runs on a thread of its own, so the executor polling
+    /// the future is free to run other tasks until it completes.
+    pub async fn compact(&self) -> Result<u64> {
+        let inner = self.tree.engine.clone();
+        task::unblock(move || compaction::compact(&inner, true)).await
+    }
+
+    /// Writes a compacted copy of the database to a new directory at `path`, for example as a
+    /// backup. The copy reflects a single point in time; writes are only held back while the
+    /// index is captured, not while values are copied.
+    pub async fn checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let (inner, path) = (self.tree.engine.clone(), path.as_ref().to_path_buf());
+        task::unblock(move || checkpoint::checkpoint(&inner, &path)).await
+    }
+
+    /// Writes a copy of the database to a new directory at `path` that hard-links the sealed
+    /// segments of the log rather than copying them, and only copies the active one, so large
+    /// databases are backed up almost at once. The copy reflects a single point in time and
+    /// takes no more space than the active segment until compaction replaces the segments it
+    /// shares. Segments are copied instead where `path` is on another file system. Writes are
+    /// only held back until the log has been flushed.
+    pub async fn snapshot_dir<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let (inner, path) = (self.tree.engine.clone(), path.as_ref().to_path_buf());
+        task::unblock(move || snapshot::snapshot(&inner, &path)).await
+    }
+
+    /// Writes every key-value pair of every tree to `writer` in a portable dump format that
+    /// does not depend on the log format or the machine, returning the number of pairs written.
+    /// Expired keys are left out.
+    ///
+    /// The dump starts with the line `tegdb dump 1`. Each record then begins with a tag byte:
+    /// `1` starts a named tree and is followed by the name, and `2` is a key-value pair made of
+    /// the key, the value and its expiration time in milliseconds since the Unix epoch as a
+    /// `u64`, or 0 if it never expires. Names, keys and values are prefixed with their length
+    /// as a `u32`, and all integers are big-endian. Pairs before the first tree record belong
+    /// to the default tree.
+    ///
+    /// Unlike most operations, this is not async: it blocks the calling thread while it reads
+    /// the values and writes to `writer`, so async callers should run it where blocking is
+    /// allowed, such as with `tokio::task::spawn_blocking`.
+    pub fn export<W: Write>(&self, writer: W) -> Result<u64> {
+        dump::export(self, writer)
+    }
+
+    /// Writes every key-value pair of a dump produced by [`Engine::export`] into the database,
+    /// creating trees as needed, and returns the number of pairs read. Existing keys are
+    /// overwritten. Like [`Engine::export`], this blocks the calling thread.
+    pub fn import<R: Read>(&self, reader: R) -> Result<u64> {
+        dump::import(self, reader)
+    }
+
+    /// Merges the dump in the file at `path`, in the format written by [`Engine::export`], into
+    /// the database and returns the number of pairs read, creating trees as needed. Unlike
+    /// [`Engine::import`], the pairs are written straight to new log segments, which are added
+    /// to the log at once, so large files from ETL jobs load far faster. Within each tree, keys
+    /// must come in ascending order, as they do in exports.
+    ///
+    /// Ingested pairs overwrite existing keys. Other writes wait until the file is merged, and
+    /// nothing is merged if it turns out to be invalid. Values are never split into chunks,
+    /// and the versions that ingested pairs replace are not retained in the history of keys.
+    pub async fn ingest_file<P: AsRef<Path>>(&self, path: P) -> Result<u64> {
+        let (engine, path) = (self.clone(), path.as_ref().to_path_buf());
+        task::unblock(move || dump::ingest(&engine, &path)).await
+    }
+
+    /// Checks every entry of the log of the database at `path`, which must not be open for
+    /// writing, and reports the ranges that cannot be read back: entries that are truncated,
+    /// cannot be decoded or whose checksum does not match. Entries written before checksums
+    /// were introduced are only checked for being decodable.
+    pub fn verify<P: AsRef<Path>>(path: P) -> Result<Verification> {
+        verify::verify(path.as_ref(), false)
+    }
+
+    /// Checks the log of the database at `path` like [`Engine::verify`] and removes the corrupt
+    /// ranges found, so that the database opens again with every intact entry. The database
+    /// must not be open. The keys whose latest writes were lost, as far as they could be told,
+    /// are listed in the returned report. Any index snapshot is removed, so the next open
+    /// replays the whole log.
+    pub fn repair<P: AsRef<Path>>(path: P) -> Result<Verification> {
+        verify::verify(path.as_ref(), true)
+    }
+}
+
+impl fmt::Debug for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Engine")
+            .field("path", &self.tree.engine.log.dir)
+            .field("keys", &self.tree.engine.live_keys())
+            .finish()
+    }
+}
+
+/// Iterates over the default tree; see [`Tree::iter`].
+impl IntoIterator for &Engine {
+    type Item = Result<(Bytes, Bytes)>;
+    type IntoIter = Iter;
+
+    fn into_iter(self) -> Iter {
+        self.tree.iter()
+    }
+}
+
+impl Deref for Engine {
+    type Target = Tree;
+
+    fn deref(&self) -> &Tree {
+        &self.tree
+    }
+}
+
+/// Returns the current time in milliseconds since the Unix epoch, as told by the simulation in
+/// `options` if there is one.
+pub(crate) fn clock_millis(options: &EngineOptions) -> u64 {
+    #[cfg(feature = "sim")]
+    if let Some(simulation) = &options.simulation {
+        return simulation.now_millis();
+    }
+    #[cfg(not(feature = "sim"))]
+    let _ = options;
+    now_millis()
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Decodes the id stored for the tree called `name` in the meta tree.
+pub(crate) fn tree_id(name: &str, id: &[u8]) -> Result<u32> {
+    let id = <[u8; 4]>::try_from(id)
+        .map_err(|_| Error::Corrupted(format!("invalid id for tree {:?}", name)))?;
+    Ok(u32::from_be_bytes(id))
+}
+
+/// Borrows the bounds of `range` as slices so they can be used to query the key map.
+pub(crate) fn as_slices(range: &impl RangeBounds<Vec<u8>>) -> (Bound<&[u8]>, Bound<&[u8]>) {
+    (
+        range.start_bound().map(Vec::as_slice),
+        range.end_bound().map(Vec::as_slice),
+    )
+}
+
+/// Turns the trees recovered by replaying the log into keyspaces.
+fn index(built_trees: log::ReplayedTrees) -> Vec<Keyspace> {
+    built_trees
+        .into_iter()
+        .map(|(id, built_map)| {
+            let mut expirations = BTreeSet::new();
+            let key_map = built_map.map_values(|key, replayed| {
+                if let Some(expires_at) = replayed.expires_at {
+                    expirations.insert((expires_at, key.clone()));
+                }
+                Entry {
+                    location: replayed.location,
+                    value_len: replayed.value_len,
+                    ticket: 0,
+                    value: replayed.value.map(Bytes::from),
+                    expires_at: replayed.expires_at,
+                    codec: replayed.codec,
+                    sequence: replayed.sequence,
+                    checksum: replayed.checksum,
+                    timestamps: replayed.timestamps,
+                    recent: AtomicBool::new(false),
+                }
+            });
+            Keyspace::new(id, RwLock::new(key_map), expirations)
+        })
+        .collect()
+}
+
+/// Progress of replaying the log, which only runs after opening when the engine is opened with
+/// [`EngineOptions::background_replay`].
+struct Replay {
+    // Set once the replay has succeeded, so that operations need not take the lock below.
+    succeeded: AtomicBool,
+    // The outcome of the replay once it has finished.
+    outcome: Mutex<Option<Result<()>>>,
+    finished: Condvar,
+}
+
+impl Replay {
+    fn pending() -> Self {
+        Self {
+            succeeded: AtomicBool::new(false),
+            outcome: Mutex::new(None),
+            finished: Condvar::new(),
+        }
+    }
+
+    fn finished() -> Self {
+        let replay = Self::pending();
+        replay.finish(Ok(()));
+        replay
+    }
+
+    fn finish(&self, outcome: Result<()>) {
+        let succeeded = outcome.is_ok();
+        *self.outcome.lock().unwrap() = Some(outcome);
+        self.succeeded.store(succeeded, Ordering::Release);
+        self.finished.notify_all();
+    }
+
+    fn is_finished(&self) -> bool {
+        self.succeeded.load(Ordering::Acquire) || self.outcome.lock().unwrap().is_some()
+    }
+
+    // Blocks until the replay has finished and returns its outcome.
+    fn wait(&self) -> Result<()> {
+        if self.succeeded.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let outcome = self.outcome.lock().unwrap();
+        let outcome = self.finished.wait_while(outcome, |outcome| outcome.is_none()).unwrap();
+        match outcome.as_ref().unwrap() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(e.duplicate()),
+        }
+    }
+}
+
+impl Inner {
+    /// Closes the database like [`Engine::close`], blocking until it is closed.
+    fn close(&self) -> Result<()> {
+        let snapshot = if self.options.index_snapshot_interval.is_some()
+            && !self.options.read_only
+            && !self.log.is_closed()
+        {
+            index::write(self)
+        } else {
+            Ok(())
+        };
+        let _compacting = self.compaction_lock.lock().unwrap();
+        let _guard = self.write_lock.lock().unwrap();
+        let closed = self.log.close().and(snapshot);
+        if self.temporary {
+            self.remove_dir();
+        }
+        closed
+    }
+
+    /// Returns the keyspace of the tree with the given id, creating an empty one if needed.
+    pub(crate) fn keyspace(&self, id: u32) -> Arc<Keyspace> {
+        if let Some(keyspace) = self.trees.read().unwrap().get(&id) {
+            return keyspace.clone();
+        }
+        let mut trees = self.trees.write().unwrap();
+        trees
+            .entry(id)
+            .or_insert_with(|| {
+                let key_map = KeyMap::new(OrderedMap::new(order::of_tree(self.options.comparator, id)));
+                Arc::new(Keyspace::new(id, key_map, BTreeSet::new()))
+            })
+            .clone()
+    }
+
+    /// Returns the keyspace of the tree with the given id, or `None` if there is no such tree,
+    /// such as once it has been dropped.
+    pub(crate) fn existing_keyspace(&self, id: u32) -> Option<Arc<Keyspace>> {
+        self.trees.read().unwrap().get(&id).cloned()
+    }
+
+    /// Returns the keyspace of every tree.
+    /// Waits until the log has been replayed, failing if the replay did.
+    pub(crate) fn replayed(&self) -> Result<()> {
+        self.replay.wait()
+    }
+
+    /// Replays the log and installs the key maps of its trees, then releases the operations
+    /// waiting for it. Run on a background thread when the engine is opened with
+    /// [`EngineOptions::background_replay`].
+    fn replay_in_background(&self) {
+        let replayed = self
+            .log
+            .build_key_map(self.options.keep_values_in_memory, self.now(), self.options.comparator)
+            .map(|built_trees| {
+                for built in index(built_trees) {
+                    let keyspace = self.keyspace(built.id);
+                    *keyspace.key_map.write().unwrap() = built.key_map.into_inner().unwrap();
+                    *keyspace.expirations.lock().unwrap() = built.expirations.into_inner().unwrap();
+                }
+                self.count_index();
+            });
+        let succeeded = replayed.is_ok();
+        self.replay.finish(replayed);
+        if succeeded && !self.options.read_only && self.needs_compaction() {
+            *self.compaction_error.lock().unwrap() = compaction::compact(self, false).err();
+        }
+    }
+
+    /// Returns the current time in milliseconds since the Unix epoch, from the simulation's
+    /// clock if the engine runs under one.
+    pub(crate) fn now(&self) -> u64 {
+        clock_millis(&self.options)
+    }
+
+    /// Returns the number of keys across every tree but the internal ones.
+    pub(crate) fn live_keys(&self) -> u64 {
+        self.trees
+            .read()
+            .unwrap()
+            .values()
+            .filter(|ks| ks.id < HISTORY_TREE)
+            .map(|ks| ks.key_map.read().unwrap().len() as u64)
+            .sum()
+    }
+
+    /// Returns the id to give the next tree created, registering its empty keyspace so that
+    /// later calls return further ids. The caller must hold the write lock.
+    pub(crate) fn next_tree_id(&self) -> u32 {
+        let trees = self.trees.read().unwrap();
+        let id = trees.keys().filter(|&&id| id < HISTORY_TREE).max().unwrap() + 1;
+        drop(trees);
+        self.keyspace(id);
+        id
+    }
+
+    pub(crate) fn keyspaces(&self) -> Vec<Arc<Keyspace>> {
+        self.trees.read().unwrap().values().cloned().collect()
+    }
+
+    /// Runs `f` while holding the write lock. When writes are synchronous, waits afterwards
+    /// until everything `f` wrote is durable; the lock is released first so that concurrent
+    /// writers can share the fsync.
+    pub(crate) fn write<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.write_and_sync(self.options.sync_writes, f)
+    }
+
+    /// Runs `f` while holding the write lock like [`Inner::write`], waiting afterwards until
+    /// everything `f` wrote is durable if `sync` is set, whether or not writes are synchronous.
+    pub(crate) fn write_and_sync<T>(&self, sync: bool, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
         }
+        self.replayed()?;
+        self.disk.check(self)?;
+        let result = {
+            let _guard = self.write_lock.lock().unwrap();
+            f()
+        };
+        let result = result.and_then(|result| {
+            if sync {
+                self.log.sync_and_wait()?;
+            }
+            Ok(result)
+        });
+        if let Err(Error::NoSpace) = result {
+            self.disk.run_out(self, health::available_space(&self.log.dir));
+        }
+        result
+    }
+
+    /// Returns the unexpired keys within `range` in ascending order.
+    pub(crate) fn keys_in(&self, ks: &Keyspace, range: &impl RangeBounds<Vec<u8>>) -> Vec<Bytes> {
+        let now = self.now();
+        ks.key_map
+            .read()
+            .unwrap()
+            .range(as_slices(range))
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Reports the change just committed to tree `tree` to changefeed subscribers and observers.
+    /// The caller must hold the write lock.
+    pub(crate) fn publish(&self, tree: u32, change: impl FnOnce() -> Change) {
+        if tree == META_TREE {
+            self.tree_names.clear();
+        }
+        if self.options.observers.is_empty() {
+            return self.feed.publish(tree, change);
+        }
+        let change = change();
+        observer::notify(self, tree, &change);
+        self.feed.publish(tree, || change);
+    }
+
+    pub(crate) fn scan(&self, ks: &Keyspace, range: &impl RangeBounds<Vec<u8>>) -> Result<Pairs> {
+        self.replayed()?;
+        self.pairs(ks, self.keys_in(ks, range))
+    }
+
+    /// Returns the pairs of `keys` that still exist, in the same order.
+    pub(crate) fn pairs(&self, ks: &Keyspace, keys: Vec<Bytes>) -> Result<Pairs> {
+        let mut results = Vec::with_capacity(keys.len());
+        for key in keys {
+            // Keys deleted since they were collected are skipped.
+            if let Some(value) = self.get(ks, &key)? {
+                results.push((key, value));
+            }
+        }
+        Ok(Pairs::new(results))
+    }
+
+    /// Writes `value` for `key`, deleting the key if the value is empty.
+    /// The caller must hold the write lock.
+    pub(crate) fn set(
+        &self,
+        ks: &Keyspace,
+        key: &[u8],
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
         if value.is_empty() {
-            return self.del(key).await;
+            return self.del(ks, key);
         }
-        if let Some(existing) = self.key_map.get(key) {
-            if *existing == value {
+        if let Some(existing) = ks.key_map.read().unwrap().get(key) {
+            let unchanged = existing.codec != log::Codec::Chunked
+                && existing.value.as_deref() == Some(value.as_slice())
+                && existing.expires_at == expires_at;
+            if unchanged {
                 return Ok(());
             }
         }
-        self.log.write_entry(key, &value);
-        self.key_map.insert(key.to_vec(), value);
+        let timestamps = self.options.record_timestamps.then(|| self.timestamps(ks, key));
+        let chunk_size = self.chunk_size().filter(|&chunk_size| value.len() > chunk_size);
+        // Chunked values only keep their small manifest in the key map.
+        self.reserve_index(ks, key, if chunk_size.is_some() { 0 } else { value.len() })?;
+        let (appended, manifest) = match chunk_size {
+            Some(chunk_size) => {
+                let manifest = chunks::write(self, ks.id, key, &value, chunk_size, expires_at)?;
+                let appended = self.log.write_chunked_entry(ks.id, key, &manifest.encode(), expires_at, timestamps)?;
+                (appended, Some(manifest))
+            }
+            _ => (self.log.write_entry(ks.id, key, &value, expires_at, timestamps)?, None),
+        };
+        let value = Bytes::from(value);
+        let key = Bytes::copy_from_slice(key);
+        let stored = match &manifest {
+            Some(manifest) => Bytes::from(manifest.encode()),
+            None => value.clone(),
+        };
+        let entry = Entry {
+            location: appended.location,
+            value_len: appended.value_len,
+            ticket: appended.ticket,
+            value: self.keeps_values().then_some(stored),
+            expires_at,
+            codec: appended.codec,
+            sequence: appended.sequence,
+            checksum: true,
+            timestamps,
+            recent: AtomicBool::new(true),
+        };
+        let mut key_map = ks.key_map.write().unwrap();
+        self.counters.index_grew(entry.footprint(key.len()), entry.value_bytes());
+        let old = key_map.insert(key.clone(), entry);
+        if let Some(old) = &old {
+            self.forget(ks, &key, old);
+        }
+        if let Some(expires_at) = expires_at {
+            ks.expirations.lock().unwrap().insert((expires_at, key.clone()));
+        }
+        drop(key_map);
+        self.demote_cold_values();
+        self.publish(ks.id, || Change::Set {
+            sequence: appended.sequence,
+            key: key.clone(),
+            value: value.clone(),
+            expires_at,
+        });
+        if self.keeps_history(ks) {
+            history::record(self, ks.id, &key, Some(&value), expires_at, appended.sequence)?;
+        }
+        let event = if ks.watchers.is_watched(&key) {
+            Some(Event {
+                key: key.clone(),
+                old_value: self.old_value(ks, &key, old.as_ref())?,
+                new_value: Some(value.clone()),
+                op: Op::Set,
+            })
+        } else {
+            None
+        };
+        // The chunks of the old value are only deleted once they are no longer needed.
+        match &manifest {
+            Some(manifest) => chunks::delete(self, ks.id, &key, Some(manifest.generation))?,
+            None if old.as_ref().is_some_and(|old| old.codec == log::Codec::Chunked) => {
+                chunks::delete(self, ks.id, &key, None)?
+            }
+            None => {}
+        }
+        if !self.keeps_values() {
+            self.cache.insert(ks.id, key, value);
+        }
+        if let Some(event) = event {
+            ks.watchers.notify(event);
+        }
         Ok(())
     }
 
-    /// Deletes a key-value pair from the store.
-    /// If the key does not exist, the operation is a no-op.
-    pub async fn del(&self, key: &[u8]) -> Result<(), std::io::Error> {
-        if self.key_map.get(key).is_none() {
+    /// Deletes `key` if it exists. The caller must hold the write lock.
+    pub(crate) fn del(&self, ks: &Keyspace, key: &[u8]) -> Result<()> {
+        if ks.key_map.read().unwrap().get(key).is_none() {
             return Ok(());
         }
-        self.log.write_entry(key, &[]);
-        self.key_map.remove(key);
+        // The key is only dropped from memory once its tombstone is in the log, so that a
+        // failed append leaves it as it is on disk.
+        let appended = self.log.write_entry(ks.id, key, &[], None, None)?;
+        let mut key_map = ks.key_map.write().unwrap();
+        // The key may have expired in the meantime.
+        let old = key_map.remove(key);
+        if let Some(old) = &old {
+            self.forget(ks, key, old);
+        }
+        drop(key_map);
+        self.publish(ks.id, || Change::Del {
+            sequence: appended.sequence,
+            key: Bytes::copy_from_slice(key),
+        });
+        if self.keeps_history(ks) {
+            history::record(self, ks.id, key, None, None, appended.sequence)?;
+        }
+        if let Some(old) = old {
+            if ks.watchers.is_watched(key) {
+                ks.watchers.notify(Event {
+                    key: Bytes::copy_from_slice(key),
+                    old_value: self.old_value(ks, key, Some(&old))?,
+                    new_value: None,
+                    op: Op::Del,
+                });
+            }
+            if old.codec == log::Codec::Chunked {
+                chunks::delete(self, ks.id, key, None)?;
+            }
+        }
+        self.cache.remove(ks.id, key);
         Ok(())
     }
 
-    /// Returns an iterator over key-value pairs within the specified range.
-    pub async fn scan<'a>(
-        &'a self,
-        range: Range<Vec<u8>>,
-    ) -> Result<Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>, std::io::Error> {
-        let mut results: Vec<(Vec<u8>, Vec<u8>)> = self
-            .key_map
-            .iter()
-            .filter(|entry| entry.key() >= &range.start && entry.key() < &range.end)
-            .map(|entry| (entry.key().clone(), entry.value().clone()))
-            .collect();
-        results.sort_by(|a, b| a.0.cmp(&b.0));
-        Ok(Box::new(results.into_iter()))
+    /// Deletes every key from `start` up to but excluding `end`, or every key from `start` on
+    /// if `end` is empty, by writing a single range tombstone. The caller must hold the write lock.
+    pub(crate) fn delete_range(&self, ks: &Keyspace, start: &[u8], end: &[u8]) -> Result<()> {
+        let appended = self.log.write_range_tombstone(ks.id, start, end)?;
+        self.publish(ks.id, || Change::DelRange {
+            sequence: appended.sequence,
+            start: Bytes::copy_from_slice(start),
+            end: (!end.is_empty()).then(|| Bytes::copy_from_slice(end)),
+        });
+        let mut key_map = ks.key_map.write().unwrap();
+        let deleted = key_map.remove_range(start, end);
+        for (key, old) in deleted.iter() {
+            self.forget(ks, key, old);
+        }
+        drop(key_map);
+        if self.keeps_history(ks) {
+            for key in deleted.keys() {
+                history::record(self, ks.id, key, None, None, appended.sequence)?;
+            }
+        }
+        for (key, old) in deleted.iter() {
+            if ks.watchers.is_watched(key) {
+                ks.watchers.notify(Event {
+                    key: key.clone(),
+                    old_value: self.old_value(ks, key, Some(old))?,
+                    new_value: None,
+                    op: Op::Del,
+                });
+            }
+            self.cache.remove(ks.id, key);
+        }
+        let mut chunked = deleted.iter().filter(|(_, old)| old.codec == log::Codec::Chunked).map(|(key, _)| key);
+        match deleted.order() {
+            None if chunked.next().is_some() => chunks::delete_range(self, ks.id, start, end)?,
+            None => {}
+            // Chunks are ordered by their key bytewise, so those of the deleted keys need not be
+            // next to each other.
+            Some(_) => {
+                for key in chunked {
+                    chunks::delete(self, ks.id, key, None)?;
+                }
+            }
+        }
+        Ok(())
     }
 
-    /// Flushes the current log and shuts down the log writer to ensure data persistence.
-    fn flush(&mut self) -> Result<(), std::io::Error> {
-        self.log.writer.flush();
-        self.log.writer.shutdown();
-        Ok(())
+    /// Returns the value of the entry `old` that was just replaced or removed from the key map,
+    /// or `None` if there was none or it had expired. The caller must hold the write lock, which
+    /// keeps compaction from removing the entry from the log.
+    pub(crate) fn old_value(&self, ks: &Keyspace, key: &[u8], old: Option<&Entry>) -> Result<Option<Bytes>> {
+        let Some(old) = old.filter(|old| !old.is_expired(self.now())) else {
+            return Ok(None);
+        };
+        if let Some(value) = old.value.as_ref().filter(|_| old.codec != log::Codec::Chunked) {
+            return Ok(Some(value.clone()));
+        }
+        if let Some(value) = self.cache.get(ks.id, key) {
+            return Ok(Some(value));
+        }
+        let value = match &old.value {
+            Some(value) => value.to_vec(),
+            None => {
+                if !self.log.is_flushed(old.ticket) {
+                    self.log.flush_and_wait();
+                }
+                let (value_len, expires_at, codec) = (old.value_len, old.expires_at, old.codec);
+                let checked_len = self.options.paranoid_checks.then(|| old.log_size(ks.id, key.len()));
+                self.log.read_value(old.location, ks.id, key.len(), value_len, expires_at, codec, checked_len)?
+            }
+        };
+        if old.codec != log::Codec::Chunked {
+            return Ok(Some(Bytes::from(value)));
+        }
+        Ok(chunks::read(self, ks.id, key, &value)?.map(Bytes::from))
     }
 
-    /// Compacts the log by building a new log file containing only valid entries.
-    /// The new log replaces the old one to reclaim storage space.
-    fn compact(&mut self) -> Result<(), std::io::Error> {
-        let mut tmp_path = self.log.path.clone();
-        tmp_path.set_extension("new");
-        let (mut new_log, new_key_map) = self.construct_log(tmp_path)?;
-        std::fs::rename(&new_log.path, &self.log.path)?;
-        new_log.path = self.log.path.clone();
-        self.log = Arc::new(new_log);
-        self.key_map = Arc::new(DashMap::new());
-        for (k, v) in new_key_map {
-            self.key_map.insert(k, v);
+    // Whether writes to `ks` record the versions they leave keys at.
+    fn keeps_history(&self, ks: &Keyspace) -> bool {
+        self.options.history_versions > 0 && ks.id < HISTORY_TREE
+    }
+
+    /// Returns whether values written from now on are kept in memory.
+    pub(crate) fn keeps_values(&self) -> bool {
+        self.options.keep_values_in_memory && !self.values_spilled.load(Ordering::Relaxed)
+    }
+
+    /// Counts the memory taken by the key maps from scratch, after they were rebuilt, and
+    /// drops the values from memory if that is over the limit.
+    fn count_index(&self) {
+        let (mut bytes, mut value_bytes) = (0, 0);
+        for ks in self.keyspaces() {
+            for (key, entry) in ks.key_map.read().unwrap().iter() {
+                bytes += entry.footprint(key.len());
+                value_bytes += entry.value_bytes();
+            }
+        }
+        self.counters.set_index_bytes(bytes, value_bytes);
+        if self.options.index_memory_limit.is_some_and(|limit| bytes > limit) {
+            self.spill_values();
+        }
+        self.demote_cold_values();
+    }
+
+    /// Makes room in the key maps for `key` with a value of `value_len` bytes, failing if that
+    /// would take them past the memory limit even once values are dropped from memory. The
+    /// caller must hold the write lock.
+    fn reserve_index(&self, ks: &Keyspace, key: &[u8], value_len: usize) -> Result<()> {
+        let Some(limit) = self.options.index_memory_limit else {
+            return Ok(());
+        };
+        let needed = |value_len: usize| {
+            let old = ks.key_map.read().unwrap().get(key).map_or(0, |old| old.footprint(key.len()));
+            let new = ENTRY_OVERHEAD + (key.len() + value_len) as u64;
+            self.counters.index_bytes() + new.saturating_sub(old)
+        };
+        if self.keeps_values() && needed(value_len) > limit {
+            self.spill_values();
+        }
+        if needed(if self.keeps_values() { value_len } else { 0 }) > limit {
+            return Err(Error::IndexMemoryLimit { limit });
         }
         Ok(())
     }
 
-    /// Constructs a compacted log file and a corresponding key map based on valid entries.
-    fn construct_log(&mut self, path: PathBuf) -> Result<(log::Log, DashMap<Vec<u8>, Vec<u8>>), std::io::Error> {
-        let new_key_map = DashMap::new();
-        let new_log = log::Log::new(path);
+    /// Drops every value kept in memory, after which values are read back from the log as if
+    /// `keep_values_in_memory` were disabled.
+    pub(crate) fn spill_values(&self) {
+        if self.values_spilled.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        for ks in self.keyspaces() {
+            for entry in ks.key_map.write().unwrap().values_mut() {
+                if let Some(value) = entry.value.take() {
+                    self.counters.index_shrank(value.len() as u64, value.len() as u64);
+                }
+            }
+        }
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            index_bytes = self.counters.index_bytes(),
+            "dropped values from memory to stay under the index memory limit"
+        );
+    }
+
+    /// Drops values from memory until they fit in `value_memory_budget` again. Entries are
+    /// visited in key order, tree after tree, from where the previous call stopped, and values
+    /// read since their entry was last visited are spared once. Must not be called while
+    /// holding a key map's lock.
+    pub(crate) fn demote_cold_values(&self) {
+        let Some(budget) = self.options.value_memory_budget else {
+            return;
+        };
+        if self.counters.value_bytes() <= budget {
+            return;
+        }
+        let mut cursor = self.demotion_cursor.lock().unwrap();
+        let (tree, start) = &mut *cursor;
+        let mut keyspaces = self.keyspaces();
+        keyspaces.sort_by_key(|ks| ks.id);
+        // Going around every tree twice clears the read marks on the way, so this is enough to
+        // drop every value if needed.
+        for _ in 0..keyspaces.len() * 2 + 1 {
+            let i = keyspaces.partition_point(|ks| ks.id < *tree) % keyspaces.len();
+            let ks = &keyspaces[i];
+            if ks.id != *tree {
+                *tree = ks.id;
+                *start = Bytes::new();
+            }
+            let mut key_map = ks.key_map.write().unwrap();
+            for (key, entry) in key_map.range_mut((Bound::Included(&start[..]), Bound::Unbounded)) {
+                if self.counters.value_bytes() <= budget {
+                    *start = key.clone();
+                    return;
+                }
+                if entry.recent.swap(false, Ordering::Relaxed) {
+                    continue;
+                }
+                if let Some(value) = entry.value.take() {
+                    self.counters.index_shrank(value.len() as u64, value.len() as u64);
+                }
+            }
+            *tree = keyspaces.get(i + 1).map_or(keyspaces[0].id, |next| next.id);
+            *start = Bytes::new();
+        }
+    }
+
+    /// Keeps `value`, which was just read back from the log, in memory again if `key` still
+    /// maps to the entry at `location` and the index has room for it.
+    fn promote(&self, ks: &Keyspace, key: &[u8], location: log::Location, value: &Bytes) {
+        let len = value.len() as u64;
+        if self.options.index_memory_limit.is_some_and(|limit| self.counters.index_bytes() + len > limit) {
+            return;
+        }
+        {
+            let mut key_map = ks.key_map.write().unwrap();
+            let Some(entry) = key_map.get_mut(key) else {
+                return;
+            };
+            if entry.location != location || entry.value.is_some() {
+                return;
+            }
+            entry.value = Some(value.clone());
+            entry.touch();
+            self.counters.index_grew(len, len);
+        }
+        self.demote_cold_values();
+    }
+
+    /// Accounts for an entry that was removed from the key map or replaced.
+    /// The caller must hold the key map's write lock.
+    pub(crate) fn forget(&self, ks: &Keyspace, key: &[u8], old: &Entry) {
+        self.counters.index_shrank(old.footprint(key.len()), old.value_bytes());
+        if let Some(expires_at) = old.expires_at {
+            ks.expirations.lock().unwrap().remove(&(expires_at, Bytes::copy_from_slice(key)));
+        }
+        self.log.mark_dead(old.location.segment, old.log_size(ks.id, key.len()));
+    }
+
+    /// Removes `key` if it is still the expired entry written at `location`. No tombstone is
+    /// needed since replaying the log treats the expired entry as a deletion.
+    pub(crate) fn expire(&self, ks: &Keyspace, key: &[u8], location: log::Location) {
+        let mut key_map = ks.key_map.write().unwrap();
+        if !key_map.get(key).is_some_and(|entry| entry.location == location) {
+            return;
+        }
+        let old = key_map.remove(key).unwrap();
+        self.forget(ks, key, &old);
+        drop(key_map);
+        self.cache.remove(ks.id, key);
+    }
+
+    /// Removes every key whose expiration time has passed.
+    pub(crate) fn remove_expired(&self) {
+        let now = self.now();
+        for ks in self.keyspaces() {
+            let due: Vec<(u64, Bytes)> = {
+                let expirations = ks.expirations.lock().unwrap();
+                expirations.range(..(now + 1, Bytes::new())).cloned().collect()
+            };
+            for (_, key) in due {
+                let location = match ks.key_map.read().unwrap().get(&key) {
+                    Some(entry) if entry.is_expired(now) => entry.location,
+                    _ => continue,
+                };
+                self.expire(&ks, &key, location);
+            }
+        }
+    }
+
+    /// Returns whether `key` exists in `ks`, without reading its value.
+    pub(crate) fn contains_key(&self, ks: &Keyspace, key: &[u8]) -> Result<bool> {
+        self.replayed()?;
+        let key_map = ks.key_map.read().unwrap();
+        let Some(entry) = key_map.get(key) else {
+            return Ok(false);
+        };
+        if entry.is_expired(self.now()) {
+            let location = entry.location;
+            drop(key_map);
+            self.expire(ks, key, location);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Returns the sequence number of the write that set `key`, or `None` if it does not exist.
+    pub(crate) fn version(&self, ks: &Keyspace, key: &[u8]) -> Result<Option<u64>> {
+        Ok(self.metadata(ks, key)?.map(|(version, _)| version))
+    }
+
+    /// Returns the sequence number of the write that set `key` and the timestamps it recorded,
+    /// or `None` if the key does not exist.
+    pub(crate) fn metadata(&self, ks: &Keyspace, key: &[u8]) -> Result<Option<(u64, Option<log::Timestamps>)>> {
+        self.replayed()?;
+        let key_map = ks.key_map.read().unwrap();
+        let Some(entry) = key_map.get(key) else {
+            return Ok(None);
+        };
+        if entry.is_expired(self.now()) {
+            let location = entry.location;
+            drop(key_map);
+            self.expire(ks, key, location);
+            return Ok(None);
+        }
+        Ok(Some((entry.sequence, entry.timestamps)))
+    }
+
+    /// Returns the timestamps of a write setting `key` now: it keeps the creation time of the
+    /// live value it replaces, if that recorded one. The caller must hold the write lock.
+    pub(crate) fn timestamps(&self, ks: &Keyspace, key: &[u8]) -> log::Timestamps {
+        let now = self.now();
+        let created_at = ks
+            .key_map
+            .read()
+            .unwrap()
+            .get(key)
+            .filter(|entry| !entry.is_expired(now))
+            .and_then(|entry| entry.timestamps)
+            .map_or(now, |timestamps| timestamps.created_at);
+        log::Timestamps {
+            created_at,
+            updated_at: now,
+        }
+    }
+
+    pub(crate) fn get(&self, ks: &Keyspace, key: &[u8]) -> Result<Option<Bytes>> {
+        self.replayed()?;
+        loop {
+            let (location, value_len, ticket, expires_at, codec, stored, checked_len) = {
+                let key_map = ks.key_map.read().unwrap();
+                let Some(entry) = key_map.get(key) else {
+                    return Ok(None);
+                };
+                if entry.is_expired(self.now()) {
+                    let location = entry.location;
+                    drop(key_map);
+                    self.expire(ks, key, location);
+                    return Ok(None);
+                }
+                if let Some(value) = entry.value.as_ref().filter(|_| entry.codec != log::Codec::Chunked) {
+                    entry.touch();
+                    return Ok(Some(value.clone()));
+                }
+                let stored = entry.value.clone();
+                let checked_len = self.options.paranoid_checks.then(|| entry.log_size(ks.id, key.len()));
+                (entry.location, entry.value_len, entry.ticket, entry.expires_at, entry.codec, stored, checked_len)
+            };
+            if let Some(value) = self.cache.get(ks.id, key) {
+                return Ok(Some(value));
+            }
+            let value = match stored {
+                Some(stored) => Ok(stored.to_vec()),
+                None => {
+                    if !self.log.is_flushed(ticket) {
+                        self.log.flush_and_wait();
+                    }
+                    self.log.read_value(location, ks.id, key.len(), value_len, expires_at, codec, checked_len)
+                }
+            };
+            let value = value.and_then(|value| match codec {
+                log::Codec::Chunked => chunks::read(self, ks.id, key, &value)?.ok_or_else(|| {
+                    Error::Corrupted(format!("missing chunk of the value at offset {}", location.offset))
+                }),
+                _ => Ok(value),
+            });
+            match value {
+                Ok(value) => {
+                    let value = Bytes::from(value);
+                    if self.keeps_values() && codec != log::Codec::Chunked {
+                        self.promote(ks, key, location, &value);
+                        return Ok(Some(value));
+                    }
+                    // Holding the entry prevents a concurrent write from being shadowed by this older value.
+                    if let Some(entry) = ks.key_map.read().unwrap().get(key) {
+                        if entry.location == location {
+                            self.cache.insert(ks.id, Bytes::copy_from_slice(key), value.clone());
+                        }
+                    }
+                    return Ok(Some(value));
+                }
+                Err(e) => {
+                    // Compaction may have moved the entry and removed the segment it was read from,
+                    // and the chunks of a value go once it is overwritten or expires.
+                    let moved = ks
+                        .key_map
+                        .read()
+                        .unwrap()
+                        .get(key)
+                        .is_some_and(|entry| entry.location != location || entry.is_expired(self.now()));
+                    if !moved {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn get_many(&self, ks: &Keyspace, keys: &[&[u8]]) -> Result<Vec<Option<Bytes>>> {
+        self.replayed()?;
+        let now = self.now();
+        let mut values = Vec::with_capacity(keys.len());
+        // Keys whose values are not in memory, or that need to be expired, take the slow path.
+        let mut misses = Vec::new();
         {
-            let file = std::fs::OpenOptions::new()
-                .write(true)
-                .open(&new_log.path)?;
-            file.set_len(0)?;
+            let key_map = ks.key_map.read().unwrap();
+            for (i, key) in keys.iter().enumerate() {
+                let value = match key_map.get(key) {
+                    Some(entry)
+                        if entry.is_expired(now)
+                            || entry.value.is_none()
+                            || entry.codec == log::Codec::Chunked =>
+                    {
+                        misses.push(i);
+                        None
+                    }
+                    Some(entry) => {
+                        entry.touch();
+                        entry.value.clone()
+                    }
+                    None => None,
+                };
+                values.push(value);
+            }
         }
-        for entry in self.key_map.iter() {
-            new_log.write_entry(entry.key(), entry.value());
-            new_key_map.insert(entry.key().clone(), entry.value().clone());
+        for i in misses {
+            values[i] = self.get(ks, keys[i])?;
         }
-        Ok((new_log, new_key_map))
+        Ok(values)
+    }
+
+    /// Returns an error if the key or value exceeds the configured size limits.
+    pub(crate) fn check_limits(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let (key_limit, value_limit) = self.limits();
+        if key.len() > key_limit {
+            return Err(Error::KeyTooLarge { len: key.len(), limit: key_limit });
+        }
+        if value.len() > value_limit {
+            return Err(Error::ValueTooLarge { len: value.len(), limit: value_limit });
+        }
+        Ok(())
+    }
+
+    /// Returns the longest key and value accepted by writes, which never exceed what a log
+    /// entry can hold.
+    pub(crate) fn limits(&self) -> (usize, usize) {
+        let key_limit = self.options.max_key_size.unwrap_or(usize::MAX).min(log::MAX_KEY_LEN);
+        let value_limit = match self.options.chunk_large_values {
+            true => log::MAX_VALUE_LEN,
+            false => self.options.max_value_size.unwrap_or(usize::MAX).min(log::MAX_VALUE_LEN),
+        };
+        (key_limit, value_limit)
+    }
+
+    /// Returns the size of the chunks that longer values are split into, or `None` if values
+    /// are not chunked.
+    pub(crate) fn chunk_size(&self) -> Option<usize> {
+        if !self.options.chunk_large_values {
+            return None;
+        }
+        Some(self.options.max_value_size?.clamp(1, log::MAX_VALUE_LEN))
+    }
+
+    /// Returns true when the log is large enough and holds enough dead entries to be worth compacting.
+    pub(crate) fn needs_compaction(&self) -> bool {
+        if let Some(strategy) = &self.options.compaction_strategy {
+            return strategy.is_due(&self.log.space_stats().segments, self.now());
+        }
+        let log_bytes = self.log.len();
+        if log_bytes < self.options.compaction_min_size {
+            return false;
+        }
+        let garbage = log_bytes.saturating_sub(self.log.live_len());
+        garbage as f64 >= log_bytes as f64 * self.options.compaction_garbage_ratio
+    }
+
+    // Deletes the directory of a temporary database.
+    fn remove_dir(&self) {
+        match std::fs::remove_dir_all(&self.log.dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                eprintln!("Failed to remove temporary database {}: {}", self.log.dir.display(), e);
+            }
+            _ => {}
+        }
+    }
+
+    /// Flushes the current log and shuts down the log writer to ensure data persistence.
+    fn flush(&self) -> Result<()> {
+        // Waiting for the writer thread keeps the writes when the process exits right after.
+        self.log.flush_and_wait();
+        self.log.shutdown();
+        Ok(())
     }
 }
 
-impl Drop for Engine {
+impl Drop for Inner {
     fn drop(&mut self) {
         self.flush().unwrap();
+        if self.temporary {
+            self.remove_dir();
+        }
     }
 }
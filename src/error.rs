@@ -0,0 +1,113 @@
+//! Error type shared by all fallible Tegdb operations.
+
+use std::io;
+use std::path::PathBuf;
+
+use crate::intercept::Rejection;
+use crate::log::ParseError;
+
+/// Errors returned by the engine.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// An underlying I/O operation failed.
+    #[error("I/O error: {0}")]
+    Io(#[source] io::Error),
+    /// The on-disk data could not be decoded.
+    #[error("corrupted log: {0}")]
+    Corrupted(String),
+    /// The database at the given path is already opened by another process.
+    #[error("database is locked by another process: {}", .0.display())]
+    DatabaseLocked(PathBuf),
+    /// A write was attempted on a database opened read-only.
+    #[error("database is opened read-only")]
+    ReadOnly,
+    /// A write was attempted after the database was closed with
+    /// [`Engine::close`](crate::Engine::close).
+    #[error("database is closed")]
+    Closed,
+    /// Encoded values could not be decoded.
+    #[error("invalid encoding: {0}")]
+    Decode(String),
+    /// A value could not be encoded.
+    #[error("cannot encode value: {0}")]
+    Encode(String),
+    /// A key was longer than the limit set by
+    /// [`EngineOptions::max_key_size`](crate::EngineOptions::max_key_size).
+    #[error("key of {len} bytes exceeds the limit of {limit} bytes")]
+    KeyTooLarge { len: usize, limit: usize },
+    /// A value was longer than the limit set by
+    /// [`EngineOptions::max_value_size`](crate::EngineOptions::max_value_size).
+    #[error("value of {len} bytes exceeds the limit of {limit} bytes")]
+    ValueTooLarge { len: usize, limit: usize },
+    /// A write would have taken the key maps past the limit set by
+    /// [`EngineOptions::index_memory_limit`](crate::EngineOptions::index_memory_limit).
+    #[error("index would exceed its memory limit of {limit} bytes")]
+    IndexMemoryLimit { limit: u64 },
+    /// The database could not be read as of `sequence`, as compaction has dropped writes that
+    /// its state then depends on. It can be read as of `earliest` or any later sequence number.
+    #[error("sequence {sequence} has been compacted away; the earliest readable one is {earliest}")]
+    Compacted { sequence: u64, earliest: u64 },
+    /// A bulk load was rejected, such as one into a tree that is not empty.
+    #[error("cannot bulk load: {0}")]
+    BulkLoad(String),
+    /// The database was opened with the comparator named `expected`, or `None` to order keys
+    /// bytewise, while it orders its keys with the one named `found`; see
+    /// [`EngineOptions::comparator`](crate::EngineOptions::comparator).
+    #[error("database orders its keys {}, not {}", ordering(.found), ordering(.expected))]
+    ComparatorMismatch { expected: Option<String>, found: Option<String> },
+    /// The disk holding the database is full, or its free space is below the reserve set by
+    /// [`EngineOptions::min_free_space`](crate::EngineOptions::min_free_space).
+    #[error("no space left on the disk holding the database")]
+    NoSpace,
+    /// A write was rejected by one of the
+    /// [`EngineOptions::interceptors`](crate::EngineOptions::interceptors), for the given reason.
+    #[error("write rejected: {0}")]
+    Rejected(#[source] Rejection),
+}
+
+/// Convenience alias for results produced by the engine.
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Describes how a database named by `ComparatorMismatch` orders its keys.
+fn ordering(comparator: &Option<String>) -> String {
+    match comparator {
+        Some(name) => format!("with comparator {:?}", name),
+        None => "bytewise".to_string(),
+    }
+}
+
+impl Error {
+    /// Returns a copy of the error, for errors the engine keeps to report more than once. I/O
+    /// errors keep their kind and message, and errors that cannot be copied, such as
+    /// rejections, are reported as corruption with their message.
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            Error::Io(e) => Error::Io(io::Error::new(e.kind(), e.to_string())),
+            Error::Corrupted(msg) => Error::Corrupted(msg.clone()),
+            Error::DatabaseLocked(path) => Error::DatabaseLocked(path.clone()),
+            Error::ReadOnly => Error::ReadOnly,
+            Error::Closed => Error::Closed,
+            Error::NoSpace => Error::NoSpace,
+            Error::Compacted { sequence, earliest } => Error::Compacted {
+                sequence: *sequence,
+                earliest: *earliest,
+            },
+            e => Error::Corrupted(e.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => Error::NoSpace,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Corrupted(e.to_string())
+    }
+}
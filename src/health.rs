@@ -0,0 +1,259 @@
+//! Health of an open engine.
+//!
+//! [`Engine::health`](crate::Engine::health) gathers what tells whether an engine can still
+//! serve reads and writes into a [`HealthReport`], which embedding applications can expose as
+//! a readiness probe: whether writes fail, how much disk space is left for the log, how far
+//! the log writer thread lags behind, what corruption reads and scrubbing have found and
+//! whether compaction, index snapshots and scrubbing fail in the background.
+//!
+//! A full disk makes the log writer thread fail, losing the writes it had not written yet.
+//! With [`EngineOptions::min_free_space`](crate::EngineOptions::min_free_space), writes check
+//! the free space on the disk ahead of that, and once it runs short the engine acts as
+//! [`EngineOptions::disk_full_policy`](crate::EngineOptions::disk_full_policy) sets out.
+
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::engine::Inner;
+use crate::error::{Error, Result};
+use crate::verify::Corruption;
+
+/// How often writes check the free space on the disk, in milliseconds.
+const SPACE_CHECK_INTERVAL: u64 = 100;
+
+/// The state of an open engine, returned by [`Engine::health`](crate::Engine::health).
+#[derive(Debug, Default)]
+pub struct HealthReport {
+    /// Why writes fail, if they do: the latest error the log writer thread ran into while
+    /// writing to disk, such as a full disk, or [`Error::Closed`] once the engine has been
+    /// closed. Writes are handed to that thread after they return, so such errors are only
+    /// reported here and by later writes: once the writer has failed the log may be missing
+    /// entries, and every write fails until the database is reopened.
+    pub write_error: Option<Error>,
+    /// Bytes available to the database on the file system holding its directory, or `None`
+    /// where that cannot be told, which is everywhere but Linux.
+    pub available_space: Option<u64>,
+    /// Number of entries waiting to be written by the log writer thread.
+    pub write_queue_depth: u64,
+    /// Time since the log was last fsynced, or `None` if it has not been since the engine was
+    /// opened.
+    pub since_last_sync: Option<Duration>,
+    /// The first corrupt range found by scrubbing the log; see
+    /// [`Engine::scrub`](crate::Engine::scrub). It is reported until the database is repaired
+    /// with [`Engine::repair`](crate::Engine::repair) and reopened.
+    pub corruption: Option<Corruption>,
+    /// Number of values read back from the log whose entry did not match its checksum, which
+    /// are only checked with
+    /// [`EngineOptions::paranoid_checks`](crate::EngineOptions::paranoid_checks).
+    pub checksum_failures: u64,
+    /// The error the latest compaction run in the background failed with, if it did, such as
+    /// one run for [`EngineOptions::background_compaction`](crate::EngineOptions::background_compaction)
+    /// or to reclaim disk space under [`DiskFullPolicy::Compact`]. It is reported until a
+    /// later one succeeds; compactions run with [`Engine::compact`](crate::Engine::compact)
+    /// return their errors instead.
+    pub compaction_error: Option<Error>,
+    /// The error writing the latest index snapshot failed with, if it did; see
+    /// [`EngineOptions::index_snapshot_interval`](crate::EngineOptions::index_snapshot_interval).
+    /// It is reported until a snapshot is written, and until then reopening the database
+    /// replays more of the log.
+    pub snapshot_error: Option<Error>,
+    /// The error the latest scrub run in the background failed with, if it did, such as a
+    /// segment that could not be read; see
+    /// [`EngineOptions::scrub_interval`](crate::EngineOptions::scrub_interval). Corruption the
+    /// scrub finds is reported in `corruption` instead. It is reported until a scrub runs
+    /// through.
+    pub scrub_error: Option<Error>,
+    /// Whether the disk is full as far as
+    /// [`EngineOptions::disk_full_policy`](crate::EngineOptions::disk_full_policy) is concerned.
+    pub disk_full: bool,
+}
+
+impl HealthReport {
+    /// Returns true if writes succeed, no corruption has been found and none of background
+    /// compaction, index snapshots and scrubbing fail.
+    pub fn is_healthy(&self) -> bool {
+        self.write_error.is_none()
+            && !self.disk_full
+            && self.corruption.is_none()
+            && self.checksum_failures == 0
+            && self.compaction_error.is_none()
+            && self.snapshot_error.is_none()
+            && self.scrub_error.is_none()
+    }
+
+    /// Returns the error writes fail with, or otherwise [`Error::Corrupted`] if corruption has
+    /// been found, or else the error background compaction, index snapshots or scrubbing fail
+    /// with, for callers that only need to tell whether the engine is healthy.
+    pub fn into_result(self) -> Result<()> {
+        if let Some(e) = self.write_error {
+            return Err(e);
+        }
+        if self.disk_full {
+            return Err(Error::NoSpace);
+        }
+        if let Some(corruption) = self.corruption {
+            let (reason, segment) = (corruption.reason, corruption.segment);
+            return Err(Error::Corrupted(format!("{} in segment {}, found by scrubbing", reason, segment)));
+        }
+        if self.checksum_failures > 0 {
+            let failures = self.checksum_failures;
+            return Err(Error::Corrupted(format!("{} values did not match their checksum", failures)));
+        }
+        match self.compaction_error.or(self.snapshot_error).or(self.scrub_error) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What the engine does once the disk holding it is full; see
+/// [`EngineOptions::disk_full_policy`](crate::EngineOptions::disk_full_policy).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DiskFullPolicy {
+    /// Writes fail with [`Error::NoSpace`] until the free space is back above the reserve.
+    #[default]
+    Fail,
+    /// The engine turns read-only: writes fail with [`Error::ReadOnly`] and no compaction runs
+    /// until the database is reopened, while reads carry on.
+    ReadOnly,
+    /// A full compaction runs on the background thread to reclaim the space taken by
+    /// overwritten and deleted entries, provided the disk still has room for the rewritten
+    /// segments. Writes fail with [`Error::NoSpace`] until the free space is back above the
+    /// reserve.
+    Compact,
+}
+
+/// A change in whether the disk holding the database is full, passed to
+/// [`EngineOptions::on_disk_full`](crate::EngineOptions::on_disk_full).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiskFullEvent {
+    /// The free space fell below the reserve, or the log writer thread ran out of space, with
+    /// `available` bytes left if that can be told.
+    Full { available: Option<u64> },
+    /// The free space is back above the reserve, with `available` bytes left, so writes go
+    /// through again.
+    Recovered { available: u64 },
+}
+
+/// A function called with every [`DiskFullEvent`]; see
+/// [`EngineOptions::on_disk_full`](crate::EngineOptions::on_disk_full).
+#[derive(Clone)]
+pub struct DiskFullHook(Arc<dyn Fn(&DiskFullEvent) + Send + Sync>);
+
+impl DiskFullHook {
+    /// Wraps `f`, which may be called from several threads.
+    pub fn new(f: impl Fn(&DiskFullEvent) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    /// Calls the wrapped function with `event`.
+    pub fn call(&self, event: &DiskFullEvent) {
+        (self.0)(event)
+    }
+}
+
+impl fmt::Debug for DiskFullHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DiskFullHook")
+    }
+}
+
+/// Tracks whether the disk holding the database is full.
+#[derive(Default)]
+pub(crate) struct DiskWatch {
+    // Set while the free space is below the reserve, and once the log writer ran out of space.
+    full: AtomicBool,
+    // Time in milliseconds since the Unix epoch at which writes next check the free space.
+    next_check: AtomicU64,
+    // Set when a compaction is due to reclaim space.
+    compaction_due: AtomicBool,
+}
+
+impl DiskWatch {
+    /// Returns whether the disk is full.
+    pub(crate) fn is_full(&self) -> bool {
+        self.full.load(Ordering::SeqCst)
+    }
+
+    /// Returns true once the engine has turned read-only because the disk was full.
+    pub(crate) fn is_read_only(&self, engine: &Inner) -> bool {
+        self.is_full() && engine.options.disk_full_policy == DiskFullPolicy::ReadOnly
+    }
+
+    /// Returns the error a write fails with if the disk is full, checking the free space
+    /// against the reserve first if that is due.
+    pub(crate) fn check(&self, engine: &Inner) -> Result<()> {
+        if self.is_read_only(engine) {
+            return Err(Error::ReadOnly);
+        }
+        let Some(reserve) = engine.options.min_free_space else {
+            return Ok(());
+        };
+        let now = engine.now();
+        if now >= self.next_check.load(Ordering::Relaxed) {
+            self.next_check.store(now + SPACE_CHECK_INTERVAL, Ordering::Relaxed);
+            match available_space(&engine.log.dir) {
+                Some(available) if available < reserve => {
+                    if !self.is_full() && engine.options.disk_full_policy == DiskFullPolicy::Compact {
+                        self.compaction_due.store(true, Ordering::SeqCst);
+                    }
+                    self.run_out(engine, Some(available));
+                }
+                // Writes keep failing once the log writer has run out of space.
+                Some(available) if engine.log.health().is_ok() && self.full.swap(false, Ordering::SeqCst) => {
+                    notify(engine, DiskFullEvent::Recovered { available });
+                }
+                _ => {}
+            }
+        }
+        if self.is_read_only(engine) {
+            Err(Error::ReadOnly)
+        } else if self.is_full() {
+            Err(Error::NoSpace)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Records that the disk is full, with `available` bytes left if that can be told, and
+    /// reports it unless it was known already.
+    pub(crate) fn run_out(&self, engine: &Inner, available: Option<u64>) {
+        if !self.full.swap(true, Ordering::SeqCst) {
+            notify(engine, DiskFullEvent::Full { available });
+        }
+    }
+
+    /// Returns true if a compaction is due to reclaim space, which it is then no longer.
+    pub(crate) fn take_compaction_due(&self) -> bool {
+        self.compaction_due.swap(false, Ordering::SeqCst)
+    }
+}
+
+fn notify(engine: &Inner, event: DiskFullEvent) {
+    if let Some(hook) = &engine.options.on_disk_full {
+        hook.call(&event);
+    }
+}
+
+/// Returns the bytes available to unprivileged users on the file system holding `dir`.
+#[cfg(target_os = "linux")]
+pub(crate) fn available_space(dir: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+    // SAFETY: `path` is a valid C string, and `statvfs` only writes to `stat`, for which any bit
+    // pattern is valid.
+    let stat = unsafe {
+        let mut stat: libc::statvfs = std::mem::zeroed();
+        (libc::statvfs(path.as_ptr(), &mut stat) == 0).then_some(stat)
+    }?;
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn available_space(_dir: &Path) -> Option<u64> {
+    None
+}
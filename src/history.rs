@@ -0,0 +1,178 @@
+//! Retained versions of keys.
+//!
+//! With [`EngineOptions::history_versions`](crate::EngineOptions::history_versions) set, every
+//! write to a tree also stores the version it leaves the key at in an internal tree, which
+//! [`Tree::get_at`](crate::Tree::get_at) and [`Tree::history`](crate::Tree::history) read. Once a
+//! key has more versions than its current one and the configured number of previous ones, the
+//! oldest are deleted, and compaction reclaims them like any other deleted entry.
+//!
+//! Version keys are made of the tree id, the key encoded so that keys keep their order, and the
+//! sequence number of the write, so the versions of a key form a range ordered from oldest to
+//! newest. A version is stored right after the write it records, so a crash in between can lose
+//! the version but never the write.
+
+use std::ops::Bound;
+
+use bytes::Bytes;
+
+use crate::engine::Inner;
+use crate::error::{Error, Result};
+use crate::keyencoding::Key;
+use crate::tree::{self, HISTORY_TREE};
+
+// Set in the flags of versions that set the key rather than delete it.
+const VALUE_FLAG: u8 = 1;
+// Set in the flags of versions whose value expires; the expiration time follows the write time.
+const EXPIRES_FLAG: u8 = 2;
+
+/// A retained version of a key, returned by [`Tree::history`](crate::Tree::history).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Version {
+    /// Sequence number of the write, as returned by [`Tree::get_versioned`](crate::Tree::get_versioned).
+    pub version: u64,
+    /// Milliseconds since the Unix epoch when the write was made.
+    pub written_at: u64,
+    /// The value the write set, or `None` if it deleted the key.
+    pub value: Option<Bytes>,
+    /// Milliseconds since the Unix epoch after which the value no longer exists.
+    pub expires_at: Option<u64>,
+}
+
+/// The point in a key's history to read it at, passed to [`Tree::get_at`](crate::Tree::get_at).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum At {
+    /// Right after the write with the given sequence number, or the latest earlier one.
+    Version(u64),
+    /// At the given time in milliseconds since the Unix epoch.
+    Time(u64),
+}
+
+/// Stores the version left by the write with `sequence` to `key` of `tree`, which set it to
+/// `value` or deleted it, and deletes the versions of the key beyond those retained. The caller
+/// must hold the write lock.
+pub(crate) fn record(
+    engine: &Inner,
+    tree: u32,
+    key: &[u8],
+    value: Option<&[u8]>,
+    expires_at: Option<u64>,
+    sequence: u64,
+) -> Result<()> {
+    let history = engine.keyspace(HISTORY_TREE);
+    let mut encoded = Vec::with_capacity(17 + value.map_or(0, <[u8]>::len));
+    let mut flags = 0;
+    if value.is_some() {
+        flags |= VALUE_FLAG;
+    }
+    if expires_at.is_some() {
+        flags |= EXPIRES_FLAG;
+    }
+    encoded.push(flags);
+    encoded.extend_from_slice(&engine.now().to_be_bytes());
+    if let Some(expires_at) = expires_at {
+        encoded.extend_from_slice(&expires_at.to_be_bytes());
+    }
+    encoded.extend_from_slice(value.unwrap_or_default());
+    let prefix = key_prefix(tree, key);
+    let version_key = [prefix.as_slice(), &sequence.to_be_bytes()].concat();
+    engine.set(&history, &version_key, encoded, None)?;
+
+    // The current version is kept along with the previous ones.
+    let kept = engine.options.history_versions + 1;
+    let end = tree::prefix_end(&prefix).unwrap_or_default();
+    let first_kept = {
+        let key_map = history.key_map.read().unwrap();
+        let versions: Vec<&Bytes> = key_map.range(bounds(&prefix, &end)).map(|(key, _)| key).collect();
+        versions.len().checked_sub(kept).filter(|&stale| stale > 0).map(|stale| versions[stale].clone())
+    };
+    match first_kept {
+        Some(first_kept) => engine.delete_range(&history, &prefix, &first_kept),
+        None => Ok(()),
+    }
+}
+
+/// Returns the retained versions of `key` of `tree`, from oldest to newest.
+pub(crate) fn versions(engine: &Inner, tree: u32, key: &[u8]) -> Result<Vec<Version>> {
+    let history = engine.keyspace(HISTORY_TREE);
+    let prefix = key_prefix(tree, key);
+    let end = tree::prefix_end(&prefix).unwrap_or_default();
+    let end = if end.is_empty() {
+        Bound::Unbounded
+    } else {
+        Bound::Excluded(end)
+    };
+    let range = (Bound::Included(prefix.clone()), end);
+    engine
+        .scan(&history, &range)?
+        .map(|(version_key, encoded)| decode(&version_key[prefix.len()..], encoded))
+        .collect()
+}
+
+/// Returns the value of `key` of `tree` at `at`, or `None` if it did not exist then or no
+/// version that old is retained.
+pub(crate) fn get_at(engine: &Inner, tree: u32, key: &[u8], at: At) -> Result<Option<Bytes>> {
+    let versions = versions(engine, tree, key)?;
+    let found = versions.into_iter().rev().find(|version| match at {
+        At::Version(sequence) => version.version <= sequence,
+        At::Time(time) => version.written_at <= time,
+    });
+    let Some(found) = found else {
+        return Ok(None);
+    };
+    let expired = match at {
+        At::Version(_) => false,
+        At::Time(time) => found.expires_at.is_some_and(|t| t <= time),
+    };
+    Ok(found.value.filter(|_| !expired))
+}
+
+/// Deletes the versions of every key of `tree`, which is being dropped. The caller must hold
+/// the write lock.
+pub(crate) fn delete_tree(engine: &Inner, tree: u32) -> Result<()> {
+    let history = engine.keyspace(HISTORY_TREE);
+    let start = tree.to_be_bytes();
+    let end = tree::prefix_end(&start).unwrap_or_default();
+    if history.key_map.read().unwrap().range(bounds(&start, &end)).next().is_none() {
+        return Ok(());
+    }
+    engine.delete_range(&history, &start, &end)
+}
+
+fn decode(sequence: &[u8], encoded: Bytes) -> Result<Version> {
+    let invalid = || Error::Corrupted("invalid version in the history of a key".to_string());
+    let version = u64::from_be_bytes(sequence.try_into().map_err(|_| invalid())?);
+    let flags = *encoded.first().ok_or_else(invalid)?;
+    let read_u64 = |at: usize| -> Result<u64> {
+        let bytes = encoded.get(at..at + 8).ok_or_else(invalid)?;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    };
+    let written_at = read_u64(1)?;
+    let (expires_at, start) = if flags & EXPIRES_FLAG != 0 {
+        (Some(read_u64(9)?), 17)
+    } else {
+        (None, 9)
+    };
+    Ok(Version {
+        version,
+        written_at,
+        value: (flags & VALUE_FLAG != 0).then(|| encoded.slice(start..)),
+        expires_at,
+    })
+}
+
+// Returns the range from `start` up to but excluding `end`, where an empty `end` is unbounded.
+fn bounds<'a>(start: &'a [u8], end: &'a [u8]) -> (Bound<&'a [u8]>, Bound<&'a [u8]>) {
+    let end = if end.is_empty() {
+        Bound::Unbounded
+    } else {
+        Bound::Excluded(end)
+    };
+    (Bound::Included(start), end)
+}
+
+// Returns the prefix shared by the version keys of `key` of `tree`, which sorts like the key.
+fn key_prefix(tree: u32, key: &[u8]) -> Vec<u8> {
+    let mut prefix = tree.to_be_bytes().to_vec();
+    key.encode_into(&mut prefix);
+    prefix
+}
@@ -0,0 +1,164 @@
+//! Retention horizon of compaction.
+//!
+//! Compaction drops overwritten values and deletions from the log as soon as it can, which
+//! takes them away from [changefeeds](crate::Tree::changes_since) and
+//! [reads of past states](crate::Tree::scan_at). The horizon is the sequence number up to which
+//! it may drop them: the writes made after it are kept in the rewritten segments as they are. It follows [`EngineOptions::retention`](crate::EngineOptions::retention)
+//! and the progress acknowledged by consumers, which is recorded in a file of its own so that
+//! consumers keep their place across restarts.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::engine::Inner;
+use crate::error::{Error, Result};
+use crate::log;
+
+/// Name of the file recording the progress of consumers.
+pub(crate) const CONSUMERS: &str = "CONSUMERS";
+
+const CONSUMERS_HEADER: &str = "tegdb consumers 1";
+
+/// Which superseded writes compaction keeps; see
+/// [`EngineOptions::retention`](crate::EngineOptions::retention).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Retention {
+    /// Writes are dropped as soon as they are superseded.
+    #[default]
+    None,
+    /// Writes made within the given time are kept. Writes made before the engine was opened
+    /// count as made when it was opened.
+    Time(Duration),
+    /// The given number of latest writes are kept.
+    Sequences(u64),
+}
+
+/// The horizon up to which compaction may drop superseded writes.
+pub(crate) struct Horizon {
+    // Sequence number acknowledged by each consumer.
+    consumers: Mutex<BTreeMap<String, u64>>,
+    // Latest sequence number at points in time, oldest first, for retention by time.
+    samples: Mutex<VecDeque<(u64, u64)>>,
+}
+
+impl Horizon {
+    /// Loads the progress of the consumers of the database in `dir`, whose latest sequence
+    /// number is `sequence` at `now`.
+    pub(crate) fn open(dir: &Path, now: u64, sequence: u64) -> Result<Self> {
+        let consumers = match std::fs::read_to_string(dir.join(CONSUMERS)) {
+            Ok(contents) => parse(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            consumers: Mutex::new(consumers),
+            samples: Mutex::new(VecDeque::from([(now, sequence)])),
+        })
+    }
+
+    /// Returns the sequence number up to which compaction may drop superseded writes, which is
+    /// `u64::MAX` when nothing holds them back.
+    pub(crate) fn sequence(&self, engine: &Inner) -> u64 {
+        let (now, latest) = (engine.now(), engine.log.last_sequence());
+        self.sample(engine, now, latest);
+        let retained = match engine.options.retention {
+            Retention::None => u64::MAX,
+            Retention::Sequences(count) => latest.saturating_sub(count),
+            Retention::Time(retention) => {
+                let cutoff = now.saturating_sub(retention.as_millis() as u64);
+                let samples = self.samples.lock().unwrap();
+                samples.iter().take_while(|&&(at, _)| at <= cutoff).last().map_or(0, |&(_, sequence)| sequence)
+            }
+        };
+        let acknowledged = self.consumers.lock().unwrap().values().min().copied();
+        retained.min(acknowledged.unwrap_or(u64::MAX))
+    }
+
+    /// Records that the latest sequence number is `sequence` at `now`, for retention by time.
+    /// Samples are kept at most every 64th of the retention time, and dropped once later ones
+    /// have passed out of it.
+    fn sample(&self, engine: &Inner, now: u64, sequence: u64) {
+        let Retention::Time(retention) = engine.options.retention else {
+            return;
+        };
+        let retention = retention.as_millis() as u64;
+        let mut samples = self.samples.lock().unwrap();
+        if samples.back().is_none_or(|&(at, _)| now >= at + retention / 64) {
+            samples.push_back((now, sequence));
+        }
+        let cutoff = now.saturating_sub(retention);
+        while samples.get(1).is_some_and(|&(at, _)| at <= cutoff) {
+            samples.pop_front();
+        }
+    }
+
+    /// Returns the sequence number acknowledged by each consumer, ordered by name.
+    pub(crate) fn consumers(&self) -> Vec<(String, u64)> {
+        let consumers = self.consumers.lock().unwrap();
+        consumers.iter().map(|(name, &sequence)| (name.clone(), sequence)).collect()
+    }
+
+    /// Records that `consumer` has processed the writes up to `sequence`, unless it had
+    /// acknowledged a later one already.
+    pub(crate) fn acknowledge(&self, engine: &Inner, consumer: &str, sequence: u64) -> Result<()> {
+        if consumer.is_empty() || consumer.contains('\n') {
+            let message = "consumer names must be a single line";
+            return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, message)));
+        }
+        let mut consumers = self.consumers.lock().unwrap();
+        let mut updated = consumers.clone();
+        let acknowledged = updated.entry(consumer.to_string()).or_insert(sequence);
+        *acknowledged = (*acknowledged).max(sequence);
+        write(&engine.log.dir, &updated)?;
+        *consumers = updated;
+        Ok(())
+    }
+
+    /// Stops `consumer` from holding back compaction, returning whether it was known.
+    pub(crate) fn remove(&self, engine: &Inner, consumer: &str) -> Result<bool> {
+        let mut consumers = self.consumers.lock().unwrap();
+        let mut updated = consumers.clone();
+        if updated.remove(consumer).is_none() {
+            return Ok(false);
+        }
+        write(&engine.log.dir, &updated)?;
+        *consumers = updated;
+        Ok(true)
+    }
+}
+
+fn parse(contents: &str) -> Result<BTreeMap<String, u64>> {
+    let mut lines = contents.lines();
+    if lines.next() != Some(CONSUMERS_HEADER) {
+        return Err(Error::Corrupted("unrecognized consumers file".to_string()));
+    }
+    lines
+        .map(|line| {
+            let (sequence, name) = line.split_once(' ').unwrap_or((line, ""));
+            match sequence.parse() {
+                Ok(sequence) if !name.is_empty() => Ok((name.to_string(), sequence)),
+                _ => Err(Error::Corrupted(format!("invalid consumer entry: {}", line))),
+            }
+        })
+        .collect()
+}
+
+// Replaces the consumers file, so that a crash leaves either the old or the new one.
+fn write(dir: &Path, consumers: &BTreeMap<String, u64>) -> Result<()> {
+    let mut contents = String::from(CONSUMERS_HEADER);
+    for (name, sequence) in consumers {
+        contents.push_str(&format!("\n{} {}", sequence, name));
+    }
+    contents.push('\n');
+    let tmp_path = dir.join(format!("{}.tmp", CONSUMERS));
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(contents.as_bytes())?;
+    tmp.sync_all()?;
+    std::fs::rename(tmp_path, dir.join(CONSUMERS))?;
+    log::sync_dir(dir)?;
+    Ok(())
+}
@@ -0,0 +1,229 @@
+//! Index snapshots.
+//!
+//! Replaying a large log on every open takes time proportional to its size. When
+//! [`EngineOptions::index_snapshot_interval`](crate::EngineOptions::index_snapshot_interval) is
+//! set, the location of every live entry is periodically written to an `INDEX` file, along with
+//! the segments it covers and how many bytes of each. Opening the database then loads the
+//! snapshot and only replays the log written after it.
+//!
+//! Segments are only ever appended to, so a snapshot stays valid for as long as the segments it
+//! covers are still the first ones of the log and have not shrunk. Compaction and repair
+//! rewrite segments, after which the snapshot is ignored and the whole log is replayed until
+//! the next snapshot is written.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use bytes::Bytes;
+
+use crate::engine::Inner;
+use crate::error::{Error, Result};
+use crate::log::{self, Codec, Location, ReplayedEntry, ReplayedTrees, SegmentInfo, Timestamps};
+use crate::order::{self, Comparator, OrderedMap};
+
+/// Name of the file holding the latest index snapshot.
+pub(crate) const INDEX: &str = "INDEX";
+// First bytes of the snapshot, identifying its format.
+const INDEX_HEADER: &[u8] = b"tegdb index 2\n";
+// Set in the flags of entries that carry an expiration time.
+const EXPIRES_FLAG: u8 = 1;
+// Set in the flags of entries ending with a checksum in the log.
+const CHECKSUM_FLAG: u8 = 2;
+// Set in the flags of entries that record timestamps, which follow the codec.
+const TIMESTAMPS_FLAG: u8 = 4;
+
+/// The index recovered from a snapshot.
+pub(crate) struct Snapshot {
+    /// Sequence number of the latest write the snapshot covers.
+    pub(crate) sequence: u64,
+    /// Ids of the segments the snapshot covers in replay order, each with the number of its
+    /// bytes the snapshot covers.
+    pub(crate) segments: Vec<(u64, u64)>,
+    pub(crate) trees: ReplayedTrees,
+}
+
+/// Writes a snapshot of the index of every tree of `engine`. Writes are only held back while
+/// the index is encoded; compaction is held off until the snapshot is in place, so that the
+/// segments it points into are still part of the log by then.
+pub(crate) fn write(engine: &Inner) -> Result<()> {
+    if engine.options.read_only {
+        return Err(Error::ReadOnly);
+    }
+    engine.replayed()?;
+    let _compacting = engine.compaction_lock.lock().unwrap();
+    let contents = {
+        let _guard = engine.write_lock.lock().unwrap();
+        encode(engine, &engine.log.segments(), engine.log.last_sequence())
+    };
+    // The snapshot must not cover entries that could still be lost in a crash.
+    engine.log.sync_and_wait()?;
+
+    let dir = &engine.log.dir;
+    let tmp_path = dir.join(format!("{}.tmp", INDEX));
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(&contents)?;
+    tmp.sync_all()?;
+    std::fs::rename(tmp_path, dir.join(INDEX))?;
+    log::sync_dir(dir)?;
+    Ok(())
+}
+
+/// Removes the snapshot of the database in `dir`, if any.
+pub(crate) fn remove(dir: &Path) -> Result<()> {
+    match std::fs::remove_file(dir.join(INDEX)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Loads the snapshot of the log in `dir` made of `segments`, leaving out the entries that
+/// expired before `now` and ordering the keys of the trees other than the internal ones with
+/// `comparator`, if any. Returns `None` if there is no snapshot, or if it is damaged or no
+/// longer matches the log, in which case the whole log must be replayed.
+pub(crate) fn load(
+    dir: &Path,
+    segments: &[SegmentInfo],
+    now: u64,
+    comparator: Option<Comparator>,
+) -> Option<Snapshot> {
+    let data = std::fs::read(dir.join(INDEX)).ok()?;
+    let (contents, crc) = data.split_last_chunk::<4>()?;
+    if log::crc32(0, contents) != u32::from_be_bytes(*crc) {
+        return None;
+    }
+    let snapshot = decode(contents.strip_prefix(INDEX_HEADER)?, now, comparator)?;
+    let valid = snapshot.segments.len() <= segments.len()
+        && snapshot
+            .segments
+            .iter()
+            .zip(segments)
+            .all(|(&(id, len), segment)| id == segment.id && len <= segment.len);
+    valid.then_some(snapshot)
+}
+
+// Encodes the index of every tree, including the internal ones. The caller must hold the write
+// lock, so that the index matches `segments` and `sequence`.
+fn encode(engine: &Inner, segments: &[SegmentInfo], sequence: u64) -> Vec<u8> {
+    let mut out = INDEX_HEADER.to_vec();
+    out.extend_from_slice(&sequence.to_be_bytes());
+    out.extend_from_slice(&(segments.len() as u64).to_be_bytes());
+    for segment in segments {
+        out.extend_from_slice(&segment.id.to_be_bytes());
+        out.extend_from_slice(&segment.len.to_be_bytes());
+    }
+    let keyspaces = engine.keyspaces();
+    out.extend_from_slice(&(keyspaces.len() as u32).to_be_bytes());
+    for keyspace in keyspaces {
+        let key_map = keyspace.key_map.read().unwrap();
+        out.extend_from_slice(&keyspace.id.to_be_bytes());
+        out.extend_from_slice(&(key_map.len() as u64).to_be_bytes());
+        for (key, entry) in key_map.iter() {
+            let mut flags = 0;
+            if entry.expires_at.is_some() {
+                flags |= EXPIRES_FLAG;
+            }
+            if entry.checksum {
+                flags |= CHECKSUM_FLAG;
+            }
+            if entry.timestamps.is_some() {
+                flags |= TIMESTAMPS_FLAG;
+            }
+            out.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            out.extend_from_slice(key);
+            out.extend_from_slice(&entry.location.segment.to_be_bytes());
+            out.extend_from_slice(&entry.location.offset.to_be_bytes());
+            out.extend_from_slice(&entry.value_len.to_be_bytes());
+            out.extend_from_slice(&entry.expires_at.unwrap_or(0).to_be_bytes());
+            out.extend_from_slice(&entry.sequence.to_be_bytes());
+            out.push(flags);
+            out.push(entry.codec.to_byte());
+            if let Some(timestamps) = entry.timestamps {
+                out.extend_from_slice(&timestamps.created_at.to_be_bytes());
+                out.extend_from_slice(&timestamps.updated_at.to_be_bytes());
+            }
+        }
+    }
+    let crc = log::crc32(0, &out);
+    out.extend_from_slice(&crc.to_be_bytes());
+    out
+}
+
+fn decode(mut data: &[u8], now: u64, comparator: Option<Comparator>) -> Option<Snapshot> {
+    let data = &mut data;
+    let sequence = read_u64(data)?;
+    let segments = (0..read_u64(data)?)
+        .map(|_| Some((read_u64(data)?, read_u64(data)?)))
+        .collect::<Option<Vec<_>>>()?;
+    let mut trees = ReplayedTrees::new();
+    for _ in 0..read_u32(data)? {
+        let tree = read_u32(data)?;
+        let key_map = trees.entry(tree).or_insert_with(|| OrderedMap::new(order::of_tree(comparator, tree)));
+        for _ in 0..read_u64(data)? {
+            let key_len = read_u32(data)? as usize;
+            let key = Bytes::copy_from_slice(take(data, key_len)?);
+            let location = Location {
+                segment: read_u64(data)?,
+                offset: read_u64(data)?,
+            };
+            let value_len = read_u32(data)?;
+            let expires_at = read_u64(data)?;
+            let sequence = read_u64(data)?;
+            let [flags, codec] = *take(data, 2)? else {
+                return None;
+            };
+            let codec = match codec {
+                0 => Codec::None,
+                byte => Codec::from_byte(byte)?,
+            };
+            let timestamps = if flags & TIMESTAMPS_FLAG != 0 {
+                Some(Timestamps {
+                    created_at: read_u64(data)?,
+                    updated_at: read_u64(data)?,
+                })
+            } else {
+                None
+            };
+            let expires_at = (flags & EXPIRES_FLAG != 0).then_some(expires_at);
+            if expires_at.is_some_and(|t| t <= now) {
+                continue;
+            }
+            let entry = ReplayedEntry {
+                location,
+                value_len,
+                expires_at,
+                codec,
+                sequence,
+                checksum: flags & CHECKSUM_FLAG != 0,
+                timestamps,
+                value: None,
+            };
+            key_map.insert(key, entry);
+        }
+    }
+    if !data.is_empty() {
+        return None;
+    }
+    Some(Snapshot {
+        sequence,
+        segments,
+        trees,
+    })
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if data.len() < len {
+        return None;
+    }
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+    Some(taken)
+}
+
+fn read_u32(data: &mut &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(take(data, 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &mut &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(take(data, 8)?.try_into().ok()?))
+}
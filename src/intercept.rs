@@ -0,0 +1,103 @@
+//! Interceptors of writes.
+//!
+//! [`WriteInterceptor`]s registered in
+//! [`EngineOptions::interceptors`](crate::EngineOptions::interceptors) run in turn over every
+//! write made to a tree through its methods, pipelines, dump imports and bulk loads, while the
+//! write lock is held and before anything reaches the log. Each sees the write as the previous
+//! one left it, and may change it or reject it, which fails the write with
+//! [`Error::Rejected`]. Writes replicated from a primary, which were intercepted there, and the
+//! deletions made by dropping a tree are applied as they are.
+
+use std::fmt;
+
+use crate::engine::Inner;
+use crate::error::{Error, Result};
+use crate::tree::{Keyspace, HISTORY_TREE};
+
+/// The reason an interceptor gives for rejecting a write, which callers find in
+/// [`Error::Rejected`] and may downcast to the interceptor's own error type.
+pub type Rejection = Box<dyn std::error::Error + Send + Sync>;
+
+/// A write to a tree, as seen by [`WriteInterceptor`]s.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mutation {
+    /// Sets `key` to `value`, expiring at `expires_at` milliseconds since the Unix epoch. An
+    /// empty value deletes the key.
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    },
+    /// Deletes `key`.
+    Del { key: Vec<u8> },
+    /// Deletes every key from `start` up to but excluding `end`, or every key from `start` on
+    /// if `end` is `None`.
+    DelRange { start: Vec<u8>, end: Option<Vec<u8>> },
+}
+
+/// Checks or rewrites the writes made to an engine before they are committed, for example to
+/// confine tenants to their key prefix or to validate values against a schema; see
+/// [`EngineOptions::interceptors`](crate::EngineOptions::interceptors).
+///
+/// Interceptors run while the write lock is held, so they must be quick and must not use the
+/// engine. `tree` is the name the tree was opened with, or the empty string for the default
+/// tree.
+pub trait WriteInterceptor: fmt::Debug + Send + Sync {
+    /// Returns an error to reject `mutation`, or changes it in place to commit another write
+    /// instead, which must still respect the engine's size limits. A rejected write leaves the
+    /// writes made before it in the same batch, such as by [`Tree::del_many`](crate::Tree::del_many)
+    /// or a [pipeline](crate::Pipeline), committed.
+    fn intercept(&self, tree: &str, mutation: &mut Mutation) -> std::result::Result<(), Rejection>;
+}
+
+/// Runs the interceptors of `engine` over `mutation`, made to the tree called `tree`, and
+/// returns the write to commit in its place.
+pub(crate) fn intercept(engine: &Inner, tree: &str, mut mutation: Mutation) -> Result<Mutation> {
+    for interceptor in &engine.options.interceptors {
+        interceptor.intercept(tree, &mut mutation).map_err(Error::Rejected)?;
+    }
+    match &mutation {
+        Mutation::Set { key, value, .. } => engine.check_limits(key, value)?,
+        Mutation::Del { key } => engine.check_limits(key, &[])?,
+        Mutation::DelRange { start, end } => {
+            engine.check_limits(start, &[])?;
+            engine.check_limits(end.as_deref().unwrap_or_default(), &[])?;
+        }
+    }
+    Ok(mutation)
+}
+
+/// Intercepts `mutation` and commits what is left of it to the tree `ks`. The caller must hold
+/// the write lock.
+pub(crate) fn apply(engine: &Inner, ks: &Keyspace, mutation: Mutation) -> Result<()> {
+    let mutation = match engine.options.interceptors.is_empty() || ks.id >= HISTORY_TREE {
+        true => mutation,
+        false => {
+            let tree = engine.tree_names.get(engine, ks.id)?.unwrap_or_default();
+            intercept(engine, &tree, mutation)?
+        }
+    };
+    match mutation {
+        Mutation::Set { key, value, expires_at } => engine.set(ks, &key, value, expires_at),
+        Mutation::Del { key } => engine.del(ks, &key),
+        Mutation::DelRange { start, end } => engine.delete_range(ks, &start, &end.unwrap_or_default()),
+    }
+}
+
+/// Intercepts the pair `key` and `value`, expiring at `expires_at`, about to be bulk loaded into
+/// the tree called `tree`, and returns the pair to load in its place.
+pub(crate) fn loaded(
+    engine: &Inner,
+    tree: &str,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    expires_at: Option<u64>,
+) -> Result<(Vec<u8>, Vec<u8>, Option<u64>)> {
+    if engine.options.interceptors.is_empty() {
+        return Ok((key, value, expires_at));
+    }
+    match intercept(engine, tree, Mutation::Set { key, value, expires_at })? {
+        Mutation::Set { key, value, expires_at } => Ok((key, value, expires_at)),
+        _ => Err(Error::BulkLoad("an interceptor turned a loaded pair into a deletion".to_string())),
+    }
+}
@@ -0,0 +1,177 @@
+//! Order-preserving encoding of composite keys.
+//!
+//! Tuples of integers, strings, byte strings and booleans are encoded into byte keys whose
+//! lexicographic order matches the order of the tuples, so they can be stored in a tree and
+//! range-scanned in tuple order, for example to build indexes.
+//!
+//! Every part is self-delimiting, so the encoding of a tuple is the concatenation of the
+//! encodings of its parts, and a tuple that is a prefix of another sorts before it.
+
+use crate::error::{Error, Result};
+
+/// A value that can be encoded as part of a key.
+pub trait Key {
+    /// Appends the order-preserving encoding of the value to `buffer`.
+    fn encode_into(&self, buffer: &mut Vec<u8>);
+}
+
+/// A value that can be decoded from a key encoded by [`Key::encode_into`].
+pub trait DecodeKey: Sized {
+    /// Decodes a value from the front of `bytes`, advancing past it.
+    fn decode_from(bytes: &mut &[u8]) -> Result<Self>;
+}
+
+/// Encodes `key` into bytes that sort in the same order as the key.
+pub fn encode<K: Key + ?Sized>(key: &K) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    key.encode_into(&mut buffer);
+    buffer
+}
+
+/// Decodes a key encoded by [`encode`], which must span all of `bytes`.
+pub fn decode<K: DecodeKey>(mut bytes: &[u8]) -> Result<K> {
+    let key = K::decode_from(&mut bytes)?;
+    if !bytes.is_empty() {
+        return Err(Error::Decode("trailing bytes after key".to_string()));
+    }
+    Ok(key)
+}
+
+impl Key for u64 {
+    fn encode_into(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+impl DecodeKey for u64 {
+    fn decode_from(bytes: &mut &[u8]) -> Result<Self> {
+        let (int, rest) = bytes
+            .split_first_chunk::<8>()
+            .ok_or_else(|| Error::Decode("truncated integer".to_string()))?;
+        *bytes = rest;
+        Ok(u64::from_be_bytes(*int))
+    }
+}
+
+// Flipping the sign bit orders negative numbers before positive ones.
+impl Key for i64 {
+    fn encode_into(&self, buffer: &mut Vec<u8>) {
+        ((*self as u64) ^ (1 << 63)).encode_into(buffer);
+    }
+}
+
+impl DecodeKey for i64 {
+    fn decode_from(bytes: &mut &[u8]) -> Result<Self> {
+        Ok((u64::decode_from(bytes)? ^ (1 << 63)) as i64)
+    }
+}
+
+impl Key for bool {
+    fn encode_into(&self, buffer: &mut Vec<u8>) {
+        buffer.push(*self as u8);
+    }
+}
+
+impl DecodeKey for bool {
+    fn decode_from(bytes: &mut &[u8]) -> Result<Self> {
+        let (&byte, rest) = bytes
+            .split_first()
+            .ok_or_else(|| Error::Decode("truncated boolean".to_string()))?;
+        *bytes = rest;
+        match byte {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::Decode(format!("invalid boolean {}", byte))),
+        }
+    }
+}
+
+// Byte strings are terminated by `00 00`, with zero bytes inside them escaped as `00 FF`, so
+// that a shorter string sorts before every longer string it is a prefix of.
+impl Key for [u8] {
+    fn encode_into(&self, buffer: &mut Vec<u8>) {
+        for &byte in self {
+            buffer.push(byte);
+            if byte == 0 {
+                buffer.push(0xFF);
+            }
+        }
+        buffer.extend_from_slice(&[0, 0]);
+    }
+}
+
+impl DecodeKey for Vec<u8> {
+    fn decode_from(bytes: &mut &[u8]) -> Result<Self> {
+        let mut decoded = Vec::new();
+        let mut iter = bytes.iter();
+        loop {
+            match iter.next() {
+                Some(0) => match iter.next() {
+                    Some(0) => break,
+                    Some(0xFF) => decoded.push(0),
+                    _ => return Err(Error::Decode("invalid escape in string".to_string())),
+                },
+                Some(&byte) => decoded.push(byte),
+                None => return Err(Error::Decode("unterminated string".to_string())),
+            }
+        }
+        *bytes = iter.as_slice();
+        Ok(decoded)
+    }
+}
+
+impl Key for Vec<u8> {
+    fn encode_into(&self, buffer: &mut Vec<u8>) {
+        self.as_slice().encode_into(buffer);
+    }
+}
+
+impl Key for str {
+    fn encode_into(&self, buffer: &mut Vec<u8>) {
+        self.as_bytes().encode_into(buffer);
+    }
+}
+
+impl Key for String {
+    fn encode_into(&self, buffer: &mut Vec<u8>) {
+        self.as_bytes().encode_into(buffer);
+    }
+}
+
+impl DecodeKey for String {
+    fn decode_from(bytes: &mut &[u8]) -> Result<Self> {
+        String::from_utf8(Vec::decode_from(bytes)?)
+            .map_err(|_| Error::Decode("string is not valid UTF-8".to_string()))
+    }
+}
+
+impl<K: Key + ?Sized> Key for &K {
+    fn encode_into(&self, buffer: &mut Vec<u8>) {
+        (**self).encode_into(buffer);
+    }
+}
+
+macro_rules! tuple_key {
+    ($($name:ident)+) => {
+        impl<$($name: Key),+> Key for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_into(&self, buffer: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_into(buffer);)+
+            }
+        }
+
+        impl<$($name: DecodeKey),+> DecodeKey for ($($name,)+) {
+            fn decode_from(bytes: &mut &[u8]) -> Result<Self> {
+                Ok(($($name::decode_from(bytes)?,)+))
+            }
+        }
+    };
+}
+
+tuple_key!(A);
+tuple_key!(A B);
+tuple_key!(A B C);
+tuple_key!(A B C D);
+tuple_key!(A B C D E);
+tuple_key!(A B C D E F);
@@ -1,4 +1,74 @@
+mod backup;
+pub mod blocking;
+mod bulk;
+mod cache;
+mod changes;
+mod checkpoint;
+mod chunks;
+mod compaction;
+#[cfg(target_os = "linux")]
+mod direct;
+mod dump;
 mod engine;
+mod error;
+mod health;
+mod history;
+mod horizon;
+mod index;
+mod intercept;
+pub mod keyencoding;
 mod log;
+mod observer;
+mod options;
+mod order;
+mod pipeline;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "replication")]
+mod replication;
+mod scan;
+#[cfg(feature = "sim")]
+mod sim;
+mod sink;
+mod snapshot;
+mod stats;
+mod strategy;
+mod task;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod tree;
+#[cfg(feature = "serde")]
+mod typed;
+pub mod types;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+mod verify;
+mod watch;
 
+pub use backup::Backup;
+pub use bytes::Bytes;
+pub use changes::Change;
+pub use compaction::{CompactionEvent, CompactionHook};
 pub use engine::Engine;
+pub use error::{Error, Result};
+pub use health::{DiskFullEvent, DiskFullHook, DiskFullPolicy, HealthReport};
+pub use history::{At, Version};
+pub use horizon::Retention;
+pub use intercept::{Mutation, Rejection, WriteInterceptor};
+pub use log::{parse_records, Codec, ParseError, Record, Records, Timestamps};
+pub use observer::EngineObserver;
+pub use options::{Compression, EngineOptions};
+pub use order::Comparator;
+pub use pipeline::Pipeline;
+#[cfg(feature = "replication")]
+pub use replication::{Primary, Replica};
+pub use scan::{Iter, Keys, Page, Pairs};
+#[cfg(feature = "sim")]
+pub use sim::Simulation;
+pub use stats::{SegmentSpace, SpaceStats, Stats, TreeStats};
+pub use strategy::{CompactionStrategy, FullRewrite, SizeTiered, TimeWindowed};
+pub use tree::{Entry, Tree};
+#[cfg(feature = "serde")]
+pub use typed::TypedTree;
+pub use verify::{Corruption, LostKey, Verification};
+pub use watch::{Event, Filter, Op};
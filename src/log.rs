@@ -1,137 +1,1340 @@
-use std::sync::mpsc::{self, Sender};
-use std::thread;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Instant, UNIX_EPOCH};
 use std::fs::File;
-use std::io::{BufWriter, Write, BufReader, Read, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::io::{Write, Read};
+use std::path::{Path, PathBuf};
 use std::fs::OpenOptions;
 
+use bytes::Bytes;
+use tegdb_core::record::{encode, header_len, CHECKSUM_FLAG, RANGE_FLAG};
+pub use tegdb_core::record::{
+    crc32, decode_record, encode_record, entry_size, entry_size_of, parse_records, Codec, ParseError,
+    Record, Records, Timestamps, MAX_KEY_LEN, MAX_VALUE_LEN,
+};
+
+use crate::engine;
+use crate::error::{Error, Result};
+use crate::health::{self, HealthReport};
+use crate::index;
+use crate::options::Compression;
+use crate::order::{self, Comparator, OrderedMap};
+use crate::stats::{SegmentSpace, SpaceStats, Stats};
+use crate::sink::{Sink, SinkOptions};
+use crate::task;
+
+/// Name of the file listing the segments that make up the log, in replay order.
+pub const MANIFEST: &str = "MANIFEST";
+/// Name of the file locked by the process that has the log open.
+pub const LOCK: &str = "LOCK";
+// First line of the manifest, identifying the on-disk format. Version 2 added expiration
+// times, version 3 added trees, version 4 added range tombstones, version 5 added compressed
+// values, version 6 added sequence numbers, version 7 added checksums, version 8 added chunked
+// values and version 9 added timestamps; each is flagged per entry, so older logs remain readable.
+const MANIFEST_HEADER: &str = "tegdb 9";
+const LEGACY_MANIFEST_HEADERS: [&str; 8] =
+    ["tegdb 1", "tegdb 2", "tegdb 3", "tegdb 4", "tegdb 5", "tegdb 6", "tegdb 7", "tegdb 8"];
+// Prefix of the manifest line recording the last sequence number handed out, which the
+// entries remaining in the log may no longer show once compaction has dropped the newest ones.
+const SEQUENCE_PREFIX: &str = "sequence ";
+// Prefix of the manifest line naming the comparator the keys are ordered with, if any.
+const COMPARATOR_PREFIX: &str = "comparator ";
+/// Position of an entry inside the segmented log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub segment: u64,
+    pub offset: u64,
+}
+
+/// A live entry recovered by replaying the log.
+pub struct ReplayedEntry {
+    pub location: Location,
+    /// Length of the value as stored.
+    pub value_len: u32,
+    pub expires_at: Option<u64>,
+    pub codec: Codec,
+    pub sequence: u64,
+    pub checksum: bool,
+    pub timestamps: Option<Timestamps>,
+    /// The decompressed value itself, if values were requested.
+    pub value: Option<Vec<u8>>,
+}
+
+/// Where an entry was appended and how its value is stored.
+pub struct Appended {
+    pub location: Location,
+    /// Ticket that can be passed to [`Log::is_flushed`].
+    pub ticket: u64,
+    /// Length of the value as stored.
+    pub value_len: u32,
+    pub codec: Codec,
+    pub sequence: u64,
+}
+
+/// Live entries recovered by replaying the log, keyed by user key.
+pub(crate) type ReplayedMap = OrderedMap<ReplayedEntry>;
+
+/// Live entries recovered by replaying the log, grouped by tree id.
+pub(crate) type ReplayedTrees = HashMap<u32, ReplayedMap>;
+
+// A record decoded during replay, along with the entry it leaves if it sets a key.
+type DecodedRecord = (Record, Option<ReplayedEntry>);
+
+/// Size accounting for a single segment file.
+#[derive(Clone, Copy, Debug)]
+pub struct SegmentInfo {
+    pub id: u64,
+    /// Bytes written to the segment.
+    pub len: u64,
+    /// Bytes belonging to entries that are still live.
+    pub live: u64,
+    /// When the segment was sealed, in milliseconds since the Unix epoch, or `None` while it
+    /// is active.
+    pub sealed_at: Option<u64>,
+}
+
+impl SegmentInfo {
+    /// Returns the accounting of the segment as reported by [`Log::space_stats`].
+    pub fn space(&self) -> SegmentSpace {
+        SegmentSpace {
+            id: self.id,
+            log_bytes: self.len,
+            live_bytes: self.live,
+            sealed_at: self.sealed_at,
+        }
+    }
+}
+
+struct Segments {
+    // Segments in replay order; the last one is the active segment receiving appends.
+    list: Vec<SegmentInfo>,
+    next_id: u64,
+    // Number of entries handed to the writer thread so far.
+    writes: u64,
+    // Number of bytes handed to the writer thread so far.
+    bytes_written: u64,
+    // Number of deletions appended since the active segment was last sealed for compaction.
+    tombstones: u64,
+    // Sequence number of the latest entry.
+    sequence: u64,
+    // Sequence number up to which compaction may have dropped deletions from the log. Nothing
+    // is known about compactions before the log was opened, so it starts out at `sequence`.
+    history_start: u64,
+    // Sequence number before which compaction may have dropped writes that the state of the
+    // database as of then depends on, such as values overwritten since. It starts out at
+    // `sequence` for the same reason.
+    view_start: u64,
+}
+
 // The Log struct encapsulates a log writer for appending entries and enables log replay to rebuild the key map.
+// Entries are appended to fixed-size segment files inside a directory; once the active segment
+// grows past `segment_size` it is sealed and never written again, so compaction can rewrite
+// old segments independently of ongoing writes.
 pub struct Log {
-    pub path: PathBuf,
-    pub writer: LogWriter,
+    pub dir: PathBuf,
+    // Absent when the log was opened read-only.
+    writer: Option<LogWriter>,
+    // Holds the lock on the log directory until the log is closed or dropped.
+    lock: Mutex<Option<File>>,
+    // Set once the log has been closed, after which nothing can be written to it.
+    closed: AtomicBool,
+    segments: Mutex<Segments>,
+    segment_size: u64,
+    compression: Compression,
+    // How segment files are written.
+    sink: SinkOptions,
+    // Read handles for segment files, opened on first use.
+    readers: Mutex<HashMap<u64, Arc<File>>>,
+    // Name of the comparator recorded in the manifest, if any.
+    comparator: Option<String>,
+    // Number of values read back whose entry did not match its checksum.
+    checksum_failures: AtomicU64,
 }
 
+/// Open handles on the segments of a log, returned by [`Log::pin`].
+pub struct Pinned(HashMap<u64, Arc<File>>);
+
 impl Log {
-    pub fn new(path: PathBuf) -> Self {
-        if let Some(dir) = path.parent() {
-            std::fs::create_dir_all(dir).unwrap();
+    /// Opens the log stored in `dir`, creating it if needed. The directory is locked exclusively,
+    /// or with a shared lock if `read_only` is set, in which case the log must already exist and
+    /// cannot be written to. Values written from now on are compressed with `compression`, at
+    /// most `queue_capacity` entries wait for the writer thread at a time, and segments are
+    /// written as `sink` sets out. A new log records the name of `comparator`, and an existing
+    /// one must have recorded the same name.
+    pub fn open(
+        dir: PathBuf,
+        segment_size: u64,
+        read_only: bool,
+        compression: Compression,
+        queue_capacity: usize,
+        sink: SinkOptions,
+        comparator: Option<&str>,
+    ) -> Result<Self> {
+        if comparator.is_some_and(|name| name.is_empty() || name.contains('\n')) {
+            let message = "comparator names must be a single line";
+            return Err(Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, message)));
         }
-        Self {
-            path: path.clone(),
-            writer: LogWriter::new(path),
-        }
-    }
-
-    pub fn build_key_map(&self) -> std::collections::BTreeMap<Vec<u8>, Vec<u8>> {
-        let mut key_map = std::collections::BTreeMap::new();
-        let mut file = OpenOptions::new().read(true).open(&self.path).unwrap();
-        let file_len = file.metadata().unwrap().len();
-        let mut r = BufReader::new(&mut file);
-        let mut pos = r.seek(SeekFrom::Start(0)).unwrap();
-        let mut len_buf = [0u8; 4];
-        while pos < file_len {
-            r.read_exact(&mut len_buf).unwrap();
-            let key_len = u32::from_be_bytes(len_buf);
-            r.read_exact(&mut len_buf).unwrap();
-            let value_len = u32::from_be_bytes(len_buf);
-            let value_pos = pos + 4 + 4 + key_len as u64;
-            let mut key = vec![0; key_len as usize];
-            r.read_exact(&mut key).unwrap();
-            let mut value = vec![0; value_len as usize];
-            r.read_exact(&mut value).unwrap();
-            if value_len == 0 {
-                key_map.remove(&key);
+        migrate_single_file(&dir, read_only)?;
+        if !read_only {
+            std::fs::create_dir_all(&dir)?;
+        }
+        let lock = lock_dir(&dir, read_only)?;
+        let (ids, sequence, recorded) = match std::fs::read_to_string(dir.join(MANIFEST)) {
+            Ok(manifest) => parse_manifest(&manifest)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !read_only => {
+                write_manifest(&dir, &[1], 0, comparator)?;
+                (vec![1], 0, comparator.map(str::to_string))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if recorded.as_deref() != comparator {
+            return Err(Error::ComparatorMismatch {
+                expected: comparator.map(str::to_string),
+                found: recorded,
+            });
+        }
+        if !read_only {
+            remove_unlisted_segments(&dir, &ids)?;
+        }
+        let mut list = Vec::with_capacity(ids.len());
+        for &id in &ids {
+            // Segments sealed before the log was opened count as sealed when last modified.
+            let (len, modified) = match std::fs::metadata(segment_path(&dir, id)) {
+                Ok(metadata) => (metadata.len(), metadata.modified().ok()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (0, None),
+                Err(e) => return Err(e.into()),
+            };
+            let sealed_at = modified
+                .filter(|_| Some(&id) != ids.last())
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|elapsed| elapsed.as_millis() as u64);
+            list.push(SegmentInfo { id, len, live: 0, sealed_at });
+        }
+        let active = list.last().unwrap().id;
+        let writer = if read_only {
+            None
+        } else {
+            Some(LogWriter::new(Sink::open(&segment_path(&dir, active), &sink)?, queue_capacity))
+        };
+        Ok(Self {
+            writer,
+            lock: Mutex::new(Some(lock)),
+            closed: AtomicBool::new(false),
+            segments: Mutex::new(Segments {
+                next_id: ids.iter().max().unwrap() + 1,
+                list,
+                writes: 0,
+                bytes_written: 0,
+                tombstones: 0,
+                sequence,
+                history_start: sequence,
+                view_start: sequence,
+            }),
+            dir,
+            segment_size,
+            compression,
+            sink,
+            readers: Mutex::new(HashMap::new()),
+            comparator: recorded,
+            checksum_failures: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns the current time in milliseconds since the Unix epoch, from the simulation's
+    /// clock if the log is written under one.
+    pub fn now(&self) -> u64 {
+        #[cfg(feature = "sim")]
+        if let Some(simulation) = &self.sink.simulation {
+            return simulation.now_millis();
+        }
+        engine::now_millis()
+    }
+
+    /// Returns the size at which segments are sealed.
+    pub fn segment_size(&self) -> u64 {
+        self.segment_size
+    }
+
+    /// Returns the path of the segment file with the given id.
+    pub fn segment_path(&self, id: u64) -> PathBuf {
+        segment_path(&self.dir, id)
+    }
+
+    /// Replays every segment in order and returns the live entries along with their locations.
+    /// Entries that expired before `now` are treated as deletions.
+    /// Values are only retained when `keep_values` is set. The keys of the trees other than the
+    /// internal ones are ordered with `comparator`, if any.
+    ///
+    /// If the directory holds an index snapshot matching the log, the entries are loaded from
+    /// it and only the part of the log written after it is replayed. Segments are decoded on as
+    /// many threads as there are cores, a batch of segments at a time, and the decoded records
+    /// are then applied to the key maps in log order.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(dir = %self.dir.display()), err)
+    )]
+    pub(crate) fn build_key_map(
+        &self,
+        keep_values: bool,
+        now: u64,
+        comparator: Option<Comparator>,
+    ) -> Result<ReplayedTrees> {
+        #[cfg(feature = "tracing")]
+        let started = std::time::Instant::now();
+        let list = self.segments();
+        let (mut trees, mut sequence, covered) = match index::load(&self.dir, &list, now, comparator) {
+            Some(snapshot) => (snapshot.trees, snapshot.sequence, snapshot.segments),
+            None => (ReplayedTrees::new(), 0, Vec::new()),
+        };
+        if keep_values {
+            self.read_values(&mut trees)?;
+        }
+        // Each segment is replayed from the end of the part the snapshot covers, if any.
+        let starts: Vec<(u64, u64)> = list
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (s.id, covered.get(i).map_or(0, |&(_, len)| len)))
+            .collect();
+        let threads = thread::available_parallelism().map_or(1, usize::from);
+        for batch in starts.chunks(threads) {
+            let decoded: Vec<Result<Vec<DecodedRecord>>> = thread::scope(|scope| {
+                let workers: Vec<_> = batch
+                    .iter()
+                    .map(|&(id, start)| scope.spawn(move || self.decode_segment(id, start, keep_values, now)))
+                    .collect();
+                workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+            });
+            for records in decoded {
+                for (record, entry) in records? {
+                    sequence = sequence.max(record.sequence);
+                    let key_map = trees
+                        .entry(record.tree)
+                        .or_insert_with(|| OrderedMap::new(order::of_tree(comparator, record.tree)));
+                    if record.deletes_range {
+                        key_map.remove_range(&record.key, &record.value);
+                    } else if let Some(entry) = entry {
+                        key_map.insert(Bytes::from(record.key), entry);
+                    } else {
+                        key_map.remove(&record.key);
+                    }
+                }
+            }
+        }
+        let mut segments = self.segments.lock().unwrap();
+        segments.sequence = segments.sequence.max(sequence);
+        for (&tree, key_map) in &trees {
+            for (key, entry) in key_map.iter() {
+                if let Some(segment) = segments.list.iter_mut().find(|s| s.id == entry.location.segment) {
+                    let (value_len, expires_at, codec) = (entry.value_len, entry.expires_at, entry.codec);
+                    let (sequence, timestamps, checksum) = (entry.sequence, entry.timestamps.is_some(), entry.checksum);
+                    segment.live += entry_size_of(
+                        tree,
+                        key.len(),
+                        value_len,
+                        expires_at,
+                        codec,
+                        sequence,
+                        timestamps,
+                        checksum,
+                    );
+                }
+            }
+        }
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            segments = segments.list.len(),
+            keys = trees.values().map(|key_map| key_map.len()).sum::<usize>(),
+            snapshot = !covered.is_empty(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "log replayed"
+        );
+        drop(segments);
+        Ok(trees)
+    }
+
+    // Decodes the records of segment `id` from offset `start` on, pairing every record that
+    // sets a key with the entry it leaves in the key map. Its value is moved into the entry,
+    // decompressed, if `keep_values` is set.
+    fn decode_segment(&self, id: u64, start: u64, keep_values: bool, now: u64) -> Result<Vec<DecodedRecord>> {
+        let mut decoded = Vec::new();
+        for record in SegmentReader::open(&self.segment_path(id), id)?.start(start) {
+            let (location, mut record) = record?;
+            if record.deletes_range || record.is_deletion(now) {
+                decoded.push((record, None));
+                continue;
+            }
+            let value_len = record.value.len() as u32;
+            let value = if keep_values {
+                Some(decompress(record.codec, std::mem::take(&mut record.value))?)
             } else {
-                key_map.insert(key, value);
+                None
+            };
+            let entry = ReplayedEntry {
+                location,
+                value_len,
+                expires_at: record.expires_at,
+                codec: record.codec,
+                sequence: record.sequence,
+                checksum: record.checksum,
+                timestamps: record.timestamps,
+                value,
+            };
+            decoded.push((record, Some(entry)));
+        }
+        Ok(decoded)
+    }
+
+    // Reads back the values of entries loaded from an index snapshot, reading each segment
+    // they point into once.
+    fn read_values(&self, trees: &mut ReplayedTrees) -> Result<()> {
+        let mut by_segment: HashMap<u64, Vec<(u64, &mut ReplayedEntry)>> = HashMap::new();
+        for (&tree, key_map) in trees.iter_mut() {
+            for (key, entry) in key_map.iter_mut() {
+                let offset = entry.location.offset + header_len(tree, entry.expires_at, entry.codec) + key.len() as u64;
+                by_segment.entry(entry.location.segment).or_default().push((offset, entry));
+            }
+        }
+        for (segment, entries) in by_segment {
+            let data = std::fs::read(self.segment_path(segment))?;
+            for (offset, entry) in entries {
+                let value = usize::try_from(offset)
+                    .ok()
+                    .and_then(|offset| data.get(offset..offset + entry.value_len as usize))
+                    .ok_or_else(|| {
+                        Error::Corrupted(format!("index snapshot points past the end of segment {}", segment))
+                    })?;
+                entry.value = Some(decompress(entry.codec, value.to_vec())?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends an entry to the active segment, compressing its value if configured, and returns
+    /// where it was written, how its value is stored and the sequence number it was given.
+    /// Tombstones (empty values) are never counted as live.
+    pub fn write_entry(
+        &self,
+        tree: u32,
+        key: &[u8],
+        value: &[u8],
+        expires_at: Option<u64>,
+        timestamps: Option<Timestamps>,
+    ) -> Result<Appended> {
+        let (codec, compressed) = compress(self.compression, value)?;
+        let stored = compressed.as_deref().unwrap_or(value);
+        let encode =
+            |sequence| encode(tree, key, stored, expires_at, codec, sequence, timestamps, CHECKSUM_FLAG);
+        self.append(encode, stored.len() as u32, codec, !value.is_empty())
+    }
+
+    /// Encodes an entry with the given sequence number as [`Log::write_entry`] would append it,
+    /// for entries written to segments outside the log, and returns it along with the length
+    /// and codec of its stored value.
+    pub fn encode_entry(
+        &self,
+        tree: u32,
+        key: &[u8],
+        value: &[u8],
+        expires_at: Option<u64>,
+        timestamps: Option<Timestamps>,
+        sequence: u64,
+    ) -> Result<(Vec<u8>, u32, Codec)> {
+        let (codec, compressed) = compress(self.compression, value)?;
+        let stored = compressed.as_deref().unwrap_or(value);
+        let buffer = encode(tree, key, stored, expires_at, codec, sequence, timestamps, CHECKSUM_FLAG);
+        Ok((buffer, stored.len() as u32, codec))
+    }
+
+    /// Appends an entry whose value is the manifest of a chunked value, stored uncompressed.
+    pub fn write_chunked_entry(
+        &self,
+        tree: u32,
+        key: &[u8],
+        manifest: &[u8],
+        expires_at: Option<u64>,
+        timestamps: Option<Timestamps>,
+    ) -> Result<Appended> {
+        let codec = Codec::Chunked;
+        let encode =
+            |sequence| encode(tree, key, manifest, expires_at, codec, sequence, timestamps, CHECKSUM_FLAG);
+        self.append(encode, manifest.len() as u32, codec, true)
+    }
+
+    /// Appends a range tombstone deleting every key of `tree` from `start` up to but excluding
+    /// `end`, or every key from `start` on if `end` is empty.
+    pub fn write_range_tombstone(&self, tree: u32, start: &[u8], end: &[u8]) -> Result<Appended> {
+        let flags = RANGE_FLAG | CHECKSUM_FLAG;
+        let encode = |sequence| encode(tree, start, end, None, Codec::None, sequence, None, flags);
+        self.append(encode, end.len() as u32, Codec::None, false)
+    }
+
+    // Appends the entry produced by `encode` for the next sequence number.
+    fn append(
+        &self,
+        encode: impl FnOnce(u64) -> Vec<u8>,
+        value_len: u32,
+        codec: Codec,
+        live: bool,
+    ) -> Result<Appended> {
+        let writer = self.writer()?;
+        let mut segments = self.segments.lock().unwrap();
+        let sequence = segments.sequence + 1;
+        let buffer = encode(sequence);
+        let active = segments.list.last().unwrap();
+        if active.len > 0 && active.len + buffer.len() as u64 > self.segment_size {
+            self.roll(&mut segments)?;
+        }
+        let active = segments.list.last_mut().unwrap();
+        let location = Location {
+            segment: active.id,
+            offset: active.len,
+        };
+        active.len += buffer.len() as u64;
+        if live {
+            active.live += buffer.len() as u64;
+        } else {
+            segments.tombstones += 1;
+        }
+        segments.writes += 1;
+        segments.bytes_written += buffer.len() as u64;
+        segments.sequence = sequence;
+        writer.write(buffer);
+        Ok(Appended {
+            location,
+            ticket: segments.writes,
+            value_len,
+            codec,
+            sequence,
+        })
+    }
+
+    /// Returns the sequence number of the latest entry, or 0 if nothing has been written.
+    pub fn last_sequence(&self) -> u64 {
+        self.segments.lock().unwrap().sequence
+    }
+
+    /// Returns the sequence number after which every deletion is still in the log, so that the
+    /// changes made after it can be replayed exactly.
+    #[cfg(feature = "replication")]
+    pub fn history_start(&self) -> u64 {
+        self.segments.lock().unwrap().history_start
+    }
+
+    /// Records that compaction dropped deletions up to `sequence` from the log.
+    pub fn truncate_history(&self, sequence: u64) {
+        let mut segments = self.segments.lock().unwrap();
+        segments.history_start = segments.history_start.max(sequence);
+    }
+
+    /// Returns the earliest sequence number the log can still be read as of.
+    pub fn view_start(&self) -> u64 {
+        self.segments.lock().unwrap().view_start
+    }
+
+    /// Records that compaction dropped writes the state as of sequence numbers before
+    /// `sequence` depends on.
+    pub fn truncate_views(&self, sequence: u64) {
+        let mut segments = self.segments.lock().unwrap();
+        segments.view_start = segments.view_start.max(sequence);
+    }
+
+    /// Returns true once the entry written with `ticket` has been flushed to its segment file.
+    pub fn is_flushed(&self, ticket: u64) -> bool {
+        self.writer
+            .as_ref()
+            .is_none_or(|writer| writer.flushed.load(Ordering::SeqCst) >= ticket)
+    }
+
+    // Returns the writer, failing if the log is read-only or closed or if the writer thread has
+    // failed.
+    fn writer(&self) -> Result<&LogWriter> {
+        if self.is_closed() {
+            return Err(Error::Closed);
+        }
+        let writer = self.writer.as_ref().ok_or(Error::ReadOnly)?;
+        writer.check()?;
+        Ok(writer)
+    }
+
+    /// Returns the latest error the writer thread ran into, after which every write fails, or
+    /// [`Error::Closed`] once the log has been closed.
+    pub fn health(&self) -> Result<()> {
+        if self.is_closed() {
+            return Err(Error::Closed);
+        }
+        match &self.writer {
+            Some(writer) => Ok(writer.check()?),
+            None => Ok(()),
+        }
+    }
+
+    /// Waits until every queued entry has been handed to the file, without blocking the calling
+    /// thread, failing if the writer thread could not write one.
+    pub async fn flush(&self) -> Result<()> {
+        if let Some(writer) = &self.writer {
+            writer.flush().await;
+        }
+        self.health()
+    }
+
+    /// Reads and decompresses the value of the entry at `location`, which belongs to `tree`,
+    /// whose key and stored value have the given lengths, which carries an expiration time if
+    /// `expires_at` is set and whose value is stored with `codec`. If `checked_len` is set, the
+    /// whole entry, which takes that many bytes, is read and checked against its checksum first.
+    /// The entry must already have been flushed.
+    #[allow(clippy::too_many_arguments)]
+    pub fn read_value(
+        &self,
+        location: Location,
+        tree: u32,
+        key_len: usize,
+        value_len: u32,
+        expires_at: Option<u64>,
+        codec: Codec,
+        checked_len: Option<u64>,
+    ) -> Result<Vec<u8>> {
+        let file = self.reader(location.segment)?;
+        self.read_value_from(&file, location, tree, key_len, value_len, expires_at, codec, checked_len)
+    }
+
+    /// Reads a value like [`Log::read_value`] from the segments pinned by `pinned` if the entry
+    /// is in one of them, so that the segment may have been compacted away since.
+    #[allow(clippy::too_many_arguments)]
+    pub fn read_pinned_value(
+        &self,
+        pinned: &Pinned,
+        location: Location,
+        tree: u32,
+        key_len: usize,
+        value_len: u32,
+        expires_at: Option<u64>,
+        codec: Codec,
+        checked_len: Option<u64>,
+    ) -> Result<Vec<u8>> {
+        let file = match pinned.0.get(&location.segment) {
+            Some(file) => file.clone(),
+            None => self.reader(location.segment)?,
+        };
+        self.read_value_from(&file, location, tree, key_len, value_len, expires_at, codec, checked_len)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn read_value_from(
+        &self,
+        file: &File,
+        location: Location,
+        tree: u32,
+        key_len: usize,
+        value_len: u32,
+        expires_at: Option<u64>,
+        codec: Codec,
+        checked_len: Option<u64>,
+    ) -> Result<Vec<u8>> {
+        let Some(len) = checked_len else {
+            let mut value = vec![0; value_len as usize];
+            let offset = location.offset + header_len(tree, expires_at, codec) + key_len as u64;
+            read_exact_at(file, &mut value, offset)?;
+            return decompress(codec, value);
+        };
+        let mut entry = vec![0; len as usize];
+        read_exact_at(file, &mut entry, location.offset)?;
+        let problem = match decode_record(&entry, 0) {
+            Ok((record, _, true)) => return decompress(codec, record.value),
+            Ok((_, _, false)) => "does not match its checksum".to_string(),
+            Err(e) => format!("cannot be decoded: {}", e),
+        };
+        self.checksum_failures.fetch_add(1, Ordering::Relaxed);
+        let (segment, offset) = (location.segment, location.offset);
+        Err(Error::Corrupted(format!("the entry at offset {} of segment {} {}", offset, segment, problem)))
+    }
+
+    /// Opens every segment of the log, so that the entries they hold at this point can still be
+    /// read with [`Log::read_pinned_value`] once compaction has deleted them. Open files stay
+    /// readable after they are deleted.
+    #[cfg(feature = "replication")]
+    pub fn pin(&self) -> Result<Pinned> {
+        let ids: Vec<u64> = self.segments.lock().unwrap().list.iter().map(|s| s.id).collect();
+        let files = ids.into_iter().map(|id| Ok((id, self.reader(id)?)));
+        Ok(Pinned(files.collect::<Result<_>>()?))
+    }
+
+    fn reader(&self, segment: u64) -> Result<Arc<File>> {
+        let mut readers = self.readers.lock().unwrap();
+        if let Some(file) = readers.get(&segment) {
+            return Ok(file.clone());
+        }
+        let file = Arc::new(File::open(self.segment_path(segment))?);
+        readers.insert(segment, file.clone());
+        Ok(file)
+    }
+
+    /// Records that `bytes` previously counted as live in `segment` have been superseded.
+    pub fn mark_dead(&self, segment: u64, bytes: u64) {
+        let mut segments = self.segments.lock().unwrap();
+        if let Some(segment) = segments.list.iter_mut().find(|s| s.id == segment) {
+            segment.live = segment.live.saturating_sub(bytes);
+        }
+    }
+
+    /// Returns a snapshot of every segment in replay order; the last one is active.
+    pub fn segments(&self) -> Vec<SegmentInfo> {
+        self.segments.lock().unwrap().list.clone()
+    }
+
+    /// Returns the total size of the log in bytes, including entries still queued for the writer thread.
+    pub fn len(&self) -> u64 {
+        self.segments.lock().unwrap().list.iter().map(|s| s.len).sum()
+    }
+
+    /// Returns the number of bytes belonging to live entries.
+    pub fn live_len(&self) -> u64 {
+        self.segments.lock().unwrap().list.iter().map(|s| s.live).sum()
+    }
+
+    /// Fills in the fields of `stats` describing the log.
+    pub fn fill_stats(&self, stats: &mut Stats) {
+        let segments = self.segments.lock().unwrap();
+        stats.log_bytes = segments.list.iter().map(|s| s.len).sum();
+        stats.live_bytes = segments.list.iter().map(|s| s.live).sum();
+        stats.bytes_written = segments.bytes_written;
+        stats.writes = segments.writes;
+        stats.write_queue_depth = self.writer.as_ref().map_or(0, |w| w.queued.load(Ordering::SeqCst));
+        stats.tombstones = segments.tombstones;
+    }
+
+    /// Fills in the fields of `report` describing the log.
+    pub fn fill_health(&self, report: &mut HealthReport) {
+        report.write_error = self.health().err();
+        report.available_space = health::available_space(&self.dir);
+        if let Some(writer) = &self.writer {
+            report.write_queue_depth = writer.queued.load(Ordering::SeqCst);
+            report.since_last_sync = writer.last_sync.lock().unwrap().map(|synced| synced.elapsed());
+        }
+        report.checksum_failures = self.checksum_failures.load(Ordering::Relaxed);
+    }
+
+    /// Returns the space accounting kept up to date as entries are written and overwritten.
+    pub fn space_stats(&self) -> SpaceStats {
+        let segments = self.segments.lock().unwrap();
+        let list: Vec<SegmentSpace> = segments
+            .list
+            .iter()
+            .map(SegmentInfo::space)
+            .collect();
+        SpaceStats {
+            log_bytes: list.iter().map(|s| s.log_bytes).sum(),
+            live_bytes: list.iter().map(|s| s.live_bytes).sum(),
+            tombstones: segments.tombstones,
+            segments: list,
+        }
+    }
+
+    /// Seals the active segment, if it holds any data, so that it becomes eligible for compaction.
+    pub fn seal(&self) -> Result<()> {
+        let mut segments = self.segments.lock().unwrap();
+        segments.tombstones = 0;
+        if segments.list.last().unwrap().len > 0 {
+            self.roll(&mut segments)?;
+        }
+        Ok(())
+    }
+
+    /// Reserves an id for a segment written outside the writer thread, such as a compaction output.
+    pub fn allocate_segment_id(&self) -> u64 {
+        let mut segments = self.segments.lock().unwrap();
+        segments.next_id += 1;
+        segments.next_id - 1
+    }
+
+    /// Adds the segments in `new`, written outside the log with entries numbered up to
+    /// `sequence`, right before the active segment, which must be empty, and makes `sequence`
+    /// the latest sequence number.
+    pub fn insert_segments(&self, new: Vec<SegmentInfo>, sequence: u64) -> Result<()> {
+        let mut segments = self.segments.lock().unwrap();
+        let mut list = segments.list.clone();
+        let active = list.pop().unwrap();
+        list.extend(new);
+        list.push(active);
+        let ids: Vec<u64> = list.iter().map(|s| s.id).collect();
+        write_manifest(&self.dir, &ids, sequence, self.comparator.as_deref())?;
+        segments.list = list;
+        segments.sequence = sequence;
+        Ok(())
+    }
+
+    /// Atomically replaces the sealed segments `old` with `new` in the manifest. The new
+    /// segments take the place of the newest replaced segment so that entries written
+    /// afterwards keep replaying on top of them. The old files are left for
+    /// [`Log::remove_segments`] to delete once nothing points into them.
+    pub fn replace_segments(&self, old: &[u64], new: &[SegmentInfo]) -> Result<()> {
+        let mut segments = self.segments.lock().unwrap();
+        let position = segments
+            .list
+            .iter()
+            .rposition(|s| old.contains(&s.id))
+            .expect("replaced segments must be part of the log");
+        let mut list = Vec::with_capacity(segments.list.len() + new.len());
+        for (i, segment) in segments.list.iter().enumerate() {
+            if i == position {
+                list.extend(new.iter().copied());
+            }
+            if !old.contains(&segment.id) {
+                list.push(*segment);
             }
-            pos = value_pos + value_len as u64;
         }
-        key_map
+        let ids: Vec<u64> = list.iter().map(|s| s.id).collect();
+        write_manifest(&self.dir, &ids, segments.sequence, self.comparator.as_deref())?;
+        segments.list = list;
+        Ok(())
+    }
+
+    /// Deletes the files of the segments `ids`, which have been replaced.
+    pub fn remove_segments(&self, ids: &[u64]) -> Result<()> {
+        let mut readers = self.readers.lock().unwrap();
+        for &id in ids {
+            readers.remove(&id);
+            std::fs::remove_file(self.segment_path(id))?;
+        }
+        Ok(())
     }
 
-    pub fn write_entry(&self, key: &[u8], value: &[u8]) {
-        if key.len() > 1024 || value.len() > 256 * 1024 {
-            panic!("Key or value exceeds allowed limit");
+    /// Blocks until every queued entry has been handed to the file.
+    pub fn flush_and_wait(&self) {
+        if let Some(writer) = &self.writer {
+            writer.flush_and_wait();
         }
-        let key_len = key.len() as u32;
-        let value_len = value.len() as u32;
-        let mut buffer = Vec::with_capacity(4 + 4 + key.len() + value.len());
-        buffer.extend_from_slice(&key_len.to_be_bytes());
-        buffer.extend_from_slice(&value_len.to_be_bytes());
-        buffer.extend_from_slice(key);
-        buffer.extend_from_slice(value);
-        self.writer.write(buffer);
+    }
+
+    /// Waits until every entry written so far is durable on disk, without blocking the calling
+    /// thread. Does nothing if the log is read-only.
+    pub async fn sync(&self) -> Result<()> {
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        synced(self.writer()?.sync().await)?;
+        self.health()
+    }
+
+    /// Blocks until every entry written so far is durable on disk, like [`Log::sync`].
+    pub fn sync_and_wait(&self) -> Result<()> {
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        synced(self.writer()?.sync().wait())?;
+        self.health()
+    }
+
+    /// Writes every queued entry, fsyncs the log, stops the writer thread and releases the lock
+    /// on the directory. Later writes fail with [`Error::Closed`], while values can still be
+    /// read back from the segments.
+    pub fn close(&self) -> Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let synced = self.sync_and_wait();
+        self.closed.store(true, Ordering::SeqCst);
+        if let Some(writer) = &self.writer {
+            writer.shutdown_and_join();
+        }
+        self.lock.lock().unwrap().take();
+        synced
+    }
+
+    /// Returns true once the log has been closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    pub fn shutdown(&self) {
+        if let Some(writer) = &self.writer {
+            writer.shutdown();
+        }
+    }
+
+    // Starts a new active segment and redirects the writer thread to it.
+    fn roll(&self, segments: &mut Segments) -> Result<()> {
+        let writer = self.writer()?;
+        let id = segments.next_id;
+        let sink = Sink::open(&self.segment_path(id), &self.sink)?;
+        let mut ids: Vec<u64> = segments.list.iter().map(|s| s.id).collect();
+        ids.push(id);
+        write_manifest(&self.dir, &ids, segments.sequence, self.comparator.as_deref())?;
+        writer.reopen(sink);
+        segments.next_id += 1;
+        segments.list.last_mut().unwrap().sealed_at = Some(self.now());
+        segments.list.push(SegmentInfo {
+            id,
+            len: 0,
+            live: 0,
+            sealed_at: None,
+        });
+        Ok(())
     }
 }
 
-impl Clone for Log {
-    fn clone(&self) -> Self {
-        Self {
-            path: self.path.clone(),
-            writer: self.writer.clone(),
+// Compresses `value` as configured, returning the codec used and the compressed value, or no
+// value when it is stored as it is because compression is disabled or would not shrink it.
+fn compress(compression: Compression, value: &[u8]) -> std::io::Result<(Codec, Option<Vec<u8>>)> {
+    let compressed: Option<(Codec, Vec<u8>)> = match compression {
+        Compression::None => None,
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => Some((Codec::Lz4, lz4_flex::compress_prepend_size(value))),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd(level) => Some((Codec::Zstd, zstd::bulk::compress(value, level)?)),
+    };
+    match compressed {
+        Some((codec, compressed)) if compressed.len() < value.len() => Ok((codec, Some(compressed))),
+        _ => Ok((Codec::None, None)),
+    }
+}
+
+/// Restores a value stored with `codec` to its original bytes. The manifest of a chunked value
+/// is returned as it is, since its chunks are separate entries.
+pub fn decompress(codec: Codec, value: Vec<u8>) -> Result<Vec<u8>> {
+    match codec {
+        Codec::None | Codec::Chunked => Ok(value),
+        #[cfg(feature = "lz4")]
+        Codec::Lz4 => lz4_flex::decompress_size_prepended(&value)
+            .map_err(|e| Error::Corrupted(format!("invalid lz4 value: {}", e))),
+        #[cfg(not(feature = "lz4"))]
+        Codec::Lz4 => Err(Error::Corrupted("value is compressed with lz4, which is not enabled".to_string())),
+        #[cfg(feature = "zstd")]
+        Codec::Zstd => zstd::stream::decode_all(value.as_slice())
+            .map_err(|e| Error::Corrupted(format!("invalid zstd value: {}", e))),
+        #[cfg(not(feature = "zstd"))]
+        Codec::Zstd => Err(Error::Corrupted("value is compressed with zstd, which is not enabled".to_string())),
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(file, buf, offset) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the path of a segment file inside the log directory.
+pub fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:08}.log", id))
+}
+
+/// Locks the log directory so that no other process can write to it while it is open.
+pub fn lock_dir(dir: &Path, shared: bool) -> Result<File> {
+    let path = dir.join(LOCK);
+    // Logs written before locking was introduced have no lock file yet, even when read-only.
+    let file = if shared && path.exists() {
+        File::open(&path)?
+    } else {
+        File::options().write(true).create(true).truncate(false).open(&path)?
+    };
+    let locked = if shared { file.try_lock_shared() } else { file.try_lock() };
+    match locked {
+        Ok(()) => Ok(file),
+        Err(std::fs::TryLockError::WouldBlock) => Err(Error::DatabaseLocked(dir.to_path_buf())),
+        Err(std::fs::TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Returns the segment ids listed in the manifest along with the last sequence number and the
+/// name of the comparator it records.
+pub fn parse_manifest(manifest: &str) -> Result<(Vec<u64>, u64, Option<String>)> {
+    let mut lines = manifest.lines().peekable();
+    let header = lines.next().unwrap_or_default();
+    if header != MANIFEST_HEADER && !LEGACY_MANIFEST_HEADERS.contains(&header) {
+        return Err(Error::Corrupted("unrecognized manifest header".to_string()));
+    }
+    let mut sequence = 0;
+    if let Some(line) = lines.next_if(|line| line.starts_with(SEQUENCE_PREFIX)) {
+        sequence = line[SEQUENCE_PREFIX.len()..]
+            .parse()
+            .map_err(|_| Error::Corrupted(format!("invalid sequence in manifest: {}", line)))?;
+    }
+    let comparator = lines
+        .next_if(|line| line.starts_with(COMPARATOR_PREFIX))
+        .map(|line| line[COMPARATOR_PREFIX.len()..].to_string());
+    let ids = lines
+        .map(|line| {
+            line.parse::<u64>()
+                .map_err(|_| Error::Corrupted(format!("invalid segment id in manifest: {}", line)))
+        })
+        .collect::<Result<Vec<u64>>>()?;
+    if ids.is_empty() {
+        return Err(Error::Corrupted("manifest lists no segments".to_string()));
+    }
+    Ok((ids, sequence, comparator))
+}
+
+// Writes the manifest to a temporary file first so a crash never leaves a partial manifest behind.
+// The directory is synced before the rename, so segments created for the new manifest are durable
+// before it refers to them, and after it, so the swap itself survives a crash.
+pub fn write_manifest(dir: &Path, ids: &[u64], sequence: u64, comparator: Option<&str>) -> std::io::Result<()> {
+    let mut contents = String::from(MANIFEST_HEADER);
+    contents.push('\n');
+    contents.push_str(SEQUENCE_PREFIX);
+    contents.push_str(&sequence.to_string());
+    if let Some(name) = comparator {
+        contents.push('\n');
+        contents.push_str(COMPARATOR_PREFIX);
+        contents.push_str(name);
+    }
+    for id in ids {
+        contents.push('\n');
+        contents.push_str(&id.to_string());
+    }
+    contents.push('\n');
+    let tmp_path = dir.join(format!("{}.tmp", MANIFEST));
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(contents.as_bytes())?;
+    tmp.sync_all()?;
+    sync_dir(dir)?;
+    std::fs::rename(tmp_path, dir.join(MANIFEST))?;
+    sync_dir(dir)
+}
+
+// A compaction interrupted by a crash leaves its output segments behind without the manifest
+// referring to them, and a crash right after the manifest swap leaves the replaced segments.
+// Neither is part of the log, and their ids would otherwise be handed out again.
+fn remove_unlisted_segments(dir: &Path, ids: &[u64]) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let unlisted = path.extension().is_some_and(|ext| ext == "log")
+            && path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+                .is_some_and(|id| !ids.contains(&id));
+        if unlisted {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+// Directories cannot be opened for syncing on Windows.
+#[cfg(windows)]
+pub fn sync_dir(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+// Databases created before segmentation are a single log file at `path`. Its entries use the
+// same encoding, so it is moved into the directory as the first segment. The file is first
+// moved aside to make way for the directory, and a migration interrupted since is finished
+// from wherever it stopped, which a read-only open cannot do.
+fn migrate_single_file(path: &Path, read_only: bool) -> Result<()> {
+    let tmp_path = path.with_extension("migrating");
+    if tmp_path.is_file() {
+        if path.is_file() {
+            return Err(Error::Corrupted(format!(
+                "cannot tell which of {} and {} to migrate",
+                path.display(),
+                tmp_path.display()
+            )));
+        }
+        if read_only {
+            return Err(Error::Corrupted(format!(
+                "migrating {} into a directory was interrupted; open it read-write to finish",
+                tmp_path.display()
+            )));
         }
+    } else if read_only || !path.is_file() {
+        return Ok(());
+    } else {
+        std::fs::rename(path, &tmp_path)?;
+    }
+    std::fs::create_dir_all(path)?;
+    let segment = segment_path(path, 1);
+    if segment.exists() {
+        return Err(Error::Corrupted(format!(
+            "cannot finish migrating {}: {} already exists",
+            tmp_path.display(),
+            segment.display()
+        )));
+    }
+    std::fs::rename(&tmp_path, segment)?;
+    write_manifest(path, &[1], 0, None)?;
+    Ok(())
+}
+
+/// Sequentially decodes the entries stored in one segment file. The segment is read into memory
+/// with a single read when opened, so decoding a record only copies it out of the buffer rather
+/// than reading each of its fields from the file, which makes replaying large logs much faster.
+pub struct SegmentReader {
+    data: Vec<u8>,
+    segment: u64,
+    pos: u64,
+    len: u64,
+}
+
+impl SegmentReader {
+    pub fn open(path: &Path, segment: u64) -> Result<Self> {
+        let mut file = OpenOptions::new().read(true).open(path)?;
+        let mut data = Vec::with_capacity(file.metadata()?.len() as usize);
+        file.read_to_end(&mut data)?;
+        Ok(Self {
+            len: data.len() as u64,
+            data,
+            segment,
+            pos: 0,
+        })
+    }
+
+    /// Starts reading at offset `pos`, which must be where an entry begins, such as the end of
+    /// the part of the segment covered by an index snapshot.
+    pub fn start(mut self, pos: u64) -> Self {
+        self.pos = pos;
+        self
+    }
+
+    /// Stops reading after the first `len` bytes, such as the bytes of the active segment known
+    /// to have been written in full.
+    pub fn limit(mut self, len: u64) -> Self {
+        self.len = self.len.min(len);
+        self
+    }
+}
+
+impl Iterator for SegmentReader {
+    type Item = Result<(Location, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut records = parse_records(&self.data[..self.len as usize]).start(self.pos);
+        let location = Location {
+            segment: self.segment,
+            offset: self.pos,
+        };
+        let record = records.next()?;
+        self.pos = records.offset();
+        Some(record.map(|record| (location, record)).map_err(Error::from))
     }
 }
 
 // Messages used to control the log writer thread.
 pub enum LogMessage {
     Write(Vec<u8>),
-    Flush,
+    // Flushes and signals the sender once every earlier message has been handled.
+    Barrier(task::Sender<()>),
+    // Flushes, fsyncs and reports the outcome once every earlier write is durable.
+    Sync(task::Sender<std::io::Result<()>>),
+    // Flushes and continues writing to a different file.
+    Reopen(Sink),
     Shutdown,
 }
 
 pub struct LogWriter {
-    sender: Sender<LogMessage>,
+    // Bounded, so that writers block once the writer thread falls `capacity` messages behind.
+    sender: SyncSender<LogMessage>,
+    // Number of written entries known to have been flushed to their segment file.
+    flushed: Arc<AtomicU64>,
+    // Number of entries sent that the writer thread has not received yet.
+    queued: Arc<AtomicU64>,
+    // The latest error the writer thread ran into, after which the file may be missing writes.
+    failure: Arc<Mutex<Option<std::io::Error>>>,
+    // When the file was last fsynced.
+    last_sync: Arc<Mutex<Option<Instant>>>,
+    // The writer thread, until it has been joined.
+    thread: Arc<Mutex<Option<JoinHandle<()>>>>,
 }
 
 impl LogWriter {
-    pub fn new(path: PathBuf) -> Self {
-        let file = File::options()
-            .append(true)
-            .create(true)
-            .open(&path)
-            .expect("failed to open log file");
-        let (sender, receiver) = mpsc::channel();
+    pub fn new(sink: Sink, capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let flushed = Arc::new(AtomicU64::new(0));
+        let published = flushed.clone();
+        let queued = Arc::new(AtomicU64::new(0));
+        let received = queued.clone();
+        let failure = Arc::new(Mutex::new(None));
+        let failed = failure.clone();
+        let last_sync = Arc::new(Mutex::new(None));
+        let synced = last_sync.clone();
         // Spawn dedicated thread to process log messages.
-        thread::spawn(move || {
-            let mut writer = BufWriter::new(file);
+        let thread = thread::spawn(move || {
+            let mut writer = sink;
+            let mut written = 0;
+            let flush = |writer: &mut Sink, written: u64| match writer.flush() {
+                Ok(()) => published.store(written, Ordering::SeqCst),
+                Err(e) => *failed.lock().unwrap() = Some(e),
+            };
+            // Group commit: every message already queued is handled as one batch, so concurrent
+            // writes reach the file with a single write and share a single fsync.
+            let mut batch = Vec::new();
+            let mut waiters = Vec::new();
+            let mut shutdown = false;
             while let Ok(msg) = receiver.recv() {
-                match msg {
-                    LogMessage::Write(data) => {
-                        if let Err(e) = writer.write_all(&data) {
-                            eprintln!("Failed to write log: {}", e);
-                        }
-                    },
-                    LogMessage::Flush => {
-                        if let Err(e) = writer.flush() {
-                            eprintln!("Failed to flush log: {}", e);
-                        }
-                    },
-                    LogMessage::Shutdown => break,
+                for msg in std::iter::once(msg).chain(receiver.try_iter()) {
+                    if let LogMessage::Write(data) = msg {
+                        received.fetch_sub(1, Ordering::SeqCst);
+                        batch.extend_from_slice(&data);
+                        written += 1;
+                        continue;
+                    }
+                    write_batch(&mut writer, &mut batch, &failed);
+                    match msg {
+                        LogMessage::Write(_) => unreachable!(),
+                        LogMessage::Barrier(done) => {
+                            flush(&mut writer, written);
+                            done.send(());
+                        },
+                        LogMessage::Sync(done) => waiters.push(done),
+                        LogMessage::Reopen(sink) => {
+                            flush(&mut writer, written);
+                            sync(&mut writer, &mut waiters, &failed, &synced);
+                            writer = sink;
+                        },
+                        LogMessage::Shutdown => {
+                            shutdown = true;
+                            break;
+                        },
+                    }
+                }
+                write_batch(&mut writer, &mut batch, &failed);
+                if !waiters.is_empty() {
+                    flush(&mut writer, written);
+                    sync(&mut writer, &mut waiters, &failed, &synced);
+                }
+                if shutdown {
+                    break;
                 }
             }
         });
-        Self { sender }
+        Self {
+            sender,
+            flushed,
+            queued,
+            failure,
+            last_sync,
+            thread: Arc::new(Mutex::new(Some(thread))),
+        }
+    }
+
+    /// Returns the latest error the writer thread ran into, if any. Once it has failed, the
+    /// file may be missing writes, so nothing more should be appended to it.
+    pub fn check(&self) -> std::io::Result<()> {
+        match &*self.failure.lock().unwrap() {
+            Some(e) => Err(std::io::Error::new(e.kind(), e.to_string())),
+            None => Ok(()),
+        }
     }
 
+    /// Queues `data` for the writer thread, blocking while the queue is full.
     pub fn write(&self, data: Vec<u8>) {
-        let _ = self.sender.send(LogMessage::Write(data));
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        if self.sender.send(LogMessage::Write(data)).is_err() {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Queues a flush of buffered data, returning a future that the writer thread completes
+    /// once every earlier write has been handed to the file, or once it has shut down.
+    pub fn flush(&self) -> task::Receiver<()> {
+        let (done, flushed) = task::channel();
+        let _ = self.sender.send(LogMessage::Barrier(done));
+        flushed
+    }
+
+    /// Flushes buffered data and waits for the writer thread to acknowledge it.
+    pub fn flush_and_wait(&self) {
+        self.flush().wait();
+    }
+
+    /// Queues an fsync, returning a future that the writer thread completes with its outcome
+    /// once every earlier write has been flushed and fsynced, or with `None` if it has shut
+    /// down. Concurrent callers are committed together.
+    pub fn sync(&self) -> task::Receiver<std::io::Result<()>> {
+        let (done, synced) = task::channel();
+        let _ = self.sender.send(LogMessage::Sync(done));
+        synced
     }
 
-    pub fn flush(&self) {
-        let _ = self.sender.send(LogMessage::Flush);
+    pub fn reopen(&self, sink: Sink) {
+        let _ = self.sender.send(LogMessage::Reopen(sink));
     }
 
     /// Initiates shutdown of the log writer thread.
     pub fn shutdown(&self) {
         let _ = self.sender.send(LogMessage::Shutdown);
     }
+
+    /// Shuts down the log writer thread and waits for it to exit.
+    pub fn shutdown_and_join(&self) {
+        self.shutdown();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
 }
 
 impl Clone for LogWriter {
     fn clone(&self) -> Self {
         Self {
             sender: self.sender.clone(),
+            flushed: self.flushed.clone(),
+            queued: self.queued.clone(),
+            failure: self.failure.clone(),
+            last_sync: self.last_sync.clone(),
+            thread: self.thread.clone(),
         }
     }
 }
+
+// Returns the outcome of a sync the writer thread completed, or an error if it shut down first.
+fn synced(outcome: Option<std::io::Result<()>>) -> std::io::Result<()> {
+    outcome.unwrap_or_else(|| Err(std::io::Error::other("log writer has shut down")))
+}
+
+// Hands the writes collected in `batch` to the file with a single write, recording the error
+// in `failure` if it fails.
+fn write_batch(writer: &mut Sink, batch: &mut Vec<u8>, failure: &Mutex<Option<std::io::Error>>) {
+    if batch.is_empty() {
+        return;
+    }
+    if let Err(e) = writer.write_batch(batch) {
+        *failure.lock().unwrap() = Some(e);
+    }
+}
+
+// Fsyncs the flushed file and reports the outcome to every waiting writer, recording the error
+// in `failure` if it fails and the time in `last_sync` otherwise.
+fn sync(
+    writer: &mut Sink,
+    waiters: &mut Vec<task::Sender<std::io::Result<()>>>,
+    failure: &Mutex<Option<std::io::Error>>,
+    last_sync: &Mutex<Option<Instant>>,
+) {
+    if waiters.is_empty() {
+        return;
+    }
+    let result = writer.sync_data();
+    match &result {
+        Ok(()) => *last_sync.lock().unwrap() = Some(Instant::now()),
+        Err(e) => *failure.lock().unwrap() = Some(std::io::Error::new(e.kind(), e.to_string())),
+    }
+    for done in waiters.drain(..) {
+        let result = match &result {
+            Ok(()) => Ok(()),
+            Err(e) => Err(std::io::Error::new(e.kind(), e.to_string())),
+        };
+        done.send(result);
+    }
+}
@@ -0,0 +1,108 @@
+//! Observers of committed writes.
+//!
+//! An [`EngineObserver`] registered in [`EngineOptions::observers`](crate::EngineOptions::observers)
+//! is called for every write to a tree right after it is appended to the log, while the write
+//! lock is held, so that it sees writes in the order they were committed, and for every step
+//! of a compaction. Observers are called with the name of the tree written to, which is looked
+//! up in the internal tree of tree names and cached until a tree is created or dropped.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::changes::Change;
+use crate::compaction::CompactionEvent;
+use crate::engine::{self, Inner};
+use crate::error::Result;
+use crate::tree::{DEFAULT_TREE, HISTORY_TREE, META_TREE};
+
+/// Receives the writes committed to an engine and its compactions, for example to keep an audit
+/// log, metrics or secondary indexes up to date; see
+/// [`EngineOptions::observers`](crate::EngineOptions::observers).
+///
+/// Writes are reported while the write lock is held, so the methods must be quick and must not
+/// use the engine. Every method does nothing by default. `tree` is the name the tree was opened
+/// with, or the empty string for the default tree.
+pub trait EngineObserver: fmt::Debug + Send + Sync {
+    /// Called once the write numbered `sequence` has set `key` of `tree` to `value`, which
+    /// expires at `expires_at` milliseconds since the Unix epoch if it is given.
+    fn on_set(&self, tree: &str, key: &[u8], value: &[u8], expires_at: Option<u64>, sequence: u64) {
+        let _ = (tree, key, value, expires_at, sequence);
+    }
+
+    /// Called once the write numbered `sequence` has deleted `key` of `tree`, which existed.
+    fn on_delete(&self, tree: &str, key: &[u8], sequence: u64) {
+        let _ = (tree, key, sequence);
+    }
+
+    /// Called once the write numbered `sequence` has deleted every key of `tree` from `start`
+    /// up to but excluding `end`, or every key from `start` on if `end` is `None`, as
+    /// [`Tree::delete_range`](crate::Tree::delete_range) and dropping the tree do.
+    fn on_delete_range(&self, tree: &str, start: &[u8], end: Option<&[u8]>, sequence: u64) {
+        let _ = (tree, start, end, sequence);
+    }
+
+    /// Called as compactions start, progress, finish or fail, on the thread running the
+    /// compaction, like [`EngineOptions::on_compaction`](crate::EngineOptions::on_compaction).
+    fn on_compact(&self, event: &CompactionEvent) {
+        let _ = event;
+    }
+}
+
+/// The names of the trees by id, looked up as observers need them.
+#[derive(Default)]
+pub(crate) struct TreeNames(Mutex<Option<HashMap<u32, String>>>);
+
+impl TreeNames {
+    /// Returns the name of tree `id`, reading the names of every tree if they are not known.
+    pub(crate) fn get(&self, engine: &Inner, id: u32) -> Result<Option<String>> {
+        if id == DEFAULT_TREE {
+            return Ok(Some(String::new()));
+        }
+        let mut names = self.0.lock().unwrap();
+        if names.is_none() {
+            let mut read = HashMap::new();
+            for (name, id) in engine.scan(&engine.keyspace(META_TREE), &(..))? {
+                let name = String::from_utf8_lossy(&name).into_owned();
+                read.insert(engine::tree_id(&name, &id)?, name);
+            }
+            *names = Some(read);
+        }
+        Ok(names.as_ref().unwrap().get(&id).cloned())
+    }
+
+    /// Forgets the names read, as a tree was created or dropped and its id may be handed out
+    /// again.
+    pub(crate) fn clear(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+}
+
+/// Reports `change`, just committed to tree `tree`, to every observer of `engine`.
+pub(crate) fn notify(engine: &Inner, tree: u32, change: &Change) {
+    if tree >= HISTORY_TREE {
+        return;
+    }
+    let name = match engine.tree_names.get(engine, tree) {
+        Ok(Some(name)) => name,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("Looking up the name of tree {} for observers failed: {}", tree, e);
+            return;
+        }
+    };
+    for observer in &engine.options.observers {
+        match change {
+            Change::Set {
+                sequence,
+                key,
+                value,
+                expires_at,
+            } => observer.on_set(&name, key, value, *expires_at, *sequence),
+            Change::Del { sequence, key } => observer.on_delete(&name, key, *sequence),
+            Change::DelRange { sequence, start, end } => {
+                observer.on_delete_range(&name, start, end.as_deref(), *sequence)
+            }
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! Tunable settings for opening an engine.
+
+use std::cmp::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::compaction::CompactionHook;
+use crate::health::{DiskFullHook, DiskFullPolicy};
+use crate::horizon::Retention;
+use crate::intercept::WriteInterceptor;
+use crate::observer::EngineObserver;
+use crate::order::Comparator;
+use crate::strategy::CompactionStrategy;
+#[cfg(feature = "sim")]
+use crate::sim::Simulation;
+
+/// Options controlling how an [`Engine`](crate::Engine) behaves once opened.
+#[derive(Debug, Clone)]
+pub struct EngineOptions {
+    /// Size in bytes at which the active log segment is sealed and a new one is started.
+    pub segment_size: u64,
+    /// Opens the database read-only under a shared lock, so that several processes can read it
+    /// at once. Writes fail with [`Error::ReadOnly`](crate::Error::ReadOnly) and no compaction runs.
+    pub read_only: bool,
+    /// Whether each write waits until it has been fsynced to disk before returning.
+    /// Writes issued concurrently are committed together and share a single fsync. Without it,
+    /// single writes can still wait for their fsync with [`Tree::set_durable`](crate::Tree::set_durable)
+    /// and [`Tree::del_durable`](crate::Tree::del_durable).
+    pub sync_writes: bool,
+    /// Number of entries that may wait to be written by the log writer thread. Once the queue
+    /// is full, writes block until the writer catches up, so a fast writer cannot queue
+    /// unbounded amounts of memory.
+    pub write_queue_capacity: usize,
+    /// Whether the log is written with `O_DIRECT` and `O_DSYNC`, so that writes bypass the page
+    /// cache instead of being buffered twice, and each batch is durable once the writer thread
+    /// has written it without a separate fsync. This trades throughput for predictable write
+    /// latency. Only supported on Linux, where file systems without direct I/O get `O_DSYNC`
+    /// alone; elsewhere the option has no effect.
+    pub direct_io: bool,
+    /// Compression applied to values as they are written to the log. Entries record how their
+    /// value is stored, so logs written with different settings can still be read, provided
+    /// the codecs they used are enabled.
+    pub compression: Compression,
+    /// Whether values are kept in memory alongside their keys. When disabled, only the location
+    /// of each value is kept and values are read back from the log, so databases larger than
+    /// memory can be opened.
+    pub keep_values_in_memory: bool,
+    /// Most bytes of values kept in memory when `keep_values_in_memory` is set, or `None` for
+    /// no limit. Once values take more, the ones read least recently are dropped from memory,
+    /// keeping only their location, and read back from the log when next needed, after which
+    /// they are kept in memory again. Hot keys are then served from memory while cold ones
+    /// take little more than their key.
+    pub value_memory_budget: Option<u64>,
+    /// Size in bytes of the cache for values read back from the log when they are not kept in memory.
+    pub value_cache_size: u64,
+    /// Approximate memory in bytes the key maps of all trees may take, or `None` for no limit.
+    /// Once kept values would take them past the limit, every value is dropped from memory and
+    /// read back from the log from then on, as if `keep_values_in_memory` were disabled. Writes
+    /// adding keys that would still take them past it fail with
+    /// [`Error::IndexMemoryLimit`](crate::Error::IndexMemoryLimit), while deletions keep
+    /// working. The memory taken is reported by [`Stats::index_bytes`](crate::Stats::index_bytes).
+    pub index_memory_limit: Option<u64>,
+    /// Whether opening the engine returns before the log has been replayed, replaying it on a
+    /// background thread instead, so that applications with large databases can start right
+    /// away. Reads and writes block until the replay has finished and fail with its error if
+    /// it failed; see [`Engine::is_replayed`](crate::Engine::is_replayed).
+    pub background_replay: bool,
+    /// How often a background thread writes a snapshot of the index of every tree to the
+    /// database directory, or `None` to never write one. A snapshot is also written when the
+    /// engine is closed. Opening the database loads the latest snapshot and only replays the
+    /// log written after it, so restarts take time proportional to the recent writes rather
+    /// than to the whole log. Snapshots pointing into segments that compaction has since
+    /// rewritten are ignored.
+    pub index_snapshot_interval: Option<Duration>,
+    /// Whether a background thread compacts the log when the thresholds below are exceeded.
+    pub background_compaction: bool,
+    /// Minimum log size in bytes before background compaction is considered.
+    pub compaction_min_size: u64,
+    /// Fraction of the log occupied by overwritten or deleted entries that triggers compaction.
+    pub compaction_garbage_ratio: f64,
+    /// How often the background compactor checks the thresholds.
+    pub compaction_interval: Duration,
+    /// Which recent writes compaction keeps in the log even once they are overwritten or
+    /// deleted, deletions included, so that [`Tree::changes_since`](crate::Tree::changes_since)
+    /// still returns them. Consumers acknowledging their progress with
+    /// [`Engine::acknowledge`](crate::Engine::acknowledge) hold writes back as well. Kept
+    /// writes take space in the log until they fall behind the horizon, which
+    /// [`Engine::gc_horizon`](crate::Engine::gc_horizon) returns.
+    pub retention: Retention,
+    /// Strategy deciding when background compaction runs and which sealed segments it
+    /// rewrites, in place of `compaction_min_size` and `compaction_garbage_ratio`, or `None` to
+    /// rewrite the segments whose own garbage ratio exceeds that threshold once the log as a
+    /// whole does. See [`FullRewrite`](crate::FullRewrite), [`SizeTiered`](crate::SizeTiered)
+    /// and [`TimeWindowed`](crate::TimeWindowed).
+    pub compaction_strategy: Option<Arc<dyn CompactionStrategy>>,
+    /// Most bytes per second a compaction reads from the log, or `None` for no limit. Slowing
+    /// compaction down leaves disk bandwidth to reads and writes on slow disks, at the cost of
+    /// garbage being reclaimed later. The rewritten entries are written at the same pace.
+    pub compaction_bytes_per_second: Option<u64>,
+    /// Function called as compactions start, progress, finish or fail, for example to report
+    /// long compactions in logs or user interfaces. It is called on the thread running the
+    /// compaction, which it holds up, and must not compact the engine itself.
+    pub on_compaction: Option<CompactionHook>,
+    /// Observers told of every write committed to a tree and of every compaction, in the order
+    /// they are given, for example to keep an audit log or a secondary index outside the
+    /// engine. See [`EngineObserver`](crate::EngineObserver).
+    pub observers: Vec<Arc<dyn EngineObserver>>,
+    /// Interceptors run in order over every write to a tree before it is committed, each of
+    /// which may change or reject it, for example to enforce key prefixes or value schemas.
+    /// Rejected writes fail with [`Error::Rejected`](crate::Error::Rejected). See
+    /// [`WriteInterceptor`](crate::WriteInterceptor).
+    pub interceptors: Vec<Arc<dyn WriteInterceptor>>,
+    /// Largest key accepted by writes, in bytes, or `None` to only enforce the log format's
+    /// limit of 64 MiB. Longer keys are rejected with [`Error::KeyTooLarge`](crate::Error::KeyTooLarge).
+    pub max_key_size: Option<usize>,
+    /// Largest value accepted by writes, in bytes, or `None` to only enforce the log format's
+    /// limit of 4 GiB. Values are held in memory whole while they are read or written, so
+    /// raising this suits blob storage only as far as memory allows. Longer values are rejected
+    /// with [`Error::ValueTooLarge`](crate::Error::ValueTooLarge) unless they are chunked.
+    pub max_value_size: Option<usize>,
+    /// Whether values longer than `max_value_size` are split into chunks of that size rather
+    /// than rejected, up to the log format's limit of 4 GiB. Chunks are separate log entries
+    /// reassembled on read, so blobs of many megabytes can be stored without any single entry
+    /// growing that large.
+    pub chunk_large_values: bool,
+    /// Whether writes record when each key was created and last modified in its log entry,
+    /// which [`Tree::get_entry`](crate::Tree::get_entry) returns, for example to sync or audit
+    /// changes. Each entry takes 16 more bytes in the log. Keys written while
+    /// it was disabled have no timestamps until they are written again.
+    pub record_timestamps: bool,
+    /// Number of previous versions of each key kept besides its current one, which
+    /// [`Tree::get_at`](crate::Tree::get_at) and [`Tree::history`](crate::Tree::history) read, for
+    /// example for audit trails or undo, or 0 to keep none. Versions are stored as separate log
+    /// entries, so each write takes about twice the space until its versions are pruned.
+    pub history_versions: usize,
+    /// Ordering of the keys of every tree, or `None` to order them bytewise. Scans, range
+    /// deletions and range filters follow it, while prefix scans still return the keys starting
+    /// with the prefix, wherever they are in the ordering. The name of the comparator is
+    /// recorded when the database is created, and opening it with another comparator, or
+    /// without one, fails with [`Error::ComparatorMismatch`](crate::Error::ComparatorMismatch).
+    /// The keys of typed trees only keep the order of the values they encode when ordered
+    /// bytewise.
+    pub comparator: Option<Comparator>,
+    /// Whether values read back from the log are checked against the checksum of their entry,
+    /// failing with [`Error::Corrupted`](crate::Error::Corrupted) when it does not match rather
+    /// than returning a damaged value. The whole entry is read rather than just its value, so
+    /// reads of values not kept in memory get slower. Entries written before checksums were
+    /// introduced can only be checked for being decodable.
+    pub paranoid_checks: bool,
+    /// How often a background thread scrubs the log, or `None` to never scrub it; see
+    /// [`Engine::scrub`](crate::Engine::scrub). Each scrub reads every sealed segment, and
+    /// corruption found is reported by [`Engine::health`](crate::Engine::health).
+    pub scrub_interval: Option<Duration>,
+    /// Bytes of free space to keep on the disk holding the database, or `None` to let the disk
+    /// fill up. Writes check the free space at most every 100 milliseconds, and once it falls
+    /// below this, the engine acts as `disk_full_policy` sets out, before a full disk makes the
+    /// log writer thread lose the writes it holds. Only supported on Linux. Running out of
+    /// space is reported either way: writes then fail with
+    /// [`Error::NoSpace`](crate::Error::NoSpace) until the database is reopened, except under
+    /// [`DiskFullPolicy::ReadOnly`].
+    pub min_free_space: Option<u64>,
+    /// What the engine does once the disk is full.
+    pub disk_full_policy: DiskFullPolicy,
+    /// Function called once the disk is found full, and once the free space is back above
+    /// `min_free_space`, for example to alert operators or free up space. It is called on the
+    /// thread making the write that found out, which it holds up.
+    pub on_disk_full: Option<DiskFullHook>,
+    /// Simulation providing the clock and running the background work, for deterministic
+    /// tests, or `None` to use the system clock and a background thread. Requires the `sim`
+    /// feature; see [`Simulation`].
+    #[cfg(feature = "sim")]
+    pub simulation: Option<Simulation>,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        Self {
+            segment_size: 64 * 1024 * 1024,
+            read_only: false,
+            sync_writes: false,
+            write_queue_capacity: 1024,
+            direct_io: false,
+            compression: Compression::None,
+            keep_values_in_memory: true,
+            value_memory_budget: None,
+            value_cache_size: 8 * 1024 * 1024,
+            index_memory_limit: None,
+            background_replay: false,
+            index_snapshot_interval: None,
+            background_compaction: true,
+            compaction_min_size: 1024 * 1024,
+            compaction_garbage_ratio: 0.5,
+            compaction_interval: Duration::from_secs(1),
+            retention: Retention::None,
+            compaction_strategy: None,
+            compaction_bytes_per_second: None,
+            on_compaction: None,
+            observers: Vec::new(),
+            interceptors: Vec::new(),
+            max_key_size: Some(1024),
+            max_value_size: Some(256 * 1024),
+            chunk_large_values: false,
+            record_timestamps: false,
+            history_versions: 0,
+            comparator: None,
+            paranoid_checks: false,
+            scrub_interval: None,
+            min_free_space: None,
+            disk_full_policy: DiskFullPolicy::Fail,
+            on_disk_full: None,
+            #[cfg(feature = "sim")]
+            simulation: None,
+        }
+    }
+}
+
+impl EngineOptions {
+    /// Orders keys with `compare` rather than bytewise, under the given name; see
+    /// [`comparator`](Self::comparator).
+    pub fn with_comparator(mut self, name: &'static str, compare: fn(&[u8], &[u8]) -> Ordering) -> Self {
+        self.comparator = Some(Comparator { name, compare });
+        self
+    }
+}
+
+/// Codecs available for compressing values. Values that do not shrink are stored uncompressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Values are stored as they are.
+    None,
+    /// LZ4, which favours speed. Requires the `lz4` feature.
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// Zstandard at the given level, which favours the compression ratio. Requires the `zstd`
+    /// feature.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
@@ -0,0 +1,280 @@
+//! Orderings of keys.
+//!
+//! Keys are ordered bytewise unless the engine is opened with a [`Comparator`], in which case the
+//! key maps of its trees order their keys with it instead, and so do scans, range deletions and
+//! range filters. The internal trees keep ordering their keys bytewise, as their keys are encoded
+//! to sort that way. The name of the comparator is recorded in the manifest when the database is
+//! created, and opening it with another comparator, or without one, fails: its range tombstones
+//! would then cover other keys than when they were written.
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::{btree_map, BTreeMap};
+use std::fmt;
+use std::ops::Bound;
+
+use bytes::Bytes;
+
+use crate::tree::HISTORY_TREE;
+
+/// Function comparing two keys.
+pub(crate) type Compare = fn(&[u8], &[u8]) -> Ordering;
+
+/// A named ordering of keys; see [`EngineOptions::comparator`](crate::EngineOptions::comparator).
+#[derive(Clone, Copy)]
+pub struct Comparator {
+    /// Name recorded in the database, a single line identifying the ordering. Databases can
+    /// only be opened with a comparator of the same name, so it should change along with the
+    /// ordering.
+    pub name: &'static str,
+    /// Compares two keys. It must be a total order in which only identical keys are equal, for
+    /// example by breaking ties bytewise, and in which the empty key comes first.
+    pub compare: fn(&[u8], &[u8]) -> Ordering,
+}
+
+impl fmt::Debug for Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Comparator").field(&self.name).finish()
+    }
+}
+
+/// Returns the ordering of the keys of `tree` when the engine is opened with `comparator`, or
+/// `None` if they are ordered bytewise.
+pub(crate) fn of_tree(comparator: Option<Comparator>, tree: u32) -> Option<Compare> {
+    comparator.filter(|_| tree < HISTORY_TREE).map(|comparator| comparator.compare)
+}
+
+/// Compares `a` with `b` in the ordering `order`, bytewise if it is `None`.
+pub(crate) fn compare(order: Option<Compare>, a: &[u8], b: &[u8]) -> Ordering {
+    match order {
+        Some(compare) => compare(a, b),
+        None => a.cmp(b),
+    }
+}
+
+/// Returns true if `key` falls within `bounds` in the ordering `order`.
+pub(crate) fn contains(order: Option<Compare>, bounds: (Bound<&[u8]>, Bound<&[u8]>), key: &[u8]) -> bool {
+    let after_start = match bounds.0 {
+        Bound::Included(start) => compare(order, start, key).is_le(),
+        Bound::Excluded(start) => compare(order, start, key).is_lt(),
+        Bound::Unbounded => true,
+    };
+    let before_end = match bounds.1 {
+        Bound::Included(end) => compare(order, key, end).is_le(),
+        Bound::Excluded(end) => compare(order, key, end).is_lt(),
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+/// Returns true when no key can fall within `bounds` in the ordering `order`.
+/// `BTreeMap::range` panics on such bounds, while a scan over them should simply be empty.
+fn is_empty_range(order: Option<Compare>, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> bool {
+    match bounds {
+        (Bound::Included(start), Bound::Included(end)) => compare(order, start, end).is_gt(),
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => compare(order, start, end).is_ge(),
+        _ => false,
+    }
+}
+
+/// A key along with the ordering of the map holding it.
+#[derive(Clone)]
+pub(crate) struct Key {
+    bytes: Bytes,
+    order: Option<Compare>,
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare(self.order, &self.bytes, &other.bytes)
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl Eq for Key {}
+
+// Only used to look up keys in maps ordered bytewise, where it agrees with `Ord`.
+impl Borrow<[u8]> for Key {
+    fn borrow(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// A map from keys to `V` in the ordering given when it was created. Lookups in a map ordered
+/// by a comparator copy the key looked up.
+pub(crate) struct OrderedMap<V> {
+    order: Option<Compare>,
+    map: BTreeMap<Key, V>,
+}
+
+impl<V> OrderedMap<V> {
+    /// Returns an empty map ordering its keys with `order`, bytewise if it is `None`.
+    pub(crate) fn new(order: Option<Compare>) -> Self {
+        Self {
+            order,
+            map: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the ordering of the keys.
+    pub(crate) fn order(&self) -> Option<Compare> {
+        self.order
+    }
+
+    /// Compares `a` with `b` in the ordering of the map.
+    pub(crate) fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        compare(self.order, a, b)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&V> {
+        match self.order {
+            None => self.map.get(key),
+            Some(_) => self.map.get(&self.key(key)),
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, key: &[u8]) -> Option<&mut V> {
+        match self.order {
+            None => self.map.get_mut(key),
+            Some(_) => {
+                let key = self.key(key);
+                self.map.get_mut(&key)
+            }
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: Bytes, value: V) -> Option<V> {
+        let key = Key {
+            bytes: key,
+            order: self.order,
+        };
+        self.map.insert(key, value)
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) -> Option<V> {
+        match self.order {
+            None => self.map.remove(key),
+            Some(_) => self.map.remove(&self.key(key)),
+        }
+    }
+
+    /// Fills the map, which must be empty, with `entries` in one go, which is quicker than
+    /// inserting them one at a time when they are sorted already.
+    pub(crate) fn fill(&mut self, entries: impl IntoIterator<Item = (Bytes, V)>) {
+        debug_assert!(self.map.is_empty());
+        let order = self.order;
+        self.map = entries.into_iter().map(|(bytes, value)| (Key { bytes, order }, value)).collect();
+    }
+
+    /// Removes every key from `start` up to but excluding `end` and returns them in a map of
+    /// their own, where an empty `start` or `end` is unbounded.
+    pub(crate) fn remove_range(&mut self, start: &[u8], end: &[u8]) -> Self {
+        let mut removed = if start.is_empty() {
+            std::mem::take(&mut self.map)
+        } else {
+            self.map.split_off(&self.key(start))
+        };
+        if !end.is_empty() {
+            self.map.append(&mut removed.split_off(&self.key(end)));
+        }
+        Self {
+            order: self.order,
+            map: removed,
+        }
+    }
+
+    /// Returns the entries within `bounds` in order, of which there are none if the bounds
+    /// leave no room for any key.
+    pub(crate) fn range<'a>(
+        &'a self,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+    ) -> impl DoubleEndedIterator<Item = (&'a Bytes, &'a V)> + 'a {
+        let range = (!is_empty_range(self.order, bounds)).then(|| match self.order {
+            None => self.map.range::<[u8], _>(bounds),
+            Some(_) => self.map.range((bounds.0.map(|b| self.key(b)), bounds.1.map(|b| self.key(b)))),
+        });
+        range.into_iter().flatten().map(|(key, value)| (&key.bytes, value))
+    }
+
+    /// Returns the entries within `bounds` in order, with mutable values.
+    pub(crate) fn range_mut<'a>(
+        &'a mut self,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+    ) -> impl DoubleEndedIterator<Item = (&'a Bytes, &'a mut V)> + 'a {
+        let order = self.order;
+        let key = |bytes: &[u8]| Key {
+            bytes: Bytes::copy_from_slice(bytes),
+            order,
+        };
+        let range = (!is_empty_range(order, bounds)).then(|| match order {
+            None => self.map.range_mut::<[u8], _>(bounds),
+            Some(_) => self.map.range_mut((bounds.0.map(key), bounds.1.map(key))),
+        });
+        range.into_iter().flatten().map(|(key, value)| (&key.bytes, value))
+    }
+
+    pub(crate) fn iter(&self) -> impl DoubleEndedIterator<Item = (&Bytes, &V)> {
+        self.map.iter().map(|(key, value)| (&key.bytes, value))
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> impl DoubleEndedIterator<Item = (&Bytes, &mut V)> {
+        self.map.iter_mut().map(|(key, value)| (&key.bytes, value))
+    }
+
+    pub(crate) fn keys(&self) -> impl DoubleEndedIterator<Item = &Bytes> {
+        self.map.keys().map(|key| &key.bytes)
+    }
+
+    pub(crate) fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.map.values_mut()
+    }
+
+    /// Returns a map in the same ordering holding the values of this one turned by `f`.
+    pub(crate) fn map_values<W>(self, mut f: impl FnMut(&Bytes, V) -> W) -> OrderedMap<W> {
+        let map = self.map.into_iter().map(|(key, value)| {
+            let value = f(&key.bytes, value);
+            (key, value)
+        });
+        OrderedMap {
+            order: self.order,
+            map: map.collect(),
+        }
+    }
+
+    // Returns `bytes` as a key of the map.
+    fn key(&self, bytes: &[u8]) -> Key {
+        Key {
+            bytes: Bytes::copy_from_slice(bytes),
+            order: self.order,
+        }
+    }
+}
+
+impl<V> IntoIterator for OrderedMap<V> {
+    type Item = (Bytes, V);
+    type IntoIter = std::iter::Map<btree_map::IntoIter<Key, V>, fn((Key, V)) -> (Bytes, V)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.map.into_iter().map(|(key, value)| (key.bytes, value))
+    }
+}
@@ -0,0 +1,133 @@
+//! Write pipelines for bulk loads.
+//!
+//! A [`Pipeline`] buffers the sets made through it and submits them together: the write lock
+//! is taken once for the whole group, whose entries then reach the log writer back to back,
+//! and the log is flushed once at the end. This saves most of the per-write overhead when one
+//! task imports many keys.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::intercept::{self, Mutation};
+use crate::tree::Tree;
+
+/// Number of buffered key and value bytes after which a pipeline submits them by default.
+const DEFAULT_MAX_BYTES: usize = 1 << 20;
+/// Time after which a pipeline submits the writes it buffered by default.
+const DEFAULT_MAX_DELAY: Duration = Duration::from_millis(100);
+
+/// Buffers writes to a tree and submits them in groups; see [`Tree::pipeline`].
+///
+/// Buffered writes are submitted once they add up to [`max_bytes`](Self::max_bytes), once the
+/// oldest of them has waited for [`max_delay`](Self::max_delay), which is checked as further
+/// writes are made rather than by a timer, and by [`flush`](Self::flush). Until then they are
+/// not visible to reads. Dropping the pipeline submits what is left but ignores errors, so
+/// callers should flush it first.
+pub struct Pipeline {
+    tree: Tree,
+    pending: Vec<(Vec<u8>, Vec<u8>)>,
+    pending_bytes: usize,
+    // Time in milliseconds since the Unix epoch of the oldest buffered write.
+    oldest: Option<u64>,
+    max_bytes: usize,
+    max_delay: Duration,
+}
+
+impl Pipeline {
+    pub(crate) fn new(tree: Tree) -> Self {
+        Self {
+            tree,
+            pending: Vec::new(),
+            pending_bytes: 0,
+            oldest: None,
+            max_bytes: DEFAULT_MAX_BYTES,
+            max_delay: DEFAULT_MAX_DELAY,
+        }
+    }
+
+    /// Sets the number of buffered key and value bytes after which the writes are submitted,
+    /// 1 MiB by default. Zero submits every write on its own.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Sets how long the oldest buffered write may wait before the writes are submitted,
+    /// 100 milliseconds by default.
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Buffers a write of `value` to `key`, deleting the key if the value is empty, and submits
+    /// the buffered writes if they are due. Returns an error right away if the key or value
+    /// exceeds the size limits, or if submitting failed.
+    pub async fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let engine = &self.tree.engine;
+        engine.check_limits(key, &value)?;
+        let now = engine.now();
+        let oldest = *self.oldest.get_or_insert(now);
+        self.pending_bytes += key.len() + value.len();
+        self.pending.push((key.to_vec(), value));
+        let waited = Duration::from_millis(now.saturating_sub(oldest));
+        if self.pending_bytes >= self.max_bytes || waited >= self.max_delay {
+            self.submit()?;
+        }
+        Ok(())
+    }
+
+    /// Submits the buffered writes and waits until they have been handed to the operating
+    /// system, as [`Engine::flush`](crate::Engine::flush) does.
+    pub async fn flush(&mut self) -> Result<()> {
+        self.submit()
+    }
+
+    /// Returns the number of buffered writes not submitted yet.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Drops the buffered writes not submitted yet, such as when the writes being made are
+    /// abandoned.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.pending_bytes = 0;
+        self.oldest = None;
+    }
+
+    fn submit(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut self.pending);
+        self.pending_bytes = 0;
+        self.oldest = None;
+        let Tree { engine, keyspace } = &self.tree;
+        engine.write(|| {
+            for (key, value) in pending {
+                intercept::apply(engine, keyspace, Mutation::Set { key, value, expires_at: None })?;
+            }
+            Ok(())
+        })?;
+        engine.log.flush_and_wait();
+        engine.log.health()
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        let _ = self.submit();
+    }
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("tree", &self.tree)
+            .field("pending", &self.pending.len())
+            .field("max_bytes", &self.max_bytes)
+            .field("max_delay", &self.max_delay)
+            .finish()
+    }
+}
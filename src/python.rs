@@ -0,0 +1,233 @@
+//! Python bindings.
+//!
+//! With the `python` feature, the crate can be built by maturin into a `tegdb` extension module
+//! (see `pyproject.toml`), so that scripts can read and write databases shared with Rust
+//! programs:
+//!
+//! ```python
+//! import tegdb
+//!
+//! with tegdb.Engine("service.db", read_only=True) as db:
+//!     print(db.get(b"key"))
+//!     for key, value in db.scan(b"user:", b"user;"):
+//!         print(key, value)
+//! ```
+//!
+//! Writes made in a `with db.batch() as batch:` block are submitted together when the block
+//! ends, or dropped if it raises.
+//!
+//! Keys and values are `bytes`. Calls release the GIL while they wait for the engine, and
+//! errors are raised as `OSError` for I/O failures, `ValueError` for keys or values over the
+//! limits, and `tegdb.Error` otherwise.
+
+use std::ops::Bound as Limit;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use pyo3::exceptions::{PyException, PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::blocking;
+use crate::error::Error;
+use crate::options::EngineOptions;
+
+pyo3::create_exception!(tegdb, TegdbError, PyException, "Raised when an engine operation fails.");
+
+impl From<Error> for PyErr {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(_) | Error::NoSpace => PyIOError::new_err(e.to_string()),
+            Error::KeyTooLarge { .. } | Error::ValueTooLarge { .. } | Error::Rejected(_) => {
+                PyValueError::new_err(e.to_string())
+            }
+            e => TegdbError::new_err(e.to_string()),
+        }
+    }
+}
+
+/// A tree of a database, exposed to Python as `tegdb.Tree`.
+#[pyclass(name = "Tree", module = "tegdb", subclass)]
+pub struct PyTree {
+    tree: blocking::Tree,
+}
+
+#[pymethods]
+impl PyTree {
+    /// Returns the value of `key`, or `None` if it does not exist.
+    fn get<'py>(&self, py: Python<'py>, key: &[u8]) -> PyResult<Option<Bound<'py, PyBytes>>> {
+        let value = py.allow_threads(|| self.tree.get(key))?;
+        Ok(value.map(|value| PyBytes::new(py, &value)))
+    }
+
+    /// Sets `key` to `value`.
+    fn set(&self, py: Python<'_>, key: &[u8], value: &[u8]) -> PyResult<()> {
+        Ok(py.allow_threads(|| self.tree.set(key, value.to_vec()))?)
+    }
+
+    /// Deletes `key` if it exists.
+    fn delete(&self, py: Python<'_>, key: &[u8]) -> PyResult<()> {
+        Ok(py.allow_threads(|| self.tree.del(key))?)
+    }
+
+    /// Returns the pairs with keys from `start` up to but excluding `end` in key order, as a
+    /// list of `(key, value)` tuples. Either bound may be left out.
+    #[pyo3(signature = (start=None, end=None))]
+    fn scan<'py>(
+        &self,
+        py: Python<'py>,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> PyResult<Vec<(Bound<'py, PyBytes>, Bound<'py, PyBytes>)>> {
+        let start = start.map_or(Limit::Unbounded, |start| Limit::Included(start.to_vec()));
+        let end = end.map_or(Limit::Unbounded, |end| Limit::Excluded(end.to_vec()));
+        let pairs = py.allow_threads(|| self.tree.scan((start, end)))?;
+        Ok(pairs.map(|(key, value)| (PyBytes::new(py, &key), PyBytes::new(py, &value))).collect())
+    }
+
+    /// Returns the pairs whose keys start with `prefix` in key order.
+    fn scan_prefix<'py>(
+        &self,
+        py: Python<'py>,
+        prefix: &[u8],
+    ) -> PyResult<Vec<(Bound<'py, PyBytes>, Bound<'py, PyBytes>)>> {
+        let pairs = py.allow_threads(|| self.tree.scan_prefix(prefix))?;
+        Ok(pairs.map(|(key, value)| (PyBytes::new(py, &key), PyBytes::new(py, &value))).collect())
+    }
+
+    /// Returns a batch of writes to the tree, to be used as a context manager.
+    fn batch(&self) -> PyBatch {
+        let pipeline = self.tree.pipeline().max_bytes(usize::MAX).max_delay(Duration::MAX);
+        PyBatch { pipeline }
+    }
+
+    fn __contains__(&self, py: Python<'_>, key: &[u8]) -> PyResult<bool> {
+        Ok(py.allow_threads(|| self.tree.contains_key(key))?)
+    }
+
+    fn __len__(&self) -> usize {
+        self.tree.len()
+    }
+}
+
+/// Writes to a tree buffered until the `with` block using them ends, exposed to Python as
+/// `tegdb.Batch`. They are submitted together, taking the write lock once, unless the block
+/// raises, in which case they are dropped, as they are if the batch is not used in a block.
+#[pyclass(name = "Batch", module = "tegdb")]
+pub struct PyBatch {
+    pipeline: blocking::Pipeline,
+}
+
+#[pymethods]
+impl PyBatch {
+    /// Adds setting `key` to `value` to the batch.
+    fn set(&mut self, py: Python<'_>, key: &[u8], value: &[u8]) -> PyResult<()> {
+        Ok(py.allow_threads(|| self.pipeline.set(key, value.to_vec()))?)
+    }
+
+    /// Adds deleting `key` to the batch.
+    fn delete(&mut self, py: Python<'_>, key: &[u8]) -> PyResult<()> {
+        Ok(py.allow_threads(|| self.pipeline.set(key, Vec::new()))?)
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __exit__(
+        &mut self,
+        py: Python<'_>,
+        exc_type: &Bound<'_, PyAny>,
+        _exc_value: &Bound<'_, PyAny>,
+        _traceback: &Bound<'_, PyAny>,
+    ) -> PyResult<bool> {
+        if exc_type.is_none() {
+            py.allow_threads(|| self.pipeline.flush())?;
+        } else {
+            self.pipeline.clear();
+        }
+        Ok(false)
+    }
+}
+
+impl Drop for PyBatch {
+    fn drop(&mut self) {
+        self.pipeline.clear();
+    }
+}
+
+/// A database, exposed to Python as `tegdb.Engine`. It is also its default tree, and closes
+/// the database when used as a context manager.
+#[pyclass(name = "Engine", module = "tegdb", extends = PyTree)]
+pub struct PyEngine {
+    engine: blocking::Engine,
+}
+
+#[pymethods]
+impl PyEngine {
+    /// Opens the database stored in the directory at `path`, creating it unless `read_only` is
+    /// set. A database opened read-only can be read while another process has it open.
+    #[new]
+    #[pyo3(signature = (path, *, read_only=false, sync_writes=false))]
+    fn new(py: Python<'_>, path: PathBuf, read_only: bool, sync_writes: bool) -> PyResult<(Self, PyTree)> {
+        let options = EngineOptions {
+            read_only,
+            sync_writes,
+            ..EngineOptions::default()
+        };
+        let engine = py.allow_threads(|| blocking::Engine::open_with_options(path, options))?;
+        let tree = PyTree { tree: (*engine).clone() };
+        Ok((Self { engine }, tree))
+    }
+
+    /// Opens the tree called `name`, creating it if it does not exist yet.
+    fn open_tree(&self, py: Python<'_>, name: &str) -> PyResult<PyTree> {
+        let tree = py.allow_threads(|| self.engine.open_tree(name))?;
+        Ok(PyTree { tree })
+    }
+
+    /// Returns the names of the trees opened with `open_tree`.
+    fn tree_names(&self) -> Vec<String> {
+        self.engine.tree_names()
+    }
+
+    /// Waits until every write made so far has been handed to the operating system.
+    fn flush(&self, py: Python<'_>) -> PyResult<()> {
+        Ok(py.allow_threads(|| self.engine.flush())?)
+    }
+
+    /// Rewrites the log so it only contains live entries, returning the bytes reclaimed.
+    fn compact(&self, py: Python<'_>) -> PyResult<u64> {
+        Ok(py.allow_threads(|| self.engine.compact())?)
+    }
+
+    /// Closes the database; further writes raise `tegdb.Error`.
+    fn close(&self, py: Python<'_>) -> PyResult<()> {
+        Ok(py.allow_threads(|| self.engine.close())?)
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __exit__(
+        &self,
+        py: Python<'_>,
+        _exc_type: &Bound<'_, PyAny>,
+        _exc_value: &Bound<'_, PyAny>,
+        _traceback: &Bound<'_, PyAny>,
+    ) -> PyResult<bool> {
+        self.close(py)?;
+        Ok(false)
+    }
+}
+
+/// The `tegdb` Python module.
+#[pymodule]
+pub fn tegdb(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyTree>()?;
+    m.add_class::<PyEngine>()?;
+    m.add_class::<PyBatch>()?;
+    m.add("Error", m.py().get_type::<TegdbError>())?;
+    Ok(())
+}
@@ -0,0 +1,458 @@
+//! Asynchronous primary-replica replication over TCP.
+//!
+//! A [`Primary`] serves its engine's changes to any number of [`Replica`]s, each of which applies
+//! them to an engine of its own. On connecting, a replica sends the sequence number of the last
+//! change it applied. The primary replays the changes made since then from its log, or sends a
+//! snapshot of every tree when they are no longer all there, and then streams each change as it
+//! is committed. Writes on the primary never wait for replicas.
+//!
+//! Trees are replicated by id along with the internal tree holding their names, so a replica
+//! mirrors the primary's trees exactly.
+//!
+//! Every message starts with a tag byte. The replica opens the connection with the line
+//! `tegdb replica 1` followed by its sequence number. A snapshot starts with its sequence number
+//! and holds an entry per key, and changes carry their own sequence number. Trees are `u32` ids,
+//! keys and values are prefixed with their length as a `u32`, expiration times are milliseconds
+//! since the Unix epoch as a `u64` or 0 for none, and all integers are big-endian.
+
+use std::fmt;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::changes::{self, Change};
+use crate::checkpoint;
+use crate::engine::{Engine, Inner};
+use crate::error::{Error, Result};
+use crate::tree::HISTORY_TREE;
+
+const HANDSHAKE: &[u8] = b"tegdb replica 1\n";
+const SNAPSHOT_TAG: u8 = 1;
+const ENTRY_TAG: u8 = 2;
+const SNAPSHOT_END_TAG: u8 = 3;
+const SET_TAG: u8 = 4;
+const DEL_TAG: u8 = 5;
+const DEL_RANGE_TAG: u8 = 6;
+const HEARTBEAT_TAG: u8 = 7;
+
+// The primary sends a heartbeat whenever it has had nothing to send for this long, so that
+// either side can tell a silent connection from a dead one.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+// A connection that has been silent for this long is considered dead.
+const TIMEOUT: Duration = Duration::from_secs(5);
+// How long a replica waits before reconnecting to its primary, and the primary before accepting
+// connections again after failing to.
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Serves the changes of an engine to replicas connecting over TCP. The engine is kept open
+/// until the primary is dropped, which stops listening and disconnects every replica. With
+/// the `tracing` feature, failed connections are reported as warnings.
+///
+/// A replica that reconnects catches up from the primary's log, unless compaction has since
+/// dropped deletions it has not seen or the primary has been restarted in the meantime; it is
+/// then sent a full snapshot instead.
+pub struct Primary {
+    local_addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl Primary {
+    /// Starts serving the changes of `engine` to replicas connecting to `addr`.
+    pub fn start(engine: &Engine, addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let engine = engine.clone();
+        let stop = stopped.clone();
+        let listener = thread::spawn(move || accept(&engine, listener, &stop));
+        Ok(Self {
+            local_addr,
+            stopped,
+            listener: Some(listener),
+        })
+    }
+
+    /// Returns the address the primary is listening on, such as the port picked by the
+    /// system when it was started on port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for Primary {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // Connecting wakes the listener from accepting, after which it sees it has been stopped.
+        // Should that fail, the listener is left to stop on the next connection it accepts
+        // rather than waited for.
+        let mut addr = self.local_addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        if TcpStream::connect_timeout(&addr, TIMEOUT).is_err() {
+            return;
+        }
+        // Waiting for the listener to close frees the address for reuse.
+        if let Some(listener) = self.listener.take() {
+            let _ = listener.join();
+        }
+    }
+}
+
+fn accept(engine: &Engine, listener: TcpListener, stopped: &Arc<AtomicBool>) {
+    loop {
+        let accepted = listener.accept();
+        if stopped.load(Ordering::Relaxed) {
+            break;
+        }
+        match accepted {
+            Ok((stream, addr)) => {
+                let engine = engine.clone();
+                let stopped = stopped.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(&engine.engine, stream, &stopped) {
+                        warn(&format!("replication to {} failed", addr), &e);
+                    }
+                });
+            }
+            Err(e) => {
+                warn("failed to accept a replica", &e);
+                thread::sleep(RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+// Brings the replica connected over `stream` up to date and then streams every change to it.
+fn serve(engine: &Inner, stream: TcpStream, stopped: &AtomicBool) -> Result<()> {
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    // A replica that stops reading would otherwise block the connection for good.
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut handshake = [0; HANDSHAKE.len()];
+    reader.read_exact(&mut handshake)?;
+    if handshake != HANDSHAKE {
+        return Err(Error::Corrupted("unrecognized replication handshake".to_string()));
+    }
+    let applied = read_u64(&mut reader)?;
+    let mut writer = BufWriter::new(stream);
+    let feed = match catch_up(engine, applied)? {
+        Some((feed, backlog)) => {
+            for (tree, change) in &backlog {
+                write_change(&mut writer, *tree, change)?;
+            }
+            feed
+        }
+        None => send_snapshot(engine, &mut writer)?,
+    };
+    writer.flush()?;
+    while !stopped.load(Ordering::Relaxed) {
+        match feed.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok((tree, change)) => {
+                write_change(&mut writer, tree, &change)?;
+                for (tree, change) in feed.try_iter() {
+                    write_change(&mut writer, tree, &change)?;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => writer.write_all(&[HEARTBEAT_TAG])?,
+            // The feed drops subscribers that fall too far behind. Closing the connection makes
+            // the replica reconnect and catch up from the log or a snapshot.
+            Err(RecvTimeoutError::Disconnected) => {
+                writer.flush()?;
+                return Err(std::io::Error::other("replica fell too far behind").into());
+            }
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+// Changes committed to the primary, along with their tree.
+type Changes = Vec<(u32, Change)>;
+type Subscription = Receiver<(u32, Change)>;
+
+// Subscribes to the feed and returns the changes made after `applied` that were committed
+// before subscribing, or `None` if they cannot all be replayed from the log.
+fn catch_up(engine: &Inner, applied: u64) -> Result<Option<(Subscription, Changes)>> {
+    if applied == 0 {
+        return Ok(None);
+    }
+    engine.replayed()?;
+    let (feed, last) = {
+        let _guard = engine.write_lock.lock().unwrap();
+        (engine.feed.subscribe(), engine.log.last_sequence())
+    };
+    // A replica ahead of the primary holds changes the primary has lost.
+    if applied > last {
+        return Ok(None);
+    }
+    let mut backlog = changes::changes_since(engine, None, applied)?;
+    // Compaction cannot have run while the changes were read, but may have run before.
+    if engine.log.history_start() > applied {
+        return Ok(None);
+    }
+    // Later changes are delivered through the feed.
+    backlog.retain(|(_, change)| change.sequence() <= last);
+    Ok(Some((feed, backlog)))
+}
+
+// Sends a snapshot of every tree, returning the feed of the changes committed after it.
+fn send_snapshot(engine: &Inner, writer: &mut impl Write) -> Result<Subscription> {
+    engine.replayed()?;
+    // The segments holding the captured entries are kept open rather than kept from being
+    // compacted, so that a slow replica does not hold up compaction.
+    let (feed, last, trees, pinned) = {
+        let _compacting = engine.compaction_lock.lock().unwrap();
+        let _guard = engine.write_lock.lock().unwrap();
+        let pinned = engine.log.pin()?;
+        (engine.feed.subscribe(), engine.log.last_sequence(), checkpoint::capture(engine), pinned)
+    };
+    // Entries must be fully written before they can be read back.
+    engine.log.flush_and_wait();
+    writer.write_all(&[SNAPSHOT_TAG])?;
+    writer.write_all(&last.to_be_bytes())?;
+    for (tree, entries) in trees {
+        for entry in entries {
+            let Some(value) = entry.value(engine, tree, Some(&pinned))? else {
+                continue;
+            };
+            writer.write_all(&[ENTRY_TAG])?;
+            writer.write_all(&tree.to_be_bytes())?;
+            write_bytes(writer, &entry.key)?;
+            write_bytes(writer, &value)?;
+            writer.write_all(&entry.expires_at.unwrap_or(0).to_be_bytes())?;
+        }
+    }
+    writer.write_all(&[SNAPSHOT_END_TAG])?;
+    Ok(feed)
+}
+
+fn write_change(writer: &mut impl Write, tree: u32, change: &Change) -> Result<()> {
+    let tag = match change {
+        Change::Set { .. } => SET_TAG,
+        Change::Del { .. } => DEL_TAG,
+        Change::DelRange { .. } => DEL_RANGE_TAG,
+    };
+    writer.write_all(&[tag])?;
+    writer.write_all(&change.sequence().to_be_bytes())?;
+    writer.write_all(&tree.to_be_bytes())?;
+    match change {
+        Change::Set {
+            key,
+            value,
+            expires_at,
+            ..
+        } => {
+            write_bytes(writer, key)?;
+            write_bytes(writer, value)?;
+            writer.write_all(&expires_at.unwrap_or(0).to_be_bytes())?;
+        }
+        Change::Del { key, .. } => write_bytes(writer, key)?,
+        Change::DelRange { start, end, .. } => {
+            write_bytes(writer, start)?;
+            write_bytes(writer, end.as_deref().unwrap_or_default())?;
+        }
+    }
+    Ok(())
+}
+
+/// Keeps an engine up to date with the changes of a [`Primary`], reconnecting whenever the
+/// connection is lost, which is reported as a warning with the `tracing` feature. Dropping the
+/// replica closes its connection and waits for it to stop applying changes, after which the
+/// engine can be closed and reopened right away.
+///
+/// The engine should not be written to other than by the replica, since such writes would be
+/// overwritten or lost whenever a snapshot is applied. While a snapshot is being applied, the
+/// engine is emptied and refilled, so reads may miss keys. A replica that is restarted starts
+/// over with a snapshot.
+pub struct Replica {
+    applied: Arc<AtomicU64>,
+    stopped: Arc<AtomicBool>,
+    // The connection to the primary, if any, which is shut down to stop the replica.
+    connection: Arc<Mutex<Option<TcpStream>>>,
+    // Dropping this sender wakes the replica while it waits to reconnect.
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Replica {
+    /// Starts replicating the primary at `primary` into `engine`, whose size limits must be at
+    /// least as high as the primary's.
+    pub fn start(engine: &Engine, primary: impl ToSocketAddrs) -> Result<Self> {
+        let addr = primary.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address for primary")
+        })?;
+        let applied = Arc::new(AtomicU64::new(0));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let connection = Arc::new(Mutex::new(None));
+        let (stop, wait) = mpsc::channel::<()>();
+        let engine = engine.clone();
+        let (sequence, halted, current) = (applied.clone(), stopped.clone(), connection.clone());
+        let thread = thread::spawn(move || loop {
+            if let Ok(stream) = TcpStream::connect_timeout(&addr, TIMEOUT) {
+                // Checked under the lock, so that the stream is either seen by `drop` or not used.
+                let registered = {
+                    let mut current = current.lock().unwrap();
+                    let registered = !halted.load(Ordering::Relaxed);
+                    if registered {
+                        *current = stream.try_clone().ok();
+                    }
+                    registered
+                };
+                if registered {
+                    let followed = follow(&engine.engine, stream, &sequence, &halted);
+                    current.lock().unwrap().take();
+                    if let Err(e) = followed {
+                        if !halted.load(Ordering::Relaxed) {
+                            warn(&format!("replication from {} failed", addr), &e);
+                        }
+                    }
+                }
+            }
+            if let Err(RecvTimeoutError::Disconnected) = wait.recv_timeout(RETRY_INTERVAL) {
+                break;
+            }
+        });
+        Ok(Self {
+            applied,
+            stopped,
+            connection,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the primary's sequence number of the last change applied to the engine, or 0
+    /// until a first snapshot has been applied.
+    pub fn applied_sequence(&self) -> u64 {
+        self.applied.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for Replica {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        self.stop.take();
+        if let Some(stream) = self.connection.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        // The thread holds a handle to the engine, which stays open until it is gone.
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Applies everything the primary sends over `stream` until the connection is lost.
+fn follow(engine: &Inner, stream: TcpStream, applied: &AtomicU64, stopped: &AtomicBool) -> Result<()> {
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    writer.write_all(HANDSHAKE)?;
+    writer.write_all(&applied.load(Ordering::Acquire).to_be_bytes())?;
+    writer.flush()?;
+    let mut reader = BufReader::new(stream);
+    let (key_limit, value_limit) = engine.limits();
+    let mut snapshot = None;
+    while !stopped.load(Ordering::Relaxed) {
+        let mut tag = [0; 1];
+        reader.read_exact(&mut tag)?;
+        match tag[0] {
+            SNAPSHOT_TAG => {
+                applied.store(0, Ordering::Release);
+                snapshot = Some(read_u64(&mut reader)?);
+                // Only the trees holding keys are emptied: the chunks and versions of their keys
+                // go along with them, and the names of trees are overwritten by the snapshot's.
+                engine.write(|| {
+                    for ks in engine.keyspaces().iter().filter(|ks| ks.id < HISTORY_TREE) {
+                        engine.delete_range(ks, &[], &[])?;
+                    }
+                    Ok(())
+                })?;
+            }
+            ENTRY_TAG => {
+                let tree = read_u32(&mut reader)?;
+                let key = read_bytes(&mut reader, key_limit)?;
+                let value = read_bytes(&mut reader, value_limit)?;
+                let expires_at = Some(read_u64(&mut reader)?).filter(|&t| t != 0);
+                let ks = engine.keyspace(tree);
+                engine.write(|| engine.set(&ks, &key, value, expires_at))?;
+            }
+            SNAPSHOT_END_TAG => {
+                let sequence = snapshot.take().ok_or_else(|| {
+                    Error::Corrupted("snapshot end without a snapshot".to_string())
+                })?;
+                applied.store(sequence, Ordering::Release);
+            }
+            SET_TAG | DEL_TAG | DEL_RANGE_TAG => {
+                let sequence = read_u64(&mut reader)?;
+                let ks = engine.keyspace(read_u32(&mut reader)?);
+                let key = read_bytes(&mut reader, key_limit)?;
+                match tag[0] {
+                    SET_TAG => {
+                        let value = read_bytes(&mut reader, value_limit)?;
+                        let expires_at = Some(read_u64(&mut reader)?).filter(|&t| t != 0);
+                        engine.write(|| engine.set(&ks, &key, value, expires_at))?;
+                    }
+                    DEL_TAG => engine.write(|| engine.del(&ks, &key))?,
+                    _ => {
+                        let end = read_bytes(&mut reader, key_limit)?;
+                        engine.write(|| engine.delete_range(&ks, &key, &end))?;
+                    }
+                }
+                applied.store(sequence, Ordering::Release);
+            }
+            HEARTBEAT_TAG => {}
+            tag => return Err(Error::Corrupted(format!("unknown replication message: {}", tag))),
+        }
+    }
+    Ok(())
+}
+
+// Reports a failed connection, as a warning with the `tracing` feature.
+fn warn(message: &str, error: &dyn fmt::Display) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(error = %error, "{}", message);
+    #[cfg(not(feature = "tracing"))]
+    let _ = (message, error);
+}
+
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_bytes(reader: &mut impl Read, limit: usize) -> Result<Vec<u8>> {
+    let len = read_u32(reader)? as usize;
+    // Guards the allocation against a corrupted length; no acceptable key or value is this large.
+    if len > limit {
+        let message = format!("length {} in replication message exceeds the limit of {}", len, limit);
+        return Err(Error::Corrupted(message));
+    }
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
@@ -0,0 +1,175 @@
+//! Iterators over the contents of a tree.
+//!
+//! [`Pairs`] and [`Keys`] hold the results of a scan that were collected up front, while
+//! [`Iter`] and the stream returned by [`Tree::scan_stream`] walk a tree's key map one key at a
+//! time.
+
+use std::iter::FusedIterator;
+use std::ops::{Bound, RangeBounds};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::vec;
+
+use bytes::Bytes;
+use futures_core::Stream;
+
+use crate::error::Result;
+use crate::tree::Tree;
+
+/// Iterator over the key-value pairs returned by [`Tree::scan`] and [`Tree::scan_prefix`], in
+/// key order. The pairs are collected when the scan is made, so it knows how many remain and
+/// can also be walked from the end.
+#[derive(Debug, Clone, Default)]
+pub struct Pairs {
+    pairs: vec::IntoIter<(Bytes, Bytes)>,
+}
+
+impl Pairs {
+    pub(crate) fn new(pairs: Vec<(Bytes, Bytes)>) -> Self {
+        Self {
+            pairs: pairs.into_iter(),
+        }
+    }
+}
+
+impl Iterator for Pairs {
+    type Item = (Bytes, Bytes);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.pairs.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.pairs.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for Pairs {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.pairs.next_back()
+    }
+}
+
+impl ExactSizeIterator for Pairs {}
+
+impl FusedIterator for Pairs {}
+
+/// Iterator over the keys returned by [`Tree::keys`], in key order.
+#[derive(Debug, Clone, Default)]
+pub struct Keys {
+    keys: vec::IntoIter<Bytes>,
+}
+
+impl Keys {
+    pub(crate) fn new(keys: Vec<Bytes>) -> Self {
+        Self {
+            keys: keys.into_iter(),
+        }
+    }
+}
+
+impl Iterator for Keys {
+    type Item = Bytes;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.keys.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.keys.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for Keys {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.keys.next_back()
+    }
+}
+
+impl ExactSizeIterator for Keys {}
+
+impl FusedIterator for Keys {}
+
+/// A page of key-value pairs in key order returned by [`Tree::scan_page`], along with the
+/// continuation token for the next page, if pairs are left.
+pub type Page = (Vec<(Bytes, Bytes)>, Option<Vec<u8>>);
+
+/// Iterator over the key-value pairs of a tree in key order, returned by iterating over a
+/// [`Tree`] or an [`Engine`](crate::Engine) by reference. Keys and values are fetched lazily,
+/// from either end, so neither the keys nor the values are collected up front. Keys written
+/// after the iterator was created are seen if it has not moved past them yet.
+#[derive(Clone)]
+pub struct Iter {
+    tree: Tree,
+    // Bounds of the keys left to yield; each moves past a key once it has been returned.
+    start: Bound<Bytes>,
+    end: Bound<Bytes>,
+}
+
+impl Iter {
+    pub(crate) fn new(tree: Tree, range: &impl RangeBounds<Vec<u8>>) -> Self {
+        let owned = |bound: Bound<&Vec<u8>>| bound.map(|key| Bytes::copy_from_slice(key));
+        Self {
+            tree,
+            start: owned(range.start_bound()),
+            end: owned(range.end_bound()),
+        }
+    }
+
+    // Returns the first key left to yield, or the last one if `back` is set.
+    fn next_key(&self, back: bool) -> Option<Bytes> {
+        let bounds = (
+            self.start.as_ref().map(Bytes::as_ref),
+            self.end.as_ref().map(Bytes::as_ref),
+        );
+        let key_map = self.tree.keyspace.key_map.read().unwrap();
+        let mut range = key_map.range(bounds);
+        let (key, _) = if back { range.next_back()? } else { range.next()? };
+        Some(key.clone())
+    }
+
+    fn advance(&mut self, back: bool) -> Option<Result<(Bytes, Bytes)>> {
+        if let Err(e) = self.tree.engine.replayed() {
+            return Some(Err(e));
+        }
+        while let Some(key) = self.next_key(back) {
+            if back {
+                self.end = Bound::Excluded(key.clone());
+            } else {
+                self.start = Bound::Excluded(key.clone());
+            }
+            // Keys deleted since they were found are skipped.
+            match self.tree.engine.get(&self.tree.keyspace, &key) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for Iter {
+    type Item = Result<(Bytes, Bytes)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.advance(false)
+    }
+}
+
+impl DoubleEndedIterator for Iter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.advance(true)
+    }
+}
+
+/// Stream of key-value pairs returned by [`Tree::scan_stream`], yielding what an [`Iter`] would.
+pub(crate) struct ScanStream(pub(crate) Iter);
+
+impl Stream for ScanStream {
+    type Item = Result<(Bytes, Bytes)>;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.get_mut().0.next())
+    }
+}
@@ -0,0 +1,145 @@
+//! Deterministic simulation for tests.
+//!
+//! With the `sim` feature, engines opened with a [`Simulation`] in
+//! [`EngineOptions::simulation`](crate::EngineOptions::simulation) read the time from its
+//! virtual clock instead of the system clock, and leave their background compaction, expiry
+//! and index snapshots to [`Simulation::advance`], which runs them on the calling thread once
+//! they are due. Expiry and compaction then happen at points chosen by the test rather than by
+//! a timer. Writes to log segments go through the simulation, which can make them fail or tear
+//! them, leaving part of a batch in the file as a crash in the middle of a write would, so
+//! tests can check what survives reopening the database afterwards.
+
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use crate::compaction::Chores;
+use crate::engine::Inner;
+
+/// A virtual clock and fault injector shared by the engines opened with it.
+#[derive(Clone, Default)]
+pub struct Simulation(Arc<State>);
+
+#[derive(Default)]
+struct State {
+    // Virtual time in milliseconds since the Unix epoch.
+    now: AtomicU64,
+    faults: Mutex<Faults>,
+    timers: Mutex<Vec<Timer>>,
+}
+
+#[derive(Default)]
+struct Faults {
+    // Number of upcoming writes that fail without writing anything.
+    failing: u64,
+    // Bytes kept of the next write, which then fails.
+    tear: Option<usize>,
+}
+
+// The background work of an engine, run by `advance` once due.
+struct Timer {
+    engine: Weak<Inner>,
+    chores: Chores,
+    interval: u64,
+    due: u64,
+}
+
+/// What happens to a write to a log segment.
+pub(crate) enum Fault {
+    /// The write fails without writing anything.
+    Fail,
+    /// Only the given number of bytes are written, and the write fails.
+    Tear(usize),
+}
+
+impl Simulation {
+    /// Returns a simulation whose clock starts at the Unix epoch and that injects no faults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a simulation whose clock starts at `start` after the Unix epoch.
+    pub fn starting_at(start: Duration) -> Self {
+        let simulation = Self::new();
+        simulation.0.now.store(start.as_millis() as u64, Ordering::SeqCst);
+        simulation
+    }
+
+    /// Returns the virtual time since the Unix epoch.
+    pub fn now(&self) -> Duration {
+        Duration::from_millis(self.now_millis())
+    }
+
+    /// Moves the clock forward by `by`, then runs the background work of every engine opened
+    /// with the simulation whose interval has elapsed since it last ran. Work that fell due
+    /// several times is run once.
+    pub fn advance(&self, by: Duration) {
+        let now = self.0.now.fetch_add(by.as_millis() as u64, Ordering::SeqCst) + by.as_millis() as u64;
+        // The lock is not held while the work runs, so compaction hooks can use the simulation.
+        let mut timers = std::mem::take(&mut *self.0.timers.lock().unwrap());
+        timers.retain_mut(|timer| {
+            let Some(engine) = timer.engine.upgrade().filter(|engine| !engine.log.is_closed()) else {
+                return false;
+            };
+            if timer.due <= now {
+                timer.due = now + timer.interval;
+                timer.chores.run(&engine);
+            }
+            true
+        });
+        let mut registered = self.0.timers.lock().unwrap();
+        let added = std::mem::replace(&mut *registered, timers);
+        registered.extend(added);
+    }
+
+    /// Makes the next `count` writes to log segments fail without writing anything. The log
+    /// writer records the first failure, which later writes and flushes report.
+    pub fn fail_writes(&self, count: u64) {
+        self.0.faults.lock().unwrap().failing = count;
+    }
+
+    /// Makes the next write to a log segment write only its first `keep` bytes and then fail,
+    /// like a crash in the middle of the write. Writes failing through
+    /// [`fail_writes`](Self::fail_writes) are counted first.
+    pub fn tear_next_write(&self, keep: usize) {
+        self.0.faults.lock().unwrap().tear = Some(keep);
+    }
+
+    pub(crate) fn now_millis(&self) -> u64 {
+        self.0.now.load(Ordering::SeqCst)
+    }
+
+    /// Leaves the background work of `engine` to `advance`, first due after `interval`.
+    pub(crate) fn schedule(&self, engine: Weak<Inner>, chores: Chores, interval: Duration) {
+        let interval = interval.as_millis() as u64;
+        self.0.timers.lock().unwrap().push(Timer {
+            engine,
+            chores,
+            interval,
+            due: self.now_millis() + interval,
+        });
+    }
+
+    /// Returns the fault to inject into the next write, if any.
+    pub(crate) fn next_write(&self) -> Option<Fault> {
+        let mut faults = self.0.faults.lock().unwrap();
+        if faults.failing > 0 {
+            faults.failing -= 1;
+            return Some(Fault::Fail);
+        }
+        faults.tear.take().map(Fault::Tear)
+    }
+}
+
+impl fmt::Debug for Simulation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Simulation").field("now", &self.now()).finish_non_exhaustive()
+    }
+}
+
+/// The error returned by writes the simulation makes fail.
+pub(crate) fn write_error() -> io::Error {
+    io::Error::other("simulated write failure")
+}
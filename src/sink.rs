@@ -0,0 +1,139 @@
+//! The files the log writer thread appends to.
+//!
+//! Segments are written through a buffer by default. With
+//! [`EngineOptions::direct_io`](crate::EngineOptions::direct_io) on Linux they bypass the page
+//! cache instead, and with the `io-uring` feature on Linux appends are submitted through
+//! io_uring when the kernel supports it. Under a [`Simulation`](crate::Simulation), writes can
+//! be made to fail or be torn.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+#[cfg(target_os = "linux")]
+use crate::direct::DirectFile;
+use crate::options::EngineOptions;
+#[cfg(feature = "sim")]
+use crate::sim::{self, Fault, Simulation};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::Ring;
+
+/// How segment files are written.
+#[derive(Clone)]
+pub(crate) struct SinkOptions {
+    /// Whether direct I/O is used where the platform supports it.
+    pub(crate) direct_io: bool,
+    /// The simulation injecting faults into writes, if any.
+    #[cfg(feature = "sim")]
+    pub(crate) simulation: Option<Simulation>,
+}
+
+impl SinkOptions {
+    pub(crate) fn new(options: &EngineOptions) -> Self {
+        Self {
+            direct_io: options.direct_io,
+            #[cfg(feature = "sim")]
+            simulation: options.simulation.clone(),
+        }
+    }
+}
+
+/// The segment file the log writer thread appends to.
+pub(crate) struct Sink(Backend);
+
+enum Backend {
+    #[cfg(feature = "sim")]
+    Simulated(Box<Sink>, Simulation),
+    File(BufWriter<File>),
+    #[cfg(target_os = "linux")]
+    Direct(DirectFile),
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    Ring(Box<Ring>),
+}
+
+impl Sink {
+    /// Opens the segment file at `path` for appending, creating it if needed. Direct I/O is
+    /// only used if enabled in `options` and supported by the platform, in which case it takes
+    /// precedence over io_uring.
+    pub(crate) fn open(path: &Path, options: &SinkOptions) -> io::Result<Self> {
+        #[cfg(feature = "sim")]
+        if let Some(simulation) = &options.simulation {
+            let options = SinkOptions {
+                simulation: None,
+                ..options.clone()
+            };
+            let sink = Self::open(path, &options)?;
+            return Ok(Self(Backend::Simulated(Box::new(sink), simulation.clone())));
+        }
+        #[cfg(target_os = "linux")]
+        if options.direct_io {
+            return Ok(Self(Backend::Direct(DirectFile::open(path)?)));
+        }
+        let file = File::options().append(true).create(true).open(path)?;
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        let file = match Ring::new(file) {
+            Ok(ring) => return Ok(Self(Backend::Ring(Box::new(ring)))),
+            Err(file) => file,
+        };
+        Ok(Self(Backend::File(BufWriter::new(file))))
+    }
+
+    /// Hands the entries in `batch` over to be written, leaving it empty.
+    pub(crate) fn write_batch(&mut self, batch: &mut Vec<u8>) -> io::Result<()> {
+        match &mut self.0 {
+            #[cfg(feature = "sim")]
+            Backend::Simulated(sink, simulation) => match simulation.next_write() {
+                None => sink.write_batch(batch),
+                Some(Fault::Fail) => {
+                    batch.clear();
+                    Err(sim::write_error())
+                }
+                Some(Fault::Tear(keep)) => {
+                    batch.truncate(keep);
+                    sink.write_batch(batch)?;
+                    sink.flush()?;
+                    Err(sim::write_error())
+                }
+            },
+            Backend::File(writer) => {
+                let written = writer.write_all(batch);
+                batch.clear();
+                written
+            }
+            #[cfg(target_os = "linux")]
+            Backend::Direct(file) => file.write(batch),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Backend::Ring(ring) => ring.write(std::mem::take(batch)),
+        }
+    }
+
+    /// Waits until every batch handed over so far has been written to the file.
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        match &mut self.0 {
+            #[cfg(feature = "sim")]
+            Backend::Simulated(sink, _) => sink.flush(),
+            Backend::File(writer) => writer.flush(),
+            #[cfg(target_os = "linux")]
+            Backend::Direct(_) => Ok(()),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Backend::Ring(ring) => ring.flush(),
+        }
+    }
+
+    /// Waits until every batch handed over so far is durable on disk.
+    pub(crate) fn sync_data(&mut self) -> io::Result<()> {
+        match &mut self.0 {
+            #[cfg(feature = "sim")]
+            Backend::Simulated(sink, _) => sink.sync_data(),
+            Backend::File(writer) => {
+                writer.flush()?;
+                writer.get_ref().sync_data()
+            }
+            // Direct writes are synchronous, so they are durable once written.
+            #[cfg(target_os = "linux")]
+            Backend::Direct(_) => Ok(()),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Backend::Ring(ring) => ring.sync_data(),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Snapshots: point-in-time copies of a database sharing its sealed segments.
+//!
+//! Sealed segments are never written to again, and compaction replaces them with new files
+//! rather than rewriting them, so a snapshot hard-links them into its own directory instead of
+//! copying them. Only the part of the active segment written so far is copied, which makes
+//! taking a snapshot of a large database take about as long as copying one segment.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::engine::Inner;
+use crate::error::{Error, Result};
+use crate::log;
+
+/// Writes a snapshot of the database of `engine` to a new directory at `path`.
+pub(crate) fn snapshot(engine: &Inner, path: &Path) -> Result<()> {
+    if path.exists() {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("snapshot destination {} already exists", path.display()),
+        )));
+    }
+    engine.replayed()?;
+    // Compaction must not remove segments while they are linked.
+    let _compacting = engine.compaction_lock.lock().unwrap();
+    let (segments, sequence) = {
+        let _guard = engine.write_lock.lock().unwrap();
+        engine.log.flush_and_wait();
+        (engine.log.segments(), engine.log.last_sequence())
+    };
+    std::fs::create_dir_all(path)?;
+    let (active, sealed) = segments.split_last().unwrap();
+    for segment in sealed {
+        let (source, target) = (engine.log.segment_path(segment.id), log::segment_path(path, segment.id));
+        match std::fs::hard_link(&source, &target) {
+            Ok(()) => {}
+            // Segments can only be linked within a file system; elsewhere they are copied.
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                std::fs::copy(&source, &target)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    // Writes made since the segments were listed are left out.
+    let source = File::open(engine.log.segment_path(active.id))?;
+    let mut copy = File::create(log::segment_path(path, active.id))?;
+    let copied = io::copy(&mut source.take(active.len), &mut copy)?;
+    if copied < active.len {
+        return Err(Error::Corrupted(format!("segment {} is shorter than expected", active.id)));
+    }
+    copy.sync_all()?;
+    let ids: Vec<u64> = segments.iter().map(|segment| segment.id).collect();
+    let comparator = engine.options.comparator.map(|comparator| comparator.name);
+    log::write_manifest(path, &ids, sequence, comparator)?;
+    Ok(())
+}
@@ -0,0 +1,294 @@
+//! Statistics about the engine's contents and activity.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// A snapshot of the engine's contents and of its activity since it was opened, returned by
+/// [`Engine::stats`](crate::Engine::stats).
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Number of keys across all trees, including expired keys not removed yet.
+    pub live_keys: u64,
+    /// Number of deletions written since the last compaction started.
+    pub tombstones: u64,
+    /// Size of the log in bytes.
+    pub log_bytes: u64,
+    /// Bytes of the log belonging to live entries; the rest can be reclaimed by compaction.
+    pub live_bytes: u64,
+    /// Bytes appended to the log.
+    pub bytes_written: u64,
+    /// Number of keys looked up.
+    pub reads: u64,
+    /// Number of entries appended to the log, each set or deletion being one entry.
+    pub writes: u64,
+    /// Number of entries waiting to be written by the log writer thread.
+    pub write_queue_depth: u64,
+    /// Reads per second, averaged over `uptime`.
+    pub reads_per_second: f64,
+    /// Writes per second, averaged over `uptime`.
+    pub writes_per_second: f64,
+    /// Number of compactions that rewrote part of the log.
+    pub compactions: u64,
+    /// Time spent in those compactions.
+    pub compaction_time: Duration,
+    /// Time the latest of them took, or zero if there was none.
+    pub last_compaction_time: Duration,
+    /// Bytes of the log being rewritten by the compaction in progress, or zero if none is
+    /// running.
+    pub compaction_bytes: u64,
+    /// Bytes of those the compaction in progress has copied so far.
+    pub compaction_done_bytes: u64,
+    /// Approximate memory in bytes taken by the key maps of all trees, including the values
+    /// kept in memory.
+    pub index_bytes: u64,
+    /// Bytes of the values kept in memory, which
+    /// [`EngineOptions::value_memory_budget`](crate::EngineOptions::value_memory_budget) bounds.
+    pub value_bytes: u64,
+    /// Whether values were dropped from memory to stay under
+    /// [`EngineOptions::index_memory_limit`](crate::EngineOptions::index_memory_limit), so that
+    /// they are read back from the log.
+    pub values_spilled: bool,
+    /// Number of values read from the log that were found in the value cache.
+    pub cache_hits: u64,
+    /// Number of values read from the log that were not found in the value cache.
+    pub cache_misses: u64,
+    /// Bytes of keys and values held by the value cache, up to
+    /// [`EngineOptions::value_cache_size`](crate::EngineOptions::value_cache_size).
+    pub cache_bytes: u64,
+    /// Time since the engine was opened.
+    pub uptime: Duration,
+}
+
+impl Stats {
+    /// Returns the share of cache lookups that were hits, or 0 if the cache was never used.
+    pub fn cache_hit_rate(&self) -> f64 {
+        let lookups = self.cache_hits + self.cache_misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.cache_hits as f64 / lookups as f64
+    }
+
+    /// Renders the statistics in the Prometheus text exposition format, with metric names
+    /// prefixed by `tegdb_`.
+    #[cfg(feature = "prometheus")]
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+            text.push_str(&format!("# HELP tegdb_{} {}\n", name, help));
+            text.push_str(&format!("# TYPE tegdb_{} {}\n", name, kind));
+            text.push_str(&format!("tegdb_{} {}\n", name, value));
+        };
+        metric("live_keys", "gauge", "Number of keys across all trees.", self.live_keys as f64);
+        metric("tombstones", "gauge", "Deletions written since the last compaction.", self.tombstones as f64);
+        metric("log_bytes", "gauge", "Size of the log in bytes.", self.log_bytes as f64);
+        metric("live_bytes", "gauge", "Bytes of the log belonging to live entries.", self.live_bytes as f64);
+        metric("written_bytes_total", "counter", "Bytes appended to the log.", self.bytes_written as f64);
+        metric("reads_total", "counter", "Keys looked up.", self.reads as f64);
+        metric("writes_total", "counter", "Entries appended to the log.", self.writes as f64);
+        metric("write_queue_depth", "gauge", "Entries waiting to be written.", self.write_queue_depth as f64);
+        metric("compactions_total", "counter", "Compactions run.", self.compactions as f64);
+        let compaction_seconds = self.compaction_time.as_secs_f64();
+        metric("compaction_seconds_total", "counter", "Time spent compacting.", compaction_seconds);
+        let (total, done) = (self.compaction_bytes as f64, self.compaction_done_bytes as f64);
+        metric("compaction_bytes", "gauge", "Bytes rewritten by the compaction in progress.", total);
+        metric("compaction_done_bytes", "gauge", "Bytes copied by the compaction in progress.", done);
+        metric("index_bytes", "gauge", "Memory taken by the key maps.", self.index_bytes as f64);
+        metric("value_bytes", "gauge", "Bytes of values kept in memory.", self.value_bytes as f64);
+        metric("cache_hits_total", "counter", "Values found in the cache.", self.cache_hits as f64);
+        metric("cache_misses_total", "counter", "Values missed by the cache.", self.cache_misses as f64);
+        metric("cache_bytes", "gauge", "Bytes held by the value cache.", self.cache_bytes as f64);
+        metric("uptime_seconds", "gauge", "Time since the engine was opened.", self.uptime.as_secs_f64());
+        text
+    }
+}
+
+/// A snapshot of the contents of a single tree, returned by
+/// [`Tree::stats`](crate::Tree::stats).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    /// Number of keys in the tree, including expired keys not removed yet.
+    pub keys: u64,
+    /// Number of those keys that have an expiration time.
+    pub expiring_keys: u64,
+    /// Total length of the keys.
+    pub key_bytes: u64,
+    /// Total length of the values as stored in the log, which is less than their actual length
+    /// for compressed values. Values split into chunks only count the reference to their chunks.
+    pub value_bytes: u64,
+    /// Bytes of the log taken up by the live entries of the tree.
+    pub live_bytes: u64,
+    /// Approximate memory in bytes taken by the key map of the tree, including the values kept
+    /// in memory.
+    pub index_bytes: u64,
+}
+
+/// How much of the log is taken up by live entries, returned by
+/// [`Engine::space_stats`](crate::Engine::space_stats) to help decide when to compact.
+#[derive(Clone, Debug, Default)]
+pub struct SpaceStats {
+    /// Size of the log in bytes.
+    pub log_bytes: u64,
+    /// Bytes of the log belonging to live entries.
+    pub live_bytes: u64,
+    /// Number of deletions written since the last compaction started.
+    pub tombstones: u64,
+    /// The same accounting for each segment in replay order; the last one is the active
+    /// segment, which compaction leaves alone.
+    pub segments: Vec<SegmentSpace>,
+}
+
+/// Space accounting for a single segment of the log.
+#[derive(Clone, Debug, Default)]
+pub struct SegmentSpace {
+    /// Id of the segment, which names its file.
+    pub id: u64,
+    /// Size of the segment in bytes.
+    pub log_bytes: u64,
+    /// Bytes of the segment belonging to live entries.
+    pub live_bytes: u64,
+    /// When the segment was sealed, in milliseconds since the Unix epoch, or `None` for the
+    /// active segment. Segments rewritten by compaction keep the time of the newest segment
+    /// they replace, and segments sealed before the database was opened count as sealed when
+    /// their file was last modified.
+    pub sealed_at: Option<u64>,
+}
+
+impl SpaceStats {
+    /// Returns the bytes of the log that compaction could reclaim.
+    pub fn garbage_bytes(&self) -> u64 {
+        self.log_bytes.saturating_sub(self.live_bytes)
+    }
+
+    /// Returns the share of the log that compaction could reclaim, or 0 if the log is empty.
+    /// Compaction runs on its own once this reaches
+    /// [`EngineOptions::compaction_garbage_ratio`](crate::EngineOptions::compaction_garbage_ratio).
+    pub fn garbage_ratio(&self) -> f64 {
+        if self.log_bytes == 0 {
+            return 0.0;
+        }
+        self.garbage_bytes() as f64 / self.log_bytes as f64
+    }
+
+    /// Returns the size of the log relative to its live bytes: 1 when nothing can be
+    /// reclaimed, and infinite when nothing in a non-empty log is live.
+    pub fn amplification(&self) -> f64 {
+        if self.log_bytes == 0 {
+            return 1.0;
+        }
+        self.log_bytes as f64 / self.live_bytes as f64
+    }
+}
+
+/// Activity counters kept by the engine while it is open.
+pub(crate) struct Counters {
+    opened: Instant,
+    reads: AtomicU64,
+    compactions: AtomicU64,
+    compaction_nanos: AtomicU64,
+    last_compaction_nanos: AtomicU64,
+    compaction_bytes: AtomicU64,
+    compaction_done_bytes: AtomicU64,
+    index_bytes: AtomicU64,
+    value_bytes: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn new() -> Self {
+        Self {
+            opened: Instant::now(),
+            reads: AtomicU64::new(0),
+            compactions: AtomicU64::new(0),
+            compaction_nanos: AtomicU64::new(0),
+            last_compaction_nanos: AtomicU64::new(0),
+            compaction_bytes: AtomicU64::new(0),
+            compaction_done_bytes: AtomicU64::new(0),
+            index_bytes: AtomicU64::new(0),
+            value_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn count_reads(&self, n: u64) {
+        self.reads.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Returns the approximate memory taken by the key maps.
+    pub(crate) fn index_bytes(&self) -> u64 {
+        self.index_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the bytes of the values kept in memory, which are part of the key maps.
+    pub(crate) fn value_bytes(&self) -> u64 {
+        self.value_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Records that the key maps now take `bytes`, `value_bytes` of which are values, after
+    /// they were rebuilt.
+    pub(crate) fn set_index_bytes(&self, bytes: u64, value_bytes: u64) {
+        self.index_bytes.store(bytes, Ordering::Relaxed);
+        self.value_bytes.store(value_bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn index_grew(&self, bytes: u64, value_bytes: u64) {
+        self.index_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.value_bytes.fetch_add(value_bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn index_shrank(&self, bytes: u64, value_bytes: u64) {
+        self.index_bytes.fetch_sub(bytes, Ordering::Relaxed);
+        self.value_bytes.fetch_sub(value_bytes, Ordering::Relaxed);
+    }
+
+    /// Records a compaction that took `elapsed`.
+    pub(crate) fn compacted(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos() as u64;
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        self.compaction_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.last_compaction_nanos.store(nanos, Ordering::Relaxed);
+    }
+
+    /// Records the start of a compaction rewriting `bytes` of the log. Its progress is reported
+    /// until the returned guard is dropped.
+    pub(crate) fn compaction_started(&self, bytes: u64) -> CompactionProgress<'_> {
+        self.compaction_done_bytes.store(0, Ordering::Relaxed);
+        self.compaction_bytes.store(bytes, Ordering::Relaxed);
+        CompactionProgress(self)
+    }
+
+    /// Fills in the fields of `stats` derived from the counters. The rates need `writes` to be
+    /// filled in already.
+    pub(crate) fn fill(&self, stats: &mut Stats) {
+        stats.uptime = self.opened.elapsed();
+        stats.reads = self.reads.load(Ordering::Relaxed);
+        stats.compactions = self.compactions.load(Ordering::Relaxed);
+        let (total, last) = (&self.compaction_nanos, &self.last_compaction_nanos);
+        stats.compaction_time = Duration::from_nanos(total.load(Ordering::Relaxed));
+        stats.last_compaction_time = Duration::from_nanos(last.load(Ordering::Relaxed));
+        stats.compaction_bytes = self.compaction_bytes.load(Ordering::Relaxed);
+        stats.compaction_done_bytes = self.compaction_done_bytes.load(Ordering::Relaxed);
+        stats.index_bytes = self.index_bytes();
+        stats.value_bytes = self.value_bytes();
+        let seconds = stats.uptime.as_secs_f64();
+        if seconds > 0.0 {
+            stats.reads_per_second = stats.reads as f64 / seconds;
+            stats.writes_per_second = stats.writes as f64 / seconds;
+        }
+    }
+}
+
+/// Progress of a running compaction, which stops being reported once this is dropped.
+pub(crate) struct CompactionProgress<'a>(&'a Counters);
+
+impl CompactionProgress<'_> {
+    /// Records that `bytes` more of the log have been copied.
+    pub(crate) fn advance(&self, bytes: u64) {
+        self.0.compaction_done_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+impl Drop for CompactionProgress<'_> {
+    fn drop(&mut self) {
+        self.0.compaction_bytes.store(0, Ordering::Relaxed);
+        self.0.compaction_done_bytes.store(0, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,163 @@
+//! Compaction strategies.
+//!
+//! Background compaction decides when to run and which sealed segments to rewrite by the
+//! thresholds in [`EngineOptions`](crate::EngineOptions) unless the engine is opened with a
+//! [`CompactionStrategy`], which workloads can pick to suit how their garbage builds up:
+//! [`FullRewrite`] for small, frequently updated data sets, [`SizeTiered`] for logs that are
+//! mostly appended to, and [`TimeWindowed`] for data written with a time to live. Strategies
+//! see the same accounting as [`Engine::space_stats`](crate::Engine::space_stats). Explicit
+//! calls to [`Engine::compact`](crate::Engine::compact) rewrite every sealed segment whatever
+//! the strategy.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::stats::SegmentSpace;
+
+/// Decides when background compaction runs and which sealed segments it rewrites; see
+/// [`EngineOptions::compaction_strategy`](crate::EngineOptions::compaction_strategy).
+pub trait CompactionStrategy: fmt::Debug + Send + Sync {
+    /// Returns whether background compaction should run, given every segment of the log in
+    /// replay order, the last one being the active segment, which is sealed first, and the
+    /// current time in milliseconds since the Unix epoch. By default it runs whenever
+    /// [`select`](Self::select) picks segments from the sealed ones.
+    fn is_due(&self, segments: &[SegmentSpace], now: u64) -> bool {
+        !self.select(&segments[..segments.len() - 1], now).is_empty()
+    }
+
+    /// Returns the ids of the segments to rewrite out of the `sealed` segments in replay order,
+    /// or none to leave the log as it is. The selection is widened to the contiguous run from
+    /// the oldest to the newest segment picked, and tombstones are only dropped when the run
+    /// starts at the oldest segment of the log.
+    fn select(&self, sealed: &[SegmentSpace], now: u64) -> Vec<u64>;
+}
+
+/// Rewrites every sealed segment once the log holds `min_size` bytes of which the share
+/// `garbage_ratio` is garbage, which drops every tombstone. Suits small data sets whose keys
+/// are overwritten often, such as counters, where the live entries are cheap to copy.
+#[derive(Clone, Copy, Debug)]
+pub struct FullRewrite {
+    /// Minimum log size in bytes before compaction is considered.
+    pub min_size: u64,
+    /// Share of the log taken by overwritten or deleted entries that makes compaction due.
+    pub garbage_ratio: f64,
+}
+
+impl Default for FullRewrite {
+    fn default() -> Self {
+        Self {
+            min_size: 1024 * 1024,
+            garbage_ratio: 0.5,
+        }
+    }
+}
+
+impl CompactionStrategy for FullRewrite {
+    fn is_due(&self, segments: &[SegmentSpace], _now: u64) -> bool {
+        let log_bytes: u64 = segments.iter().map(|s| s.log_bytes).sum();
+        let live_bytes: u64 = segments.iter().map(|s| s.live_bytes).sum();
+        log_bytes >= self.min_size
+            && log_bytes.saturating_sub(live_bytes) as f64 >= log_bytes as f64 * self.garbage_ratio
+    }
+
+    fn select(&self, sealed: &[SegmentSpace], _now: u64) -> Vec<u64> {
+        sealed.iter().map(|s| s.id).collect()
+    }
+}
+
+/// Groups sealed segments into tiers by their live bytes, each `tier_factor` times larger than
+/// the one below, and merges the oldest run of at least `min_segments` adjacent segments of the
+/// same tier once that frees at least a segment's worth of space. Live entries are then only
+/// copied a few times over as they move up the tiers, which suits logs that are mostly
+/// appended to and rarely overwritten. Segments holding nothing live at the start of the log
+/// are dropped right away.
+#[derive(Clone, Copy, Debug)]
+pub struct SizeTiered {
+    /// Number of adjacent segments of the same tier that are merged together.
+    pub min_segments: usize,
+    /// Factor between the live bytes of one tier and the next.
+    pub tier_factor: f64,
+}
+
+impl Default for SizeTiered {
+    fn default() -> Self {
+        Self {
+            min_segments: 4,
+            tier_factor: 4.0,
+        }
+    }
+}
+
+impl SizeTiered {
+    fn tier(&self, segment: &SegmentSpace) -> i32 {
+        match segment.live_bytes {
+            0 => -1,
+            live => (live as f64).log(self.tier_factor.max(1.01)).floor() as i32,
+        }
+    }
+}
+
+impl CompactionStrategy for SizeTiered {
+    fn select(&self, sealed: &[SegmentSpace], _now: u64) -> Vec<u64> {
+        for (i, run) in sealed.chunk_by(|a, b| self.tier(a) == self.tier(b)).enumerate() {
+            // Segments at the start of the log holding nothing live are dropped without copying
+            // anything, so they need not wait for others.
+            let dropped = i == 0 && self.tier(&run[0]) < 0;
+            if run.len() < self.min_segments.max(2) && !dropped {
+                continue;
+            }
+            let garbage: u64 = run.iter().map(|s| s.log_bytes.saturating_sub(s.live_bytes)).sum();
+            if garbage >= run.iter().map(|s| s.log_bytes).max().unwrap_or(0) {
+                return run.iter().map(|s| s.id).collect();
+            }
+        }
+        Vec::new()
+    }
+}
+
+/// Groups sealed segments into windows of `window` by when they were sealed, and compacts the
+/// log from its oldest segment up to the end of the latest window that has closed, so that the
+/// share `garbage_ratio` of the bytes rewritten is garbage. Entries expiring together then
+/// stay together, whole windows are dropped once their entries have expired, and nothing is
+/// rewritten while the entries of the current window are still live. Suits data written with
+/// a time to live, such as caches and sessions.
+#[derive(Clone, Copy, Debug)]
+pub struct TimeWindowed {
+    /// Span of time whose segments are grouped together.
+    pub window: Duration,
+    /// Share of the rewritten bytes taken by expired, overwritten or deleted entries that
+    /// makes compaction due.
+    pub garbage_ratio: f64,
+}
+
+impl Default for TimeWindowed {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(60 * 60),
+            garbage_ratio: 0.5,
+        }
+    }
+}
+
+impl CompactionStrategy for TimeWindowed {
+    fn select(&self, sealed: &[SegmentSpace], now: u64) -> Vec<u64> {
+        let window = (self.window.as_millis() as u64).max(1);
+        let current = now / window;
+        let (mut log_bytes, mut live_bytes, mut end) = (0, 0, 0);
+        for (i, segment) in sealed.iter().enumerate() {
+            let Some(sealed_at) = segment.sealed_at.filter(|at| at / window < current) else {
+                break;
+            };
+            log_bytes += segment.log_bytes;
+            live_bytes += segment.live_bytes;
+            // A window is only compacted whole.
+            let next = sealed.get(i + 1).and_then(|s| s.sealed_at);
+            let closes_window = next.is_none_or(|next| next / window != sealed_at / window);
+            let garbage = log_bytes - live_bytes.min(log_bytes);
+            if closes_window && garbage > 0 && garbage as f64 >= log_bytes as f64 * self.garbage_ratio {
+                end = i + 1;
+            }
+        }
+        sealed[..end].iter().map(|s| s.id).collect()
+    }
+}
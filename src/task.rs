@@ -0,0 +1,112 @@
+//! Futures completed by the engine's own threads.
+//!
+//! The engine does not depend on an async runtime, so an operation that waits on one of its
+//! threads, such as the log writer thread, is handed a [`Receiver`] that the thread completes
+//! through its [`Sender`] and wakes whichever executor polls it. Internal callers that are not
+//! async wait on the same receiver by blocking instead. Long-running disk work, such as
+//! compacting the log or writing a checkpoint, runs on a thread of its own with [`unblock`].
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+// The value sent, if any, and what to wake once it is.
+struct Slot<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+    // Set once the sender is gone, with or without sending a value.
+    closed: bool,
+}
+
+struct Shared<T> {
+    slot: Mutex<Slot<T>>,
+    ready: Condvar,
+}
+
+/// Completes the paired [`Receiver`] with a value, or without one if dropped before sending.
+pub(crate) struct Sender<T>(Arc<Shared<T>>);
+
+/// A future resolving to the value sent by the paired [`Sender`], or to `None` if it was dropped
+/// without sending one, for example because the thread holding it has shut down.
+pub(crate) struct Receiver<T>(Arc<Shared<T>>);
+
+/// Returns a sender and a receiver for a single value.
+pub(crate) fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        slot: Mutex::new(Slot {
+            value: None,
+            waker: None,
+            closed: false,
+        }),
+        ready: Condvar::new(),
+    });
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+impl<T> Sender<T> {
+    /// Completes the receiver with `value`.
+    pub(crate) fn send(self, value: T) {
+        self.0.slot.lock().unwrap().value = Some(value);
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut slot = self.0.slot.lock().unwrap();
+            slot.closed = true;
+            slot.waker.take()
+        };
+        self.0.ready.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Blocks the calling thread until the sender has sent a value or has been dropped.
+    pub(crate) fn wait(self) -> Option<T> {
+        let mut slot = self.0.slot.lock().unwrap();
+        while !slot.closed {
+            slot = self.0.ready.wait(slot).unwrap();
+        }
+        slot.value.take()
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut slot = self.0.slot.lock().unwrap();
+        if slot.closed {
+            return Poll::Ready(slot.value.take());
+        }
+        slot.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Runs `f` on a thread of its own and returns its result, so that the executor polling the
+/// future is free to run other tasks meanwhile. `f` runs to completion even if the future is
+/// dropped, and if it panics, the panic is resumed where the future is polled. With the
+/// `tracing` feature, `f` reports to the caller's subscriber, within the caller's span.
+pub(crate) async fn unblock<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    #[cfg(feature = "tracing")]
+    let f = {
+        let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+        let span = tracing::Span::current();
+        move || tracing::dispatcher::with_default(&dispatch, || span.in_scope(f))
+    };
+    let (done, finished) = channel();
+    thread::spawn(move || done.send(panic::catch_unwind(AssertUnwindSafe(f))));
+    match finished.await {
+        Some(Ok(value)) => value,
+        Some(Err(payload)) => panic::resume_unwind(payload),
+        None => unreachable!("the thread sends the outcome of `f` before exiting"),
+    }
+}
@@ -0,0 +1,317 @@
+//! Model-based consistency testing.
+//!
+//! With the `testing` feature, [`ModelTest`] applies a random sequence of writes, reads and
+//! scans both to an engine and to a `BTreeMap` standing in for it, and fails as soon as the two
+//! disagree. Applications can run it against the options they deploy with, such as their
+//! compression, segment size or compaction settings, to check that the engine behaves like a
+//! map under them:
+//!
+//! ```no_run
+//! use tegdb::testing::ModelTest;
+//! use tegdb::EngineOptions;
+//!
+//! let test = ModelTest {
+//!     ops: 10_000,
+//!     crash_rate: 0.01,
+//!     torn_writes: true,
+//!     ..ModelTest::default()
+//! };
+//! let options = EngineOptions {
+//!     segment_size: 64 * 1024,
+//!     ..EngineOptions::default()
+//! };
+//! test.run("model.db", &options).unwrap();
+//! ```
+//!
+//! Runs are reproducible: the operations only depend on [`ModelTest::seed`], which a failure
+//! reports along with the operation at which the engine diverged.
+//!
+//! At crash points, the engine is dropped without being closed and opened again, after which
+//! it must hold every write made before. With [`ModelTest::torn_writes`], a random part of the
+//! log written since the previous crash point is also cut off, as a crash in the middle of
+//! writing would, and the log is repaired before reopening it. The engine must then hold the
+//! writes up to some point since the previous crash point, as writes can be lost but never
+//! reordered or half applied. Crash points following a compaction are not torn, as compaction
+//! drops entries shadowed by writes that a torn log would lose.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::blocking::Engine;
+use crate::compaction::{CompactionEvent, CompactionHook};
+use crate::error::{Error, Result};
+use crate::log;
+use crate::options::EngineOptions;
+
+type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Settings for a model-based consistency test; see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct ModelTest {
+    /// Seed of the random operations, so that a failing run can be replayed.
+    pub seed: u64,
+    /// Number of operations to apply.
+    pub ops: usize,
+    /// Number of distinct keys the operations pick from; fewer keys make for more overwrites.
+    pub keys: u32,
+    /// Length in bytes of the longest value written.
+    pub max_value_len: usize,
+    /// Fraction of the operations that are crash points.
+    pub crash_rate: f64,
+    /// Whether crash points also cut off part of the log written since the previous one.
+    pub torn_writes: bool,
+}
+
+impl Default for ModelTest {
+    fn default() -> Self {
+        Self {
+            seed: 1,
+            ops: 1000,
+            keys: 64,
+            max_value_len: 64,
+            crash_rate: 0.0,
+            torn_writes: false,
+        }
+    }
+}
+
+/// An operation applied by a [`ModelTest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    /// Sets `key` to `value`.
+    Set { key: Vec<u8>, value: Vec<u8> },
+    /// Deletes `key`.
+    Del { key: Vec<u8> },
+    /// Deletes the keys from `start` up to but excluding `end`.
+    DelRange { start: Vec<u8>, end: Vec<u8> },
+    /// Reads `key`.
+    Get { key: Vec<u8> },
+    /// Reads the keys from `start` up to but excluding `end`.
+    Scan { start: Vec<u8>, end: Vec<u8> },
+    /// Drops the engine without closing it and opens it again.
+    Crash,
+}
+
+/// Where a [`ModelTest`] found the engine to disagree with the model, or to fail.
+#[derive(Debug, Clone)]
+pub struct Failure {
+    /// Seed of the run, which replays it.
+    pub seed: u64,
+    /// Index of the operation at which the run failed, counting from 0.
+    pub step: usize,
+    /// The operation at which the run failed, or `None` if opening the engine first or the
+    /// final comparison of its contents failed.
+    pub operation: Option<Operation>,
+    /// What went wrong.
+    pub reason: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.operation {
+            Some(operation) => write!(f, "seed {}, step {} ({:?}): {}", self.seed, self.step, operation, self.reason),
+            None => write!(f, "seed {}, step {}: {}", self.seed, self.step, self.reason),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+impl ModelTest {
+    /// Runs the test against a database created in the directory at `path`, which must not
+    /// exist yet, opened with `options`. The directory is left behind, so that it can be
+    /// inspected if the run fails.
+    pub fn run<P: AsRef<Path>>(&self, path: P, options: &EngineOptions) -> std::result::Result<(), Failure> {
+        let path = path.as_ref();
+        let mut rng = Rng(self.seed.max(1));
+        let mut model = Model::new();
+        // The states of the model since the latest crash point, which a torn log may roll back to.
+        let mut history = vec![model.clone()];
+        let fail = |step: usize, operation: Option<&Operation>, reason: String| Failure {
+            seed: self.seed,
+            step,
+            operation: operation.cloned(),
+            reason,
+        };
+        // Set once a compaction has started since the latest crash point.
+        let compacted = Arc::new(AtomicBool::new(false));
+        let mut options = options.clone();
+        let hook = options.on_compaction.take();
+        let started = compacted.clone();
+        options.on_compaction = Some(CompactionHook::new(move |event| {
+            if let CompactionEvent::Started { .. } = event {
+                started.store(true, Ordering::SeqCst);
+            }
+            if let Some(hook) = &hook {
+                hook.call(event);
+            }
+        }));
+        let open = |step: usize, operation: Option<&Operation>| -> std::result::Result<_, Failure> {
+            compacted.store(false, Ordering::SeqCst);
+            let engine = Engine::open_with_options(path, options.clone())
+                .map_err(|e| fail(step, operation, format!("opening failed: {}", e)))?;
+            let mark = active_segment(path).map_err(|e| fail(step, operation, e.to_string()))?;
+            Ok((engine, mark))
+        };
+        let (mut engine, mut mark) = open(0, None)?;
+        for step in 0..self.ops {
+            let operation = self.operation(&mut rng);
+            let failed = |reason: String| fail(step, Some(&operation), reason);
+            match &operation {
+                Operation::Set { key, value } => {
+                    engine.set(key, value.clone()).map_err(|e| failed(e.to_string()))?;
+                    model.insert(key.clone(), value.clone());
+                    history.push(model.clone());
+                }
+                Operation::Del { key } => {
+                    engine.del(key).map_err(|e| failed(e.to_string()))?;
+                    model.remove(key);
+                    history.push(model.clone());
+                }
+                Operation::DelRange { start, end } => {
+                    engine
+                        .delete_range(start.clone()..end.clone())
+                        .map_err(|e| failed(e.to_string()))?;
+                    let mut deleted = model.split_off(start);
+                    model.append(&mut deleted.split_off(end));
+                    history.push(model.clone());
+                }
+                Operation::Get { key } => {
+                    let value = engine.get(key).map_err(|e| failed(e.to_string()))?;
+                    let expected = model.get(key);
+                    if value.as_deref() != expected.map(Vec::as_slice) {
+                        return Err(failed(format!("read {:?}, expected {:?}", value, expected)));
+                    }
+                }
+                Operation::Scan { start, end } => {
+                    let pairs = engine
+                        .scan(start.clone()..end.clone())
+                        .map_err(|e| failed(e.to_string()))?;
+                    let pairs: Vec<(Vec<u8>, Vec<u8>)> = pairs.map(|(k, v)| (k.to_vec(), v.to_vec())).collect();
+                    let range = (Bound::Included(start.clone()), Bound::Excluded(end.clone()));
+                    let expected: Vec<(Vec<u8>, Vec<u8>)> =
+                        model.range(range).map(|(k, v)| (k.clone(), v.clone())).collect();
+                    if pairs != expected {
+                        return Err(failed(format!("scanned {:?}, expected {:?}", pairs, expected)));
+                    }
+                }
+                Operation::Crash => {
+                    drop(engine);
+                    released(path).map_err(|e| failed(e.to_string()))?;
+                    let torn = self.torn_writes && !compacted.load(Ordering::SeqCst);
+                    if torn {
+                        tear(path, mark, &mut rng).map_err(|e| failed(format!("tearing the log failed: {}", e)))?;
+                    }
+                    (engine, mark) = open(step, Some(&operation))?;
+                    let state = contents(&engine).map_err(|e| failed(e.to_string()))?;
+                    // Without torn writes, only the latest state is acceptable.
+                    let earliest = if torn { 0 } else { history.len() - 1 };
+                    let Some(recovered) = history[earliest..].iter().rposition(|s| *s == state) else {
+                        return Err(failed(format!("recovered {} keys that match no earlier state", state.len())));
+                    };
+                    model = history.swap_remove(earliest + recovered);
+                    history = vec![model.clone()];
+                }
+            }
+        }
+        let state = contents(&engine).map_err(|e| fail(self.ops, None, e.to_string()))?;
+        if state != model {
+            let reason = format!("ended with {} keys, expected {}", state.len(), model.len());
+            return Err(fail(self.ops, None, reason));
+        }
+        engine.close().map_err(|e| fail(self.ops, None, format!("closing failed: {}", e)))
+    }
+
+    // Picks the next operation.
+    fn operation(&self, rng: &mut Rng) -> Operation {
+        if rng.chance(self.crash_rate) {
+            return Operation::Crash;
+        }
+        let key = |rng: &mut Rng| format!("key{:08}", rng.below(self.keys.max(1) as u64)).into_bytes();
+        match rng.below(100) {
+            0..=44 => {
+                let len = 1 + rng.below(self.max_value_len.max(1) as u64) as usize;
+                let value = (0..len).map(|_| rng.next() as u8).collect();
+                Operation::Set { key: key(rng), value }
+            }
+            45..=59 => Operation::Del { key: key(rng) },
+            60..=62 => {
+                let (a, b) = (key(rng), key(rng));
+                let (start, end) = if a <= b { (a, b) } else { (b, a) };
+                Operation::DelRange { start, end }
+            }
+            63..=94 => Operation::Get { key: key(rng) },
+            _ => {
+                let (a, b) = (key(rng), key(rng));
+                let (start, end) = if a <= b { (a, b) } else { (b, a) };
+                Operation::Scan { start, end }
+            }
+        }
+    }
+}
+
+// Returns every pair in the default tree.
+fn contents(engine: &Engine) -> Result<Model> {
+    Ok(engine.scan(..)?.map(|(k, v)| (k.to_vec(), v.to_vec())).collect())
+}
+
+// Waits until the engine dropped last has let go of the database in `dir`, which background
+// threads still running when it was dropped may delay.
+fn released(dir: &Path) -> Result<()> {
+    loop {
+        match log::lock_dir(dir, false) {
+            Ok(_) => return Ok(()),
+            Err(Error::DatabaseLocked(_)) => thread::sleep(Duration::from_millis(1)),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Returns the id and length of the active segment of the database in `dir`.
+fn active_segment(dir: &Path) -> Result<(u64, u64)> {
+    let (ids, ..) = log::parse_manifest(&std::fs::read_to_string(dir.join(log::MANIFEST))?)?;
+    let id = *ids.last().unwrap();
+    let len = std::fs::metadata(log::segment_path(dir, id)).map_or(0, |metadata| metadata.len());
+    Ok((id, len))
+}
+
+// Cuts a random part off the end of what was written to the active segment since `mark`, the
+// active segment and its length at the previous crash point, and repairs the log.
+fn tear(dir: &Path, mark: (u64, u64), rng: &mut Rng) -> Result<()> {
+    let (id, len) = active_segment(dir)?;
+    let start = if id == mark.0 { mark.1 } else { 0 };
+    if len > start {
+        let cut = start + rng.below(len - start + 1);
+        File::options().write(true).open(log::segment_path(dir, id))?.set_len(cut)?;
+        Engine::repair(dir)?;
+    }
+    Ok(())
+}
+
+// A xorshift64* generator, so that runs only depend on their seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // Returns a number below `n`, which must not be 0.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
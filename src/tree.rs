@@ -0,0 +1,558 @@
+//! Trees: isolated keyspaces sharing one log.
+//!
+//! Every key belongs to a tree. The engine itself exposes the default tree; further trees are
+//! opened by name and keep their own key map, so scans and counts only ever see their own keys.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_core::Stream;
+
+use crate::bulk;
+use crate::changes::{self, Change};
+use crate::engine::{Inner, KeyMap};
+use crate::error::{Error, Result};
+use crate::history::{self, At, Version};
+use crate::intercept::{self, Mutation};
+use crate::pipeline::Pipeline;
+use crate::scan::{Iter, Keys, Page, Pairs, ScanStream};
+use crate::stats::TreeStats;
+use crate::watch::{Event, Filter, Watchers};
+
+/// Id of the tree the engine itself reads and writes.
+pub(crate) const DEFAULT_TREE: u32 = 0;
+/// Id of the internal tree mapping tree names to their ids.
+pub(crate) const META_TREE: u32 = u32::MAX;
+/// Id of the internal tree holding the chunks of large values.
+pub(crate) const CHUNK_TREE: u32 = u32::MAX - 1;
+/// Id of the internal tree holding the retained versions of keys, the lowest internal tree id.
+pub(crate) const HISTORY_TREE: u32 = u32::MAX - 2;
+
+/// The index of a single tree.
+pub(crate) struct Keyspace {
+    pub(crate) id: u32,
+    pub(crate) key_map: KeyMap,
+    // Keys with an expiration time, ordered by when they expire.
+    pub(crate) expirations: Mutex<BTreeSet<(u64, Bytes)>>,
+    pub(crate) watchers: Watchers,
+}
+
+impl Keyspace {
+    pub(crate) fn new(id: u32, key_map: KeyMap, expirations: BTreeSet<(u64, Bytes)>) -> Self {
+        let watchers = Watchers::new(key_map.read().unwrap().order());
+        Self {
+            id,
+            key_map,
+            expirations: Mutex::new(expirations),
+            watchers,
+        }
+    }
+}
+
+/// A value along with its metadata, returned by [`Tree::get_entry`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// The value of the key.
+    pub value: Bytes,
+    /// Milliseconds since the Unix epoch when the key was set after not existing, or `None` if
+    /// its latest write did not record timestamps; see
+    /// [`EngineOptions::record_timestamps`](crate::EngineOptions::record_timestamps).
+    pub created_at: Option<u64>,
+    /// Milliseconds since the Unix epoch when the key was last set, or `None` if that write did
+    /// not record timestamps.
+    pub updated_at: Option<u64>,
+    /// The version of the key, as returned by [`Tree::get_versioned`].
+    pub version: u64,
+}
+
+/// A keyspace within an [`Engine`](crate::Engine), isolated from the keys of every other tree.
+/// Trees share the engine's log, so writes to different trees are ordered with each other.
+/// Like the engine, a tree can be shared by reference between threads or cheaply cloned.
+#[derive(Clone)]
+pub struct Tree {
+    pub(crate) engine: Arc<Inner>,
+    pub(crate) keyspace: Arc<Keyspace>,
+}
+
+impl Tree {
+    /// Retrieves the value associated with the given key asynchronously.
+    /// The returned buffer shares memory with the engine, so no copy is made for values
+    /// kept in memory; values that are only kept on disk are read back from the log.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(tree = self.keyspace.id, key_len = key.len()),
+            err
+        )
+    )]
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.engine.counters.count_reads(1);
+        self.engine.get(&self.keyspace, key)
+    }
+
+    /// Returns true if the tree holds `key`. Only the index is consulted, which holds every key
+    /// even when values are only kept on disk, so the log is never read, whether or not the key
+    /// exists.
+    pub async fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        self.engine.counters.count_reads(1);
+        self.engine.contains_key(&self.keyspace, key)
+    }
+
+    /// Retrieves the values of several keys at once, in the order the keys are given.
+    /// The index is consulted once for the whole batch, so this is cheaper than calling
+    /// [`Tree::get`] for each key.
+    pub async fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Bytes>>> {
+        self.engine.counters.count_reads(keys.len() as u64);
+        self.engine.get_many(&self.keyspace, keys)
+    }
+
+    /// Inserts or updates the value for the given key.
+    /// If an empty value is provided, the key is removed.
+    /// Returns an error if the key or value exceeds predefined size limits.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(tree = self.keyspace.id, key_len = key.len(), value_len = value.len()),
+            err
+        )
+    )]
+    pub async fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.engine.check_limits(key, &value)?;
+        let mutation = Mutation::Set { key: key.to_vec(), value, expires_at: None };
+        self.engine.write(|| intercept::apply(&self.engine, &self.keyspace, mutation))
+    }
+
+    /// Inserts or updates the value for the given key like [`Tree::set`], and waits until the
+    /// write has been fsynced to disk before returning, even if
+    /// [`EngineOptions::sync_writes`](crate::EngineOptions::sync_writes) is off. Plain writes
+    /// are then only queued for the log writer thread, so an engine can take high volumes of
+    /// writes that may be lost if the machine loses power alongside a few that must not be;
+    /// the fsync also makes every write queued before this one durable.
+    pub async fn set_durable(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.engine.check_limits(key, &value)?;
+        let mutation = Mutation::Set { key: key.to_vec(), value, expires_at: None };
+        self.engine.write_and_sync(true, || intercept::apply(&self.engine, &self.keyspace, mutation))
+    }
+
+    /// Inserts or updates the value for the given key so that it expires after `ttl`.
+    /// Expired keys are no longer returned by reads; their space is reclaimed by compaction.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(tree = self.keyspace.id, key_len = key.len(), value_len = value.len()),
+            err
+        )
+    )]
+    pub async fn set_with_ttl(&self, key: &[u8], value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.engine.check_limits(key, &value)?;
+        let ttl = ttl.as_millis().try_into().unwrap_or(u64::MAX);
+        let expires_at = self.engine.now().saturating_add(ttl);
+        let mutation = Mutation::Set { key: key.to_vec(), value, expires_at: Some(expires_at) };
+        self.engine.write(|| intercept::apply(&self.engine, &self.keyspace, mutation))
+    }
+
+    /// Deletes a key-value pair from the store.
+    /// If the key does not exist, the operation is a no-op.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(tree = self.keyspace.id, key_len = key.len()),
+            err
+        )
+    )]
+    pub async fn del(&self, key: &[u8]) -> Result<()> {
+        let mutation = Mutation::Del { key: key.to_vec() };
+        self.engine.write(|| intercept::apply(&self.engine, &self.keyspace, mutation))
+    }
+
+    /// Deletes a key-value pair like [`Tree::del`], and waits until the deletion has been fsynced
+    /// to disk before returning, as [`Tree::set_durable`] does.
+    pub async fn del_durable(&self, key: &[u8]) -> Result<()> {
+        let mutation = Mutation::Del { key: key.to_vec() };
+        self.engine.write_and_sync(true, || intercept::apply(&self.engine, &self.keyspace, mutation))
+    }
+
+    /// Loads `pairs` into the tree, which must be empty, and returns the number of keys loaded.
+    /// This is meant for the initial import of a large data set: the entries are written
+    /// straight to new log segments and the index is built from them in one pass, which is far
+    /// faster than setting the keys one by one. Keys must come in ascending order, and pairs with
+    /// empty values are skipped.
+    ///
+    /// Other writes wait until the load finishes. The keys are durable once it returns, and none
+    /// of them is loaded if it fails. Values are never split into chunks, and the loaded keys
+    /// start without retained versions.
+    pub async fn bulk_load(&self, pairs: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<u64> {
+        let _compacting = self.engine.compaction_lock.lock().unwrap();
+        self.engine.write(|| {
+            if !self.keyspace.key_map.read().unwrap().is_empty() {
+                return Err(Error::BulkLoad("the tree is not empty".to_string()));
+            }
+            let tree = self.engine.tree_names.get(&self.engine, self.keyspace.id)?.unwrap_or_default();
+            bulk::load(&self.engine, |loader| {
+                let mut count = 0;
+                for (key, value) in pairs {
+                    let (key, value, expires_at) = intercept::loaded(&self.engine, &tree, key, value, None)?;
+                    if loader.set(&self.keyspace, key, value, expires_at)? {
+                        count += 1;
+                    }
+                }
+                Ok(count)
+            })
+        })
+    }
+
+    /// Returns a pipeline buffering writes to the tree and submitting them in groups, each
+    /// with a single flush of the log, which speeds up bulk loads made by one task.
+    pub fn pipeline(&self) -> Pipeline {
+        Pipeline::new(self.clone())
+    }
+
+    /// Deletes several keys at once, taking the write lock only once for the whole batch.
+    /// Keys that do not exist are ignored.
+    pub async fn del_many(&self, keys: &[&[u8]]) -> Result<()> {
+        self.engine.write(|| {
+            for key in keys {
+                let mutation = Mutation::Del { key: key.to_vec() };
+                intercept::apply(&self.engine, &self.keyspace, mutation)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Deletes every key in the tree with a single log entry.
+    pub async fn clear(&self) -> Result<()> {
+        let mutation = Mutation::DelRange { start: Vec::new(), end: None };
+        self.engine.write(|| intercept::apply(&self.engine, &self.keyspace, mutation))
+    }
+
+    /// Deletes every key within the specified range with a single log entry, however many
+    /// keys it holds. Any range form is accepted, as with [`Tree::scan`].
+    pub async fn delete_range(&self, range: impl RangeBounds<Vec<u8>>) -> Result<()> {
+        let Tree { engine, keyspace } = self;
+        engine.write(|| {
+            // Range tombstones cover the keys from their start up to but excluding their end,
+            // where an empty end means unbounded.
+            let start = match range.start_bound() {
+                Bound::Included(start) => start.clone(),
+                Bound::Excluded(start) => match self.key_after(start) {
+                    Some(start) => start,
+                    None => return Ok(()),
+                },
+                Bound::Unbounded => Vec::new(),
+            };
+            let end = match range.end_bound() {
+                Bound::Included(end) => self.key_after(end).unwrap_or_default(),
+                Bound::Excluded(end) if end.is_empty() => return Ok(()),
+                Bound::Excluded(end) => end.clone(),
+                Bound::Unbounded => Vec::new(),
+            };
+            if !end.is_empty() && keyspace.key_map.read().unwrap().compare(&start, &end).is_ge() {
+                return Ok(());
+            }
+            engine.check_limits(&start, &[])?;
+            engine.check_limits(&end, &[])?;
+            let end = (!end.is_empty()).then_some(end);
+            intercept::apply(engine, keyspace, Mutation::DelRange { start, end })
+        })
+    }
+
+    // Returns the smallest key after `key`, which is `key` followed by a zero byte, or with a
+    // comparator the next key the tree holds, if any. The caller must hold the write lock, so
+    // that no key can be added in between.
+    fn key_after(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let key_map = self.keyspace.key_map.read().unwrap();
+        if key_map.order().is_none() {
+            return Some([key, &[0]].concat());
+        }
+        let (next, _) = key_map.range((Bound::Excluded(key), Bound::Unbounded)).next()?;
+        Some(next.to_vec())
+    }
+
+    /// Atomically replaces the value of `key` with `new` if its current value is `expected`,
+    /// returning whether the swap took place. `None` stands for an absent key on either side,
+    /// so `expected: None` only succeeds if the key does not exist and `new: None` deletes it.
+    pub async fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let new = new.unwrap_or_default();
+        self.engine.check_limits(key, &new)?;
+        self.engine.write(|| {
+            if self.engine.get(&self.keyspace, key)?.as_deref() != expected {
+                return Ok(false);
+            }
+            let mutation = Mutation::Set { key: key.to_vec(), value: new, expires_at: None };
+            intercept::apply(&self.engine, &self.keyspace, mutation)?;
+            Ok(true)
+        })
+    }
+
+    /// Returns the value of `key` along with its version, the sequence number of the write that
+    /// set it, or `None` if it does not exist. The version changes whenever the key is written
+    /// again, so passing it to [`Tree::set_if_version`] updates the key only if nobody else has
+    /// since, without comparing whole values. Writes leaving the value as it was are skipped,
+    /// and keep the version.
+    pub async fn get_versioned(&self, key: &[u8]) -> Result<Option<(Bytes, u64)>> {
+        loop {
+            let Some(version) = self.engine.version(&self.keyspace, key)? else {
+                return Ok(None);
+            };
+            let value = self.engine.get(&self.keyspace, key)?;
+            // A write between the two reads would pair the value with the wrong version.
+            if self.engine.version(&self.keyspace, key)? == Some(version) {
+                return Ok(value.map(|value| (value, version)));
+            }
+        }
+    }
+
+    /// Returns the value of `key` along with when it was created and last modified and its
+    /// version, or `None` if it does not exist. Like versions, the timestamps are left as they
+    /// were by writes that keep the value.
+    pub async fn get_entry(&self, key: &[u8]) -> Result<Option<Entry>> {
+        loop {
+            let Some((version, timestamps)) = self.engine.metadata(&self.keyspace, key)? else {
+                return Ok(None);
+            };
+            let value = self.engine.get(&self.keyspace, key)?;
+            // A write between the two reads would pair the value with the wrong metadata.
+            if self.engine.version(&self.keyspace, key)? == Some(version) {
+                return Ok(value.map(|value| Entry {
+                    value,
+                    created_at: timestamps.map(|timestamps| timestamps.created_at),
+                    updated_at: timestamps.map(|timestamps| timestamps.updated_at),
+                    version,
+                }));
+            }
+        }
+    }
+
+    /// Returns the value `key` had at `at`, or `None` if it did not exist then, reading the
+    /// versions kept with [`EngineOptions::history_versions`](crate::EngineOptions::history_versions).
+    /// Points before the oldest version kept also read as `None`.
+    pub async fn get_at(&self, key: &[u8], at: At) -> Result<Option<Bytes>> {
+        history::get_at(&self.engine, self.keyspace.id, key, at)
+    }
+
+    /// Returns the versions kept of `key` from oldest to newest, the last one being its current
+    /// state, unless it was written while versions were not kept. Deleting the key is a version
+    /// without a value, while expiring it is not recorded.
+    pub async fn history(&self, key: &[u8]) -> Result<Vec<Version>> {
+        history::versions(&self.engine, self.keyspace.id, key)
+    }
+
+    /// Atomically sets `key` to `value` if its version is still `version`, as returned by
+    /// [`Tree::get_versioned`], returning whether it was set. `None` only succeeds if the key
+    /// does not exist, and an empty value deletes the key.
+    pub async fn set_if_version(&self, key: &[u8], value: Vec<u8>, version: Option<u64>) -> Result<bool> {
+        self.engine.check_limits(key, &value)?;
+        self.engine.write(|| {
+            if self.engine.version(&self.keyspace, key)? != version {
+                return Ok(false);
+            }
+            let mutation = Mutation::Set { key: key.to_vec(), value, expires_at: None };
+            intercept::apply(&self.engine, &self.keyspace, mutation)?;
+            Ok(true)
+        })
+    }
+
+    /// Returns an iterator over key-value pairs within the specified range, in key order.
+    /// Any range form is accepted, including inclusive (`a..=b`), open-ended (`a..`) and
+    /// unbounded (`..`) ranges.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(tree = self.keyspace.id), err)
+    )]
+    pub async fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Pairs> {
+        self.engine.scan(&self.keyspace, &range)
+    }
+
+    /// Returns an iterator over every key-value pair whose key starts with `prefix`, in key order.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(tree = self.keyspace.id, prefix_len = prefix.len()),
+            err
+        )
+    )]
+    pub async fn scan_prefix(&self, prefix: &[u8]) -> Result<Pairs> {
+        if self.keyspace.key_map.read().unwrap().order().is_some() {
+            // A comparator need not keep the keys starting with the prefix together.
+            self.engine.replayed()?;
+            let mut keys = self.engine.keys_in(&self.keyspace, &(..));
+            keys.retain(|key| key.starts_with(prefix));
+            return self.engine.pairs(&self.keyspace, keys);
+        }
+        let end = match prefix_end(prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.engine.scan(&self.keyspace, &(Bound::Included(prefix.to_vec()), end))
+    }
+
+    /// Returns an iterator over the keys within the specified range, in key order, without
+    /// reading their values.
+    pub async fn keys(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Keys> {
+        self.engine.replayed()?;
+        Ok(Keys::new(self.engine.keys_in(&self.keyspace, &range)))
+    }
+
+    /// Returns a page of at most `limit` key-value pairs within the specified range, in key
+    /// order, along with a continuation token if pairs are left after it. Passing the token
+    /// back with the same range returns the next page, so that large ranges can be paged
+    /// through, for example by HTTP handlers, without holding an iterator between requests.
+    /// Pages see the writes made in between, and every page but the last holds `limit` pairs,
+    /// at least one.
+    pub async fn scan_page(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+        continuation: Option<Vec<u8>>,
+    ) -> Result<Page> {
+        // The token is the last key returned, which the next page starts after.
+        let start = match continuation {
+            Some(last) => Bound::Excluded(last),
+            None => range.start_bound().cloned(),
+        };
+        let mut pairs = Iter::new(self.clone(), &(start, range.end_bound().cloned()));
+        let page = pairs.by_ref().take(limit.max(1)).collect::<Result<Vec<_>>>()?;
+        let continuation = match pairs.next().transpose()? {
+            Some(_) => page.last().map(|(key, _)| key.to_vec()),
+            None => None,
+        };
+        Ok((page, continuation))
+    }
+
+    /// Returns a stream over key-value pairs within the specified range, in key order.
+    /// Keys and values are fetched lazily as the stream is polled, so large ranges can be
+    /// scanned without holding them in memory.
+    pub fn scan_stream(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+    ) -> impl Stream<Item = Result<(Bytes, Bytes)>> + Send + 'static {
+        ScanStream(Iter::new(self.clone(), &range))
+    }
+
+    /// Returns a stream of the changes accepted by `filter` from now on, in the order they were
+    /// committed. Filters are evaluated as writes are committed, so subscribers to part of a
+    /// large tree are not flooded with the events of unrelated keys. Events are produced and
+    /// buffered like those of [`Tree::watch_prefix`].
+    pub fn watch(&self, filter: Filter) -> impl Stream<Item = Event> + Send + 'static {
+        self.keyspace.watchers.watch(filter)
+    }
+
+    /// Returns a stream of the changes made to keys starting with `prefix` from now on, in the
+    /// order they were committed. Every `set` and `del` produces an event, as does every key
+    /// removed by a range deletion; keys that expire do not. Events are buffered until the
+    /// stream is polled, and the stream ends once the tree is dropped.
+    pub fn watch_prefix(&self, prefix: &[u8]) -> impl Stream<Item = Event> + Send + 'static {
+        self.watch(Filter::prefix(prefix))
+    }
+
+    /// Returns the changes made to the tree after the write numbered `sequence`, in the order
+    /// they were committed. Passing 0 returns every change still in the log, and passing the
+    /// sequence number of the last change seen resumes a feed where it left off.
+    ///
+    /// Changes are read back from the log, so compaction coalesces them: overwritten values and
+    /// the changes of expired keys are dropped, and deletions may be dropped along with the
+    /// values they deleted, unless [`EngineOptions::retention`](crate::EngineOptions::retention)
+    /// or a consumer acknowledging its progress with
+    /// [`Engine::acknowledge`](crate::Engine::acknowledge) holds them back. Writes made before
+    /// sequence numbers were introduced are left out.
+    pub async fn changes_since(&self, sequence: u64) -> Result<Vec<Change>> {
+        let changes = changes::changes_since(&self.engine, Some(self.keyspace.id), sequence)?;
+        Ok(changes.into_iter().map(|(_, change)| change).collect())
+    }
+
+    /// Returns an iterator over the key-value pairs within the specified range as they were
+    /// right after the write with sequence number `sequence`, such as one returned by
+    /// [`Engine::last_sequence`](crate::Engine::last_sequence), so that a stable view can be read
+    /// while writes go on. The log is replayed up to that write, which takes time proportional
+    /// to its size.
+    ///
+    /// Compaction drops the values that later writes overwrote or deleted, after which reading
+    /// as of earlier sequence numbers fails with [`Error::Compacted`](crate::Error::Compacted).
+    /// Nothing is known about compactions before the database was opened, so reads as of
+    /// sequence numbers from before then fail as well. Keys that have expired since are left out.
+    pub async fn scan_at(&self, range: impl RangeBounds<Vec<u8>>, sequence: u64) -> Result<Pairs> {
+        changes::scan_at(&self.engine, self.keyspace.id, &range, sequence)
+    }
+
+    /// Returns the number of keys in the tree. Keys that have expired but have not been
+    /// removed yet are still counted.
+    pub fn len(&self) -> usize {
+        // No key is counted if the replay failed.
+        let _ = self.engine.replayed();
+        self.keyspace.key_map.read().unwrap().len()
+    }
+
+    /// Returns true if the tree holds no keys.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns statistics about the keys of the tree, computed from its key map without reading
+    /// the log.
+    pub fn stats(&self) -> TreeStats {
+        // Nothing is counted if the replay failed.
+        let _ = self.engine.replayed();
+        let mut stats = TreeStats::default();
+        for (key, entry) in self.keyspace.key_map.read().unwrap().iter() {
+            stats.keys += 1;
+            stats.expiring_keys += entry.expires_at.is_some() as u64;
+            stats.key_bytes += key.len() as u64;
+            stats.value_bytes += entry.value_len as u64;
+            stats.live_bytes += entry.log_size(self.keyspace.id, key.len());
+            stats.index_bytes += entry.footprint(key.len());
+        }
+        stats
+    }
+
+    /// Returns an iterator over every key-value pair of the tree, in key order, that fetches
+    /// them lazily. Iterating over `&tree` does the same.
+    pub fn iter(&self) -> Iter {
+        Iter::new(self.clone(), &(..))
+    }
+}
+
+impl fmt::Debug for Tree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tree")
+            .field("id", &self.keyspace.id)
+            .field("keys", &self.len())
+            .finish()
+    }
+}
+
+impl IntoIterator for &Tree {
+    type Item = Result<(Bytes, Bytes)>;
+    type IntoIter = Iter;
+
+    fn into_iter(self) -> Iter {
+        self.iter()
+    }
+}
+
+/// Returns the smallest key greater than every key starting with `prefix`, or `None` when no
+/// such key exists because the prefix is empty or consists only of `0xFF` bytes.
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|&b| b != 0xFF)?;
+    let mut end = prefix[..=last].to_vec();
+    end[last] += 1;
+    Some(end)
+}
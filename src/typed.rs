@@ -0,0 +1,137 @@
+//! Typed access to a tree through serde.
+//!
+//! A [`TypedTree`] serializes keys and values with bincode, so that structs and other serde
+//! types can be stored directly. Integers are encoded big-endian with a fixed width, so unsigned
+//! integer keys are scanned in numeric order; other keys are ordered by their encoding, for
+//! which [`keyencoding`](crate::keyencoding) gives more control.
+
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+use crate::tree::Tree;
+
+/// A handle to a [`Tree`] whose keys are of type `K` and whose values are of type `V`, returned
+/// by [`Tree::typed`]. Requires the `serde` feature.
+///
+/// Empty values delete keys, so values that serialize to no bytes at all, such as `()`, cannot
+/// be stored and are rejected with [`Error::Encode`].
+pub struct TypedTree<K, V> {
+    tree: Tree,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> Clone for TypedTree<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            tree: self.tree.clone(),
+            types: PhantomData,
+        }
+    }
+}
+
+impl<K, V> TypedTree<K, V> {
+    /// Wraps `tree`, whose keys and values must have been written as `K` and `V`.
+    pub fn new(tree: Tree) -> Self {
+        Self {
+            tree,
+            types: PhantomData,
+        }
+    }
+
+    /// Returns the underlying tree, which reads and writes the encoded bytes.
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// Returns the number of keys in the tree.
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Returns true if the tree holds no keys.
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+}
+
+impl<K: Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> TypedTree<K, V> {
+    /// Retrieves and deserializes the value of `key`.
+    pub async fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.tree.get(&encode(key)?).await? {
+            Some(value) => Ok(Some(decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns true if the tree holds `key`.
+    pub async fn contains_key(&self, key: &K) -> Result<bool> {
+        self.tree.contains_key(&encode(key)?).await
+    }
+
+    /// Serializes `value` and stores it for `key`.
+    pub async fn put(&self, key: &K, value: &V) -> Result<()> {
+        self.tree.set(&encode(key)?, encode_value(value)?).await
+    }
+
+    /// Serializes `value` and stores it for `key` so that it expires after `ttl`.
+    pub async fn put_with_ttl(&self, key: &K, value: &V, ttl: Duration) -> Result<()> {
+        self.tree.set_with_ttl(&encode(key)?, encode_value(value)?, ttl).await
+    }
+
+    /// Deletes `key` if it exists.
+    pub async fn del(&self, key: &K) -> Result<()> {
+        self.tree.del(&encode(key)?).await
+    }
+
+    /// Returns the pairs whose encoded key falls within the encoded bounds of `range`, in the
+    /// order of the encoded keys.
+    pub async fn scan(&self, range: impl RangeBounds<K>) -> Result<Vec<(K, V)>> {
+        let encode_bound = |bound: Bound<&K>| -> Result<Bound<Vec<u8>>> {
+            Ok(match bound {
+                Bound::Included(key) => Bound::Included(encode(key)?),
+                Bound::Excluded(key) => Bound::Excluded(encode(key)?),
+                Bound::Unbounded => Bound::Unbounded,
+            })
+        };
+        let bounds = (encode_bound(range.start_bound())?, encode_bound(range.end_bound())?);
+        self.tree
+            .scan(bounds)
+            .await?
+            .map(|(key, value)| Ok((decode(&key)?, decode(&value)?)))
+            .collect()
+    }
+}
+
+impl Tree {
+    /// Returns a handle to the tree that stores keys of type `K` and values of type `V`,
+    /// serialized with serde. Requires the `serde` feature.
+    pub fn typed<K, V>(&self) -> TypedTree<K, V> {
+        TypedTree::new(self.clone())
+    }
+}
+
+fn options() -> impl Options {
+    bincode::options().with_big_endian().with_fixint_encoding()
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    options().serialize(value).map_err(|e| Error::Encode(e.to_string()))
+}
+
+fn encode_value<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let encoded = encode(value)?;
+    if encoded.is_empty() {
+        return Err(Error::Encode("value serializes to no bytes".to_string()));
+    }
+    Ok(encoded)
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    options().deserialize(bytes).map_err(|e| Error::Decode(e.to_string()))
+}
@@ -0,0 +1,102 @@
+//! Typed values and their order-preserving encoding.
+//!
+//! Rows of [`Value`]s are encoded so that comparing the encoded bytes gives the same order as
+//! comparing the rows value by value, which makes the encoding suitable for composite keys as
+//! well as for storing rows. Each value is tagged with its type and otherwise encoded as
+//! described in [`keyencoding`](crate::keyencoding). Values of different types order as
+//! `Null < Bool < Int < Real < Text < Blob`, and a row that is a prefix of another sorts
+//! before it.
+
+use crate::error::{Error, Result};
+use crate::keyencoding::{DecodeKey, Key};
+
+/// A typed value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+const NULL_TAG: u8 = 0x01;
+const FALSE_TAG: u8 = 0x02;
+const TRUE_TAG: u8 = 0x03;
+const INT_TAG: u8 = 0x04;
+const REAL_TAG: u8 = 0x05;
+const TEXT_TAG: u8 = 0x06;
+const BLOB_TAG: u8 = 0x07;
+
+/// Encodes `values` into bytes that sort in the same order as the values.
+pub fn encode_row(values: &[Value]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for value in values {
+        value.encode_into(&mut buffer);
+    }
+    buffer
+}
+
+/// Decodes a row encoded by [`encode_row`].
+pub fn decode_row(mut bytes: &[u8]) -> Result<Vec<Value>> {
+    let mut values = Vec::new();
+    while !bytes.is_empty() {
+        values.push(Value::decode_from(&mut bytes)?);
+    }
+    Ok(values)
+}
+
+impl Key for Value {
+    fn encode_into(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Value::Null => buffer.push(NULL_TAG),
+            Value::Bool(false) => buffer.push(FALSE_TAG),
+            Value::Bool(true) => buffer.push(TRUE_TAG),
+            Value::Int(i) => {
+                buffer.push(INT_TAG);
+                i.encode_into(buffer);
+            }
+            Value::Real(r) => {
+                buffer.push(REAL_TAG);
+                // Negative numbers have all bits flipped so that larger magnitudes sort first;
+                // positive numbers only have the sign bit flipped to sort after them.
+                let bits = r.to_bits();
+                let bits = if bits >> 63 == 1 { !bits } else { bits ^ (1 << 63) };
+                bits.encode_into(buffer);
+            }
+            Value::Text(s) => {
+                buffer.push(TEXT_TAG);
+                s.encode_into(buffer);
+            }
+            Value::Blob(b) => {
+                buffer.push(BLOB_TAG);
+                b.encode_into(buffer);
+            }
+        }
+    }
+}
+
+impl DecodeKey for Value {
+    fn decode_from(bytes: &mut &[u8]) -> Result<Value> {
+        let (&tag, rest) = bytes
+            .split_first()
+            .ok_or_else(|| Error::Decode("missing value".to_string()))?;
+        *bytes = rest;
+        let value = match tag {
+            NULL_TAG => Value::Null,
+            FALSE_TAG => Value::Bool(false),
+            TRUE_TAG => Value::Bool(true),
+            INT_TAG => Value::Int(i64::decode_from(bytes)?),
+            REAL_TAG => {
+                let bits = u64::decode_from(bytes)?;
+                let bits = if bits >> 63 == 1 { bits ^ (1 << 63) } else { !bits };
+                Value::Real(f64::from_bits(bits))
+            }
+            TEXT_TAG => Value::Text(String::decode_from(bytes)?),
+            BLOB_TAG => Value::Blob(Vec::decode_from(bytes)?),
+            tag => return Err(Error::Decode(format!("unknown value tag {}", tag))),
+        };
+        Ok(value)
+    }
+}
@@ -0,0 +1,197 @@
+//! Log appends through io_uring.
+//!
+//! With the `io-uring` feature on Linux, the log writer thread submits each batch of entries
+//! as a write to an io_uring instead of writing it itself, so it can gather the next batch
+//! while the kernel writes the previous ones. Batches are written at explicit offsets, so
+//! several can be in flight at once, and fsyncs are submitted to the same ring once every
+//! earlier write has completed. Kernels without io_uring support, or processes not allowed to
+//! use it, fall back to writing through a buffer like on other platforms.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+
+use io_uring::{opcode, types, IoUring};
+
+// Number of submissions the ring holds, which also bounds the writes in flight.
+const ENTRIES: u32 = 64;
+// User data identifying the completion of an fsync; writes are numbered from 0.
+const FSYNC: u64 = u64::MAX;
+
+// A write submitted to the ring, which owns its buffer until it has completed.
+struct InFlight {
+    data: Vec<u8>,
+    offset: u64,
+    // Bytes already written, when the kernel wrote the buffer in several parts.
+    written: usize,
+}
+
+/// Appends to a segment file through io_uring.
+pub(crate) struct Ring {
+    ring: IoUring,
+    file: File,
+    // Offset at which the next batch is written.
+    offset: u64,
+    in_flight: HashMap<u64, InFlight>,
+    next_id: u64,
+    // The outcome of the submitted fsync once it has completed.
+    synced: Option<io::Result<()>>,
+    // The first write that failed, reported by the next call.
+    failure: Option<io::Error>,
+}
+
+impl Ring {
+    /// Takes over `file`, which is opened for appending. Appends are made at explicit offsets
+    /// instead, since the kernel would otherwise append writes in the order they run rather
+    /// than the order they were submitted. Returns the file if the kernel does not support
+    /// io_uring or the file cannot be switched over.
+    pub(crate) fn new(file: File) -> std::result::Result<Self, File> {
+        let Ok(ring) = IoUring::new(ENTRIES) else {
+            return Err(file);
+        };
+        let Ok(metadata) = file.metadata() else {
+            return Err(file);
+        };
+        let fd = file.as_raw_fd();
+        // SAFETY: `fd` is a valid descriptor owned by `file`, and F_GETFL/F_SETFL only read and
+        // update its status flags.
+        let cleared = unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            flags >= 0 && libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_APPEND) == 0
+        };
+        if !cleared {
+            return Err(file);
+        }
+        Ok(Self {
+            ring,
+            file,
+            offset: metadata.len(),
+            in_flight: HashMap::new(),
+            next_id: 0,
+            synced: None,
+            failure: None,
+        })
+    }
+
+    /// Submits `data` to be written after the data submitted before it.
+    pub(crate) fn write(&mut self, data: Vec<u8>) -> io::Result<()> {
+        if data.is_empty() {
+            return self.take_failure();
+        }
+        while self.in_flight.len() >= ENTRIES as usize {
+            self.reap(1)?;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let offset = self.offset;
+        self.offset += data.len() as u64;
+        self.in_flight.insert(id, InFlight { data, offset, written: 0 });
+        self.submit_write(id)?;
+        self.reap(0)
+    }
+
+    /// Waits until every write submitted so far has completed.
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        while !self.in_flight.is_empty() {
+            self.reap(1)?;
+        }
+        self.take_failure()
+    }
+
+    /// Waits until every write submitted so far is durable on disk.
+    pub(crate) fn sync_data(&mut self) -> io::Result<()> {
+        self.flush()?;
+        let fsync = opcode::Fsync::new(types::Fd(self.file.as_raw_fd()))
+            .flags(types::FsyncFlags::DATASYNC)
+            .build()
+            .user_data(FSYNC);
+        self.push(&fsync)?;
+        while self.synced.is_none() {
+            self.reap(1)?;
+        }
+        self.synced.take().unwrap()
+    }
+
+    // Submits the part of write `id` that has not been written yet.
+    fn submit_write(&mut self, id: u64) -> io::Result<()> {
+        let write = &self.in_flight[&id];
+        let remaining = &write.data[write.written..];
+        let entry = opcode::Write::new(
+            types::Fd(self.file.as_raw_fd()),
+            remaining.as_ptr(),
+            remaining.len().min(u32::MAX as usize) as u32,
+        )
+        .offset(write.offset + write.written as u64)
+        .build()
+        .user_data(id);
+        self.push(&entry)
+    }
+
+    fn push(&mut self, entry: &io_uring::squeue::Entry) -> io::Result<()> {
+        // SAFETY: the buffer of a write is owned by `in_flight` and neither moved nor freed
+        // until its completion has been reaped, and `Drop` waits for every completion.
+        while unsafe { self.ring.submission().push(entry) }.is_err() {
+            self.ring.submit()?;
+        }
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    // Waits for at least `want` completions and handles every completion available.
+    fn reap(&mut self, want: usize) -> io::Result<()> {
+        if want > 0 {
+            match self.ring.submit_and_wait(want) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                result => {
+                    result?;
+                }
+            }
+        }
+        let completions: Vec<(u64, i32)> =
+            self.ring.completion().map(|cqe| (cqe.user_data(), cqe.result())).collect();
+        for (id, result) in completions {
+            if id == FSYNC {
+                self.synced = Some(match result {
+                    0.. => Ok(()),
+                    _ => Err(io::Error::from_raw_os_error(-result)),
+                });
+                continue;
+            }
+            let write = self.in_flight.get_mut(&id).unwrap();
+            let failure = match result {
+                0 => Some(io::Error::from(io::ErrorKind::WriteZero)),
+                1.. => {
+                    write.written += result as usize;
+                    if write.written < write.data.len() {
+                        self.submit_write(id)?;
+                        continue;
+                    }
+                    None
+                }
+                _ => Some(io::Error::from_raw_os_error(-result)),
+            };
+            self.in_flight.remove(&id);
+            if self.failure.is_none() {
+                self.failure = failure;
+            }
+        }
+        Ok(())
+    }
+
+    fn take_failure(&mut self) -> io::Result<()> {
+        match self.failure.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // The kernel may still be reading the buffers of writes in flight, which are leaked
+        // rather than freed if their completions cannot be waited for.
+        while !self.in_flight.is_empty() && self.reap(1).is_ok() {}
+        std::mem::forget(std::mem::take(&mut self.in_flight));
+    }
+}
@@ -0,0 +1,233 @@
+//! Offline checking and repair of a database's log.
+//!
+//! Every segment listed in the manifest is decoded entry by entry. An entry that cannot be
+//! framed, or whose checksum does not match, starts a corrupt range that extends up to the next
+//! entry with an intact checksum. Repairing a segment rewrites it with only its intact entries,
+//! which truncates corrupt tails and skips corrupt ranges in the middle.
+//!
+//! Open engines can scrub their sealed segments the same way, periodically with
+//! [`EngineOptions::scrub_interval`](crate::EngineOptions::scrub_interval), to find corruption
+//! before reads run into it.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::engine::Inner;
+use crate::error::Result;
+use crate::index;
+use crate::log::{self, ParseError};
+use crate::tree::META_TREE;
+
+/// A range of a segment file whose entries could not be read back.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Corruption {
+    /// Id of the segment, which names its file.
+    pub segment: u64,
+    /// Offset of the range within the segment.
+    pub offset: u64,
+    /// Length of the range, which reaches up to the next intact entry or the end of the segment.
+    pub len: u64,
+    /// What is wrong with the first entry of the range.
+    pub reason: String,
+}
+
+/// A key whose latest write may have been lost with a corrupt range.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LostKey {
+    /// Name of the tree the key belongs to, or `None` for the default tree. Trees whose name
+    /// could not be recovered are named `#ID` after their internal id.
+    pub tree: Option<String>,
+    pub key: Vec<u8>,
+}
+
+/// Outcome of [`Engine::verify`](crate::Engine::verify) or
+/// [`Engine::repair`](crate::Engine::repair).
+#[derive(Clone, Debug, Default)]
+pub struct Verification {
+    /// Number of segments checked.
+    pub segments: usize,
+    /// Number of intact entries found.
+    pub entries: u64,
+    /// Corrupt ranges, in log order.
+    pub corruptions: Vec<Corruption>,
+    /// Keys of the entries at the start of corrupt ranges, as far as they could be read. A
+    /// corrupt range may hold entries of further keys that cannot be told apart.
+    pub lost_keys: Vec<LostKey>,
+    /// Whether the corrupt ranges were removed from the log.
+    pub repaired: bool,
+}
+
+impl Verification {
+    /// Returns true if no corruption was found.
+    pub fn is_ok(&self) -> bool {
+        self.corruptions.is_empty()
+    }
+}
+
+/// Checks the log of the database in `dir`, rewriting the segments holding corrupt ranges if
+/// `repair` is set. The directory is locked like an open database, shared unless repairing.
+pub(crate) fn verify(dir: &Path, repair: bool) -> Result<Verification> {
+    let _lock = log::lock_dir(dir, !repair)?;
+    // Rewriting a segment moves the entries after its corrupt ranges, so an index snapshot
+    // would no longer point at them.
+    if repair {
+        index::remove(dir)?;
+    }
+    let (ids, ..) = log::parse_manifest(&std::fs::read_to_string(dir.join(log::MANIFEST))?)?;
+    let mut checker = Checker::default();
+    for id in ids {
+        let path = log::segment_path(dir, id);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                checker.verification.segments += 1;
+                checker.verification.corruptions.push(Corruption {
+                    segment: id,
+                    offset: 0,
+                    len: 0,
+                    reason: "segment file is missing".to_string(),
+                });
+                if repair {
+                    File::create(&path)?.sync_all()?;
+                }
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let intact = checker.check_segment(id, &data);
+        if repair && intact.iter().map(|range| range.len() as u64).sum::<u64>() < data.len() as u64 {
+            rewrite_segment(dir, id, intact.into_iter().map(|range| &data[range]))?;
+        }
+    }
+    let mut verification = checker.finish();
+    verification.repaired = repair && !verification.corruptions.is_empty();
+    Ok(verification)
+}
+
+/// Checks the sealed segments of the log of an open engine like [`verify`], recording the
+/// first corrupt range found so that [`Engine::health`](crate::Engine::health) reports it. The
+/// active segment is left out, as it is still being written.
+pub(crate) fn scrub(engine: &Inner) -> Result<Verification> {
+    let segments = engine.log.segments();
+    let mut checker = Checker::default();
+    for segment in &segments[..segments.len() - 1] {
+        let data = match std::fs::read(engine.log.segment_path(segment.id)) {
+            Ok(data) => data,
+            // Compaction removed the segment since it was listed.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        checker.check_segment(segment.id, &data);
+    }
+    let verification = checker.finish();
+    if let Some(corruption) = verification.corruptions.first() {
+        engine.corruption.lock().unwrap().get_or_insert_with(|| corruption.clone());
+    }
+    Ok(verification)
+}
+
+// Gathers the outcome of checking segments one after the other.
+#[derive(Default)]
+struct Checker {
+    verification: Verification,
+    // Names of the trees found in intact entries, by id.
+    tree_names: HashMap<u32, Vec<u8>>,
+    // Trees and keys of the entries at the start of corrupt ranges.
+    lost: Vec<(u32, Vec<u8>)>,
+}
+
+impl Checker {
+    // Checks the entries of segment `id`, whose contents are `data`, and returns the ranges of
+    // its intact entries.
+    fn check_segment(&mut self, id: u64, data: &[u8]) -> Vec<Range<usize>> {
+        self.verification.segments += 1;
+        let len = data.len() as u64;
+        let mut intact = Vec::new();
+        let mut pos = 0;
+        while pos < len {
+            let reason = match log::decode_record(data, pos) {
+                Ok((record, end, true)) => {
+                    if record.tree == META_TREE && !record.deletes_range {
+                        if let Ok(id) = <[u8; 4]>::try_from(record.value.as_slice()) {
+                            self.tree_names.insert(u32::from_be_bytes(id), record.key);
+                        }
+                    }
+                    self.verification.entries += 1;
+                    intact.push(pos as usize..end as usize);
+                    pos = end;
+                    continue;
+                }
+                Ok((record, _, false)) => {
+                    self.lost.push((record.tree, record.key));
+                    ParseError::ChecksumMismatch { offset: pos }.to_string()
+                }
+                Err(e) => e.to_string(),
+            };
+            let next = next_intact(data, pos + 1);
+            self.verification.corruptions.push(Corruption {
+                segment: id,
+                offset: pos,
+                len: next - pos,
+                reason,
+            });
+            pos = next;
+        }
+        intact
+    }
+
+    // Returns the outcome, naming the trees of the lost keys.
+    fn finish(self) -> Verification {
+        let tree_names = self.tree_names;
+        let lost_keys = self
+            .lost
+            .into_iter()
+            .map(|(tree, key)| {
+                let tree = match tree {
+                    0 => None,
+                    id => Some(match tree_names.get(&id) {
+                        Some(name) => String::from_utf8_lossy(name).into_owned(),
+                        None => format!("#{}", id),
+                    }),
+                };
+                LostKey { tree, key }
+            })
+            .collect();
+        Verification {
+            lost_keys,
+            ..self.verification
+        }
+    }
+}
+
+// Returns the offset of the first entry at or after `pos` whose checksum matches, or the end
+// of `data` if there is none. Entries without a checksum cannot be trusted to be aligned.
+fn next_intact(data: &[u8], mut pos: u64) -> u64 {
+    let len = data.len() as u64;
+    while pos < len {
+        if let Ok((record, _, true)) = log::decode_record(data, pos) {
+            if record.checksum {
+                return pos;
+            }
+        }
+        pos += 1;
+    }
+    len
+}
+
+// Replaces segment `id` with the concatenation of `entries`. The new contents are written to a
+// temporary file first, so a crash leaves either the old segment or the repaired one.
+fn rewrite_segment<'a>(dir: &Path, id: u64, entries: impl Iterator<Item = &'a [u8]>) -> Result<()> {
+    let path = log::segment_path(dir, id);
+    let tmp_path = path.with_extension("repair");
+    let mut tmp = File::create(&tmp_path)?;
+    for entry in entries {
+        tmp.write_all(entry)?;
+    }
+    tmp.sync_all()?;
+    std::fs::rename(tmp_path, path)?;
+    log::sync_dir(dir)?;
+    Ok(())
+}
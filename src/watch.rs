@@ -0,0 +1,192 @@
+//! Change notifications.
+//!
+//! Each tree keeps a list of watchers, one per stream returned by [`Tree::watch`]. Writes push an
+//! [`Event`] to every watcher whose [`Filter`] accepts it while the write lock is held, so events
+//! arrive in the order the writes were committed, and events a watcher is not interested in are
+//! never queued for it.
+//!
+//! [`Tree::watch`]: crate::Tree::watch
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::{Bound, RangeBounds};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+
+use bytes::Bytes;
+use futures_core::Stream;
+
+use crate::order::{self, Compare};
+
+/// Kind of write that produced an [`Event`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Set,
+    Del,
+}
+
+/// A committed change to a watched key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub key: Bytes,
+    /// The value before the change, or `None` if the key did not exist.
+    pub old_value: Option<Bytes>,
+    /// The value after the change, or `None` if the key was deleted.
+    pub new_value: Option<Bytes>,
+    pub op: Op,
+}
+
+/// Selects the events a watcher receives; see [`Tree::watch`](crate::Tree::watch).
+#[derive(Clone)]
+pub struct Filter(Matcher);
+
+#[derive(Clone)]
+enum Matcher {
+    Prefix(Bytes),
+    Range(Bound<Bytes>, Bound<Bytes>),
+    Predicate(Arc<dyn Fn(&Event) -> bool + Send + Sync>),
+}
+
+impl Filter {
+    /// Accepts the events of keys starting with `prefix`.
+    pub fn prefix(prefix: &[u8]) -> Self {
+        Self(Matcher::Prefix(Bytes::copy_from_slice(prefix)))
+    }
+
+    /// Accepts the events of keys within `range`, in the ordering of the tree's keys.
+    pub fn range(range: impl RangeBounds<Vec<u8>>) -> Self {
+        let bound = |bound: Bound<&Vec<u8>>| bound.map(|key| Bytes::copy_from_slice(key));
+        Self(Matcher::Range(bound(range.start_bound()), bound(range.end_bound())))
+    }
+
+    /// Accepts the events for which `f` returns true. It is called while the write lock is held,
+    /// so it must be quick and must not use the engine. Unlike the other filters, it needs the
+    /// event to be built first, which reads the old value of every key written.
+    pub fn predicate(f: impl Fn(&Event) -> bool + Send + Sync + 'static) -> Self {
+        Self(Matcher::Predicate(Arc::new(f)))
+    }
+
+    // Returns false if no event for `key` can be accepted, so that it need not be built. Keys
+    // are ordered by `order`.
+    fn may_accept(&self, key: &[u8], order: Option<Compare>) -> bool {
+        match &self.0 {
+            Matcher::Prefix(prefix) => key.starts_with(prefix),
+            Matcher::Range(start, end) => {
+                let bounds = (start.as_ref().map(|s| &s[..]), end.as_ref().map(|e| &e[..]));
+                order::contains(order, bounds, key)
+            }
+            Matcher::Predicate(_) => true,
+        }
+    }
+
+    fn accepts(&self, event: &Event, order: Option<Compare>) -> bool {
+        match &self.0 {
+            Matcher::Predicate(f) => f(event),
+            _ => self.may_accept(&event.key, order),
+        }
+    }
+}
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Matcher::Prefix(prefix) => f.debug_tuple("Prefix").field(prefix).finish(),
+            Matcher::Range(start, end) => f.debug_tuple("Range").field(start).field(end).finish(),
+            Matcher::Predicate(_) => f.write_str("Predicate"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    events: VecDeque<Event>,
+    waker: Option<Waker>,
+    // Set once the tree is gone and no more events can arrive.
+    closed: bool,
+}
+
+/// Watchers registered on a tree.
+pub(crate) struct Watchers {
+    // Filters along with the queue of their stream; dropped streams are pruned lazily.
+    list: Mutex<Vec<(Filter, Weak<Mutex<Queue>>)>>,
+    // Ordering of the tree's keys, which range filters follow.
+    order: Option<Compare>,
+}
+
+impl Watchers {
+    pub(crate) fn new(order: Option<Compare>) -> Self {
+        Self {
+            list: Mutex::new(Vec::new()),
+            order,
+        }
+    }
+
+    pub(crate) fn watch(&self, filter: Filter) -> WatchStream {
+        let queue = Arc::new(Mutex::new(Queue::default()));
+        let mut list = self.list.lock().unwrap();
+        list.retain(|(_, queue)| queue.strong_count() > 0);
+        list.push((filter, Arc::downgrade(&queue)));
+        WatchStream { queue }
+    }
+
+    /// Returns true if any watcher may be interested in `key`.
+    pub(crate) fn is_watched(&self, key: &[u8]) -> bool {
+        let list = self.list.lock().unwrap();
+        list.iter().any(|(filter, _)| filter.may_accept(key, self.order))
+    }
+
+    /// Delivers `event` to every watcher whose filter accepts it.
+    pub(crate) fn notify(&self, event: Event) {
+        let mut list = self.list.lock().unwrap();
+        list.retain(|(filter, queue)| {
+            let Some(queue) = queue.upgrade() else {
+                return false;
+            };
+            if filter.accepts(&event, self.order) {
+                let mut queue = queue.lock().unwrap();
+                queue.events.push_back(event.clone());
+                if let Some(waker) = queue.waker.take() {
+                    waker.wake();
+                }
+            }
+            true
+        });
+    }
+}
+
+impl Drop for Watchers {
+    fn drop(&mut self) {
+        for (_, queue) in self.list.get_mut().unwrap().drain(..) {
+            if let Some(queue) = queue.upgrade() {
+                let mut queue = queue.lock().unwrap();
+                queue.closed = true;
+                if let Some(waker) = queue.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+/// Stream of the events delivered to one watcher. Events are queued until the stream is
+/// polled, and the stream ends once its tree has been dropped.
+pub(crate) struct WatchStream {
+    queue: Arc<Mutex<Queue>>,
+}
+
+impl Stream for WatchStream {
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(event) = queue.events.pop_front() {
+            return Poll::Ready(Some(event));
+        }
+        if queue.closed {
+            return Poll::Ready(None);
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
@@ -0,0 +1,23 @@
+//! The log format of TegDB and a key-value store over a single log, usable without std.
+//!
+//! [`record`] frames, checksums and parses log entries the way the `tegdb` engine writes them,
+//! and is the only part of this crate the engine uses. [`Store`] is a separate, much smaller
+//! store that keeps an index of the keys in a single log over any [`Storage`], such as a flash
+//! partition on an embedded target or a file in the browser. The engine does not go through
+//! [`Storage`]: it keeps its segments, manifest and index snapshots in files of its directory.
+//! The crate only needs `core` and `alloc` when its `std` feature, which implements
+//! [`Storage`] for files, is disabled.
+
+#![no_std]
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod record;
+mod storage;
+mod store;
+
+pub use record::{parse_records, Codec, ParseError, Record, Records, Timestamps};
+pub use storage::{MemoryStorage, OutOfBounds, Storage};
+pub use store::{Error, Store};
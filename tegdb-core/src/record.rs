@@ -0,0 +1,468 @@
+//! Framing of log entries.
+//!
+//! Each entry starts with the lengths of its key and value, whose top bits flag the optional
+//! fields the entry carries, followed by those fields, the key and the value, and ends with its
+//! sequence number, timestamps and a CRC-32 of its preceding bytes. Every field is big-endian.
+//! Entries are written back to back, so a log is parsed from its start.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+// Set in the key length of entries that carry an expiration time after their lengths.
+const EXPIRES_FLAG: u32 = 1 << 31;
+// Set in the key length of entries that belong to a tree other than the default one.
+const TREE_FLAG: u32 = 1 << 30;
+/// Set in the key length of range tombstones.
+pub const RANGE_FLAG: u32 = 1 << 29;
+// Set in the key length of entries whose value is compressed; a byte naming the codec
+// follows the expiration time.
+const COMPRESSED_FLAG: u32 = 1 << 28;
+// Set in the key length of entries followed by their sequence number, which is stored after
+// the value so that values are found at the same offset either way.
+const SEQUENCE_FLAG: u32 = 1 << 27;
+/// Set in the key length of entries ending with a CRC-32 of all their preceding bytes.
+pub const CHECKSUM_FLAG: u32 = 1 << 26;
+const FLAGS: u32 =
+    EXPIRES_FLAG | TREE_FLAG | RANGE_FLAG | COMPRESSED_FLAG | SEQUENCE_FLAG | CHECKSUM_FLAG;
+// Set in the sequence number of entries followed by the times they were created and modified.
+// The key length has no bits left to spare, and sequence numbers never get anywhere near it.
+const TIMESTAMPS_FLAG: u64 = 1 << 63;
+/// Longest key an entry can hold, since key lengths share their field with the flags above.
+pub const MAX_KEY_LEN: usize = (1 << 26) - 1;
+/// Longest value an entry can hold.
+pub const MAX_VALUE_LEN: usize = u32::MAX as usize;
+
+/// A decoded log entry. An empty value marks the key as deleted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Id of the tree the key belongs to; 0 is the default tree.
+    pub tree: u32,
+    /// The key, or the start of the range deleted by a range tombstone.
+    pub key: Vec<u8>,
+    /// The value as stored, which is compressed unless `codec` is [`Codec::None`].
+    pub value: Vec<u8>,
+    /// Milliseconds since the Unix epoch after which the entry no longer exists.
+    pub expires_at: Option<u64>,
+    /// How the value is stored.
+    pub codec: Codec,
+    /// Position of the write in the order of all writes, or 0 for entries written before
+    /// sequence numbers were introduced.
+    pub sequence: u64,
+    /// Whether the entry ends with a checksum, which entries written before checksums were
+    /// introduced lack.
+    pub checksum: bool,
+    /// When the key was created and last modified, if the entry records it.
+    pub timestamps: Option<Timestamps>,
+    /// Whether the entry is a range tombstone, deleting every key of its tree from `key` up to
+    /// but excluding `value`. An empty `value` leaves the range unbounded.
+    pub deletes_range: bool,
+}
+
+impl Record {
+    /// Returns true if the entry deletes its key, either explicitly or by having expired at `now`.
+    pub fn is_deletion(&self, now: u64) -> bool {
+        self.value.is_empty() || self.expires_at.is_some_and(|t| t <= now)
+    }
+}
+
+/// When a key was created and last modified, in milliseconds since the Unix epoch. The engine
+/// only records them when its `record_timestamps` option is set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timestamps {
+    /// When the key was set after not existing.
+    pub created_at: u64,
+    /// When the key was last set.
+    pub updated_at: u64,
+}
+
+/// Codec a value is stored with. It is recorded in each entry, so compressed and uncompressed
+/// entries can be mixed within one log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// The value is stored as it is.
+    None,
+    /// The value is compressed with LZ4.
+    Lz4,
+    /// The value is compressed with Zstandard.
+    Zstd,
+    /// The value is a manifest listing the chunks a large value was split into.
+    Chunked,
+}
+
+impl Codec {
+    /// Returns the codec recorded as `byte` in compressed or chunked entries, if it is known.
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(Codec::Lz4),
+            2 => Some(Codec::Zstd),
+            3 => Some(Codec::Chunked),
+            _ => None,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+            Codec::Chunked => 3,
+        }
+    }
+}
+
+/// Returns the number of bytes a record occupies in the log.
+pub fn entry_size(record: &Record) -> u64 {
+    let (value_len, expires_at, codec) = (record.value.len() as u32, record.expires_at, record.codec);
+    let (sequence, timestamps, checksum) = (record.sequence, record.timestamps.is_some(), record.checksum);
+    entry_size_of(record.tree, record.key.len(), value_len, expires_at, codec, sequence, timestamps, checksum)
+}
+
+/// Returns the number of bytes an entry with the given key and stored value lengths occupies
+/// in the log.
+#[allow(clippy::too_many_arguments)]
+pub fn entry_size_of(
+    tree: u32,
+    key_len: usize,
+    value_len: u32,
+    expires_at: Option<u64>,
+    codec: Codec,
+    sequence: u64,
+    timestamps: bool,
+    checksum: bool,
+) -> u64 {
+    header_len(tree, expires_at, codec)
+        + key_len as u64
+        + value_len as u64
+        + trailer_len(sequence, timestamps, checksum)
+}
+
+// Length of the fields following an entry's value: its sequence number, timestamps and
+// checksum, when the entry carries them.
+fn trailer_len(sequence: u64, timestamps: bool, checksum: bool) -> u64 {
+    let mut len = 0;
+    if sequence != 0 {
+        len += 8;
+    }
+    if timestamps {
+        len += 16;
+    }
+    if checksum {
+        len += 4;
+    }
+    len
+}
+
+/// Returns the length of the fields preceding an entry's key: the key and value lengths,
+/// followed by the tree id, expiration time and codec when the entry carries them.
+pub fn header_len(tree: u32, expires_at: Option<u64>, codec: Codec) -> u64 {
+    let mut len = 4 + 4;
+    if tree != 0 {
+        len += 4;
+    }
+    if expires_at.is_some() {
+        len += 8;
+    }
+    if codec != Codec::None {
+        len += 1;
+    }
+    len
+}
+
+/// Serializes a record read back from the log into its on-disk representation.
+pub fn encode_record(record: &Record) -> Vec<u8> {
+    let mut flags = 0;
+    if record.deletes_range {
+        flags |= RANGE_FLAG;
+    }
+    if record.checksum {
+        flags |= CHECKSUM_FLAG;
+    }
+    let (value, expires_at, codec) = (&record.value, record.expires_at, record.codec);
+    encode(record.tree, &record.key, value, expires_at, codec, record.sequence, record.timestamps, flags)
+}
+
+/// Serializes an entry, setting the key length `flags` ([`RANGE_FLAG`] and [`CHECKSUM_FLAG`])
+/// along with those of the fields it carries. Timestamps are only written along with a sequence
+/// number, whose top bit flags them.
+#[allow(clippy::too_many_arguments)]
+pub fn encode(
+    tree: u32,
+    key: &[u8],
+    value: &[u8],
+    expires_at: Option<u64>,
+    codec: Codec,
+    sequence: u64,
+    timestamps: Option<Timestamps>,
+    flags: u32,
+) -> Vec<u8> {
+    let timestamps = timestamps.filter(|_| sequence != 0);
+    let mut key_len = key.len() as u32 | flags;
+    if tree != 0 {
+        key_len |= TREE_FLAG;
+    }
+    if expires_at.is_some() {
+        key_len |= EXPIRES_FLAG;
+    }
+    if codec != Codec::None {
+        key_len |= COMPRESSED_FLAG;
+    }
+    if sequence != 0 {
+        key_len |= SEQUENCE_FLAG;
+    }
+    let value_len = value.len() as u32;
+    let checksum = flags & CHECKSUM_FLAG != 0;
+    let size =
+        entry_size_of(tree, key.len(), value_len, expires_at, codec, sequence, timestamps.is_some(), checksum);
+    let mut buffer = Vec::with_capacity(size as usize);
+    buffer.extend_from_slice(&key_len.to_be_bytes());
+    buffer.extend_from_slice(&value_len.to_be_bytes());
+    if tree != 0 {
+        buffer.extend_from_slice(&tree.to_be_bytes());
+    }
+    if let Some(expires_at) = expires_at {
+        buffer.extend_from_slice(&expires_at.to_be_bytes());
+    }
+    if codec != Codec::None {
+        buffer.push(codec.to_byte());
+    }
+    buffer.extend_from_slice(key);
+    buffer.extend_from_slice(value);
+    if let Some(timestamps) = timestamps {
+        buffer.extend_from_slice(&(sequence | TIMESTAMPS_FLAG).to_be_bytes());
+        buffer.extend_from_slice(&timestamps.created_at.to_be_bytes());
+        buffer.extend_from_slice(&timestamps.updated_at.to_be_bytes());
+    } else if sequence != 0 {
+        buffer.extend_from_slice(&sequence.to_be_bytes());
+    }
+    if checksum {
+        buffer.extend_from_slice(&crc32(0, &buffer).to_be_bytes());
+    }
+    buffer
+}
+
+/// Returns the CRC-32 (IEEE) of `data`, continued from the checksum `crc` of the bytes before it.
+/// Eight bytes are folded in at a time through lookup tables, as checksumming every record
+/// would otherwise dominate the time taken to replay the log.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let table = |t: usize, byte: u32| CRC_TABLES[t][(byte & 0xFF) as usize];
+    let mut crc = !crc;
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let low = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) ^ crc;
+        let high = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+        crc = table(7, low) ^ table(6, low >> 8) ^ table(5, low >> 16) ^ table(4, low >> 24)
+            ^ table(3, high) ^ table(2, high >> 8) ^ table(1, high >> 16) ^ table(0, high >> 24);
+    }
+    for &byte in chunks.remainder() {
+        crc = (crc >> 8) ^ table(0, crc ^ byte as u32);
+    }
+    !crc
+}
+
+// `CRC_TABLES[0][b]` is the remainder of the byte `b` divided by the reflected IEEE polynomial,
+// and `CRC_TABLES[t][b]` that of `b` followed by `t` zero bytes.
+const CRC_TABLES: [[u32; 256]; 8] = crc_tables();
+
+const fn crc_tables() -> [[u32; 256]; 8] {
+    let mut tables = [[0; 256]; 8];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+            bit += 1;
+        }
+        tables[0][byte] = crc;
+        byte += 1;
+    }
+    let mut t = 1;
+    while t < 8 {
+        let mut byte = 0;
+        while byte < 256 {
+            let previous = tables[t - 1][byte];
+            tables[t][byte] = (previous >> 8) ^ tables[0][(previous & 0xFF) as usize];
+            byte += 1;
+        }
+        t += 1;
+    }
+    tables
+}
+
+/// Why [`parse_records`] could not parse a record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The record at `offset` runs past the end of the data.
+    Truncated { offset: u64 },
+    /// The record at `offset` does not match its checksum.
+    ChecksumMismatch { offset: u64 },
+    /// The record at `offset` names a codec that does not exist.
+    UnknownCodec { offset: u64, codec: u8 },
+}
+
+impl ParseError {
+    /// Returns the offset of the record that could not be parsed.
+    pub fn offset(&self) -> u64 {
+        match *self {
+            ParseError::Truncated { offset }
+            | ParseError::ChecksumMismatch { offset }
+            | ParseError::UnknownCodec { offset, .. } => offset,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Truncated { offset } => write!(f, "truncated record at offset {}", offset),
+            ParseError::ChecksumMismatch { offset } => {
+                write!(f, "checksum mismatch in record at offset {}", offset)
+            }
+            ParseError::UnknownCodec { offset, codec } => {
+                write!(f, "unknown codec {} in record at offset {}", codec, offset)
+            }
+        }
+    }
+}
+
+impl core::error::Error for ParseError {}
+
+/// Parses the records stored back to back in `data`, such as the contents of a segment file.
+/// This does no I/O and never panics, whatever `data` holds, so it can be fuzzed or used to
+/// inspect logs from other tools. Records whose checksum does not match are reported as
+/// errors, and parsing stops after the first error, as the bytes after it cannot be framed
+/// reliably.
+pub fn parse_records(data: &[u8]) -> Records<'_> {
+    Records { data, pos: 0 }
+}
+
+/// Iterator over the records of a log, returned by [`parse_records`].
+pub struct Records<'a> {
+    data: &'a [u8],
+    pos: u64,
+}
+
+impl Records<'_> {
+    /// Starts parsing at `offset`, which must be where a record begins.
+    pub fn start(mut self, offset: u64) -> Self {
+        self.pos = offset;
+        self
+    }
+
+    /// Returns the offset at which the next record starts.
+    pub fn offset(&self) -> u64 {
+        self.pos
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = Result<Record, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = self.data.len() as u64;
+        if self.pos >= len {
+            return None;
+        }
+        let offset = self.pos;
+        // Stop after the first error; the remaining bytes cannot be framed reliably.
+        self.pos = len;
+        Some(match decode_record(self.data, offset) {
+            Ok((record, end, true)) => {
+                self.pos = end;
+                Ok(record)
+            }
+            Ok((_, _, false)) => Err(ParseError::ChecksumMismatch { offset }),
+            Err(e) => Err(e),
+        })
+    }
+}
+
+/// Decodes the record starting at offset `pos` of `data`, returning it along with the offset
+/// it ends at and whether its checksum matches. Records without a checksum are taken as they
+/// are.
+pub fn decode_record(data: &[u8], pos: u64) -> Result<(Record, u64, bool), ParseError> {
+    let truncated = ParseError::Truncated { offset: pos };
+    let mut rest = data.get(pos as usize..).ok_or(truncated)?;
+    let key_len = read_u32(&mut rest).ok_or(truncated)?;
+    let value_len = read_u32(&mut rest).ok_or(truncated)?;
+    let tree = if key_len & TREE_FLAG != 0 {
+        read_u32(&mut rest).ok_or(truncated)?
+    } else {
+        0
+    };
+    let expires_at = if key_len & EXPIRES_FLAG != 0 {
+        Some(read_u64(&mut rest).ok_or(truncated)?)
+    } else {
+        None
+    };
+    let codec = if key_len & COMPRESSED_FLAG != 0 {
+        let byte = take(&mut rest, 1).ok_or(truncated)?[0];
+        Codec::from_byte(byte).ok_or(ParseError::UnknownCodec { offset: pos, codec: byte })?
+    } else {
+        Codec::None
+    };
+    let flags = key_len & FLAGS;
+    let key_len = key_len & !FLAGS;
+    let value_pos = pos + header_len(tree, expires_at, codec) + key_len as u64;
+    let has_sequence = flags & SEQUENCE_FLAG != 0;
+    let checksum = flags & CHECKSUM_FLAG != 0;
+    // The timestamps are not counted yet, as only the sequence number tells whether they follow.
+    let mut end = value_pos + value_len as u64 + if has_sequence { 8 } else { 0 } + if checksum { 4 } else { 0 };
+    if end > data.len() as u64 {
+        return Err(truncated);
+    }
+    let key = take(&mut rest, key_len as usize).ok_or(truncated)?.to_vec();
+    let value = take(&mut rest, value_len as usize).ok_or(truncated)?.to_vec();
+    let mut sequence = if has_sequence {
+        read_u64(&mut rest).ok_or(truncated)?
+    } else {
+        0
+    };
+    let timestamps = if sequence & TIMESTAMPS_FLAG != 0 {
+        sequence &= !TIMESTAMPS_FLAG;
+        end += 16;
+        Some(Timestamps {
+            created_at: read_u64(&mut rest).ok_or(truncated)?,
+            updated_at: read_u64(&mut rest).ok_or(truncated)?,
+        })
+    } else {
+        None
+    };
+    let intact = if checksum {
+        let covered = &data[pos as usize..end as usize - 4];
+        read_u32(&mut rest).ok_or(truncated)? == crc32(0, covered)
+    } else {
+        true
+    };
+    let record = Record {
+        tree,
+        key,
+        value,
+        expires_at,
+        codec,
+        sequence,
+        checksum,
+        timestamps,
+        deletes_range: flags & RANGE_FLAG != 0,
+    };
+    Ok((record, end, intact))
+}
+
+// Splits the first `len` bytes off `data`, or returns None if it holds fewer.
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if data.len() < len {
+        return None;
+    }
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+    Some(taken)
+}
+
+fn read_u32(data: &mut &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(take(data, 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &mut &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(take(data, 8)?.try_into().ok()?))
+}
+
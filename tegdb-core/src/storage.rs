@@ -0,0 +1,117 @@
+//! Where a [`Store`](crate::Store) keeps its log.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+/// An append-only byte log, such as a file, a flash partition or a browser file. Appends need
+/// not be durable until [`sync`](Storage::sync) returns.
+pub trait Storage {
+    /// The error returned by the storage's operations.
+    type Error: fmt::Debug + fmt::Display;
+
+    /// Returns the number of bytes in the log.
+    fn size(&self) -> Result<u64, Self::Error>;
+
+    /// Fills `buf` with the bytes of the log starting at `offset`, all of which were appended
+    /// before.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Appends `data` to the log.
+    fn append(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Waits until every byte appended so far is durable.
+    fn sync(&mut self) -> Result<(), Self::Error>;
+}
+
+/// The error of a [`MemoryStorage`]: a read of `len` bytes at `offset` that runs past the end
+/// of the log, which holds `size` bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("read of {len} bytes at offset {offset} runs past the end of a log of {size} bytes")]
+pub struct OutOfBounds {
+    pub offset: u64,
+    pub len: usize,
+    pub size: u64,
+}
+
+/// A log held in memory, for tests or for targets that persist it as a whole, such as to
+/// IndexedDB in a browser.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryStorage(Vec<u8>);
+
+impl MemoryStorage {
+    /// Returns an empty log.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the bytes of the log.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the bytes of the log, consuming it.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl From<Vec<u8>> for MemoryStorage {
+    fn from(data: Vec<u8>) -> Self {
+        Self(data)
+    }
+}
+
+impl Storage for MemoryStorage {
+    type Error = OutOfBounds;
+
+    fn size(&self) -> Result<u64, OutOfBounds> {
+        Ok(self.0.len() as u64)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), OutOfBounds> {
+        let out_of_bounds = OutOfBounds {
+            offset,
+            len: buf.len(),
+            size: self.0.len() as u64,
+        };
+        let start = usize::try_from(offset).map_err(|_| out_of_bounds)?;
+        let end = start.checked_add(buf.len()).ok_or(out_of_bounds)?;
+        buf.copy_from_slice(self.0.get(start..end).ok_or(out_of_bounds)?);
+        Ok(())
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<(), OutOfBounds> {
+        self.0.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), OutOfBounds> {
+        Ok(())
+    }
+}
+
+/// A file opened for reading and appending, such as a copy of a segment of the `tegdb` engine's
+/// log, which the engine itself writes without going through [`Storage`].
+#[cfg(feature = "std")]
+impl Storage for std::fs::File {
+    type Error = std::io::Error;
+
+    fn size(&self) -> std::io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        use std::io::{Read, Seek, SeekFrom};
+        let mut file = self;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+
+    fn append(&mut self, data: &[u8]) -> std::io::Result<()> {
+        std::io::Write::write_all(self, data)
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.sync_data()
+    }
+}
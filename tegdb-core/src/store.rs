@@ -0,0 +1,277 @@
+//! A key-value store over a single log.
+//!
+//! The store keeps the location of every live entry in a map held in memory, which it rebuilds
+//! by replaying the log when opened, like the `tegdb` engine does for each of its segments.
+//! Writes are appended to the log in the engine's format, and a log written by either can be
+//! read by the other, except that the store cannot decompress values, enforce expiration times
+//! without a clock or order keys with a custom comparator. Entries of trees other than the
+//! default one are kept as they are, so compacting the log preserves them.
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Bound;
+
+use crate::record::{self, Codec, ParseError, CHECKSUM_FLAG, MAX_KEY_LEN, MAX_VALUE_LEN};
+use crate::storage::Storage;
+
+/// Errors returned by a [`Store`] over a storage failing with `E`.
+#[derive(Debug, thiserror::Error)]
+pub enum Error<E> {
+    /// The storage failed.
+    #[error("storage error: {0}")]
+    Storage(E),
+    /// The log could not be parsed.
+    #[error("corrupted log: {0}")]
+    Corrupted(ParseError),
+    /// The entry at `offset` needs support the store lacks, such as a decompressor.
+    #[error("unsupported entry at offset {offset}: {reason}")]
+    Unsupported { offset: u64, reason: &'static str },
+    /// A key was longer than an entry can hold.
+    #[error("key of {len} bytes exceeds the limit of {limit} bytes")]
+    KeyTooLarge { len: usize, limit: usize },
+    /// A value was longer than an entry can hold.
+    #[error("value of {len} bytes exceeds the limit of {limit} bytes")]
+    ValueTooLarge { len: usize, limit: usize },
+}
+
+// A key of the index: a tree id and a key of that tree.
+type Key = (u32, Vec<u8>);
+
+// Where a live entry is in the log and how its value is stored.
+#[derive(Clone, Copy, Debug)]
+struct Slot {
+    offset: u64,
+    len: u64,
+    value_offset: u64,
+    value_len: u32,
+    codec: Codec,
+    expires_at: Option<u64>,
+}
+
+/// A key-value store keeping its log in a [`Storage`]. Keys are ordered bytewise, and writes
+/// are durable once [`sync`](Store::sync) returns.
+#[derive(Debug)]
+pub struct Store<S> {
+    storage: S,
+    // Live entries by tree id and key.
+    index: BTreeMap<Key, Slot>,
+    // Length of the log, and how many of its bytes the live entries take up.
+    len: u64,
+    live: u64,
+    // The last sequence number handed out.
+    sequence: u64,
+}
+
+impl<S: Storage> Store<S> {
+    /// Opens the log in `storage`, which may be empty, reading it into memory to replay it.
+    pub fn open(storage: S) -> Result<Self, Error<S::Error>> {
+        let len = storage.size().map_err(Error::Storage)?;
+        let mut data = vec![0; len as usize];
+        storage.read_at(0, &mut data).map_err(Error::Storage)?;
+        let mut store = Self {
+            storage,
+            index: BTreeMap::new(),
+            len,
+            live: 0,
+            sequence: 0,
+        };
+        let mut records = record::parse_records(&data);
+        loop {
+            let offset = records.offset();
+            let Some(record) = records.next() else {
+                break;
+            };
+            let record = record.map_err(Error::Corrupted)?;
+            store.sequence = store.sequence.max(record.sequence);
+            let (tree, key) = (record.tree, record.key);
+            if record.deletes_range {
+                store.remove_range(tree, &key, &record.value);
+            } else if record.value.is_empty() {
+                store.remove(tree, &key);
+            } else {
+                let (codec, expires_at) = (record.codec, record.expires_at);
+                let header_len = record::header_len(tree, expires_at, codec);
+                let slot = Slot {
+                    offset,
+                    len: records.offset() - offset,
+                    value_offset: offset + header_len + key.len() as u64,
+                    value_len: record.value.len() as u32,
+                    codec,
+                    expires_at,
+                };
+                store.insert(tree, key, slot);
+            }
+        }
+        Ok(store)
+    }
+
+    /// Returns the value of `key`, if it is set.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error<S::Error>> {
+        let Some(slot) = self.index.get(&(0, key.to_vec())) else {
+            return Ok(None);
+        };
+        if slot.codec != Codec::None {
+            let reason = "the value is compressed or split into chunks";
+            return Err(Error::Unsupported { offset: slot.offset, reason });
+        }
+        let mut value = vec![0; slot.value_len as usize];
+        self.storage.read_at(slot.value_offset, &mut value).map_err(Error::Storage)?;
+        Ok(Some(value))
+    }
+
+    /// Returns when `key` expires, in milliseconds since the Unix epoch, if it is set and was
+    /// written with an expiration time. Expired keys are still returned by the store, which
+    /// has no clock to tell that they have expired.
+    pub fn expires_at(&self, key: &[u8]) -> Option<u64> {
+        self.index.get(&(0, key.to_vec()))?.expires_at
+    }
+
+    /// Sets `key` to `value`. An empty value deletes the key.
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error<S::Error>> {
+        if value.is_empty() {
+            return self.del(key).map(|_| ());
+        }
+        if key.len() > MAX_KEY_LEN {
+            return Err(Error::KeyTooLarge { len: key.len(), limit: MAX_KEY_LEN });
+        }
+        if value.len() > MAX_VALUE_LEN {
+            return Err(Error::ValueTooLarge { len: value.len(), limit: MAX_VALUE_LEN });
+        }
+        let offset = self.append(key, value)?;
+        let slot = Slot {
+            offset,
+            len: self.len - offset,
+            value_offset: offset + record::header_len(0, None, Codec::None) + key.len() as u64,
+            value_len: value.len() as u32,
+            codec: Codec::None,
+            expires_at: None,
+        };
+        self.insert(0, key.to_vec(), slot);
+        Ok(())
+    }
+
+    /// Deletes `key`, returning whether it was set. Nothing is written if it was not.
+    pub fn del(&mut self, key: &[u8]) -> Result<bool, Error<S::Error>> {
+        if !self.index.contains_key(&(0, key.to_vec())) {
+            return Ok(false);
+        }
+        self.append(key, &[])?;
+        self.remove(0, key);
+        Ok(true)
+    }
+
+    /// Returns the keys that are set, in order.
+    pub fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.index.range(tree_range(0)).map(|((_, key), _)| key.as_slice())
+    }
+
+    /// Returns the number of keys that are set.
+    pub fn len(&self) -> usize {
+        self.index.range(tree_range(0)).count()
+    }
+
+    /// Returns true if no key is set.
+    pub fn is_empty(&self) -> bool {
+        self.index.range(tree_range(0)).next().is_none()
+    }
+
+    /// Returns the number of bytes in the log, and how many of them are taken up by entries
+    /// that have since been overwritten or deleted, which compacting the log reclaims.
+    pub fn space(&self) -> (u64, u64) {
+        (self.len, self.len - self.live)
+    }
+
+    /// Waits until every write made so far is durable.
+    pub fn sync(&mut self) -> Result<(), Error<S::Error>> {
+        self.storage.sync().map_err(Error::Storage)
+    }
+
+    /// Copies the live entries, in the order they were written, to `target`, which must be
+    /// empty, and returns a store over it. This store is left as it is, so the caller decides
+    /// when to switch over and discard its storage. `target` may be a different kind of storage,
+    /// such as memory to build the compacted log in before writing it out.
+    pub fn compact_into<T>(&self, mut target: T) -> Result<Store<T>, Error<S::Error>>
+    where
+        T: Storage,
+        S::Error: From<T::Error>,
+    {
+        let mut live: Vec<_> = self.index.iter().collect();
+        live.sort_by_key(|(_, slot)| slot.offset);
+        let mut index = BTreeMap::new();
+        let mut len = 0;
+        let mut entry = Vec::new();
+        for ((tree, key), slot) in live {
+            entry.resize(slot.len as usize, 0);
+            self.storage.read_at(slot.offset, &mut entry).map_err(Error::Storage)?;
+            target.append(&entry).map_err(|e| Error::Storage(e.into()))?;
+            let moved = Slot {
+                offset: len,
+                value_offset: slot.value_offset - slot.offset + len,
+                ..*slot
+            };
+            index.insert((*tree, key.clone()), moved);
+            len += slot.len;
+        }
+        target.sync().map_err(|e| Error::Storage(e.into()))?;
+        Ok(Store {
+            storage: target,
+            index,
+            len,
+            live: len,
+            sequence: self.sequence,
+        })
+    }
+
+    /// Returns the storage holding the log.
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    // Appends an entry setting `key` of the default tree to `value`, and returns its offset.
+    fn append(&mut self, key: &[u8], value: &[u8]) -> Result<u64, Error<S::Error>> {
+        let sequence = self.sequence + 1;
+        let entry = record::encode(0, key, value, None, Codec::None, sequence, None, CHECKSUM_FLAG);
+        self.storage.append(&entry).map_err(Error::Storage)?;
+        self.sequence = sequence;
+        let offset = self.len;
+        self.len += entry.len() as u64;
+        Ok(offset)
+    }
+
+    fn insert(&mut self, tree: u32, key: Vec<u8>, slot: Slot) {
+        self.live += slot.len;
+        if let Some(old) = self.index.insert((tree, key), slot) {
+            self.live -= old.len;
+        }
+    }
+
+    fn remove(&mut self, tree: u32, key: &[u8]) {
+        if let Some(old) = self.index.remove(&(tree, key.to_vec())) {
+            self.live -= old.len;
+        }
+    }
+
+    // Removes the keys of `tree` from `start` up to but excluding `end`, or every key from
+    // `start` on if `end` is empty, as a range tombstone does.
+    fn remove_range(&mut self, tree: u32, start: &[u8], end: &[u8]) {
+        let upper = match end.is_empty() {
+            true => tree_range(tree).1,
+            false => Bound::Excluded((tree, end.to_vec())),
+        };
+        let range = (Bound::Included((tree, start.to_vec())), upper);
+        let keys: Vec<Key> = self.index.range(range).map(|(key, _)| key.clone()).collect();
+        for (tree, key) in keys {
+            self.remove(tree, &key);
+        }
+    }
+}
+
+// The bounds of the keys of `tree` in the index.
+fn tree_range(tree: u32) -> (Bound<Key>, Bound<Key>) {
+    let end = match tree.checked_add(1) {
+        Some(next) => Bound::Excluded((next, Vec::new())),
+        None => Bound::Unbounded,
+    };
+    (Bound::Included((tree, Vec::new())), end)
+}
@@ -0,0 +1,152 @@
+use std::fs::File;
+
+use tegdb_core::{
+    parse_records, record, Codec, Error, MemoryStorage, OutOfBounds, ParseError, Record, Storage,
+    Store,
+};
+
+#[test]
+fn test_store() {
+    let mut store = Store::open(MemoryStorage::new()).unwrap();
+    assert!(store.is_empty());
+    store.set(b"b", b"2").unwrap();
+    store.set(b"a", b"1").unwrap();
+    store.set(b"c", b"3").unwrap();
+    store.set(b"a", b"one").unwrap();
+    assert!(store.del(b"c").unwrap());
+    assert!(!store.del(b"missing").unwrap());
+    store.set(b"b", b"").unwrap();
+    assert_eq!(store.get(b"a").unwrap(), Some(b"one".to_vec()));
+    assert_eq!(store.get(b"b").unwrap(), None);
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec![&b"a"[..]]);
+    assert_eq!(store.len(), 1);
+    let (len, garbage) = store.space();
+    assert!(garbage > 0 && garbage < len);
+
+    // Every write is a checksummed entry with the next sequence number.
+    let storage = store.into_storage();
+    let records: Vec<Record> = parse_records(storage.as_bytes()).map(Result::unwrap).collect();
+    assert_eq!(records.len(), 6);
+    assert!(records.iter().enumerate().all(|(i, r)| r.sequence == i as u64 + 1 && r.checksum));
+    assert_eq!(records.iter().map(record::entry_size).sum::<u64>(), len);
+
+    // Reopening replays the log and continues its sequence numbers.
+    let mut store = Store::open(storage).unwrap();
+    assert_eq!(store.get(b"a").unwrap(), Some(b"one".to_vec()));
+    assert_eq!(store.get(b"c").unwrap(), None);
+    assert_eq!(store.space(), (len, garbage));
+    store.set(b"d", b"4").unwrap();
+    let storage = store.into_storage();
+    assert_eq!(parse_records(storage.as_bytes()).last().unwrap().unwrap().sequence, 7);
+}
+
+#[test]
+fn test_compaction() {
+    let mut store = Store::open(MemoryStorage::new()).unwrap();
+    for i in 0..100u32 {
+        store.set(format!("key_{:02}", i % 10).as_bytes(), &i.to_be_bytes()).unwrap();
+    }
+    store.del(b"key_09").unwrap();
+    let compacted = store.compact_into(MemoryStorage::new()).unwrap();
+    assert_eq!(compacted.space().1, 0);
+    assert_eq!(compacted.space().0, store.space().0 - store.space().1);
+    let compacted = Store::open(compacted.into_storage()).unwrap();
+    assert_eq!(compacted.len(), 9);
+    for i in 90..99u32 {
+        let key = format!("key_{:02}", i % 10);
+        assert_eq!(compacted.get(key.as_bytes()).unwrap(), Some(i.to_be_bytes().to_vec()));
+    }
+    assert_eq!(compacted.get(b"key_09").unwrap(), None);
+}
+
+#[test]
+fn test_other_entries() {
+    // Entries the store does not write itself, as the engine writes them.
+    let entry = |tree, key: &[u8], value: &[u8], codec, deletes_range| Record {
+        tree,
+        key: key.to_vec(),
+        value: value.to_vec(),
+        expires_at: None,
+        codec,
+        sequence: 0,
+        checksum: true,
+        timestamps: None,
+        deletes_range,
+    };
+    let mut log = Vec::new();
+    for (key, value) in [(&b"a"[..], &b"1"[..]), (b"b", b"2"), (b"c", b"3"), (b"d", b"4")] {
+        log.extend(record::encode_record(&entry(0, key, value, Codec::None, false)));
+    }
+    log.extend(record::encode_record(&entry(0, b"b", b"d", Codec::None, true)));
+    log.extend(record::encode_record(&entry(7, b"a", b"tree", Codec::None, false)));
+    log.extend(record::encode_record(&entry(0, b"z", b"packed", Codec::Lz4, false)));
+    let mut expiring = entry(0, b"e", b"5", Codec::None, false);
+    expiring.expires_at = Some(1000);
+    log.extend(record::encode_record(&expiring));
+
+    let store = Store::open(MemoryStorage::from(log)).unwrap();
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec![&b"a"[..], b"d", b"e", b"z"]);
+    assert_eq!(store.get(b"e").unwrap(), Some(b"5".to_vec()));
+    assert_eq!(store.expires_at(b"e"), Some(1000));
+    assert!(matches!(store.get(b"z"), Err(Error::Unsupported { .. })));
+
+    // The entries of other trees survive compaction.
+    let compacted = store.compact_into(MemoryStorage::new()).unwrap().into_storage();
+    let trees: Vec<u32> = parse_records(compacted.as_bytes()).map(|r| r.unwrap().tree).collect();
+    assert_eq!(trees, vec![0, 0, 7, 0, 0]);
+}
+
+#[test]
+fn test_corruption() {
+    let mut store = Store::open(MemoryStorage::new()).unwrap();
+    store.set(b"key", b"value").unwrap();
+    store.set(b"other", b"value").unwrap();
+    let log = store.into_storage().into_bytes();
+    let first = record::entry_size(&parse_records(&log).next().unwrap().unwrap());
+
+    let mut flipped = log.clone();
+    flipped[first as usize + 10] ^= 1;
+    let error = Store::open(MemoryStorage::from(flipped)).unwrap_err();
+    assert!(matches!(error, Error::Corrupted(ParseError::ChecksumMismatch { offset }) if offset == first));
+
+    let torn = log[..log.len() - 1].to_vec();
+    let error = Store::open(MemoryStorage::from(torn)).unwrap_err();
+    assert!(matches!(error, Error::Corrupted(ParseError::Truncated { offset }) if offset == first));
+    assert_eq!(error.to_string(), format!("corrupted log: truncated record at offset {}", first));
+}
+
+#[test]
+fn test_memory_storage() {
+    let mut storage = MemoryStorage::new();
+    storage.append(b"0123456789").unwrap();
+    let mut buf = [0; 4];
+    storage.read_at(6, &mut buf).unwrap();
+    assert_eq!(&buf, b"6789");
+
+    // Reads past the end fail instead of panicking, even where the offset would overflow.
+    let error = storage.read_at(7, &mut buf).unwrap_err();
+    assert_eq!(error, OutOfBounds { offset: 7, len: 4, size: 10 });
+    let message = "read of 4 bytes at offset 7 runs past the end of a log of 10 bytes";
+    assert_eq!(error.to_string(), message);
+    assert!(storage.read_at(u64::MAX, &mut buf).is_err());
+    assert!(storage.read_at(usize::MAX as u64 - 1, &mut buf).is_err());
+}
+
+#[test]
+fn test_file_storage() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("core.log");
+    let open = || File::options().read(true).append(true).create(true).open(&path).unwrap();
+    let mut store = Store::open(open()).unwrap();
+    store.set(b"key", b"value").unwrap();
+    store.set(b"other", b"value").unwrap();
+    store.sync().unwrap();
+    drop(store);
+
+    let mut store = Store::open(open()).unwrap();
+    assert_eq!(store.get(b"key").unwrap(), Some(b"value".to_vec()));
+    store.del(b"key").unwrap();
+    assert_eq!(store.get(b"other").unwrap(), Some(b"value".to_vec()));
+    drop(store);
+    assert_eq!(Store::open(open()).unwrap().keys().collect::<Vec<_>>(), vec![&b"other"[..]]);
+}
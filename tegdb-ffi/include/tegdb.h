@@ -0,0 +1,133 @@
+/*
+ * C API for TegDB, implemented by the tegdb-ffi crate.
+ *
+ * Handles are opaque pointers owned by the caller, who releases them with the matching
+ * _close or _free function. Keys and values are passed as pointer and length pairs and are
+ * copied, so the caller keeps ownership of its buffers. Values returned by tegdb_get and
+ * tegdb_get_many are allocated by the library and released with tegdb_free. Functions return
+ * TEGDB_OK, TEGDB_NOT_FOUND or TEGDB_ERROR, in which case tegdb_last_error describes the error.
+ */
+
+#ifndef TEGDB_H
+#define TEGDB_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The call succeeded. */
+#define TEGDB_OK 0
+/* The key does not exist, or an iterator is exhausted. */
+#define TEGDB_NOT_FOUND 1
+/* The call failed; see tegdb_last_error. */
+#define TEGDB_ERROR -1
+
+/* Opens the database read-only, so that it can be read while another process has it open. */
+#define TEGDB_READ_ONLY 1u
+/* Makes each write wait until it has been fsynced. */
+#define TEGDB_SYNC_WRITES 2u
+
+/* An open database. */
+typedef struct tegdb_engine tegdb_engine;
+/* A scan in progress, holding the pair it returned last. */
+typedef struct tegdb_iter tegdb_iter;
+/* Writes buffered until they are committed. */
+typedef struct tegdb_batch tegdb_batch;
+
+/*
+ * Returns a description of the latest error on the calling thread, or NULL if there was none.
+ * The string stays valid until the next failing call on the same thread.
+ */
+const char *tegdb_last_error(void);
+
+/*
+ * Opens the database in the directory at path, creating it if needed, and stores its handle
+ * in *engine. flags combines TEGDB_READ_ONLY and TEGDB_SYNC_WRITES.
+ */
+int tegdb_open(const char *path, uint32_t flags, tegdb_engine **engine);
+
+/*
+ * Closes the database and releases engine, which must not be used afterwards, even if
+ * closing failed. Scans still in progress keep working.
+ */
+int tegdb_close(tegdb_engine *engine);
+
+/*
+ * Looks up key and, if it exists, stores a copy of its value in *value and *value_len, to be
+ * released with tegdb_free.
+ */
+int tegdb_get(const tegdb_engine *engine, const uint8_t *key, size_t key_len, uint8_t **value,
+              size_t *value_len);
+
+/* Releases a value returned by tegdb_get. */
+void tegdb_free(uint8_t *value, size_t len);
+
+/* Sets key to value. An empty value deletes the key. */
+int tegdb_set(const tegdb_engine *engine, const uint8_t *key, size_t key_len, const uint8_t *value,
+              size_t value_len);
+
+/* Deletes key if it exists. */
+int tegdb_del(const tegdb_engine *engine, const uint8_t *key, size_t key_len);
+
+/*
+ * Looks up the count keys in keys, whose lengths are in key_lens, and stores a copy of the
+ * value of each in the same position of values and value_lens, to be released with
+ * tegdb_free, or NULL and 0 for the keys that do not exist.
+ */
+int tegdb_get_many(const tegdb_engine *engine, const uint8_t *const *keys, const size_t *key_lens,
+                   size_t count, uint8_t **values, size_t *value_lens);
+
+/*
+ * Deletes the count keys in keys, whose lengths are in key_lens, that exist, taking the write
+ * lock once for all of them.
+ */
+int tegdb_del_many(const tegdb_engine *engine, const uint8_t *const *keys, const size_t *key_lens,
+                   size_t count);
+
+/*
+ * Starts a scan of the keys from start up to but excluding end in key order and stores it in
+ * *iter, to be released with tegdb_iter_free. A NULL start scans from the first key and a
+ * NULL end up to the last one. Pairs are fetched as the scan advances.
+ */
+int tegdb_scan(const tegdb_engine *engine, const uint8_t *start, size_t start_len,
+               const uint8_t *end, size_t end_len, tegdb_iter **iter);
+
+/*
+ * Advances the scan and points *key and *value at the next pair, returning TEGDB_NOT_FOUND
+ * once every pair has been returned. The pair stays valid until the next call on the same
+ * scan or until it is released.
+ */
+int tegdb_iter_next(tegdb_iter *iter, const uint8_t **key, size_t *key_len, const uint8_t **value,
+                    size_t *value_len);
+
+/* Releases a scan. */
+void tegdb_iter_free(tegdb_iter *iter);
+
+/*
+ * Starts a batch of writes to the database and stores it in *batch. The writes are buffered
+ * until tegdb_batch_commit submits them together, taking the write lock once, and are not
+ * visible to reads until then.
+ */
+int tegdb_batch_begin(const tegdb_engine *engine, tegdb_batch **batch);
+
+/* Adds setting key to value to the batch. An empty value deletes the key. */
+int tegdb_batch_set(tegdb_batch *batch, const uint8_t *key, size_t key_len, const uint8_t *value,
+                    size_t value_len);
+
+/* Adds deleting key to the batch. */
+int tegdb_batch_del(tegdb_batch *batch, const uint8_t *key, size_t key_len);
+
+/* Submits the writes added to the batch so far, which can then be added to again. */
+int tegdb_batch_commit(tegdb_batch *batch);
+
+/* Releases a batch, dropping the writes added since it was last committed. */
+void tegdb_batch_free(tegdb_batch *batch);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* TEGDB_H */
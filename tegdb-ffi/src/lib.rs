@@ -0,0 +1,411 @@
+//! C API for TegDB, declared in `include/tegdb.h`.
+//!
+//! Handles are opaque pointers owned by the caller, who releases them with the matching
+//! `_close` or `_free` function. Keys and values are passed as pointer and length pairs and are
+//! copied, so the caller keeps ownership of its buffers. Values returned by `tegdb_get` and
+//! `tegdb_get_many` are allocated by the library and released with `tegdb_free`. Functions
+//! return `TEGDB_OK`, `TEGDB_NOT_FOUND` or `TEGDB_ERROR`, in which case `tegdb_last_error`
+//! describes the error.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::ops::Bound;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::time::Duration;
+
+use tegdb::{blocking, Bytes, EngineOptions, Error, Iter};
+
+/// The call succeeded.
+pub const TEGDB_OK: c_int = 0;
+/// The key does not exist, or an iterator is exhausted.
+pub const TEGDB_NOT_FOUND: c_int = 1;
+/// The call failed; see `tegdb_last_error`.
+pub const TEGDB_ERROR: c_int = -1;
+
+/// Opens the database read-only, so that it can be read while another process has it open.
+pub const TEGDB_READ_ONLY: u32 = 1;
+/// Makes each write wait until it has been fsynced.
+pub const TEGDB_SYNC_WRITES: u32 = 2;
+
+/// An open database, `tegdb_engine` in C.
+pub struct Engine(blocking::Engine);
+
+/// A scan in progress, `tegdb_iter` in C, holding the pair it returned last.
+pub struct Scan {
+    iter: Iter,
+    current: Option<(Bytes, Bytes)>,
+}
+
+/// Writes buffered until they are committed, `tegdb_batch` in C.
+pub struct Batch(blocking::Pipeline);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn fail(message: impl ToString) -> c_int {
+    // Messages never contain NUL bytes, but one would only truncate the message.
+    let message = message.to_string().replace('\0', " ");
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(message).ok());
+    TEGDB_ERROR
+}
+
+fn status(result: Result<(), Error>) -> c_int {
+    match result {
+        Ok(()) => TEGDB_OK,
+        Err(e) => fail(e),
+    }
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        return &[];
+    }
+    slice::from_raw_parts(data, len)
+}
+
+unsafe fn key_list<'a>(keys: *const *const u8, key_lens: *const usize, count: usize) -> Vec<&'a [u8]> {
+    (0..count).map(|i| bytes(*keys.add(i), *key_lens.add(i))).collect()
+}
+
+/// Returns a description of the latest error on the calling thread, or NULL if there was none.
+/// The string stays valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn tegdb_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+/// Opens the database in the directory at `path`, creating it if needed, and stores its handle
+/// in `*engine`. `flags` combines `TEGDB_READ_ONLY` and `TEGDB_SYNC_WRITES`.
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string and `engine` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn tegdb_open(path: *const c_char, flags: u32, engine: *mut *mut Engine) -> c_int {
+    if path.is_null() || engine.is_null() {
+        return fail("null argument");
+    }
+    let Ok(path) = CStr::from_ptr(path).to_str() else {
+        return fail("path is not valid UTF-8");
+    };
+    let options = EngineOptions {
+        read_only: flags & TEGDB_READ_ONLY != 0,
+        sync_writes: flags & TEGDB_SYNC_WRITES != 0,
+        ..EngineOptions::default()
+    };
+    match blocking::Engine::open_with_options(Path::new(path), options) {
+        Ok(opened) => {
+            *engine = Box::into_raw(Box::new(Engine(opened)));
+            TEGDB_OK
+        }
+        Err(e) => fail(e),
+    }
+}
+
+/// Closes the database and releases `engine`, which must not be used afterwards, even if
+/// closing failed. Scans still in progress keep working.
+///
+/// # Safety
+///
+/// `engine` must be NULL or a handle returned by `tegdb_open` that has not been closed.
+#[no_mangle]
+pub unsafe extern "C" fn tegdb_close(engine: *mut Engine) -> c_int {
+    if engine.is_null() {
+        return TEGDB_OK;
+    }
+    let engine = Box::from_raw(engine);
+    status(engine.0.close())
+}
+
+/// Looks up `key` and, if it exists, stores a copy of its value in `*value` and `*value_len`,
+/// to be released with `tegdb_free`.
+///
+/// # Safety
+///
+/// `engine` must be an open handle, `key` must point to `key_len` readable bytes, and `value`
+/// and `value_len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn tegdb_get(
+    engine: *const Engine,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *mut u8,
+    value_len: *mut usize,
+) -> c_int {
+    if engine.is_null() || value.is_null() || value_len.is_null() {
+        return fail("null argument");
+    }
+    match (*engine).0.get(bytes(key, key_len)) {
+        Ok(Some(found)) => {
+            let found: Box<[u8]> = found.to_vec().into_boxed_slice();
+            *value_len = found.len();
+            *value = Box::into_raw(found).cast();
+            TEGDB_OK
+        }
+        Ok(None) => TEGDB_NOT_FOUND,
+        Err(e) => fail(e),
+    }
+}
+
+/// Releases a value returned by `tegdb_get`.
+///
+/// # Safety
+///
+/// `value` must be NULL or a value returned by `tegdb_get` along with `len`, not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn tegdb_free(value: *mut u8, len: usize) {
+    if !value.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(value, len)));
+    }
+}
+
+/// Sets `key` to `value`. An empty value deletes the key.
+///
+/// # Safety
+///
+/// `engine` must be an open handle, and `key` and `value` must point to `key_len` and
+/// `value_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn tegdb_set(
+    engine: *const Engine,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> c_int {
+    if engine.is_null() {
+        return fail("null argument");
+    }
+    status((*engine).0.set(bytes(key, key_len), bytes(value, value_len).to_vec()))
+}
+
+/// Deletes `key` if it exists.
+///
+/// # Safety
+///
+/// `engine` must be an open handle and `key` must point to `key_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn tegdb_del(engine: *const Engine, key: *const u8, key_len: usize) -> c_int {
+    if engine.is_null() {
+        return fail("null argument");
+    }
+    status((*engine).0.del(bytes(key, key_len)))
+}
+
+/// Looks up the `count` keys in `keys`, whose lengths are in `key_lens`, and stores a copy of
+/// the value of each in the same position of `values` and `value_lens`, to be released with
+/// `tegdb_free`, or NULL and 0 for the keys that do not exist.
+///
+/// # Safety
+///
+/// `engine` must be an open handle, `keys` and `key_lens` must hold `count` keys and lengths,
+/// each key pointing to as many readable bytes, and `values` and `value_lens` must be valid for
+/// `count` writes.
+#[no_mangle]
+pub unsafe extern "C" fn tegdb_get_many(
+    engine: *const Engine,
+    keys: *const *const u8,
+    key_lens: *const usize,
+    count: usize,
+    values: *mut *mut u8,
+    value_lens: *mut usize,
+) -> c_int {
+    let arrays = [keys.is_null(), key_lens.is_null(), values.is_null(), value_lens.is_null()];
+    if engine.is_null() || count > 0 && arrays.contains(&true) {
+        return fail("null argument");
+    }
+    let found = match (*engine).0.get_many(&key_list(keys, key_lens, count)) {
+        Ok(found) => found,
+        Err(e) => return fail(e),
+    };
+    for (i, found) in found.into_iter().enumerate() {
+        (*values.add(i), *value_lens.add(i)) = match found {
+            Some(found) => {
+                let found: Box<[u8]> = found.to_vec().into_boxed_slice();
+                let len = found.len();
+                (Box::into_raw(found).cast(), len)
+            }
+            None => (ptr::null_mut(), 0),
+        };
+    }
+    TEGDB_OK
+}
+
+/// Deletes the `count` keys in `keys`, whose lengths are in `key_lens`, that exist, taking the
+/// write lock once for all of them.
+///
+/// # Safety
+///
+/// `engine` must be an open handle, and `keys` and `key_lens` must hold `count` keys and
+/// lengths, each key pointing to as many readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn tegdb_del_many(
+    engine: *const Engine,
+    keys: *const *const u8,
+    key_lens: *const usize,
+    count: usize,
+) -> c_int {
+    if engine.is_null() || count > 0 && (keys.is_null() || key_lens.is_null()) {
+        return fail("null argument");
+    }
+    status((*engine).0.del_many(&key_list(keys, key_lens, count)))
+}
+
+/// Starts a scan of the keys from `start` up to but excluding `end` in key order and stores
+/// it in `*iter`, to be released with `tegdb_iter_free`. A NULL `start` scans from the first
+/// key and a NULL `end` up to the last one. Pairs are fetched as the scan advances.
+///
+/// # Safety
+///
+/// `engine` must be an open handle, `start` and `end` must be NULL or point to `start_len` and
+/// `end_len` readable bytes, and `iter` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn tegdb_scan(
+    engine: *const Engine,
+    start: *const u8,
+    start_len: usize,
+    end: *const u8,
+    end_len: usize,
+    iter: *mut *mut Scan,
+) -> c_int {
+    if engine.is_null() || iter.is_null() {
+        return fail("null argument");
+    }
+    let start = match start.is_null() {
+        true => Bound::Unbounded,
+        false => Bound::Included(bytes(start, start_len).to_vec()),
+    };
+    let end = match end.is_null() {
+        true => Bound::Unbounded,
+        false => Bound::Excluded(bytes(end, end_len).to_vec()),
+    };
+    let scan = Scan {
+        iter: (*engine).0.scan_iter((start, end)),
+        current: None,
+    };
+    *iter = Box::into_raw(Box::new(scan));
+    TEGDB_OK
+}
+
+/// Advances the scan and points `*key` and `*value` at the next pair, returning
+/// `TEGDB_NOT_FOUND` once every pair has been returned. The pair stays valid until the next
+/// call on the same scan or until it is released.
+///
+/// # Safety
+///
+/// `iter` must be a scan returned by `tegdb_scan` and not released yet, and the other
+/// arguments must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn tegdb_iter_next(
+    iter: *mut Scan,
+    key: *mut *const u8,
+    key_len: *mut usize,
+    value: *mut *const u8,
+    value_len: *mut usize,
+) -> c_int {
+    if iter.is_null() || key.is_null() || key_len.is_null() || value.is_null() || value_len.is_null() {
+        return fail("null argument");
+    }
+    let scan = &mut *iter;
+    scan.current = match scan.iter.next() {
+        Some(Ok(pair)) => Some(pair),
+        Some(Err(e)) => return fail(e),
+        None => return TEGDB_NOT_FOUND,
+    };
+    let (k, v) = scan.current.as_ref().unwrap();
+    (*key, *key_len) = (k.as_ptr(), k.len());
+    (*value, *value_len) = (v.as_ptr(), v.len());
+    TEGDB_OK
+}
+
+/// Releases a scan.
+///
+/// # Safety
+///
+/// `iter` must be NULL or a scan returned by `tegdb_scan` and not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn tegdb_iter_free(iter: *mut Scan) {
+    if !iter.is_null() {
+        drop(Box::from_raw(iter));
+    }
+}
+
+/// Starts a batch of writes to the database and stores it in `*batch`. The writes are buffered
+/// until `tegdb_batch_commit` submits them together, taking the write lock once, and are not
+/// visible to reads until then.
+///
+/// # Safety
+///
+/// `engine` must be an open handle and `batch` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn tegdb_batch_begin(engine: *const Engine, batch: *mut *mut Batch) -> c_int {
+    if engine.is_null() || batch.is_null() {
+        return fail("null argument");
+    }
+    let pipeline = (*engine).0.pipeline().max_bytes(usize::MAX).max_delay(Duration::MAX);
+    *batch = Box::into_raw(Box::new(Batch(pipeline)));
+    TEGDB_OK
+}
+
+/// Adds setting `key` to `value` to the batch. An empty value deletes the key.
+///
+/// # Safety
+///
+/// `batch` must be a batch returned by `tegdb_batch_begin` and not released yet, and `key`
+/// and `value` must point to `key_len` and `value_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn tegdb_batch_set(
+    batch: *mut Batch,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> c_int {
+    if batch.is_null() {
+        return fail("null argument");
+    }
+    status((*batch).0.set(bytes(key, key_len), bytes(value, value_len).to_vec()))
+}
+
+/// Adds deleting `key` to the batch.
+///
+/// # Safety
+///
+/// `batch` must be a batch returned by `tegdb_batch_begin` and not released yet, and `key`
+/// must point to `key_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn tegdb_batch_del(batch: *mut Batch, key: *const u8, key_len: usize) -> c_int {
+    if batch.is_null() {
+        return fail("null argument");
+    }
+    status((*batch).0.set(bytes(key, key_len), Vec::new()))
+}
+
+/// Submits the writes added to the batch so far, which can then be added to again.
+///
+/// # Safety
+///
+/// `batch` must be a batch returned by `tegdb_batch_begin` and not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn tegdb_batch_commit(batch: *mut Batch) -> c_int {
+    if batch.is_null() {
+        return fail("null argument");
+    }
+    status((*batch).0.flush())
+}
+
+/// Releases a batch, dropping the writes added since it was last committed.
+///
+/// # Safety
+///
+/// `batch` must be NULL or a batch returned by `tegdb_batch_begin` and not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn tegdb_batch_free(batch: *mut Batch) {
+    if !batch.is_null() {
+        let mut batch = Box::from_raw(batch);
+        batch.0.clear();
+    }
+}
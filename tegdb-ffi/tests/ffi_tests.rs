@@ -0,0 +1,222 @@
+use std::ffi::{CStr, CString};
+use std::ptr;
+use std::slice;
+
+use tegdb_ffi::*;
+
+// Opens a database in a new temporary directory, which is removed along with the returned guard.
+unsafe fn open_temporary() -> (tempfile::TempDir, *mut Engine) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = CString::new(dir.path().join("ffi.db").to_str().unwrap()).unwrap();
+    let mut engine = ptr::null_mut();
+    assert_eq!(tegdb_open(path.as_ptr(), 0, &mut engine), TEGDB_OK);
+    (dir, engine)
+}
+
+unsafe fn get(engine: *const Engine, key: &[u8]) -> Option<Vec<u8>> {
+    let (mut value, mut value_len) = (ptr::null_mut(), 0);
+    match tegdb_get(engine, key.as_ptr(), key.len(), &mut value, &mut value_len) {
+        TEGDB_NOT_FOUND => None,
+        status => {
+            assert_eq!(status, TEGDB_OK);
+            let found = slice::from_raw_parts(value, value_len).to_vec();
+            tegdb_free(value, value_len);
+            Some(found)
+        }
+    }
+}
+
+#[test]
+fn test_c_api() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = CString::new(dir.path().join("ffi.db").to_str().unwrap()).unwrap();
+    unsafe {
+        let mut engine = ptr::null_mut();
+        assert_eq!(tegdb_open(path.as_ptr(), 0, &mut engine), TEGDB_OK);
+        for (key, value) in [(&b"b"[..], &b"2"[..]), (b"a", b"1"), (b"c", b"3")] {
+            assert_eq!(tegdb_set(engine, key.as_ptr(), key.len(), value.as_ptr(), value.len()), TEGDB_OK);
+        }
+        assert_eq!(tegdb_del(engine, b"c".as_ptr(), 1), TEGDB_OK);
+
+        let (mut value, mut value_len) = (ptr::null_mut(), 0);
+        assert_eq!(tegdb_get(engine, b"a".as_ptr(), 1, &mut value, &mut value_len), TEGDB_OK);
+        assert_eq!(slice::from_raw_parts(value, value_len), b"1");
+        tegdb_free(value, value_len);
+        assert_eq!(tegdb_get(engine, b"c".as_ptr(), 1, &mut value, &mut value_len), TEGDB_NOT_FOUND);
+
+        let mut iter = ptr::null_mut();
+        assert_eq!(tegdb_scan(engine, ptr::null(), 0, b"c".as_ptr(), 1, &mut iter), TEGDB_OK);
+        let mut pairs = Vec::new();
+        let (mut key, mut key_len, mut pair_value, mut pair_value_len) = (ptr::null(), 0, ptr::null(), 0);
+        while tegdb_iter_next(iter, &mut key, &mut key_len, &mut pair_value, &mut pair_value_len) == TEGDB_OK {
+            let pair_value = slice::from_raw_parts(pair_value, pair_value_len);
+            pairs.push((slice::from_raw_parts(key, key_len).to_vec(), pair_value.to_vec()));
+        }
+        tegdb_iter_free(iter);
+        assert_eq!(pairs, vec![(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), b"2".to_vec())]);
+
+        let key = vec![b'k'; 2000];
+        assert_eq!(tegdb_set(engine, key.as_ptr(), key.len(), b"v".as_ptr(), 1), TEGDB_ERROR);
+        let error = CStr::from_ptr(tegdb_last_error()).to_str().unwrap();
+        assert!(error.contains("exceeds the limit"));
+        assert_eq!(tegdb_close(engine), TEGDB_OK);
+
+        assert_eq!(tegdb_open(path.as_ptr(), TEGDB_READ_ONLY, &mut engine), TEGDB_OK);
+        assert_eq!(tegdb_set(engine, b"x".as_ptr(), 1, b"y".as_ptr(), 1), TEGDB_ERROR);
+        assert_eq!(tegdb_get(engine, b"b".as_ptr(), 1, &mut value, &mut value_len), TEGDB_OK);
+        tegdb_free(value, value_len);
+        assert_eq!(tegdb_close(engine), TEGDB_OK);
+    }
+}
+
+#[test]
+fn test_many() {
+    unsafe {
+        let (_dir, engine) = open_temporary();
+        for key in [&b"a"[..], b"b", b"c"] {
+            assert_eq!(tegdb_set(engine, key.as_ptr(), 1, key.as_ptr(), 1), TEGDB_OK);
+        }
+        let keys = [&b"a"[..], b"missing", b"c"];
+        let key_ptrs: Vec<*const u8> = keys.iter().map(|key| key.as_ptr()).collect();
+        let key_lens: Vec<usize> = keys.iter().map(|key| key.len()).collect();
+        let mut values = [ptr::null_mut(); 3];
+        let mut value_lens = [usize::MAX; 3];
+        let status = tegdb_get_many(engine, key_ptrs.as_ptr(), key_lens.as_ptr(), 3, values.as_mut_ptr(), value_lens.as_mut_ptr());
+        assert_eq!(status, TEGDB_OK);
+        assert_eq!(slice::from_raw_parts(values[0], value_lens[0]), b"a");
+        assert!(values[1].is_null());
+        assert_eq!(value_lens[1], 0);
+        assert_eq!(slice::from_raw_parts(values[2], value_lens[2]), b"c");
+        for (value, len) in values.into_iter().zip(value_lens) {
+            tegdb_free(value, len);
+        }
+
+        assert_eq!(tegdb_del_many(engine, key_ptrs.as_ptr(), key_lens.as_ptr(), 3), TEGDB_OK);
+        assert_eq!(get(engine, b"a"), None);
+        assert_eq!(get(engine, b"b"), Some(b"b".to_vec()));
+        assert_eq!(get(engine, b"c"), None);
+        assert_eq!(tegdb_del_many(engine, ptr::null(), ptr::null(), 0), TEGDB_OK);
+        assert_eq!(tegdb_close(engine), TEGDB_OK);
+    }
+}
+
+#[test]
+fn test_batch() {
+    unsafe {
+        let (_dir, engine) = open_temporary();
+        assert_eq!(tegdb_set(engine, b"old".as_ptr(), 3, b"1".as_ptr(), 1), TEGDB_OK);
+        let mut batch = ptr::null_mut();
+        assert_eq!(tegdb_batch_begin(engine, &mut batch), TEGDB_OK);
+        assert_eq!(tegdb_batch_set(batch, b"a".as_ptr(), 1, b"1".as_ptr(), 1), TEGDB_OK);
+        assert_eq!(tegdb_batch_del(batch, b"old".as_ptr(), 3), TEGDB_OK);
+        // Nothing is written until the batch is committed.
+        assert_eq!(get(engine, b"a"), None);
+        assert_eq!(get(engine, b"old"), Some(b"1".to_vec()));
+        assert_eq!(tegdb_batch_commit(batch), TEGDB_OK);
+        assert_eq!(get(engine, b"a"), Some(b"1".to_vec()));
+        assert_eq!(get(engine, b"old"), None);
+
+        // Writes added after the last commit are dropped with the batch.
+        assert_eq!(tegdb_batch_set(batch, b"b".as_ptr(), 1, b"2".as_ptr(), 1), TEGDB_OK);
+        tegdb_batch_free(batch);
+        assert_eq!(get(engine, b"b"), None);
+
+        let key = vec![b'k'; 2000];
+        assert_eq!(tegdb_batch_begin(engine, &mut batch), TEGDB_OK);
+        assert_eq!(tegdb_batch_set(batch, key.as_ptr(), key.len(), b"v".as_ptr(), 1), TEGDB_ERROR);
+        tegdb_batch_free(batch);
+        assert_eq!(tegdb_close(engine), TEGDB_OK);
+    }
+}
+
+// Translates a type of the Rust API into the C type the header declares for it.
+fn c_type(rust: &str) -> String {
+    let rust = rust.trim();
+    if let Some(pointee) = rust.strip_prefix("*const ") {
+        return match pointee.starts_with('*') {
+            true => format!("{} const *", c_type(pointee)),
+            false => format!("const {} *", c_type(pointee)),
+        };
+    }
+    if let Some(pointee) = rust.strip_prefix("*mut ") {
+        return format!("{} *", c_type(pointee));
+    }
+    let c = match rust {
+        "c_char" => "char",
+        "c_int" => "int",
+        "u8" => "uint8_t",
+        "u32" => "uint32_t",
+        "usize" => "size_t",
+        "Engine" => "tegdb_engine",
+        "Scan" => "tegdb_iter",
+        "Batch" => "tegdb_batch",
+        _ => panic!("no C type for {}", rust),
+    };
+    c.to_string()
+}
+
+// Splits C source into tokens separated by single spaces, so that declarations compare equal
+// however they are laid out.
+fn c_tokens(source: &str) -> String {
+    let mut spaced = String::new();
+    for c in source.chars() {
+        match c {
+            '*' | '(' | ')' | ',' | ';' => spaced.extend([' ', c, ' ']),
+            _ => spaced.push(c),
+        }
+    }
+    spaced.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[test]
+fn test_header_matches() {
+    let source = include_str!("../src/lib.rs");
+    let mut header = include_str!("../include/tegdb.h").to_string();
+    while let Some(start) = header.find("/*") {
+        let end = start + header[start..].find("*/").unwrap() + 2;
+        header.replace_range(start..end, " ");
+    }
+    let header = c_tokens(&header);
+
+    let mut functions = 0;
+    for item in source.split("extern \"C\" fn ").skip(1) {
+        let signature = &item[..item.find('{').unwrap()];
+        let (name, rest) = signature.split_once('(').unwrap();
+        let (params, returns) = rest.rsplit_once(')').unwrap();
+        let params: Vec<String> = params
+            .split(',')
+            .filter(|param| !param.trim().is_empty())
+            .map(|param| {
+                let (param, rust) = param.split_once(':').unwrap();
+                format!("{} {}", c_type(rust), param.trim())
+            })
+            .collect();
+        let params = match params.is_empty() {
+            true => "void".to_string(),
+            false => params.join(", "),
+        };
+        let returns = returns.trim().strip_prefix("->").map_or("void".to_string(), c_type);
+        let declaration = c_tokens(&format!("{} {}({});", returns, name, params));
+        assert!(header.contains(&declaration), "tegdb.h does not declare {}", declaration);
+        functions += 1;
+    }
+    let declared = header.split(' ').collect::<Vec<_>>();
+    let declared = declared.windows(2).filter(|w| w[0].starts_with("tegdb_") && w[1] == "(").count();
+    assert_eq!(declared, functions, "tegdb.h declares functions the library does not export");
+
+    for line in source.lines() {
+        let Some(constant) = line.strip_prefix("pub const ") else {
+            continue;
+        };
+        let (name, rest) = constant.split_once(':').unwrap();
+        let (rust, value) = rest.split_once('=').unwrap();
+        let value = value.trim().trim_end_matches(';');
+        let suffix = if rust.trim() == "u32" { "u" } else { "" };
+        let define = c_tokens(&format!("#define {} {}{}", name, value, suffix));
+        assert!(header.contains(&define), "tegdb.h does not define {}", define);
+    }
+    for handle in ["tegdb_engine", "tegdb_iter", "tegdb_batch"] {
+        let typedef = c_tokens(&format!("typedef struct {0} {0};", handle));
+        assert!(header.contains(&typedef), "tegdb.h does not declare {}", handle);
+    }
+}
@@ -0,0 +1,125 @@
+//! TegDB for the browser, built with `wasm-pack build tegdb-wasm`.
+//!
+//! A [`Database`] keeps its log in a file of the Origin Private File System, in the format of
+//! the `tegdb` engine, and indexes its keys in memory with [`tegdb_core::Store`]. Browsers only
+//! allow files to be opened for synchronous access from dedicated workers, so databases must be
+//! opened from one. There, writes reach the file as they are made, without a writer thread:
+//! only opening a database waits on the browser, and [`Database::sync`] flushes the file to
+//! make the writes made so far durable.
+
+mod opfs;
+
+use js_sys::{Array, Uint8Array};
+use tegdb_core::{MemoryStorage, Store};
+use wasm_bindgen::prelude::*;
+use web_sys::FileSystemSyncAccessHandle;
+
+pub use opfs::{JsFailure, OpfsStorage};
+
+/// An open database, `tegdb.Database` in JavaScript.
+#[wasm_bindgen]
+pub struct Database {
+    // None once a compaction failed to reopen the log it wrote.
+    store: Option<Store<OpfsStorage>>,
+    // The file holding the log, and the one compacted logs are staged in.
+    file: FileSystemSyncAccessHandle,
+    scratch: FileSystemSyncAccessHandle,
+}
+
+#[wasm_bindgen]
+impl Database {
+    /// Opens the database kept in the file `name` of the origin's private file system,
+    /// creating it if needed, and finishes a compaction it was interrupted in. A database can
+    /// only be open once at a time.
+    pub async fn open(name: String) -> Result<Database, JsError> {
+        let dir = opfs::directory().await?;
+        let file = opfs::open(&dir, &name).await?;
+        let scratch = match opfs::open(&dir, &format!("{}.compacting", name)).await {
+            Ok(scratch) => scratch,
+            Err(e) => {
+                file.close();
+                return Err(e.into());
+            }
+        };
+        let mut database = Database { store: None, file, scratch };
+        opfs::finish(&database.file, &database.scratch)?;
+        database.store = Some(Store::open(OpfsStorage::new(database.file.clone())?)?);
+        Ok(database)
+    }
+
+    /// Returns the value of `key`, or `undefined` if it is not set.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, JsError> {
+        Ok(self.store()?.get(key)?)
+    }
+
+    /// Sets `key` to `value`. An empty value deletes the key.
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<(), JsError> {
+        Ok(self.store_mut()?.set(key, value)?)
+    }
+
+    /// Deletes `key`, returning whether it was set.
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool, JsError> {
+        Ok(self.store_mut()?.del(key)?)
+    }
+
+    /// Returns the keys that are set, in order, as an array of `Uint8Array`s.
+    pub fn keys(&self) -> Result<Array, JsError> {
+        Ok(self.store()?.keys().map(Uint8Array::from).collect())
+    }
+
+    /// Returns the number of keys that are set.
+    pub fn len(&self) -> Result<usize, JsError> {
+        Ok(self.store()?.len())
+    }
+
+    /// Returns true if no key is set.
+    #[wasm_bindgen(js_name = isEmpty)]
+    pub fn is_empty(&self) -> Result<bool, JsError> {
+        Ok(self.store()?.is_empty())
+    }
+
+    /// Returns the number of bytes taken up by entries that have since been overwritten or
+    /// deleted, which compacting the database reclaims.
+    pub fn garbage(&self) -> Result<f64, JsError> {
+        Ok(self.store()?.space().1 as f64)
+    }
+
+    /// Waits until every write made so far is durable.
+    pub fn sync(&mut self) -> Result<(), JsError> {
+        Ok(self.store_mut()?.sync()?)
+    }
+
+    /// Rewrites the log with only the entries that are still live. The compacted log is built
+    /// in memory, so this needs as much memory as the live entries take up in the file.
+    pub fn compact(&mut self) -> Result<(), JsError> {
+        let log = self.store()?.compact_into(MemoryStorage::new())?.into_storage().into_bytes();
+        opfs::stage(&self.scratch, &log)?;
+        self.store = None;
+        opfs::finish(&self.file, &self.scratch)?;
+        self.store = Some(Store::open(OpfsStorage::new(self.file.clone())?)?);
+        Ok(())
+    }
+
+    /// Flushes the writes made so far and closes the database, so that it can be opened again.
+    pub fn close(self) -> Result<(), JsError> {
+        self.file.flush().map_err(JsFailure::from)?;
+        Ok(())
+    }
+}
+
+impl Database {
+    fn store(&self) -> Result<&Store<OpfsStorage>, JsError> {
+        self.store.as_ref().ok_or_else(|| JsError::new("database is closed"))
+    }
+
+    fn store_mut(&mut self) -> Result<&mut Store<OpfsStorage>, JsError> {
+        self.store.as_mut().ok_or_else(|| JsError::new("database is closed"))
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        self.file.close();
+        self.scratch.close();
+    }
+}
@@ -0,0 +1,184 @@
+//! Files of the Origin Private File System, read and written through sync access handles.
+//!
+//! A compacted log replaces the database's file in place, as web-sys offers no way to rename
+//! files. It is first written to a scratch file after a header that is only set to its length
+//! once the whole log is in place, and copied over the database's file from there, so that
+//! [`finish`] can complete a compaction interrupted by a crash when the database is next
+//! opened.
+
+use std::fmt;
+
+use js_sys::Promise;
+use tegdb_core::{OutOfBounds, Storage};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    FileSystemDirectoryHandle, FileSystemFileHandle, FileSystemGetFileOptions,
+    FileSystemReadWriteOptions, FileSystemSyncAccessHandle, WorkerGlobalScope,
+};
+
+// Length of the header of the scratch file.
+const HEADER_LEN: u64 = 8;
+// Header of a scratch file whose log is still being written.
+const INCOMPLETE: u64 = u64::MAX;
+
+/// An error raised by a browser API, described by its message.
+#[derive(Debug)]
+pub struct JsFailure(String);
+
+impl From<JsValue> for JsFailure {
+    fn from(value: JsValue) -> Self {
+        let message = match value.dyn_ref::<js_sys::Error>() {
+            Some(error) => String::from(error.message()),
+            None => value.as_string().unwrap_or_else(|| format!("{:?}", value)),
+        };
+        Self(message)
+    }
+}
+
+impl From<OutOfBounds> for JsFailure {
+    fn from(e: OutOfBounds) -> Self {
+        Self(e.to_string())
+    }
+}
+
+impl fmt::Display for JsFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for JsFailure {}
+
+/// The log of a database, kept in a file of the Origin Private File System. Writes reach the
+/// file as they are made, and flushing it makes them durable. The handle is closed by its
+/// owner, which keeps it to replace the log when compacting it.
+pub struct OpfsStorage {
+    handle: FileSystemSyncAccessHandle,
+    len: u64,
+}
+
+impl OpfsStorage {
+    pub(crate) fn new(handle: FileSystemSyncAccessHandle) -> Result<Self, JsFailure> {
+        let len = handle.get_size()? as u64;
+        Ok(Self { handle, len })
+    }
+}
+
+impl Storage for OpfsStorage {
+    type Error = JsFailure;
+
+    fn size(&self) -> Result<u64, JsFailure> {
+        Ok(self.len)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), JsFailure> {
+        read_exact(&self.handle, offset, buf)
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<(), JsFailure> {
+        write_all(&self.handle, self.len, data)?;
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), JsFailure> {
+        Ok(self.handle.flush()?)
+    }
+}
+
+/// Returns the root of the origin's private file system. Files can only be opened for
+/// synchronous access from a dedicated worker, so this fails anywhere else.
+pub(crate) async fn directory() -> Result<FileSystemDirectoryHandle, JsFailure> {
+    let Ok(scope) = js_sys::global().dyn_into::<WorkerGlobalScope>() else {
+        return Err(JsFailure("databases can only be opened from a dedicated worker".to_string()));
+    };
+    resolve(scope.navigator().storage().get_directory()).await
+}
+
+/// Opens the file `name` of `dir` for synchronous access, creating it if needed. The file
+/// cannot be opened again until the returned handle is closed.
+pub(crate) async fn open(
+    dir: &FileSystemDirectoryHandle,
+    name: &str,
+) -> Result<FileSystemSyncAccessHandle, JsFailure> {
+    let options = FileSystemGetFileOptions::new();
+    options.set_create(true);
+    let file: FileSystemFileHandle =
+        resolve(dir.get_file_handle_with_options(name, &options)).await?;
+    resolve(file.create_sync_access_handle()).await
+}
+
+async fn resolve<T: JsCast>(promise: Promise) -> Result<T, JsFailure> {
+    Ok(JsFuture::from(promise).await?.unchecked_into())
+}
+
+/// Writes the compacted `log` to `scratch`, from which [`finish`] copies it.
+pub(crate) fn stage(scratch: &FileSystemSyncAccessHandle, log: &[u8]) -> Result<(), JsFailure> {
+    scratch.truncate_with_f64(0.0)?;
+    write_all(scratch, 0, &INCOMPLETE.to_be_bytes())?;
+    write_all(scratch, HEADER_LEN, log)?;
+    scratch.flush()?;
+    write_all(scratch, 0, &(log.len() as u64).to_be_bytes())?;
+    Ok(scratch.flush()?)
+}
+
+/// Replaces the log in `file` with the compacted log staged in `scratch`, if it was written
+/// in full, and empties `scratch`.
+pub(crate) fn finish(
+    file: &FileSystemSyncAccessHandle,
+    scratch: &FileSystemSyncAccessHandle,
+) -> Result<(), JsFailure> {
+    let size = scratch.get_size()? as u64;
+    if size == 0 {
+        return Ok(());
+    }
+    let mut header = [0; HEADER_LEN as usize];
+    if size >= HEADER_LEN {
+        read_exact(scratch, 0, &mut header)?;
+    }
+    let len = u64::from_be_bytes(header);
+    if size >= HEADER_LEN && len == size - HEADER_LEN {
+        let mut log = vec![0; len as usize];
+        read_exact(scratch, HEADER_LEN, &mut log)?;
+        file.truncate_with_f64(0.0)?;
+        write_all(file, 0, &log)?;
+        file.flush()?;
+    }
+    scratch.truncate_with_f64(0.0)?;
+    Ok(scratch.flush()?)
+}
+
+fn read_exact(
+    handle: &FileSystemSyncAccessHandle,
+    offset: u64,
+    buf: &mut [u8],
+) -> Result<(), JsFailure> {
+    let options = FileSystemReadWriteOptions::new();
+    options.set_at(offset as f64);
+    let read = handle.read_with_u8_array_and_options(buf, &options)? as usize;
+    match read == buf.len() {
+        true => Ok(()),
+        false => {
+            let message = format!("read {} of {} bytes at offset {}", read, buf.len(), offset);
+            Err(JsFailure(message))
+        }
+    }
+}
+
+fn write_all(
+    handle: &FileSystemSyncAccessHandle,
+    offset: u64,
+    data: &[u8],
+) -> Result<(), JsFailure> {
+    let options = FileSystemReadWriteOptions::new();
+    options.set_at(offset as f64);
+    let written = handle.write_with_u8_array_and_options(data, &options)? as usize;
+    match written == data.len() {
+        true => Ok(()),
+        false => {
+            let message = format!("wrote {} of {} bytes at offset {}", written, data.len(), offset);
+            Err(JsFailure(message))
+        }
+    }
+}
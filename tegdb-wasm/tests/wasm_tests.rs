@@ -0,0 +1,65 @@
+//! Run with `wasm-pack test --headless --chrome tegdb-wasm`, which opens the databases from a
+//! dedicated worker as browsers require.
+
+#![cfg(target_arch = "wasm32")]
+
+use js_sys::Uint8Array;
+use tegdb_wasm::Database;
+use wasm_bindgen_test::{wasm_bindgen_test, wasm_bindgen_test_configure};
+
+wasm_bindgen_test_configure!(run_in_dedicated_worker);
+
+// Opens the database `name`, emptied of whatever earlier runs left in it.
+async fn open_empty(name: &str) -> Database {
+    let mut database = Database::open(name.to_string()).await.unwrap();
+    for key in database.keys().unwrap().iter() {
+        database.delete(&Uint8Array::new(&key).to_vec()).unwrap();
+    }
+    database.compact().unwrap();
+    database
+}
+
+#[wasm_bindgen_test]
+async fn test_database() {
+    let mut database = open_empty("test_database").await;
+    assert!(database.is_empty().unwrap());
+    database.set(b"b", b"2").unwrap();
+    database.set(b"a", b"1").unwrap();
+    database.set(b"c", b"3").unwrap();
+    database.set(b"a", b"one").unwrap();
+    assert!(database.delete(b"c").unwrap());
+    assert!(!database.delete(b"missing").unwrap());
+    assert_eq!(database.get(b"a").unwrap(), Some(b"one".to_vec()));
+    assert_eq!(database.get(b"c").unwrap(), None);
+    let keys: Vec<Vec<u8>> =
+        database.keys().unwrap().iter().map(|key| Uint8Array::new(&key).to_vec()).collect();
+    assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]);
+    database.sync().unwrap();
+    database.close().unwrap();
+
+    // The writes are replayed from the file, and compacting it keeps only the live entries.
+    let mut database = Database::open("test_database".to_string()).await.unwrap();
+    assert_eq!(database.len().unwrap(), 2);
+    assert_eq!(database.get(b"a").unwrap(), Some(b"one".to_vec()));
+    assert!(database.garbage().unwrap() > 0.0);
+    database.compact().unwrap();
+    assert_eq!(database.garbage().unwrap(), 0.0);
+    database.set(b"d", b"4").unwrap();
+    database.close().unwrap();
+
+    let database = Database::open("test_database".to_string()).await.unwrap();
+    assert_eq!(database.get(b"a").unwrap(), Some(b"one".to_vec()));
+    assert_eq!(database.get(b"b").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(database.get(b"d").unwrap(), Some(b"4".to_vec()));
+    assert_eq!(database.garbage().unwrap(), 0.0);
+    database.close().unwrap();
+}
+
+#[wasm_bindgen_test]
+async fn test_open_once() {
+    let database = open_empty("test_open_once").await;
+    // The file stays locked by the open database's access handle until it is closed.
+    assert!(Database::open("test_open_once".to_string()).await.is_err());
+    database.close().unwrap();
+    Database::open("test_open_once".to_string()).await.unwrap().close().unwrap();
+}
@@ -1,25 +1,36 @@
 use std::sync::Arc;
-use std::path::PathBuf;
+use std::ops::Bound;
+use std::path::Path;
 use std::fs;
-use tegdb::Engine;
+use std::time::Duration;
+use futures::StreamExt;
+use tegdb::{blocking, At, Backup, Bytes, Change, CompactionEvent, CompactionHook, DiskFullEvent, DiskFullHook, DiskFullPolicy, Engine, EngineObserver, EngineOptions, Error, Event, Filter, Mutation, Op, Rejection, WriteInterceptor};
+
+fn dir_size(path: &Path) -> u64 {
+    fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum()
+}
 
 #[tokio::test]
 async fn test_engine() {
-    let path = PathBuf::from("test.db");
-    let engine = Engine::new(path.clone());
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.db");
+    let engine = Engine::open(path.clone()).unwrap();
     let key = b"key";
     let value = b"value";
     engine.set(key, value.to_vec()).await.unwrap();
-    let get_value = engine.get(key).await.unwrap();
+    let get_value = engine.get(key).await.unwrap().unwrap();
     assert_eq!(
         get_value,
-        value,
+        &value[..],
         "Expected: {}, Got: {}",
         String::from_utf8_lossy(value),
         String::from_utf8_lossy(&get_value)
     );
     engine.del(key).await.unwrap();
-    let get_value = engine.get(key).await;
+    let get_value = engine.get(key).await.unwrap();
     assert_eq!(
         get_value,
         None,
@@ -36,7 +47,7 @@ async fn test_engine() {
         .await
         .unwrap()
         .collect::<Vec<_>>();
-    let expected = vec![
+    let expected = [
         (start_key.to_vec(), b"start_value".to_vec()),
         (end_key.to_vec(), b"end_value".to_vec()),
     ];
@@ -51,23 +62,22 @@ async fn test_engine() {
         "Expected: {:?}, Got: {:?}",
         expected_strings, result_strings
     );
-    drop(engine);
-    fs::remove_file(path).unwrap();
+    engine.close().await.unwrap();
 }
 
-#[tokio::test]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
 async fn test_concurrent_access() {
-    use tokio::sync::Mutex;
-    let path = PathBuf::from("concurrent.db");
-    let engine = Arc::new(Mutex::new(Engine::new(path.clone())));
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("concurrent.db");
+    let engine = Arc::new(Engine::open(path.clone()).unwrap());
     let tasks: Vec<_> = (0..10)
         .map(|i| {
             let engine = engine.clone();
             tokio::spawn(async move {
                 let key = format!("key_{}", i).into_bytes();
                 let value = format!("value_{}", i).into_bytes();
-                engine.lock().await.set(&key, value.clone()).await.unwrap();
-                let got = engine.lock().await.get(&key).await.unwrap();
+                engine.set(&key, value.clone()).await.unwrap();
+                let got = engine.get(&key).await.unwrap().unwrap();
                 assert_eq!(got, value);
             })
         })
@@ -75,6 +85,3945 @@ async fn test_concurrent_access() {
     for t in tasks {
         t.await.unwrap();
     }
+    engine.close().await.unwrap();
+}
+
+#[test]
+fn test_shared_handle() {
+    fn assert_shareable<T: Send + Sync + Clone + 'static>() {}
+    assert_shareable::<Engine>();
+    assert_shareable::<tegdb::Tree>();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("shared_handle.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(4).build().unwrap();
+    // Every thread borrows the same handle, with no clone and no lock.
+    std::thread::scope(|scope| {
+        for i in 0..8 {
+            let (engine, runtime) = (&engine, &runtime);
+            scope.spawn(move || {
+                runtime.block_on(async {
+                    for j in 0..100 {
+                        let key = format!("key_{}_{}", i, j).into_bytes();
+                        engine.set(&key, b"value".to_vec()).await.unwrap();
+                        assert!(engine.get(&key).await.unwrap().is_some());
+                        if j % 2 == 0 {
+                            engine.del(&key).await.unwrap();
+                        }
+                        engine.scan(b"key_".to_vec()..).await.unwrap().for_each(drop);
+                    }
+                })
+            });
+        }
+    });
+    assert_eq!(engine.len(), 400);
+    drop(engine);
+}
+
+#[test]
+fn test_error_messages() {
+    use std::error::Error as _;
+
+    let io = Error::Io(std::io::Error::other("disk on fire"));
+    assert_eq!(io.to_string(), "I/O error: disk on fire");
+    assert_eq!(io.source().unwrap().to_string(), "disk on fire");
+    let rejected = Error::Rejected("outside the tenant".into());
+    assert_eq!(rejected.to_string(), "write rejected: outside the tenant");
+    assert_eq!(rejected.source().unwrap().to_string(), "outside the tenant");
+    let mismatch = Error::ComparatorMismatch {
+        expected: None,
+        found: Some("reverse".to_string()),
+    };
+    assert_eq!(mismatch.to_string(), "database orders its keys with comparator \"reverse\", not bytewise");
+    assert_eq!(
+        Error::KeyTooLarge { len: 2000, limit: 1024 }.to_string(),
+        "key of 2000 bytes exceeds the limit of 1024 bytes"
+    );
+    assert!(Error::Closed.source().is_none());
+}
+
+#[tokio::test]
+async fn test_open_corrupted_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("corrupted.db");
+    fs::create_dir_all(&path).unwrap();
+    // A record header claiming a 16-byte key, followed by nothing.
+    fs::write(path.join("00000001.log"), [0, 0, 0, 16, 0, 0, 0, 1]).unwrap();
+    let result = Engine::open(path.clone());
+    assert!(
+        matches!(result, Err(tegdb::Error::Corrupted(_))),
+        "Expected: Err(Corrupted), Got: {:?}",
+        result.map(|_| ())
+    );
+}
+
+#[tokio::test]
+async fn test_verify_and_repair() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("verify.db");
+    {
+        let engine = Engine::open(path.clone()).unwrap();
+        engine.set(b"a", b"first".to_vec()).await.unwrap();
+        engine.set(b"b", b"second".to_vec()).await.unwrap();
+        engine.set(b"c", b"third".to_vec()).await.unwrap();
+        let tree = engine.open_tree("users").unwrap();
+        tree.set(b"alice", b"admin".to_vec()).await.unwrap();
+    }
+    let verification = Engine::verify(&path).unwrap();
+    assert!(verification.is_ok());
+    assert_eq!((verification.segments, verification.entries), (1, 5));
+
+    // Damage the value of "b" and append a torn write.
+    let segment = path.join("00000001.log");
+    let mut data = fs::read(&segment).unwrap();
+    let pos = data.windows(6).position(|w| w == b"second").unwrap();
+    data[pos] = b'S';
+    data.extend_from_slice(&[0, 0, 0, 16, 0, 0, 0, 1]);
+    fs::write(&segment, &data).unwrap();
+    assert!(matches!(Engine::open(path.clone()), Err(Error::Corrupted(_))));
+
+    let verification = Engine::verify(&path).unwrap();
+    assert!(!verification.is_ok() && !verification.repaired);
+    assert_eq!(verification.entries, 4);
+    assert_eq!(verification.corruptions.len(), 2);
+    assert_eq!(verification.corruptions[1].len, 8);
+    assert_eq!(verification.corruptions[1].offset + 8, data.len() as u64);
+    assert_eq!(verification.lost_keys.len(), 1);
+    let lost = &verification.lost_keys[0];
+    assert_eq!((lost.tree.as_deref(), &lost.key[..]), (None, &b"b"[..]));
+
+    let verification = Engine::repair(&path).unwrap();
+    assert!(verification.repaired);
+    assert_eq!(verification.corruptions.len(), 2);
+    assert!(Engine::verify(&path).unwrap().is_ok());
+    let engine = Engine::open(path.clone()).unwrap();
+    assert_eq!(engine.get(b"a").await.unwrap().as_deref(), Some(&b"first"[..]));
+    assert_eq!(engine.get(b"b").await.unwrap(), None);
+    assert_eq!(engine.get(b"c").await.unwrap().as_deref(), Some(&b"third"[..]));
+    let tree = engine.open_tree("users").unwrap();
+    assert_eq!(tree.get(b"alice").await.unwrap().as_deref(), Some(&b"admin"[..]));
+    drop(tree);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_paranoid_checks_and_scrubbing() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("paranoid.db");
+    let options = EngineOptions {
+        keep_values_in_memory: false,
+        value_cache_size: 0,
+        background_compaction: false,
+        segment_size: 64,
+        paranoid_checks: true,
+        scrub_interval: Some(Duration::from_millis(10)),
+        ..EngineOptions::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    engine.set(b"a", b"first".to_vec()).await.unwrap();
+    engine.set(b"b", b"second".to_vec()).await.unwrap();
+    engine.set(b"c", vec![b'c'; 64]).await.unwrap();
+    engine.flush().await.unwrap();
+    assert_eq!(engine.get(b"b").await.unwrap().as_deref(), Some(&b"second"[..]));
+    assert!(engine.scrub().await.unwrap().is_ok());
+    assert!(engine.health().is_healthy());
+
+    // Damage the value of "b" in the sealed first segment.
+    let segment = path.join("00000001.log");
+    let mut data = fs::read(&segment).unwrap();
+    let pos = data.windows(6).position(|w| w == b"second").unwrap();
+    data[pos] = b'S';
+    fs::write(&segment, &data).unwrap();
+    assert!(matches!(engine.get(b"b").await, Err(Error::Corrupted(_))));
+    assert_eq!(engine.get(b"a").await.unwrap().as_deref(), Some(&b"first"[..]));
+    assert_eq!(engine.health().checksum_failures, 1);
+
+    for _ in 0..500 {
+        if engine.health().corruption.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(engine.health().corruption.map(|corruption| corruption.segment), Some(1));
+    assert!(matches!(engine.health().into_result(), Err(Error::Corrupted(_))));
+    let verification = engine.scrub().await.unwrap();
+    assert_eq!(verification.corruptions.len(), 1);
+    assert_eq!(verification.corruptions[0].segment, 1);
+    assert_eq!(verification.lost_keys[0].key, b"b");
+    drop(engine);
+}
+
+#[tokio::test]
+async fn test_parse_records() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("parse_records.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    engine.set(b"a", b"first".to_vec()).await.unwrap();
+    engine.set_with_ttl(b"b", b"second".to_vec(), Duration::from_secs(3600)).await.unwrap();
+    engine.del(b"a").await.unwrap();
+    engine.close().await.unwrap();
+
+    let data = fs::read(path.join("00000001.log")).unwrap();
+    let records: Vec<tegdb::Record> = tegdb::parse_records(&data).map(Result::unwrap).collect();
+    assert_eq!(records.len(), 3);
+    assert_eq!((&records[0].key[..], &records[0].value[..]), (&b"a"[..], &b"first"[..]));
+    assert!(records[1].expires_at.is_some());
+    assert!(records[2].value.is_empty() && records[2].sequence > records[1].sequence);
+
+    // Every prefix parses without panicking, and a partial last record is reported.
+    for len in 0..data.len() {
+        let parsed: Vec<_> = tegdb::parse_records(&data[..len]).collect();
+        if let Some(Err(e)) = parsed.last() {
+            assert!(matches!(e, tegdb::ParseError::Truncated { .. }));
+            assert!(parsed[..parsed.len() - 1].iter().all(Result::is_ok));
+        }
+    }
+    let mut damaged = data.clone();
+    let pos = damaged.windows(6).position(|w| w == b"second").unwrap();
+    damaged[pos] = b'S';
+    let parsed: Vec<_> = tegdb::parse_records(&damaged).collect();
+    assert_eq!(parsed.len(), 2);
+    assert!(matches!(parsed[1], Err(tegdb::ParseError::ChecksumMismatch { .. })));
+}
+
+#[tokio::test]
+async fn test_core_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("core_store.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    engine.set(b"a", b"first".to_vec()).await.unwrap();
+    engine.set(b"b", b"second".to_vec()).await.unwrap();
+    engine.del(b"a").await.unwrap();
+    engine.close().await.unwrap();
+    drop(engine);
+
+    // The core store reads a segment the engine wrote, and the engine replays its writes.
+    let segment = path.join("00000001.log");
+    let storage = tegdb_core::MemoryStorage::from(fs::read(&segment).unwrap());
+    let mut store = tegdb_core::Store::open(storage).unwrap();
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec![&b"b"[..]]);
+    assert_eq!(store.get(b"b").unwrap(), Some(b"second".to_vec()));
+    store.set(b"c", b"third").unwrap();
+    store.del(b"b").unwrap();
+    fs::write(&segment, store.into_storage().into_bytes()).unwrap();
+
+    let engine = Engine::open(path).unwrap();
+    assert_eq!(engine.get(b"b").await.unwrap(), None);
+    assert_eq!(engine.get(b"c").await.unwrap(), Some(Bytes::from("third")));
+    engine.close().await.unwrap();
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_model_consistency() {
+    use tegdb::testing::ModelTest;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("model_consistency.db");
+    let options = EngineOptions {
+        segment_size: 4096,
+        compaction_min_size: 4096,
+        compaction_interval: Duration::from_millis(5),
+        ..Default::default()
+    };
+    for seed in 1..=4 {
+        let _ = fs::remove_dir_all(&path);
+        let test = ModelTest {
+            seed,
+            ops: 2000,
+            keys: 32,
+            crash_rate: 0.01,
+            torn_writes: seed % 2 == 0,
+            ..ModelTest::default()
+        };
+        if let Err(failure) = test.run(&path, &options) {
+            panic!("{}", failure);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_background_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("background_compaction.db");
+    let options = EngineOptions {
+        compaction_min_size: 1024,
+        compaction_garbage_ratio: 0.5,
+        compaction_interval: Duration::from_millis(20),
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    for i in 0..1000 {
+        engine.set(b"key", format!("value_{}", i).into_bytes()).await.unwrap();
+    }
+    let mut compacted = false;
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        if dir_size(&path) < 1024 {
+            compacted = true;
+            break;
+        }
+    }
+    assert!(compacted, "Expected the log to be compacted below 1024 bytes");
+    engine.set(b"other", b"value".to_vec()).await.unwrap();
+    engine.close().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    assert_eq!(engine.get(b"key").await.unwrap(), Some(Bytes::from_static(b"value_999")));
+    assert_eq!(engine.get(b"other").await.unwrap(), Some(Bytes::from_static(b"value")));
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_manual_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("manual_compaction.db");
+    let options = EngineOptions {
+        background_compaction: false,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    for i in 0..100 {
+        engine.set(b"key", format!("value_{}", i).into_bytes()).await.unwrap();
+    }
+    engine.set(b"removed", b"value".to_vec()).await.unwrap();
+    engine.del(b"removed").await.unwrap();
+    let reclaimed = engine.compact().await.unwrap();
+    assert!(reclaimed > 0, "Expected compaction to reclaim space");
+    assert_eq!(engine.compact().await.unwrap(), 0);
+    assert_eq!(engine.get(b"key").await.unwrap(), Some(Bytes::from_static(b"value_99")));
+    assert_eq!(engine.get(b"removed").await.unwrap(), None);
+    engine.close().await.unwrap();
+}
+
+#[test]
+fn test_compaction_throttle() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("compaction_throttle.db");
+    let options = EngineOptions {
+        background_compaction: false,
+        compaction_bytes_per_second: Some(1024 * 1024),
+        ..Default::default()
+    };
+    let engine = blocking::Engine::open_with_options(&path, options).unwrap();
+    for i in 0..400 {
+        engine.set(format!("key_{}", i % 10).as_bytes(), vec![i as u8; 1000]).unwrap();
+    }
+    let log_bytes = engine.stats().log_bytes;
+    assert!(log_bytes > 400 * 1000);
+    assert_eq!(engine.stats().compaction_bytes, 0);
+
+    // About 400 KB at 1 MB/s takes at least a third of a second, during which progress is
+    // reported.
+    let started = std::time::Instant::now();
+    let compaction = std::thread::spawn({
+        let engine = engine.clone();
+        move || engine.compact().unwrap()
+    });
+    let mut progress = Vec::new();
+    while !compaction.is_finished() {
+        let stats = engine.stats();
+        progress.push((stats.compaction_bytes, stats.compaction_done_bytes));
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert!(compaction.join().unwrap() > 0);
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert!(progress.iter().any(|&(total, done)| total == log_bytes && done > 0 && done < total));
+    let stats = engine.stats();
+    assert_eq!((stats.compaction_bytes, stats.compaction_done_bytes), (0, 0));
+    assert_eq!(engine.len(), 10);
+    engine.close().unwrap();
+}
+
+#[tokio::test]
+async fn test_throttled_compaction_leaves_executor_free() {
+    let engine = Engine::open_temporary_with_options(EngineOptions {
+        background_compaction: false,
+        compaction_bytes_per_second: Some(1024 * 1024),
+        ..Default::default()
+    })
+    .unwrap();
+    for i in 0..400 {
+        engine.set(format!("key_{}", i % 10).as_bytes(), vec![i as u8; 1000]).await.unwrap();
+    }
+
+    // The compaction is paced to take a third of a second, during which the single thread of
+    // the executor keeps serving reads and writes.
+    let compaction = tokio::spawn({
+        let engine = engine.clone();
+        async move { engine.compact().await }
+    });
+    tokio::task::yield_now().await;
+    engine.set(b"during", b"compaction".to_vec()).await.unwrap();
+    assert_eq!(engine.get(b"key_0").await.unwrap().map(|value| value[0]), Some(390_u32 as u8));
+    assert!(!compaction.is_finished());
+    assert!(compaction.await.unwrap().unwrap() > 0);
+    assert_eq!(engine.get(b"during").await.unwrap(), Some(Bytes::from_static(b"compaction")));
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_compact_leaves_executor_free() {
+    let engine = Engine::open_temporary_with_options(EngineOptions {
+        background_compaction: false,
+        ..Default::default()
+    })
+    .unwrap();
+    for i in 0..400 {
+        engine.set(format!("key_{}", i % 10).as_bytes(), vec![i as u8; 1000]).await.unwrap();
+    }
+
+    // The test runs on a single-threaded executor, so the ticker only gets to run if the
+    // compaction yields it while rewriting the log.
+    let ticks = Arc::new(std::sync::atomic::AtomicU64::new(0));
+    let ticker = tokio::spawn({
+        let ticks = ticks.clone();
+        async move {
+            loop {
+                ticks.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::task::yield_now().await;
+            }
+        }
+    });
+    assert!(engine.compact().await.unwrap() > 0);
+    assert!(ticks.load(std::sync::atomic::Ordering::SeqCst) > 0);
+    ticker.abort();
+    assert_eq!(engine.len(), 10);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_compaction_events() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("compaction_events.db");
+    let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let options = EngineOptions {
+        segment_size: 4096,
+        background_compaction: false,
+        on_compaction: Some(CompactionHook::new({
+            let events = events.clone();
+            move |event| events.lock().unwrap().push(event.clone())
+        })),
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    for i in 0..100 {
+        engine.set(format!("key_{}", i % 10).as_bytes(), vec![i as u8; 200]).await.unwrap();
+    }
+    let log_bytes = engine.stats().log_bytes;
+    let reclaimed = engine.compact().await.unwrap();
+    let events = events.lock().unwrap().clone();
+    let CompactionEvent::Started { segments, bytes } = events[0] else {
+        panic!("unexpected first event {:?}", events[0]);
+    };
+    assert!(segments > 1);
+    assert_eq!(bytes, log_bytes);
+    assert_eq!(events.len(), segments + 2);
+    let mut previous = 0;
+    for event in &events[1..=segments] {
+        let &CompactionEvent::Progress { done, total } = event else {
+            panic!("unexpected event {:?}", event);
+        };
+        assert!(done > previous && done <= total);
+        assert_eq!(total, bytes);
+        previous = done;
+    }
+    assert_eq!(previous, bytes);
+    let CompactionEvent::Finished { reclaimed: finished, .. } = events[segments + 1] else {
+        panic!("unexpected last event {:?}", events[segments + 1]);
+    };
+    assert_eq!(finished, reclaimed);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_compaction_strategies() {
+    use tegdb::{CompactionStrategy, FullRewrite, SegmentSpace, SizeTiered, TimeWindowed};
+
+    let segment = |id, live_bytes, sealed_at| SegmentSpace {
+        id,
+        log_bytes: 4096,
+        live_bytes,
+        sealed_at,
+    };
+    let active = segment(9, 4096, None);
+
+    let full = FullRewrite::default();
+    let sealed = vec![segment(1, 0, Some(0)), segment(2, 4096, Some(0))];
+    assert_eq!(full.select(&sealed, 0), vec![1, 2]);
+    assert!(!full.is_due(&[sealed.clone(), vec![active.clone()]].concat(), 0));
+    let large: Vec<SegmentSpace> = (0..512).map(|id| segment(id, 2048, Some(0))).collect();
+    assert!(full.is_due(&large, 0));
+
+    // Four small segments are merged, but not together with the full one after them.
+    let tiered = SizeTiered::default();
+    let mut sealed: Vec<SegmentSpace> = (1..=4).map(|id| segment(id, 1000, Some(0))).collect();
+    sealed.push(segment(5, 4096, Some(0)));
+    assert_eq!(tiered.select(&sealed, 0), vec![1, 2, 3, 4]);
+    assert!(tiered.is_due(&[sealed.clone(), vec![active.clone()]].concat(), 0));
+    assert!(tiered.select(&sealed[1..], 0).is_empty());
+    sealed[0].live_bytes = 0;
+    assert_eq!(tiered.select(&sealed, 0), vec![1]);
+    assert!(tiered.select(&[segment(1, 1000, Some(0)), segment(2, 0, Some(0))], 0).is_empty());
+    let full_segments: Vec<SegmentSpace> = (1..=4).map(|id| segment(id, 4000, Some(0))).collect();
+    assert!(tiered.select(&full_segments, 0).is_empty());
+
+    // Only closed windows are compacted, and only as far as enough of them is garbage.
+    let windowed = TimeWindowed {
+        window: Duration::from_secs(1),
+        garbage_ratio: 0.5,
+    };
+    let sealed = vec![
+        segment(1, 0, Some(100)),
+        segment(2, 3072, Some(500)),
+        segment(3, 4096, Some(1200)),
+        segment(4, 0, Some(2500)),
+    ];
+    assert_eq!(windowed.select(&sealed, 2600), vec![1, 2]);
+    assert_eq!(windowed.select(&sealed, 900), Vec::<u64>::new());
+    assert_eq!(windowed.select(&sealed, 3000), vec![1, 2, 3, 4]);
+    assert!(windowed.select(&sealed[2..3], 3000).is_empty());
+
+    // An engine compacts in the background as its strategy sets out.
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("compaction_strategies.db");
+    let options = EngineOptions {
+        segment_size: 1024,
+        compaction_interval: Duration::from_millis(20),
+        compaction_strategy: Some(Arc::new(SizeTiered::default())),
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    for i in 0..100 {
+        engine.set(format!("key_{}", i % 5).as_bytes(), vec![i as u8; 100]).await.unwrap();
+    }
+    let space = engine.space_stats();
+    let (active, sealed) = space.segments.split_last().unwrap();
+    assert!(sealed.iter().all(|segment| segment.sealed_at.is_some()));
+    assert_eq!(active.sealed_at, None);
+    let mut compacted = false;
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        if engine.space_stats().segments.len() < 4 {
+            compacted = true;
+            break;
+        }
+    }
+    assert!(compacted, "Expected the small segments to be merged");
+    for i in 95..100 {
+        let value = engine.get(format!("key_{}", i % 5).as_bytes()).await.unwrap();
+        assert_eq!(value.as_deref(), Some(&vec![i as u8; 100][..]));
+    }
+    engine.close().await.unwrap();
+}
+
+#[derive(Debug, Default)]
+struct RecordingObserver {
+    calls: std::sync::Mutex<Vec<String>>,
+}
+
+impl EngineObserver for RecordingObserver {
+    fn on_set(&self, tree: &str, key: &[u8], value: &[u8], _expires_at: Option<u64>, sequence: u64) {
+        let (key, value) = (String::from_utf8_lossy(key), String::from_utf8_lossy(value));
+        self.calls.lock().unwrap().push(format!("set {:?} {} {} @{}", tree, key, value, sequence));
+    }
+
+    fn on_delete(&self, tree: &str, key: &[u8], sequence: u64) {
+        let key = String::from_utf8_lossy(key);
+        self.calls.lock().unwrap().push(format!("del {:?} {} @{}", tree, key, sequence));
+    }
+
+    fn on_delete_range(&self, tree: &str, start: &[u8], end: Option<&[u8]>, sequence: u64) {
+        let (start, end) = (String::from_utf8_lossy(start), end.map(String::from_utf8_lossy));
+        self.calls.lock().unwrap().push(format!("delete_range {:?} {}..{:?} @{}", tree, start, end, sequence));
+    }
+
+    fn on_compact(&self, event: &CompactionEvent) {
+        if let CompactionEvent::Finished { .. } = event {
+            self.calls.lock().unwrap().push("compacted".to_string());
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_observers() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("observers.db");
+    let observer = Arc::new(RecordingObserver::default());
+    let options = EngineOptions {
+        background_compaction: false,
+        observers: vec![observer.clone()],
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    engine.set(b"a", b"1".to_vec()).await.unwrap();
+    let users = engine.open_tree("users").unwrap();
+    users.set(b"alice", b"admin".to_vec()).await.unwrap();
+    users.del(b"alice").await.unwrap();
+    // Deleting a missing key writes nothing.
+    users.del(b"bob").await.unwrap();
+    engine.delete_range(b"a".to_vec()..b"b".to_vec()).await.unwrap();
+    assert!(engine.drop_tree("users").unwrap());
+    // The id of a dropped tree is handed out again under its new name.
+    let orders = engine.open_tree("orders").unwrap();
+    orders.set(b"1", b"shipped".to_vec()).await.unwrap();
+    engine.compact().await.unwrap();
+    let sequence = engine.last_sequence();
+    let calls = observer.calls.lock().unwrap().clone();
+    assert_eq!(
+        calls,
+        vec![
+            "set \"\" a 1 @1".to_string(),
+            "set \"users\" alice admin @3".to_string(),
+            "del \"users\" alice @4".to_string(),
+            "delete_range \"\" a..Some(\"b\") @5".to_string(),
+            "delete_range \"users\" ..None @6".to_string(),
+            format!("set \"orders\" 1 shipped @{}", sequence),
+            "compacted".to_string(),
+        ]
+    );
+    engine.close().await.unwrap();
+}
+
+#[derive(Debug, PartialEq)]
+struct OutsideTenant(Vec<u8>);
+
+impl std::fmt::Display for OutsideTenant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "key {:?} is outside the tenant", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl std::error::Error for OutsideTenant {}
+
+// Confines the writes to the tree "docs" to the keys of tenant "t1/", and trims the values.
+#[derive(Debug)]
+struct Tenant;
+
+impl WriteInterceptor for Tenant {
+    fn intercept(&self, tree: &str, mutation: &mut Mutation) -> Result<(), Rejection> {
+        if tree != "docs" {
+            return Ok(());
+        }
+        let key = match mutation {
+            Mutation::Set { key, value, .. } => {
+                *value = value.trim_ascii().to_vec();
+                key
+            }
+            Mutation::Del { key } => key,
+            Mutation::DelRange { start, end } => {
+                // Clearing the tree only clears the tenant's keys.
+                if start.is_empty() && end.is_none() {
+                    *mutation = Mutation::DelRange { start: b"t1/".to_vec(), end: Some(b"t10".to_vec()) };
+                }
+                return Ok(());
+            }
+        };
+        match key.starts_with(b"t1/") {
+            true => Ok(()),
+            false => Err(Box::new(OutsideTenant(key.clone()))),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_interceptors() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("interceptors.db");
+    let options = EngineOptions {
+        interceptors: vec![Arc::new(Tenant)],
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    let docs = engine.open_tree("docs").unwrap();
+    docs.set(b"t1/a", b"  one ".to_vec()).await.unwrap();
+    assert_eq!(docs.get(b"t1/a").await.unwrap(), Some(Bytes::from("one")));
+    let Err(Error::Rejected(reason)) = docs.set(b"t2/a", b"two".to_vec()).await else {
+        panic!("the write was not rejected");
+    };
+    assert_eq!(reason.downcast_ref(), Some(&OutsideTenant(b"t2/a".to_vec())));
+    assert!(matches!(docs.del(b"t2/a").await, Err(Error::Rejected(_))));
+    assert_eq!(docs.len(), 1);
+
+    // Other trees are left alone.
+    engine.set(b"t2/a", b" two ".to_vec()).await.unwrap();
+    assert_eq!(engine.get(b"t2/a").await.unwrap(), Some(Bytes::from(" two ")));
+
+    let mut pipeline = docs.pipeline();
+    pipeline.set(b"t1/b", b" two ".to_vec()).await.unwrap();
+    pipeline.set(b"t2/b", b"two".to_vec()).await.unwrap();
+    assert!(matches!(pipeline.flush().await, Err(Error::Rejected(_))));
+    drop(pipeline);
+    // The writes made before the rejected one in the same batch stay.
+    assert_eq!(docs.get(b"t1/b").await.unwrap(), Some(Bytes::from("two")));
+
+    // Clearing the tree is narrowed to the tenant's keys, which are all it holds.
+    docs.clear().await.unwrap();
+    assert!(docs.is_empty());
+    assert_eq!(docs.bulk_load(vec![(b"t1/c".to_vec(), b" three".to_vec())]).await.unwrap(), 1);
+    assert_eq!(docs.get(b"t1/c").await.unwrap(), Some(Bytes::from("three")));
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_segmented_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("segmented.db");
+    let options = EngineOptions {
+        segment_size: 256,
+        background_compaction: false,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    for i in 0..50 {
+        let key = format!("key_{}", i % 10).into_bytes();
+        engine.set(&key, format!("value_{}", i).into_bytes()).await.unwrap();
+    }
+    let segments = fs::read_dir(&path)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+        .count();
+    assert!(segments > 1, "Expected the log to span several segments, Got: {}", segments);
+    let reclaimed = engine.compact().await.unwrap();
+    assert!(reclaimed > 0, "Expected compaction to reclaim space");
+    engine.set(b"key_0", b"latest".to_vec()).await.unwrap();
+    engine.close().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    assert_eq!(engine.get(b"key_0").await.unwrap(), Some(Bytes::from_static(b"latest")));
+    for i in 1..10 {
+        let key = format!("key_{}", i).into_bytes();
+        assert_eq!(engine.get(&key).await.unwrap(), Some(Bytes::from(format!("value_{}", 40 + i))));
+    }
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_replay_across_segments() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("replay_segments.db");
+    let options = EngineOptions {
+        segment_size: 512,
+        background_compaction: false,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    let mut expected = std::collections::BTreeMap::new();
+    for i in 0..300 {
+        let key = format!("key_{:02}", i % 40);
+        match i % 7 {
+            // Segments are decoded in parallel, so later segments must still win on replay.
+            3 => {
+                engine.del(key.as_bytes()).await.unwrap();
+                expected.remove(&key);
+            }
+            6 => {
+                let end = format!("key_{:02}", i % 40 + 3);
+                engine.delete_range(key.clone().into_bytes()..end.clone().into_bytes()).await.unwrap();
+                expected.retain(|k: &String, _| *k < key || *k >= end);
+            }
+            _ => {
+                let value = format!("value_{}", i);
+                engine.set(key.as_bytes(), value.clone().into_bytes()).await.unwrap();
+                expected.insert(key, value);
+            }
+        }
+    }
+    engine.close().await.unwrap();
+    drop(engine);
+
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    let replayed: Vec<(String, String)> = engine
+        .scan(..)
+        .await
+        .unwrap()
+        .map(|(k, v)| (String::from_utf8(k.to_vec()).unwrap(), String::from_utf8(v.to_vec()).unwrap()))
+        .collect();
+    assert_eq!(replayed, expected.into_iter().collect::<Vec<_>>());
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_startup_replay() {
+    // CRC-32 computed a bit at a time, to check the table-driven one records are written with.
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &byte in data {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+            }
+        }
+        !crc
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("startup_replay.db");
+    let options = EngineOptions {
+        segment_size: 4096,
+        background_compaction: false,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    // Values of every length up to 40 bytes leave every remainder after the checksum's 8-byte
+    // steps.
+    let value = |i: usize| vec![i as u8; i % 40 + 1];
+    for i in 0..1000 {
+        engine.set(format!("key_{:04}", i).as_bytes(), value(i)).await.unwrap();
+    }
+    engine.close().await.unwrap();
+    drop(engine);
+
+    let mut checked = 0;
+    for entry in fs::read_dir(&path).unwrap() {
+        let segment = entry.unwrap().path();
+        if segment.extension() != Some("log".as_ref()) {
+            continue;
+        }
+        let data = fs::read(&segment).unwrap();
+        let mut records = tegdb::parse_records(&data);
+        loop {
+            let start = records.offset() as usize;
+            let Some(record) = records.next() else {
+                break;
+            };
+            let end = records.offset() as usize;
+            assert!(record.unwrap().checksum);
+            assert_eq!(crc32(&data[start..end - 4]), u32::from_be_bytes(data[end - 4..end].try_into().unwrap()));
+            checked += 1;
+        }
+    }
+    assert_eq!(checked, 1000);
+
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    assert_eq!(engine.len(), 1000);
+    for i in 0..1000 {
+        let found = engine.get(format!("key_{:04}", i).as_bytes()).await.unwrap();
+        assert_eq!(found.as_deref(), Some(&value(i)[..]));
+    }
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_background_replay() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("background_replay.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    let users = engine.open_tree("users").unwrap();
+    for i in 0..1000 {
+        engine.set(format!("key_{}", i).as_bytes(), vec![1; 100]).await.unwrap();
+    }
+    users.set(b"alice", b"admin".to_vec()).await.unwrap();
+    engine.close().await.unwrap();
+    drop((engine, users));
+
+    let options = EngineOptions {
+        background_replay: true,
+        ..Default::default()
+    };
+    // Operations wait for the replay, however early they are made.
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    assert_eq!(engine.get(b"key_999").await.unwrap().unwrap().len(), 100);
+    assert!(engine.is_replayed());
+    assert_eq!(engine.len(), 1000);
+    engine.set(b"key_1000", vec![2; 100]).await.unwrap();
+    assert_eq!(engine.len(), 1001);
+    let users = engine.open_tree("users").unwrap();
+    assert_eq!(users.get(b"alice").await.unwrap().unwrap().as_ref(), b"admin");
+    engine.close().await.unwrap();
+    drop((engine, users));
+    fs::remove_dir_all(&path).unwrap();
+
+    // A replay that fails makes operations fail rather than find nothing.
+    fs::create_dir_all(&path).unwrap();
+    fs::write(path.join("00000001.log"), [0, 0, 0, 16, 0, 0, 0, 1]).unwrap();
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    assert!(matches!(engine.get(b"key").await, Err(Error::Corrupted(_))));
+    assert!(matches!(engine.set(b"key", b"value".to_vec()).await, Err(Error::Corrupted(_))));
+    assert!(engine.is_replayed());
+    drop(engine);
+}
+
+#[tokio::test]
+async fn test_index_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("index_snapshot.db");
+    let options = EngineOptions {
+        segment_size: 1024,
+        background_compaction: false,
+        index_snapshot_interval: Some(Duration::from_secs(3600)),
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    let users = engine.open_tree("users").unwrap();
+    for i in 0..200 {
+        engine.set(format!("key_{:03}", i).as_bytes(), format!("value_{}", i).into_bytes()).await.unwrap();
+    }
+    engine.set_with_ttl(b"short", b"lived".to_vec(), Duration::from_millis(50)).await.unwrap();
+    users.set(b"alice", b"admin".to_vec()).await.unwrap();
+    engine.close().await.unwrap();
+    drop((engine, users));
+    assert!(path.join("INDEX").exists());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Writes after the snapshot are replayed on top of it; dropping the engine without closing
+    // it leaves the snapshot as it was.
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    assert_eq!(engine.len(), 200);
+    assert_eq!(engine.get(b"key_007").await.unwrap().unwrap().as_ref(), b"value_7");
+    engine.del(b"key_000").await.unwrap();
+    engine.delete_range(b"key_100".to_vec()..b"key_150".to_vec()).await.unwrap();
+    engine.set(b"key_199", b"updated".to_vec()).await.unwrap();
+    engine.open_tree("users").unwrap().del(b"alice").await.unwrap();
+    engine.open_tree("groups").unwrap().set(b"admins", b"alice".to_vec()).await.unwrap();
+    engine.flush().await.unwrap();
+    drop(engine);
+
+    let check = |engine: Engine| async move {
+        assert_eq!(engine.len(), 149);
+        assert_eq!(engine.get(b"key_000").await.unwrap(), None);
+        assert_eq!(engine.get(b"key_120").await.unwrap(), None);
+        assert_eq!(engine.get(b"key_099").await.unwrap().unwrap().as_ref(), b"value_99");
+        assert_eq!(engine.get(b"key_199").await.unwrap().unwrap().as_ref(), b"updated");
+        assert!(engine.open_tree("users").unwrap().is_empty());
+        let groups = engine.open_tree("groups").unwrap();
+        assert_eq!(groups.get(b"admins").await.unwrap().unwrap().as_ref(), b"alice");
+        engine.close().await.unwrap();
+    };
+    check(Engine::open_with_options(path.clone(), options.clone()).unwrap()).await;
+    let on_disk = EngineOptions {
+        keep_values_in_memory: false,
+        ..options.clone()
+    };
+    check(Engine::open_with_options(path.clone(), on_disk).unwrap()).await;
+
+    // Snapshots that are damaged or point into rewritten segments are ignored.
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    engine.compact().await.unwrap();
+    drop(engine);
+    check(Engine::open_with_options(path.clone(), options.clone()).unwrap()).await;
+    fs::write(path.join("INDEX"), b"garbage").unwrap();
+    check(Engine::open_with_options(path.clone(), options.clone()).unwrap()).await;
+
+    // Snapshots are also written periodically.
+    fs::remove_file(path.join("INDEX")).unwrap();
+    let periodic = EngineOptions {
+        index_snapshot_interval: Some(Duration::from_millis(10)),
+        ..options
+    };
+    let engine = Engine::open_with_options(path.clone(), periodic).unwrap();
+    engine.set(b"key_000", b"again".to_vec()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(path.join("INDEX").exists());
     drop(engine);
-    fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_interrupted_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("interrupted_compaction.db");
+    let options = EngineOptions {
+        background_compaction: false,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    engine.set(b"key", b"value".to_vec()).await.unwrap();
+    engine.close().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // A crash during compaction leaves output segments the manifest does not list yet.
+    fs::write(path.join("00000002.log"), b"partial compaction output").unwrap();
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    assert!(!path.join("00000002.log").exists());
+    engine.compact().await.unwrap();
+    engine.set(b"other", b"value".to_vec()).await.unwrap();
+    engine.close().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    assert_eq!(engine.get(b"key").await.unwrap(), Some(Bytes::from_static(b"value")));
+    assert_eq!(engine.get(b"other").await.unwrap(), Some(Bytes::from_static(b"value")));
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_failed_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("failed_compaction.db");
+    let options = EngineOptions {
+        segment_size: 512,
+        background_compaction: false,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    for i in 0..40 {
+        engine.set(format!("live_{:02}", i).as_bytes(), b"value".to_vec()).await.unwrap();
+    }
+    for i in 0..200 {
+        let key = format!("key_{:02}", i % 20).into_bytes();
+        engine.set(&key, format!("value_{}", i).into_bytes()).await.unwrap();
+    }
+    engine.flush().await.unwrap();
+
+    // Corrupt the last record of a sealed segment past those holding live entries, so that the
+    // compaction fails once it has written output.
+    let segments = engine.space_stats().segments;
+    let corrupted = path.join(format!("{:08}.log", segments[segments.len() / 2].id));
+    let mut data = fs::read(&corrupted).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    fs::write(&corrupted, data).unwrap();
+    assert!(engine.compact().await.is_err());
+
+    let mut listed: Vec<_> = engine.space_stats().segments.iter().map(|s| s.id).collect();
+    let mut on_disk: Vec<u64> = fs::read_dir(&path)
+        .unwrap()
+        .filter_map(|entry| {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            name.strip_suffix(".log").map(|id| id.parse().unwrap())
+        })
+        .collect();
+    listed.sort();
+    on_disk.sort();
+    assert_eq!(listed, on_disk);
+    assert_eq!(engine.get(b"key_00").await.unwrap(), Some(Bytes::from_static(b"value_180")));
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_open_single_file_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("single_file.db");
+    // A log written before segmentation: one record with a 3-byte key and 5-byte value.
+    let mut legacy = vec![0, 0, 0, 3, 0, 0, 0, 5];
+    legacy.extend_from_slice(b"keyvalue");
+    fs::write(&path, legacy).unwrap();
+    let engine = Engine::open(path.clone()).unwrap();
+    assert_eq!(engine.get(b"key").await.unwrap(), Some(Bytes::from_static(b"value")));
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_interrupted_migration() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("migrated.db");
+    let mut legacy = vec![0, 0, 0, 3, 0, 0, 0, 5];
+    legacy.extend_from_slice(b"keyvalue");
+
+    // Interrupted after the file was moved aside, and again after the directory was created.
+    for create_dir in [false, true] {
+        let _ = fs::remove_dir_all(&path);
+        fs::write(dir.path().join("migrated.migrating"), &legacy).unwrap();
+        if create_dir {
+            fs::create_dir(&path).unwrap();
+        }
+        let options = EngineOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            Engine::open_with_options(path.clone(), options),
+            Err(tegdb::Error::Corrupted(_))
+        ));
+        let engine = Engine::open(path.clone()).unwrap();
+        assert_eq!(engine.get(b"key").await.unwrap(), Some(Bytes::from_static(b"value")));
+        engine.close().await.unwrap();
+        assert!(!dir.path().join("migrated.migrating").exists());
+    }
+
+    // A file moved aside next to a new single-file log is left for the user to sort out.
+    fs::remove_dir_all(&path).unwrap();
+    fs::write(&path, &legacy).unwrap();
+    fs::write(dir.path().join("migrated.migrating"), &legacy).unwrap();
+    assert!(matches!(Engine::open(path.clone()), Err(tegdb::Error::Corrupted(_))));
+}
+
+#[tokio::test]
+async fn test_values_on_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("values_on_disk.db");
+    let options = EngineOptions {
+        keep_values_in_memory: false,
+        value_cache_size: 64,
+        segment_size: 512,
+        background_compaction: false,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    for i in 0..100 {
+        let key = format!("key_{:02}", i % 20).into_bytes();
+        engine.set(&key, format!("value_{}", i).into_bytes()).await.unwrap();
+    }
+    for i in 0..20 {
+        let key = format!("key_{:02}", i).into_bytes();
+        assert_eq!(engine.get(&key).await.unwrap(), Some(Bytes::from(format!("value_{}", 80 + i))));
+    }
+    engine.compact().await.unwrap();
+    let scanned: Vec<_> = engine
+        .scan(b"key_00".to_vec()..b"key_03".to_vec())
+        .await
+        .unwrap()
+        .collect();
+    assert_eq!(
+        scanned,
+        vec![
+            (Bytes::from_static(b"key_00"), Bytes::from_static(b"value_80")),
+            (Bytes::from_static(b"key_01"), Bytes::from_static(b"value_81")),
+            (Bytes::from_static(b"key_02"), Bytes::from_static(b"value_82")),
+        ]
+    );
+    engine.close().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    for i in 0..20 {
+        let key = format!("key_{:02}", i).into_bytes();
+        assert_eq!(engine.get(&key).await.unwrap(), Some(Bytes::from(format!("value_{}", 80 + i))));
+    }
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_get_does_not_copy() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("zero_copy.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    engine.set(b"key", vec![7; 1024]).await.unwrap();
+    let first = engine.get(b"key").await.unwrap().unwrap();
+    let second = engine.get(b"key").await.unwrap().unwrap();
+    assert_eq!(first.as_ptr(), second.as_ptr(), "Expected both reads to share one buffer");
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_scan_stream() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("scan_stream.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    for i in 0..10 {
+        let key = format!("key_{}", i).into_bytes();
+        engine.set(&key, format!("value_{}", i).into_bytes()).await.unwrap();
+    }
+    let mut stream = Box::pin(engine.scan_stream(b"key_2".to_vec()..b"key_6".to_vec()));
+    assert_eq!(
+        stream.next().await.unwrap().unwrap(),
+        (Bytes::from_static(b"key_2"), Bytes::from_static(b"value_2"))
+    );
+    // Keys deleted after the stream was created are skipped.
+    engine.del(b"key_3").await.unwrap();
+    let rest: Vec<_> = stream.map(|item| item.unwrap()).collect().await;
+    assert_eq!(
+        rest,
+        vec![
+            (Bytes::from_static(b"key_4"), Bytes::from_static(b"value_4")),
+            (Bytes::from_static(b"key_5"), Bytes::from_static(b"value_5")),
+        ]
+    );
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_scan_prefix() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("scan_prefix.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    let keys: [&[u8]; 7] = [
+        b"a",
+        b"ab",
+        b"ab\xff",
+        b"ac",
+        b"\xff",
+        b"\xff\xff\x01",
+        b"\xfe\xff",
+    ];
+    for key in keys {
+        engine.set(key, b"value".to_vec()).await.unwrap();
+    }
+    let scan_keys = |prefix: &'static [u8]| {
+        let engine = engine.clone();
+        async move {
+            engine
+                .scan_prefix(prefix)
+                .await
+                .unwrap()
+                .map(|(key, _)| key.to_vec())
+                .collect::<Vec<_>>()
+        }
+    };
+    assert_eq!(scan_keys(b"ab").await, vec![b"ab".to_vec(), b"ab\xff".to_vec()]);
+    assert_eq!(scan_keys(b"ab\xff").await, vec![b"ab\xff".to_vec()]);
+    assert_eq!(scan_keys(b"\xfe").await, vec![b"\xfe\xff".to_vec()]);
+    assert_eq!(scan_keys(b"\xff").await, vec![b"\xff".to_vec(), b"\xff\xff\x01".to_vec()]);
+    assert_eq!(scan_keys(b"\xff\xff").await, vec![b"\xff\xff\x01".to_vec()]);
+    assert_eq!(scan_keys(b"").await.len(), keys.len());
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_scan_range_bounds() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("scan_range_bounds.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    for key in [b"a", b"b", b"c", b"d"] {
+        engine.set(key, key.to_vec()).await.unwrap();
+    }
+    let keys = |iter: tegdb::Pairs| {
+        iter.map(|(key, _)| key).collect::<Vec<_>>()
+    };
+    assert_eq!(
+        keys(engine.scan(b"b".to_vec()..=b"c".to_vec()).await.unwrap()),
+        vec![Bytes::from_static(b"b"), Bytes::from_static(b"c")]
+    );
+    assert_eq!(
+        keys(engine.scan(b"c".to_vec()..).await.unwrap()),
+        vec![Bytes::from_static(b"c"), Bytes::from_static(b"d")]
+    );
+    assert_eq!(
+        keys(engine.scan(..b"b".to_vec()).await.unwrap()),
+        vec![Bytes::from_static(b"a")]
+    );
+    assert_eq!(keys(engine.scan(..).await.unwrap()).len(), 4);
+    let streamed: Vec<_> = engine.scan_stream(..=b"b".to_vec()).map(|item| item.unwrap().0).collect().await;
+    assert_eq!(streamed, vec![Bytes::from_static(b"a"), Bytes::from_static(b"b")]);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_scan_empty_range() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("scan_empty_range.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    engine.set(b"b", b"value".to_vec()).await.unwrap();
+    #[allow(clippy::reversed_empty_ranges)]
+    let reversed = b"c".to_vec()..b"a".to_vec();
+    assert_eq!(engine.scan(reversed.clone()).await.unwrap().count(), 0);
+    assert_eq!(engine.scan_stream(reversed).count().await, 0);
+    assert_eq!(engine.scan(b"b".to_vec()..b"b".to_vec()).await.unwrap().count(), 0);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_scan_iterators() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("scan_iterators.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    for key in [b"a", b"b", b"c", b"d"] {
+        engine.set(key, key.to_vec()).await.unwrap();
+    }
+
+    let mut pairs = engine.scan(..).await.unwrap();
+    assert_eq!(pairs.len(), 4);
+    assert_eq!(pairs.next_back().unwrap().0.as_ref(), b"d");
+    assert_eq!(pairs.next().unwrap().0.as_ref(), b"a");
+    assert_eq!(pairs.len(), 2);
+    let keys: Vec<Bytes> = engine.keys(..).await.unwrap().rev().collect();
+    assert_eq!(keys, vec![&b"d"[..], b"c", b"b", b"a"]);
+
+    let mut iter = engine.iter();
+    assert_eq!(iter.next().unwrap().unwrap().0.as_ref(), b"a");
+    assert_eq!(iter.next_back().unwrap().unwrap().0.as_ref(), b"d");
+    engine.del(b"c").await.unwrap();
+    let rest: Vec<_> = iter.map(|pair| pair.unwrap().0).collect();
+    assert_eq!(rest, vec![&b"b"[..]]);
+
+    let mut count = 0;
+    for pair in &*engine {
+        let (key, value) = pair.unwrap();
+        assert_eq!(key, value);
+        count += 1;
+    }
+    assert_eq!(count, 3);
+    assert_eq!((&engine).into_iter().count(), 3);
+
+    let debug = format!("{:?}", engine);
+    assert!(debug.contains("scan_iterators.db"), "{}", debug);
+    assert!(debug.contains("keys: 3"), "{}", debug);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_scan_page() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("scan_page.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    for i in 0..10 {
+        engine.set(format!("key_{}", i).as_bytes(), vec![i]).await.unwrap();
+    }
+    engine.set(b"other", b"value".to_vec()).await.unwrap();
+
+    let range = b"key_".to_vec()..b"key_~".to_vec();
+    let (page, continuation) = engine.scan_page(range.clone(), 4, None).await.unwrap();
+    let keys: Vec<Bytes> = page.into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, vec![&b"key_0"[..], b"key_1", b"key_2", b"key_3"]);
+    assert_eq!(continuation.as_deref(), Some(&b"key_3"[..]));
+
+    // Writes made between pages are seen by the later ones.
+    engine.del(b"key_4").await.unwrap();
+    let (page, continuation) = engine.scan_page(range.clone(), 4, continuation).await.unwrap();
+    assert_eq!(page[0], (Bytes::from("key_5"), Bytes::from(vec![5])));
+    assert_eq!(page.len(), 4);
+    let (page, continuation) = engine.scan_page(range.clone(), 4, continuation).await.unwrap();
+    assert_eq!(page, vec![(Bytes::from("key_9"), Bytes::from(vec![9]))]);
+    assert_eq!(continuation, None);
+
+    // A page ending on the last pair leaves no continuation.
+    let (page, continuation) = engine.scan_page(..b"key_2".to_vec(), 2, None).await.unwrap();
+    assert_eq!(page.len(), 2);
+    assert_eq!(continuation, None);
+    let (page, continuation) = engine.scan_page(.., 0, None).await.unwrap();
+    assert_eq!(page.len(), 1);
+    assert_eq!(continuation.as_deref(), Some(&b"key_0"[..]));
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_versioned_writes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("versioned_writes.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    assert_eq!(engine.get_versioned(b"key").await.unwrap(), None);
+    assert!(engine.set_if_version(b"key", b"1".to_vec(), None).await.unwrap());
+    assert!(!engine.set_if_version(b"key", b"2".to_vec(), None).await.unwrap());
+    let (value, version) = engine.get_versioned(b"key").await.unwrap().unwrap();
+    assert_eq!(value, Bytes::from_static(b"1"));
+
+    // A write in between makes the version stale, unless it left the value as it was.
+    engine.set(b"key", b"1".to_vec()).await.unwrap();
+    assert_eq!(engine.get_versioned(b"key").await.unwrap(), Some((value.clone(), version)));
+    engine.set(b"key", b"3".to_vec()).await.unwrap();
+    engine.set(b"key", b"1".to_vec()).await.unwrap();
+    assert!(!engine.set_if_version(b"key", b"2".to_vec(), Some(version)).await.unwrap());
+    let (_, version) = engine.get_versioned(b"key").await.unwrap().unwrap();
+    assert!(engine.set_if_version(b"key", b"2".to_vec(), Some(version)).await.unwrap());
+    let (value, newer) = engine.get_versioned(b"key").await.unwrap().unwrap();
+    assert_eq!(value, Bytes::from_static(b"2"));
+    assert!(newer > version);
+
+    // Versions survive reopening, and an empty value deletes the key.
+    engine.close().await.unwrap();
+    let engine = Engine::open(path.clone()).unwrap();
+    assert_eq!(engine.get_versioned(b"key").await.unwrap(), Some((value, newer)));
+    assert!(engine.set_if_version(b"key", Vec::new(), Some(newer)).await.unwrap());
+    assert_eq!(engine.get(b"key").await.unwrap(), None);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_entry_timestamps() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("entry_timestamps.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    engine.set(b"old", b"1".to_vec()).await.unwrap();
+    let entry = engine.get_entry(b"old").await.unwrap().unwrap();
+    assert_eq!((entry.created_at, entry.updated_at), (None, None));
+    engine.close().await.unwrap();
+
+    let options = EngineOptions {
+        record_timestamps: true,
+        ..EngineOptions::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    assert_eq!(engine.get_entry(b"missing").await.unwrap(), None);
+    engine.set(b"key", b"1".to_vec()).await.unwrap();
+    let first = engine.get_entry(b"key").await.unwrap().unwrap();
+    assert_eq!(first.value, Bytes::from_static(b"1"));
+    assert_eq!(first.created_at, first.updated_at);
+    assert!(first.created_at.is_some());
+
+    // Overwriting keeps the creation time, while deleting the key starts over.
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    engine.set(b"key", b"2".to_vec()).await.unwrap();
+    let second = engine.get_entry(b"key").await.unwrap().unwrap();
+    assert_eq!(second.created_at, first.created_at);
+    assert!(second.updated_at > first.updated_at);
+    assert!(second.version > first.version);
+    engine.set(b"old", b"2".to_vec()).await.unwrap();
+    engine.del(b"old").await.unwrap();
+    engine.set(b"old", b"3".to_vec()).await.unwrap();
+    let recreated = engine.get_entry(b"old").await.unwrap().unwrap();
+    assert!(recreated.created_at >= second.updated_at);
+
+    // The timestamps survive compaction and reopening.
+    engine.compact().await.unwrap();
+    assert_eq!(engine.get_entry(b"key").await.unwrap(), Some(second.clone()));
+    engine.close().await.unwrap();
+    let engine = blocking::Engine::open_with_options(path.clone(), options).unwrap();
+    assert_eq!(engine.get_entry(b"key").unwrap(), Some(second));
+    assert_eq!(engine.get_entry(b"old").unwrap(), Some(recreated));
+    engine.close().unwrap();
+}
+
+#[tokio::test]
+async fn test_history() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("history.db");
+    let options = EngineOptions {
+        history_versions: 2,
+        ..EngineOptions::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    let mut versions = Vec::new();
+    for value in [b"1", b"2", b"3", b"4"] {
+        engine.set(b"key", value.to_vec()).await.unwrap();
+        versions.push(engine.get_versioned(b"key").await.unwrap().unwrap().1);
+        tokio::time::sleep(Duration::from_millis(2)).await;
+    }
+    engine.del(b"key").await.unwrap();
+
+    // The deletion and the two versions before it are kept.
+    let history = engine.history(b"key").await.unwrap();
+    let values: Vec<Option<Bytes>> = history.iter().map(|version| version.value.clone()).collect();
+    assert_eq!(values, [Some(Bytes::from_static(b"3")), Some(Bytes::from_static(b"4")), None]);
+    assert_eq!(history[0].version, versions[2]);
+    assert_eq!(engine.get_at(b"key", At::Version(versions[3])).await.unwrap(), Some(Bytes::from_static(b"4")));
+    assert_eq!(engine.get_at(b"key", At::Version(versions[1])).await.unwrap(), None);
+    assert_eq!(engine.get_at(b"key", At::Time(history[0].written_at)).await.unwrap(), Some(Bytes::from_static(b"3")));
+    assert_eq!(engine.get_at(b"key", At::Time(history[2].written_at)).await.unwrap(), None);
+
+    // Undoing the deletion is a version of its own.
+    let undone = engine.get_at(b"key", At::Version(versions[3])).await.unwrap().unwrap();
+    engine.set(b"key", undone.to_vec()).await.unwrap();
+    assert_eq!(engine.history(b"key").await.unwrap().len(), 3);
+
+    // Versions survive compaction and reopening, and go away with their tree.
+    let tree = engine.open_tree("other").unwrap();
+    tree.set(b"key", b"a".to_vec()).await.unwrap();
+    engine.compact().await.unwrap();
+    let history = engine.history(b"key").await.unwrap();
+    engine.close().await.unwrap();
+    let engine = blocking::Engine::open_with_options(path.clone(), options).unwrap();
+    assert_eq!(engine.history(b"key").unwrap(), history);
+    assert_eq!(engine.open_tree("other").unwrap().history(b"key").unwrap().len(), 1);
+    assert!(engine.drop_tree("other").unwrap());
+    assert!(engine.open_tree("other").unwrap().history(b"key").unwrap().is_empty());
+    engine.close().unwrap();
+}
+
+#[tokio::test]
+async fn test_scan_at() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("scan_at.db");
+    let options = EngineOptions {
+        max_value_size: Some(16),
+        chunk_large_values: true,
+        background_compaction: false,
+        ..EngineOptions::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    let pairs = |pairs: &[(&str, &[u8])]| -> Vec<(Bytes, Bytes)> {
+        pairs.iter().map(|(k, v)| (Bytes::from(k.to_string()), Bytes::copy_from_slice(v))).collect()
+    };
+    engine.set(b"a", b"1".to_vec()).await.unwrap();
+    engine.set(b"b", b"1".to_vec()).await.unwrap();
+    engine.set(b"big", vec![1; 40]).await.unwrap();
+    let first = engine.last_sequence();
+    engine.set(b"a", b"2".to_vec()).await.unwrap();
+    engine.del(b"b").await.unwrap();
+    engine.set(b"big", vec![2; 40]).await.unwrap();
+    engine.delete_range(b"c".to_vec()..).await.unwrap();
+
+    let old: Vec<_> = engine.scan_at(.., first).await.unwrap().collect();
+    assert_eq!(old, pairs(&[("a", b"1"), ("b", b"1"), ("big", &[1; 40])]));
+    let old: Vec<_> = engine.scan_at(b"b".to_vec()..b"bz".to_vec(), first).await.unwrap().collect();
+    assert_eq!(old, pairs(&[("b", b"1"), ("big", &[1; 40])]));
+    let latest: Vec<_> = engine.scan_at(.., engine.last_sequence()).await.unwrap().collect();
+    assert_eq!(latest, engine.scan(..).await.unwrap().collect::<Vec<_>>());
+
+    // Compaction drops the overwritten values, and with them the earlier views.
+    engine.compact().await.unwrap();
+    assert!(matches!(
+        engine.scan_at(.., first).await,
+        Err(Error::Compacted { sequence, .. }) if sequence == first
+    ));
+    let latest: Vec<_> = engine.scan_at(.., engine.last_sequence()).await.unwrap().collect();
+    assert_eq!(latest, pairs(&[("a", b"2"), ("big", &[2; 40])]));
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_compare_and_swap() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("compare_and_swap.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    assert!(engine.compare_and_swap(b"key", None, Some(b"1".to_vec())).await.unwrap());
+    assert!(!engine.compare_and_swap(b"key", None, Some(b"2".to_vec())).await.unwrap());
+    assert!(!engine.compare_and_swap(b"key", Some(b"2"), Some(b"3".to_vec())).await.unwrap());
+    assert_eq!(engine.get(b"key").await.unwrap(), Some(Bytes::from_static(b"1")));
+    assert!(engine.compare_and_swap(b"key", Some(b"1"), Some(b"2".to_vec())).await.unwrap());
+    assert_eq!(engine.get(b"key").await.unwrap(), Some(Bytes::from_static(b"2")));
+    assert!(engine.compare_and_swap(b"key", Some(b"2"), None).await.unwrap());
+    assert_eq!(engine.get(b"key").await.unwrap(), None);
+
+    // Concurrent increments through compare-and-swap never lose an update.
+    engine.set(b"counter", b"0".to_vec()).await.unwrap();
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let engine = engine.clone();
+        handles.push(tokio::spawn(async move {
+            for _ in 0..50 {
+                loop {
+                    let current = engine.get(b"counter").await.unwrap().unwrap();
+                    let next: u32 = std::str::from_utf8(&current).unwrap().parse::<u32>().unwrap() + 1;
+                    if engine
+                        .compare_and_swap(b"counter", Some(&current), Some(next.to_string().into_bytes()))
+                        .await
+                        .unwrap()
+                    {
+                        break;
+                    }
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    assert_eq!(engine.get(b"counter").await.unwrap(), Some(Bytes::from_static(b"200")));
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_size_limits() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("size_limits.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    let result = engine.set(&[0; 1025], b"value".to_vec()).await;
+    assert!(matches!(result, Err(Error::KeyTooLarge { len: 1025, limit: 1024 })));
+    let result = engine.set(b"key", vec![0; 256 * 1024 + 1]).await;
+    assert!(matches!(result, Err(Error::ValueTooLarge { len: 262145, limit: 262144 })));
+    assert!(matches!(engine.open_tree(&"t".repeat(1025)), Err(Error::KeyTooLarge { .. })));
+    engine.set(&[0; 1024], vec![0; 256 * 1024]).await.unwrap();
+    engine.close().await.unwrap();
+
+    let options = EngineOptions {
+        max_key_size: Some(16),
+        max_value_size: None,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    assert!(matches!(engine.set(&[1; 17], b"value".to_vec()).await, Err(Error::KeyTooLarge { .. })));
+    engine.set(b"blob", vec![7; 4 * 1024 * 1024]).await.unwrap();
+    engine.close().await.unwrap();
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    assert_eq!(engine.get(b"blob").await.unwrap().unwrap().len(), 4 * 1024 * 1024);
+    // Keys written under a higher limit remain readable.
+    assert!(engine.get(&[0; 1024]).await.unwrap().is_some());
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_chunked_values() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("chunked_values.db");
+    let options = EngineOptions {
+        max_value_size: Some(1024),
+        chunk_large_values: true,
+        background_compaction: false,
+        ..EngineOptions::default()
+    };
+    let blob: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    engine.set(b"blob", blob.clone()).await.unwrap();
+    engine.set(b"small", b"value".to_vec()).await.unwrap();
+    assert_eq!(engine.get(b"blob").await.unwrap().unwrap(), blob);
+    assert_eq!(engine.len(), 2);
+    assert_eq!(engine.stats().live_keys, 2);
+    let values: Vec<_> = engine.scan(..).await.unwrap().map(|(_, value)| value.len()).collect();
+    assert_eq!(values, vec![10_000, 5]);
+    let changes = engine.changes_since(0).await.unwrap();
+    assert!(matches!(&changes[0], Change::Set { key, value, .. } if key == "blob" && value == &blob));
+
+    // Overwritten and deleted values leave no chunks behind once compacted.
+    engine.set(b"blob", blob[..3000].to_vec()).await.unwrap();
+    assert_eq!(engine.get(b"blob").await.unwrap().unwrap(), blob[..3000]);
+    let tree = engine.open_tree("tree").unwrap();
+    tree.set(b"a", blob.clone()).await.unwrap();
+    tree.set(b"b", blob.clone()).await.unwrap();
+    tree.del(b"a").await.unwrap();
+    tree.clear().await.unwrap();
+    assert_eq!(tree.get(b"b").await.unwrap(), None);
+    engine.compact().await.unwrap();
+    assert!(engine.size_on_disk().unwrap() < 4000);
+    drop((tree, engine));
+
+    let options = EngineOptions {
+        keep_values_in_memory: false,
+        ..options
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    assert_eq!(engine.get(b"blob").await.unwrap().unwrap(), blob[..3000]);
+    engine.set(b"blob", b"short".to_vec()).await.unwrap();
+    assert_eq!(engine.get(b"blob").await.unwrap(), Some(Bytes::from_static(b"short")));
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_ttl() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("ttl.db");
+    let options = EngineOptions {
+        background_compaction: false,
+        ..EngineOptions::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    engine.set(b"old", b"persisted".to_vec()).await.unwrap();
+    engine.set_with_ttl(b"old", b"short".to_vec(), Duration::from_millis(100)).await.unwrap();
+    engine.set_with_ttl(b"long", b"lived".to_vec(), Duration::from_secs(3600)).await.unwrap();
+    engine.set_with_ttl(b"reset", b"value".to_vec(), Duration::from_millis(100)).await.unwrap();
+    engine.set(b"reset", b"value".to_vec()).await.unwrap();
+    assert_eq!(engine.get(b"old").await.unwrap(), Some(Bytes::from_static(b"short")));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    assert_eq!(engine.get(b"old").await.unwrap(), None);
+    assert_eq!(engine.get(b"long").await.unwrap(), Some(Bytes::from_static(b"lived")));
+    assert_eq!(engine.get(b"reset").await.unwrap(), Some(Bytes::from_static(b"value")));
+    let keys: Vec<_> = engine.scan(..).await.unwrap().map(|(key, _)| key).collect();
+    assert_eq!(keys, vec![Bytes::from_static(b"long"), Bytes::from_static(b"reset")]);
+    engine.close().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // The expired entry keeps shadowing the value written before it after reopening and compacting.
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    assert_eq!(engine.get(b"old").await.unwrap(), None);
+    assert!(engine.compact().await.unwrap() > 0);
+    assert_eq!(engine.get(b"old").await.unwrap(), None);
+    assert_eq!(engine.get(b"long").await.unwrap(), Some(Bytes::from_static(b"lived")));
+    engine.close().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    assert_eq!(engine.get(b"old").await.unwrap(), None);
+    assert_eq!(engine.get(b"long").await.unwrap(), Some(Bytes::from_static(b"lived")));
+    assert_eq!(engine.get(b"reset").await.unwrap(), Some(Bytes::from_static(b"value")));
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_get_many_and_del_many() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("batch_reads.db");
+    let options = EngineOptions {
+        keep_values_in_memory: false,
+        ..EngineOptions::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    engine.set(b"a", b"1".to_vec()).await.unwrap();
+    engine.set(b"b", b"2".to_vec()).await.unwrap();
+    engine.set(b"c", b"3".to_vec()).await.unwrap();
+    assert_eq!(
+        engine.get_many(&[b"c", b"missing", b"a"]).await.unwrap(),
+        vec![Some(Bytes::from_static(b"3")), None, Some(Bytes::from_static(b"1"))]
+    );
+    engine.del_many(&[b"a", b"c", b"missing"]).await.unwrap();
+    assert_eq!(
+        engine.get_many(&[b"a", b"b", b"c"]).await.unwrap(),
+        vec![None, Some(Bytes::from_static(b"2")), None]
+    );
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_contains_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("contains_key.db");
+    let options = EngineOptions {
+        keep_values_in_memory: false,
+        ..EngineOptions::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    engine.set(b"a", b"1".to_vec()).await.unwrap();
+    engine.set(b"b", b"2".to_vec()).await.unwrap();
+    engine.set_with_ttl(b"c", b"3".to_vec(), Duration::from_millis(50)).await.unwrap();
+    engine.del(b"b").await.unwrap();
+    assert!(engine.contains_key(b"a").await.unwrap());
+    assert!(!engine.contains_key(b"b").await.unwrap());
+    assert!(engine.contains_key(b"c").await.unwrap());
+    assert!(!engine.contains_key(b"missing").await.unwrap());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!engine.contains_key(b"c").await.unwrap());
+    // Values kept on disk are not read back.
+    let stats = engine.stats();
+    assert_eq!(stats.cache_hits + stats.cache_misses, 0);
+
+    let users = engine.open_tree("users").unwrap();
+    users.set(b"alice", b"admin".to_vec()).await.unwrap();
+    assert!(users.contains_key(b"alice").await.unwrap());
+    assert!(!engine.contains_key(b"alice").await.unwrap());
+    engine.close().await.unwrap();
+    drop((engine, users));
+}
+
+#[tokio::test]
+async fn test_open_temporary() {
+    let engine = Engine::open_temporary().unwrap();
+    let path = engine.path().to_path_buf();
+    assert!(path.starts_with(std::env::temp_dir()));
+    engine.set(b"a", b"1".to_vec()).await.unwrap();
+    assert_eq!(engine.get(b"a").await.unwrap(), Some(Bytes::from_static(b"1")));
+    let other = Engine::open_temporary().unwrap();
+    assert_ne!(other.path(), path);
+    assert_eq!(other.get(b"a").await.unwrap(), None);
+    engine.close().await.unwrap();
+    assert!(!path.exists());
+
+    let path = other.path().to_path_buf();
+    assert!(path.exists());
+    drop(other);
+    assert!(!path.exists());
+
+    let engine = blocking::Engine::open_temporary().unwrap();
+    let path = engine.path().to_path_buf();
+    drop(engine);
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn test_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("keys.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    engine.set(b"b", vec![1; 1024]).await.unwrap();
+    engine.set(b"a", vec![2; 1024]).await.unwrap();
+    engine.set(b"c", vec![3; 1024]).await.unwrap();
+    engine.set_with_ttl(b"d", vec![4; 1024], Duration::ZERO).await.unwrap();
+    let keys: Vec<_> = engine.keys(..).await.unwrap().collect();
+    assert_eq!(
+        keys,
+        vec![Bytes::from_static(b"a"), Bytes::from_static(b"b"), Bytes::from_static(b"c")]
+    );
+    let keys: Vec<_> = engine.keys(b"b".to_vec()..).await.unwrap().collect();
+    assert_eq!(keys, vec![Bytes::from_static(b"b"), Bytes::from_static(b"c")]);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_len_and_size_on_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("len.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    assert!(engine.is_empty());
+    for i in 0..10 {
+        engine.set(format!("key_{}", i).as_bytes(), vec![0; 100]).await.unwrap();
+    }
+    engine.set(b"key_0", vec![1; 100]).await.unwrap();
+    engine.del(b"key_1").await.unwrap();
+    assert_eq!(engine.len(), 9);
+    assert!(!engine.is_empty());
+    engine.close().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let engine = Engine::open(path.clone()).unwrap();
+    assert_eq!(engine.len(), 9);
+    assert_eq!(engine.size_on_disk().unwrap(), dir_size(&path));
+    assert!(engine.size_on_disk().unwrap() > 11 * 100);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_stats() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("stats.db");
+    let options = EngineOptions {
+        keep_values_in_memory: false,
+        // Room for a single value.
+        value_cache_size: 150,
+        background_compaction: false,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    let tree = engine.open_tree("users").unwrap();
+    for i in 0..10 {
+        engine.set(format!("key_{}", i).as_bytes(), vec![1; 100]).await.unwrap();
+    }
+    tree.set(b"alice", b"admin".to_vec()).await.unwrap();
+    engine.del(b"key_0").await.unwrap();
+    engine.delete_range(b"key_8".to_vec()..).await.unwrap();
+    engine.get(b"key_1").await.unwrap();
+    engine.get(b"key_1").await.unwrap();
+    engine.get_many(&[b"key_2", b"missing"]).await.unwrap();
+
+    let stats = engine.stats();
+    assert_eq!(stats.live_keys, 8);
+    assert_eq!(stats.tombstones, 2);
+    // Ten sets, the tree's creation, its key and two deletions.
+    assert_eq!(stats.writes, 14);
+    assert_eq!(stats.reads, 4);
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 2));
+    assert!(stats.cache_bytes > 100 && stats.cache_bytes <= 150);
+    assert_eq!(stats.log_bytes, stats.bytes_written);
+    assert!(stats.live_bytes < stats.log_bytes);
+    assert!(stats.writes_per_second > 0.0 && stats.reads_per_second > 0.0);
+    assert_eq!(stats.compactions, 0);
+
+    engine.compact().await.unwrap();
+    let stats = engine.stats();
+    assert_eq!(stats.tombstones, 0);
+    assert_eq!(stats.compactions, 1);
+    assert!(stats.compaction_time > Duration::ZERO);
+    assert_eq!(stats.compaction_time, stats.last_compaction_time);
+    assert_eq!(stats.live_bytes, stats.log_bytes);
+    assert_eq!(stats.write_queue_depth, 0);
+    drop(tree);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_value_cache() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("value_cache.db");
+    let options = EngineOptions {
+        keep_values_in_memory: false,
+        // Room for two keys with their 100-byte values.
+        value_cache_size: 250,
+        background_compaction: false,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path, options).unwrap();
+    let cache = |engine: &Engine| {
+        let stats = engine.stats();
+        (stats.cache_hits, stats.cache_misses, stats.cache_bytes)
+    };
+    // Values written are cached, evicting the least recently used ones.
+    for key in [b"a", b"b", b"c"] {
+        engine.set(key, vec![key[0]; 100]).await.unwrap();
+    }
+    assert_eq!(cache(&engine), (0, 0, 202));
+
+    // A miss reads the value back from the log and caches it in place of "b".
+    assert_eq!(engine.get(b"a").await.unwrap(), Some(Bytes::from(vec![b'a'; 100])));
+    assert_eq!(cache(&engine), (0, 1, 202));
+    assert_eq!(engine.get(b"a").await.unwrap(), Some(Bytes::from(vec![b'a'; 100])));
+    assert_eq!(engine.get(b"c").await.unwrap(), Some(Bytes::from(vec![b'c'; 100])));
+    assert_eq!(cache(&engine), (2, 1, 202));
+    assert_eq!(engine.get(b"b").await.unwrap(), Some(Bytes::from(vec![b'b'; 100])));
+    assert_eq!(engine.get(b"c").await.unwrap(), Some(Bytes::from(vec![b'c'; 100])));
+    assert_eq!(engine.get(b"a").await.unwrap(), Some(Bytes::from(vec![b'a'; 100])));
+    assert_eq!(cache(&engine), (3, 3, 202));
+
+    // Deleted values leave the cache, and values larger than it never enter it.
+    engine.del(b"c").await.unwrap();
+    assert_eq!(cache(&engine), (3, 3, 101));
+    engine.set(b"d", vec![b'd'; 300]).await.unwrap();
+    assert_eq!(engine.get(b"d").await.unwrap(), Some(Bytes::from(vec![b'd'; 300])));
+    assert_eq!(cache(&engine), (3, 4, 101));
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_space_stats() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("space_stats.db");
+    let options = EngineOptions {
+        background_compaction: false,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    let space = engine.space_stats();
+    assert_eq!((space.log_bytes, space.garbage_bytes()), (0, 0));
+    assert_eq!(space.amplification(), 1.0);
+
+    for i in 0..10 {
+        engine.set(format!("key_{}", i).as_bytes(), vec![1; 100]).await.unwrap();
+    }
+    let space = engine.space_stats();
+    assert_eq!(space.live_bytes, space.log_bytes);
+    assert_eq!(space.garbage_ratio(), 0.0);
+    for i in 0..5 {
+        engine.set(format!("key_{}", i).as_bytes(), vec![2; 100]).await.unwrap();
+    }
+    engine.del(b"key_9").await.unwrap();
+
+    let space = engine.space_stats();
+    let stats = engine.stats();
+    assert_eq!(space.log_bytes, stats.log_bytes);
+    assert_eq!(space.live_bytes, stats.live_bytes);
+    assert_eq!(space.tombstones, 1);
+    assert_eq!(space.segments.iter().map(|s| s.log_bytes).sum::<u64>(), space.log_bytes);
+    assert!(space.garbage_ratio() > 0.3 && space.garbage_ratio() < 0.5);
+    assert!(space.amplification() > 1.5);
+
+    engine.compact().await.unwrap();
+    let space = engine.space_stats();
+    assert_eq!(space.garbage_bytes(), 0);
+    assert_eq!(space.tombstones, 0);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_index_memory_limit() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("index_memory_limit.db");
+    let options = EngineOptions {
+        index_memory_limit: Some(64 * 1024),
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    for i in 0..10 {
+        engine.set(format!("key_{}", i).as_bytes(), vec![i; 1000]).await.unwrap();
+    }
+    let stats = engine.stats();
+    assert!(stats.index_bytes > 10_000 && stats.index_bytes < 20_000);
+    assert!(!stats.values_spilled);
+
+    // Values no longer fit, so they are dropped from memory and read back from the log.
+    for i in 10..100 {
+        engine.set(format!("key_{}", i).as_bytes(), vec![i; 1000]).await.unwrap();
+    }
+    let stats = engine.stats();
+    assert!(stats.values_spilled);
+    assert!(stats.index_bytes < 64 * 1024);
+    assert_eq!(engine.get(b"key_3").await.unwrap().unwrap(), vec![3; 1000]);
+    assert_eq!(engine.get(b"key_42").await.unwrap().unwrap(), vec![42; 1000]);
+
+    // Keys alone eventually no longer fit either.
+    let mut i = 0u32;
+    let err = loop {
+        match engine.set(format!("more_{}", i).as_bytes(), b"v".to_vec()).await {
+            Ok(()) => i += 1,
+            Err(e) => break e,
+        }
+    };
+    assert!(matches!(err, Error::IndexMemoryLimit { limit: 65536 }));
+    assert_eq!(engine.get(format!("more_{}", i).as_bytes()).await.unwrap(), None);
+    // Deleting keys frees room again.
+    let before = engine.stats().index_bytes;
+    engine.delete_range(b"key_".to_vec()..b"key_~".to_vec()).await.unwrap();
+    assert!(engine.stats().index_bytes < before);
+    engine.set(format!("more_{}", i).as_bytes(), b"v".to_vec()).await.unwrap();
+    engine.close().await.unwrap();
+    drop(engine);
+
+    // The limit is enforced again when the log is replayed.
+    let options = EngineOptions {
+        index_memory_limit: Some(16 * 1024),
+        ..options
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    let stats = engine.stats();
+    assert!(stats.values_spilled);
+    assert_eq!(engine.get(b"more_0").await.unwrap().unwrap(), b"v".to_vec());
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_value_memory_budget() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("value_memory_budget.db");
+    let options = EngineOptions {
+        value_memory_budget: Some(10_000),
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    for i in 0..100 {
+        engine.set(format!("key_{:02}", i).as_bytes(), vec![i; 1000]).await.unwrap();
+    }
+    let stats = engine.stats();
+    assert!(stats.value_bytes > 5_000 && stats.value_bytes <= 10_000);
+    assert!(stats.index_bytes > stats.value_bytes);
+
+    // Cold values are read back from the log and kept in memory in place of others.
+    for i in 0..100 {
+        let value = engine.get(format!("key_{:02}", i).as_bytes()).await.unwrap();
+        assert_eq!(value.unwrap(), vec![i; 1000]);
+    }
+    assert!(engine.stats().value_bytes <= 10_000);
+    // Hot keys stay in memory while others are read.
+    for i in 0..100 {
+        engine.get(b"key_07").await.unwrap();
+        engine.get(format!("key_{:02}", i).as_bytes()).await.unwrap();
+    }
+    assert!(engine.stats().value_bytes <= 10_000);
+    engine.close().await.unwrap();
+    drop(engine);
+
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    assert!(engine.stats().value_bytes <= 10_000);
+    assert_eq!(engine.get(b"key_99").await.unwrap().unwrap(), vec![99; 1000]);
+    engine.close().await.unwrap();
+}
+
+#[cfg(feature = "python")]
+#[test]
+fn test_python_bindings() {
+    use pyo3::prelude::*;
+    use pyo3::types::PyDict;
+    use tegdb::python::tegdb as tegdb_module;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("python_bindings.db");
+    pyo3::append_to_inittab!(tegdb_module);
+    pyo3::prepare_freethreaded_python();
+    Python::with_gil(|py| {
+        let locals = PyDict::new(py);
+        locals.set_item("path", &path).unwrap();
+        py.run(
+            c"
+import tegdb
+
+with tegdb.Engine(path) as db:
+    db.set(b'b', b'2')
+    db.set(b'a', b'1')
+    db.set(b'c', b'3')
+    db.delete(b'c')
+    assert db.get(b'a') == b'1'
+    assert db.get(b'c') is None
+    assert b'b' in db and len(db) == 2
+    assert db.scan() == [(b'a', b'1'), (b'b', b'2')]
+    assert db.scan(b'b') == [(b'b', b'2')]
+    users = db.open_tree('users')
+    users.set(b'alice', b'admin')
+    assert users.scan_prefix(b'al') == [(b'alice', b'admin')]
+    assert db.tree_names() == ['users']
+    with users.batch() as batch:
+        batch.set(b'bob', b'user')
+        batch.delete(b'alice')
+        assert users.get(b'bob') is None
+    assert users.scan() == [(b'bob', b'user')]
+    try:
+        with users.batch() as batch:
+            batch.set(b'carol', b'user')
+            raise KeyError('abandoned')
+    except KeyError:
+        pass
+    assert users.get(b'carol') is None
+    try:
+        db.set(b'k' * 2000, b'v')
+        raise AssertionError('no error')
+    except ValueError:
+        pass
+
+db = tegdb.Engine(path, read_only=True)
+assert db.get(b'b') == b'2'
+try:
+    db.set(b'x', b'y')
+    raise AssertionError('no error')
+except tegdb.Error as e:
+    assert 'read-only' in str(e)
+db.close()
+",
+            None,
+            Some(&locals),
+        )
+        .unwrap();
+    });
+}
+
+#[cfg(feature = "prometheus")]
+#[test]
+fn test_prometheus_stats() {
+    let stats = tegdb::Stats {
+        live_keys: 3,
+        cache_hits: 1,
+        ..Default::default()
+    };
+    let text = stats.to_prometheus();
+    assert!(text.contains("# TYPE tegdb_live_keys gauge\ntegdb_live_keys 3\n"));
+    assert!(text.contains("# TYPE tegdb_cache_hits_total counter\ntegdb_cache_hits_total 1\n"));
+}
+
+#[cfg(feature = "tracing")]
+#[tokio::test]
+async fn test_tracing() {
+    use std::sync::Mutex;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Metadata, Subscriber};
+
+    // Records the names of the spans opened and the messages of the events emitted.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    struct Message(String);
+
+    impl Visit for Message {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.0 = format!("{:?}", value);
+            }
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            self.0.lock().unwrap().push(span.metadata().name().to_string());
+            Id::from_u64(1)
+        }
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut message = Message(String::new());
+            event.record(&mut message);
+            self.0.lock().unwrap().push(message.0);
+        }
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tracing.db");
+    let recorder = Recorder::default();
+    let _guard = tracing::subscriber::set_default(recorder.clone());
+    let engine = Engine::open(path.clone()).unwrap();
+    engine.set(b"a", b"1".to_vec()).await.unwrap();
+    engine.get(b"a").await.unwrap();
+    engine.del(b"a").await.unwrap();
+    let _ = engine.scan(..).await.unwrap();
+    engine.compact().await.unwrap();
+    engine.close().await.unwrap();
+
+    let recorded = recorder.0.lock().unwrap().clone();
+    let expected = ["build_key_map", "log replayed", "set", "get", "del", "scan", "compact", "compaction finished"];
+    for expected in expected {
+        assert!(recorded.iter().any(|r| r == expected), "{} missing from {:?}", expected, recorded);
+    }
+}
+
+#[tokio::test]
+async fn test_trees() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("trees.db");
+    let options = EngineOptions {
+        keep_values_in_memory: false,
+        ..EngineOptions::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    let users = engine.open_tree("users").unwrap();
+    let orders = engine.open_tree("orders").unwrap();
+    engine.set(b"key", b"default".to_vec()).await.unwrap();
+    users.set(b"key", b"user".to_vec()).await.unwrap();
+    users.set(b"other", b"user".to_vec()).await.unwrap();
+    orders.set(b"key", b"order".to_vec()).await.unwrap();
+    orders.del(b"key").await.unwrap();
+
+    assert_eq!(engine.get(b"key").await.unwrap(), Some(Bytes::from_static(b"default")));
+    assert_eq!(users.get(b"key").await.unwrap(), Some(Bytes::from_static(b"user")));
+    assert_eq!(orders.get(b"key").await.unwrap(), None);
+    assert_eq!(engine.len(), 1);
+    assert_eq!(users.len(), 2);
+    assert!(orders.is_empty());
+    let keys: Vec<_> = users.keys(..).await.unwrap().collect();
+    assert_eq!(keys, vec![Bytes::from_static(b"key"), Bytes::from_static(b"other")]);
+    engine.compact().await.unwrap();
+    drop((engine, users, orders));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    let users = engine.open_tree("users").unwrap();
+    assert_eq!(engine.get(b"key").await.unwrap(), Some(Bytes::from_static(b"default")));
+    assert_eq!(users.get(b"key").await.unwrap(), Some(Bytes::from_static(b"user")));
+    assert_eq!(users.len(), 2);
+    assert!(engine.open_tree("orders").unwrap().is_empty());
+    assert!(engine.open_tree("new").unwrap().is_empty());
+    drop((engine, users));
+}
+
+#[tokio::test]
+async fn test_clear_and_drop_tree() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("clear.db");
+    let options = EngineOptions {
+        background_compaction: false,
+        ..EngineOptions::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    let logs = engine.open_tree("logs").unwrap();
+    for i in 0..100 {
+        let key = format!("key_{:03}", i).into_bytes();
+        engine.set(&key, vec![1; 100]).await.unwrap();
+        logs.set(&key, vec![2; 100]).await.unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let size_before = dir_size(&path);
+    engine.clear().await.unwrap();
+    assert!(engine.is_empty());
+    assert_eq!(logs.len(), 100);
+    engine.set(b"after", b"clear".to_vec()).await.unwrap();
+    assert!(engine.drop_tree("logs").unwrap());
+    assert!(!engine.drop_tree("logs").unwrap());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    // Each deletion is a single small log entry rather than one per key.
+    assert!(dir_size(&path) < size_before + 200);
+    drop((engine, logs));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    assert_eq!(engine.len(), 1);
+    assert_eq!(engine.get(b"after").await.unwrap(), Some(Bytes::from_static(b"clear")));
+    assert!(engine.open_tree("logs").unwrap().is_empty());
+    engine.compact().await.unwrap();
+    engine.close().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    assert_eq!(engine.len(), 1);
+    assert!(engine.open_tree("logs").unwrap().is_empty());
+    assert!(dir_size(&path) < size_before / 10);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_tree_listing_and_stats() {
+    let engine = Engine::open_temporary().unwrap();
+    let users = engine.open_tree("users").unwrap();
+    engine.open_tree("orders").unwrap();
+    users.set(b"alice", b"admin".to_vec()).await.unwrap();
+    users.set_with_ttl(b"bob", b"guest".to_vec(), Duration::from_secs(3600)).await.unwrap();
+    engine.set(b"key", b"default".to_vec()).await.unwrap();
+
+    let trees = engine.trees().unwrap();
+    let names: Vec<_> = trees.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, vec!["orders", "users"]);
+    assert!(trees[0].1.is_empty());
+    assert_eq!(trees[1].1.get(b"alice").await.unwrap(), Some(Bytes::from_static(b"admin")));
+
+    let stats = users.stats();
+    assert_eq!((stats.keys, stats.expiring_keys), (2, 1));
+    assert_eq!((stats.key_bytes, stats.value_bytes), (8, 10));
+    assert!(stats.live_bytes > stats.key_bytes + stats.value_bytes);
+    assert!(stats.index_bytes > stats.key_bytes + stats.value_bytes);
+    assert_eq!(trees[0].1.stats(), Default::default());
+    let keys: u64 = trees.iter().map(|(_, tree)| tree.stats().keys).sum();
+    assert_eq!(engine.stats().live_keys, keys + engine.len() as u64);
+
+    users.del(b"alice").await.unwrap();
+    assert_eq!(users.stats().keys, 1);
+    assert!(engine.drop_tree("orders").unwrap());
+    assert_eq!(engine.trees().unwrap().len(), 1);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_comparator() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("comparator.db");
+    let options = EngineOptions::default().with_comparator("case-insensitive", |a, b| {
+        a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase()).then_with(|| a.cmp(b))
+    });
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    let mut watched = Box::pin(engine.watch(Filter::range(b"a".to_vec()..b"c".to_vec())));
+    for key in [&b"b"[..], b"A", b"C", b"a", b"ab"] {
+        engine.set(key, key.to_vec()).await.unwrap();
+    }
+    let keys = |engine: Engine| async move { engine.keys(..).await.unwrap().collect::<Vec<_>>() };
+    assert_eq!(keys(engine.clone()).await, [&b"A"[..], b"a", b"ab", b"b", b"C"]);
+    let scanned: Vec<_> = engine.scan(b"a".to_vec()..b"c".to_vec()).await.unwrap().map(|(k, _)| k).collect();
+    assert_eq!(scanned, [&b"a"[..], b"ab", b"b", b"C"]);
+    let scanned: Vec<_> = engine.scan_prefix(b"a").await.unwrap().map(|(k, _)| k).collect();
+    assert_eq!(scanned, [&b"a"[..], b"ab"]);
+    let watched: Vec<Bytes> = watched.as_mut().take(4).map(|event| event.key).collect().await;
+    assert_eq!(watched, [&b"b"[..], b"C", b"a", b"ab"]);
+
+    engine.delete_range(..=b"a".to_vec()).await.unwrap();
+    engine.delete_range((Bound::Excluded(b"b".to_vec()), Bound::Unbounded)).await.unwrap();
+    assert_eq!(keys(engine.clone()).await, [&b"ab"[..], b"b"]);
+
+    let names = engine.open_tree("names").unwrap();
+    let pairs = [(b"B".to_vec(), vec![1]), (b"a".to_vec(), vec![1])];
+    assert!(matches!(names.bulk_load(pairs).await, Err(Error::BulkLoad(_))));
+    let pairs = [(b"a".to_vec(), vec![1]), (b"B".to_vec(), vec![1])];
+    assert_eq!(names.bulk_load(pairs).await.unwrap(), 2);
+    engine.close().await.unwrap();
+
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    assert_eq!(keys(engine.clone()).await, [&b"ab"[..], b"b"]);
+    let names: Vec<_> = engine.open_tree("names").unwrap().keys(..).await.unwrap().collect();
+    assert_eq!(names, [&b"a"[..], b"B"]);
+    engine.close().await.unwrap();
+
+    match Engine::open(path.clone()) {
+        Err(Error::ComparatorMismatch { expected: None, found }) => {
+            assert_eq!(found.as_deref(), Some("case-insensitive"));
+        }
+        other => panic!("expected a comparator mismatch, got {:?}", other.map(|_| ())),
+    }
+    let other = EngineOptions::default().with_comparator("bytewise", |a, b| a.cmp(b));
+    assert!(matches!(
+        Engine::open_with_options(path.clone(), other),
+        Err(Error::ComparatorMismatch { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_delete_range() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("delete_range.db");
+    let options = EngineOptions {
+        background_compaction: false,
+        segment_size: 4096,
+        ..EngineOptions::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    let key = |i: u32| format!("key_{:03}", i).into_bytes();
+    for i in 0..100 {
+        engine.set(&key(i), vec![1; 100]).await.unwrap();
+    }
+    engine.delete_range(key(10)..key(20)).await.unwrap();
+    engine.delete_range(key(30)..=key(39)).await.unwrap();
+    engine.delete_range((Bound::Excluded(key(89)), Bound::Unbounded)).await.unwrap();
+    engine.set(&key(15), b"rewritten".to_vec()).await.unwrap();
+    #[allow(clippy::reversed_empty_ranges)]
+    engine.delete_range(key(50)..key(40)).await.unwrap();
+
+    let check = |engine: Engine| async move {
+        let keys: Vec<_> = engine.keys(..).await.unwrap().collect();
+        let expected: Vec<_> = (0..90)
+            .filter(|i| !(10..20).contains(i) && !(30..40).contains(i) || *i == 15)
+            .map(|i| Bytes::from(key(i)))
+            .collect();
+        assert_eq!(keys, expected);
+        assert_eq!(engine.get(&key(15)).await.unwrap(), Some(Bytes::from_static(b"rewritten")));
+    };
+    check(engine.clone()).await;
+    engine.close().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    check(engine.clone()).await;
+    engine.compact().await.unwrap();
+    check(engine.clone()).await;
+    engine.close().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    check(engine.clone()).await;
+    engine.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_sync_writes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sync_writes.db");
+    let options = EngineOptions {
+        sync_writes: true,
+        ..EngineOptions::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    let mut handles = Vec::new();
+    for task in 0..8 {
+        let engine = engine.clone();
+        handles.push(tokio::spawn(async move {
+            for i in 0..25 {
+                let key = format!("key_{}_{}", task, i).into_bytes();
+                engine.set(&key, vec![0; 100]).await.unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    // Every acknowledged write has reached the file without waiting for a flush.
+    let entry_size = 4 + 4 + "key_0_00".len() as u64 + 100;
+    assert!(dir_size(&path) >= 200 * (entry_size - 1));
+    assert_eq!(engine.len(), 200);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_flush_and_sync() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("flush_and_sync.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    engine.set(b"key_1", vec![1; 100]).await.unwrap();
+    engine.flush().await.unwrap();
+    // The flushed entry has reached the file.
+    assert!(dir_size(&path) >= 100);
+    engine.set(b"key_2", vec![2; 100]).await.unwrap();
+    engine.sync().await.unwrap();
+    assert!(dir_size(&path) >= 200);
+    engine.close().await.unwrap();
+
+    let options = EngineOptions {
+        read_only: true,
+        ..EngineOptions::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    engine.flush().await.unwrap();
+    engine.sync().await.unwrap();
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_durable_writes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("durable_writes.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    let metrics = engine.open_tree("metrics").unwrap();
+    for i in 0..100 {
+        metrics.set(format!("cpu_{:03}", i).as_bytes(), vec![i; 100]).await.unwrap();
+    }
+    engine.set_durable(b"config", b"v2".to_vec()).await.unwrap();
+    // The durable write and every write queued before it have reached the file.
+    assert!(dir_size(&path) >= 100 * 100 + 2);
+    engine.set(b"stale", b"value".to_vec()).await.unwrap();
+    engine.del_durable(b"stale").await.unwrap();
+    assert_eq!(engine.get(b"stale").await.unwrap(), None);
+    engine.close().await.unwrap();
+
+    let engine = Engine::open(path.clone()).unwrap();
+    assert_eq!(engine.get(b"config").await.unwrap(), Some(Bytes::from("v2")));
+    assert_eq!(engine.get(b"stale").await.unwrap(), None);
+    assert_eq!(engine.open_tree("metrics").unwrap().len(), 100);
+    engine.close().await.unwrap();
+
+    let options = EngineOptions {
+        read_only: true,
+        ..EngineOptions::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    assert!(matches!(engine.set_durable(b"config", b"v3".to_vec()).await, Err(Error::ReadOnly)));
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_close() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("close.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    let tree = engine.open_tree("users").unwrap();
+    engine.set(b"key", b"value".to_vec()).await.unwrap();
+    tree.set(b"alice", b"admin".to_vec()).await.unwrap();
+    engine.close().await.unwrap();
+    engine.close().await.unwrap();
+
+    // Handles still read, but no longer write.
+    assert_eq!(engine.get(b"key").await.unwrap().unwrap().as_ref(), b"value");
+    assert!(matches!(engine.set(b"key", b"other".to_vec()).await, Err(Error::Closed)));
+    assert!(matches!(tree.del(b"alice").await, Err(Error::Closed)));
+    assert!(matches!(engine.health().write_error, Some(Error::Closed)));
+
+    // The lock is released while the handles are still alive.
+    let reopened = Engine::open(path.clone()).unwrap();
+    assert_eq!(reopened.get(b"key").await.unwrap().unwrap().as_ref(), b"value");
+    let users = reopened.open_tree("users").unwrap();
+    assert_eq!(users.get(b"alice").await.unwrap().unwrap().as_ref(), b"admin");
+    reopened.close().await.unwrap();
+    drop((engine, tree, reopened, users));
+}
+
+#[tokio::test]
+async fn test_del_after_close() {
+    let dir = tempfile::tempdir().unwrap();
+    let engine = Engine::open(dir.path()).unwrap();
+    engine.set(b"key", b"value".to_vec()).await.unwrap();
+    engine.close().await.unwrap();
+    // A deletion that never reached the log leaves the key in place.
+    assert!(matches!(engine.del(b"key").await, Err(Error::Closed)));
+    assert_eq!(engine.get(b"key").await.unwrap(), Some(Bytes::from("value")));
+    assert_eq!(engine.len(), 1);
+    drop(engine);
+
+    let engine = Engine::open(dir.path()).unwrap();
+    assert_eq!(engine.get(b"key").await.unwrap(), Some(Bytes::from("value")));
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_direct_io() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("direct_io.db");
+    let options = EngineOptions {
+        direct_io: true,
+        segment_size: 64 * 1024,
+        background_compaction: false,
+        ..Default::default()
+    };
+    let value = |i: usize| vec![i as u8; i * 37 % 3000 + 1];
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    for i in 0..100 {
+        engine.set(format!("key_{:03}", i).as_bytes(), value(i)).await.unwrap();
+        if i % 10 == 0 {
+            engine.sync().await.unwrap();
+        }
+    }
+    engine.close().await.unwrap();
+    drop(engine);
+
+    // Reopening appends after a partially written block.
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    for i in 100..200 {
+        engine.set(format!("key_{:03}", i).as_bytes(), value(i)).await.unwrap();
+    }
+    engine.close().await.unwrap();
+    drop(engine);
+
+    let verification = Engine::verify(&path).unwrap();
+    assert!(verification.corruptions.is_empty());
+    assert!(verification.segments > 1);
+    assert_eq!(verification.entries, 200);
+    let engine = Engine::open(path.clone()).unwrap();
+    for i in 0..200 {
+        assert_eq!(engine.get(format!("key_{:03}", i).as_bytes()).await.unwrap().unwrap().as_ref(), value(i));
+    }
+    engine.close().await.unwrap();
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_io_uring() {
+    // Without io_uring the writer falls back to plain writes, which the other tests cover.
+    if io_uring::IoUring::new(2).is_err() {
+        eprintln!("io_uring is not available, skipping");
+        return;
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("io_uring.db");
+    let options = EngineOptions {
+        segment_size: 64 * 1024,
+        background_compaction: false,
+        ..Default::default()
+    };
+    let value = |i: usize| vec![i as u8; i * 37 % 3000 + 1];
+
+    // Concurrent writers keep several batches in flight, across segments and fsyncs.
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    let mut handles = Vec::new();
+    for writer in 0..4 {
+        let engine = engine.clone();
+        handles.push(tokio::spawn(async move {
+            for i in (writer..200).step_by(4) {
+                engine.set(format!("key_{:03}", i).as_bytes(), value(i)).await.unwrap();
+                if i % 25 == 0 {
+                    engine.sync().await.unwrap();
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    engine.del(b"key_000").await.unwrap();
+    engine.close().await.unwrap();
+    drop(engine);
+
+    // Reopening appends at the end of the last segment, then a large value is appended at once.
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    for i in 200..300 {
+        engine.set(format!("key_{:03}", i).as_bytes(), value(i)).await.unwrap();
+    }
+    engine.set(b"large", vec![7; 200 * 1024]).await.unwrap();
+    engine.close().await.unwrap();
+    drop(engine);
+
+    let verification = Engine::verify(&path).unwrap();
+    assert!(verification.corruptions.is_empty());
+    assert!(verification.segments > 1);
+    let engine = Engine::open_with_options(path, options).unwrap();
+    assert_eq!(engine.get(b"key_000").await.unwrap(), None);
+    for i in 1..300 {
+        let key = format!("key_{:03}", i);
+        assert_eq!(engine.get(key.as_bytes()).await.unwrap().unwrap().as_ref(), value(i));
+    }
+    assert_eq!(engine.get(b"large").await.unwrap().unwrap().as_ref(), vec![7; 200 * 1024]);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_write_queue_capacity() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("write_queue_capacity.db");
+    let options = EngineOptions {
+        write_queue_capacity: 1,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    for i in 0..1000 {
+        engine.set(format!("key_{}", i).as_bytes(), vec![1; 100]).await.unwrap();
+        // The queued entry and the one blocked waiting for room.
+        assert!(engine.stats().write_queue_depth <= 2);
+    }
+    engine.close().await.unwrap();
+
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    assert_eq!(engine.len(), 1000);
+    assert_eq!(engine.get(b"key_999").await.unwrap().unwrap().len(), 100);
+    engine.close().await.unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_writer_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("writer_errors.db");
+    let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = events.clone();
+    let options = EngineOptions {
+        segment_size: 1024,
+        background_compaction: false,
+        on_disk_full: Some(DiskFullHook::new(move |event| seen.lock().unwrap().push(event.clone()))),
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    engine.set(b"key_1", vec![1; 600]).await.unwrap();
+    engine.flush().await.unwrap();
+    let health = engine.health();
+    assert!(health.is_healthy());
+    assert!(health.available_space.is_some_and(|space| space > 0));
+    assert!(health.since_last_sync.is_none());
+    engine.sync().await.unwrap();
+    assert!(engine.health().since_last_sync.is_some());
+
+    // The next segment is a device on which every write fails as if the disk were full.
+    std::os::unix::fs::symlink("/dev/full", path.join("00000002.log")).unwrap();
+    engine.set(b"key_2", vec![2; 600]).await.unwrap();
+    assert!(matches!(engine.flush().await, Err(Error::NoSpace)));
+    assert!(matches!(engine.health().write_error, Some(Error::NoSpace)));
+    assert!(!engine.health().is_healthy());
+    assert!(events.lock().unwrap().is_empty());
+    assert!(matches!(engine.set(b"key_3", vec![3]).await, Err(Error::NoSpace)));
+    assert!(matches!(events.lock().unwrap()[..], [DiskFullEvent::Full { .. }]));
+    assert!(engine.health().disk_full);
+    assert!(matches!(engine.close().await, Err(Error::NoSpace)));
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_disk_full_policies() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("disk_full.db");
+    let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = events.clone();
+    let compactions = Arc::new(std::sync::Mutex::new(0));
+    let started = compactions.clone();
+    // No disk has this much space left, so the reserve is never met.
+    let options = EngineOptions {
+        background_compaction: false,
+        compaction_interval: Duration::from_millis(10),
+        min_free_space: Some(u64::MAX),
+        on_disk_full: Some(DiskFullHook::new(move |event| seen.lock().unwrap().push(event.clone()))),
+        on_compaction: Some(CompactionHook::new(move |event| {
+            if let CompactionEvent::Started { .. } = event {
+                *started.lock().unwrap() += 1;
+            }
+        })),
+        ..EngineOptions::default()
+    };
+    // Leave an overwritten entry behind for compaction to reclaim.
+    let engine = Engine::open(path.clone()).unwrap();
+    engine.set(b"key", b"old".to_vec()).await.unwrap();
+    engine.set(b"key", b"value".to_vec()).await.unwrap();
+    engine.close().await.unwrap();
+
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    assert!(matches!(engine.set(b"key", b"other".to_vec()).await, Err(Error::NoSpace)));
+    assert!(matches!(engine.del(b"key").await, Err(Error::NoSpace)));
+    assert!(matches!(events.lock().unwrap()[..], [DiskFullEvent::Full { available: Some(_) }]));
+    let health = engine.health();
+    assert!(health.disk_full && !health.is_healthy());
+    assert!(matches!(health.into_result(), Err(Error::NoSpace)));
+    assert_eq!(engine.get(b"key").await.unwrap().as_deref(), Some(&b"value"[..]));
+    engine.close().await.unwrap();
+
+    let read_only = EngineOptions {
+        disk_full_policy: DiskFullPolicy::ReadOnly,
+        ..options.clone()
+    };
+    let engine = Engine::open_with_options(path.clone(), read_only).unwrap();
+    assert!(matches!(engine.set(b"key", b"value".to_vec()).await, Err(Error::ReadOnly)));
+    assert!(matches!(engine.compact().await, Err(Error::ReadOnly)));
+    engine.close().await.unwrap();
+
+    let compact = EngineOptions {
+        disk_full_policy: DiskFullPolicy::Compact,
+        ..options
+    };
+    let engine = Engine::open_with_options(path.clone(), compact).unwrap();
+    // The segment holding the garbage is out of reach, so the compaction fails.
+    let segment = path.join("00000001.log");
+    let moved = dir.path().join("00000001.log");
+    fs::rename(&segment, &moved).unwrap();
+    assert!(matches!(engine.set(b"key", b"value".to_vec()).await, Err(Error::NoSpace)));
+    for _ in 0..500 {
+        if engine.health().compaction_error.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(*compactions.lock().unwrap(), 1);
+    assert!(matches!(engine.health().compaction_error, Some(Error::Io(_))));
+    fs::rename(&moved, &segment).unwrap();
+    engine.close().await.unwrap();
+    assert_eq!(events.lock().unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn test_database_lock() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("locked.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    engine.set(b"key", b"value".to_vec()).await.unwrap();
+    assert!(matches!(Engine::open(path.clone()), Err(Error::DatabaseLocked(_))));
+    let read_only = EngineOptions {
+        read_only: true,
+        ..EngineOptions::default()
+    };
+    assert!(matches!(
+        Engine::open_with_options(path.clone(), read_only.clone()),
+        Err(Error::DatabaseLocked(_))
+    ));
+    engine.close().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Several read-only handles can share the database, but writers are locked out.
+    let reader = Engine::open_with_options(path.clone(), read_only.clone()).unwrap();
+    let other_reader = Engine::open_with_options(path.clone(), read_only).unwrap();
+    assert_eq!(reader.get(b"key").await.unwrap(), Some(Bytes::from_static(b"value")));
+    assert_eq!(other_reader.get(b"key").await.unwrap(), Some(Bytes::from_static(b"value")));
+    assert!(matches!(reader.set(b"key", b"other".to_vec()).await, Err(Error::ReadOnly)));
+    assert!(matches!(reader.compact().await, Err(Error::ReadOnly)));
+    assert!(matches!(Engine::open(path.clone()), Err(Error::DatabaseLocked(_))));
+    drop((reader, other_reader));
+
+    let engine = Engine::open(path.clone()).unwrap();
+    assert_eq!(engine.get(b"key").await.unwrap(), Some(Bytes::from_static(b"value")));
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_checkpoint() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("checkpoint_source.db");
+    let copy = dir.path().join("checkpoint_copy.db");
+    let options = EngineOptions {
+        keep_values_in_memory: false,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    for i in 0..10 {
+        engine.set(b"key", format!("value_{}", i).into_bytes()).await.unwrap();
+    }
+    engine.set(b"removed", b"value".to_vec()).await.unwrap();
+    engine.del(b"removed").await.unwrap();
+    let tree = engine.open_tree("tree").unwrap();
+    tree.set(b"key", b"tree_value".to_vec()).await.unwrap();
+    engine.checkpoint(&copy).await.unwrap();
+    engine.set(b"later", b"value".to_vec()).await.unwrap();
+    assert!(matches!(engine.checkpoint(&copy).await, Err(Error::Io(_))));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(dir_size(&copy) < dir_size(&path));
+
+    let restored = Engine::open(copy.clone()).unwrap();
+    assert_eq!(restored.get(b"key").await.unwrap(), Some(Bytes::from_static(b"value_9")));
+    assert_eq!(restored.get(b"removed").await.unwrap(), None);
+    assert_eq!(restored.get(b"later").await.unwrap(), None);
+    let tree = restored.open_tree("tree").unwrap();
+    assert_eq!(tree.get(b"key").await.unwrap(), Some(Bytes::from_static(b"tree_value")));
+    drop((engine, restored));
+}
+
+#[tokio::test]
+async fn test_snapshot_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("snapshot_source.db");
+    let copy = dir.path().join("snapshot_copy.db");
+    let options = EngineOptions {
+        segment_size: 256,
+        background_compaction: false,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    let key = |i: u32| format!("key_{:03}", i).into_bytes();
+    for i in 0..20 {
+        engine.set(&key(i), vec![1; 50]).await.unwrap();
+    }
+    engine.open_tree("tree").unwrap().set(b"key", b"tree_value".to_vec()).await.unwrap();
+    engine.snapshot_dir(&copy).await.unwrap();
+    assert!(matches!(engine.snapshot_dir(&copy).await, Err(Error::Io(_))));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert_eq!(fs::metadata(copy.join("00000001.log")).unwrap().nlink(), 2);
+    }
+
+    // Neither later writes nor compaction of the source reach the snapshot.
+    for i in 0..20 {
+        engine.set(&key(i), vec![2; 50]).await.unwrap();
+    }
+    engine.set(b"later", b"value".to_vec()).await.unwrap();
+    engine.compact().await.unwrap();
+    assert!(!path.join("00000001.log").exists());
+
+    let restored = Engine::open(copy.clone()).unwrap();
+    assert_eq!(restored.len(), 20);
+    for i in 0..20 {
+        assert_eq!(restored.get(&key(i)).await.unwrap().as_deref(), Some(&[1; 50][..]));
+    }
+    assert_eq!(restored.get(b"later").await.unwrap(), None);
+    let tree = restored.open_tree("tree").unwrap();
+    assert_eq!(tree.get(b"key").await.unwrap(), Some(Bytes::from_static(b"tree_value")));
+    drop((engine, tree, restored));
+}
+
+#[tokio::test]
+async fn test_incremental_backup() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("backup_source.db");
+    let backup = dir.path().join("backup.bak");
+    let restored = dir.path().join("backup_restored.db");
+    let options = EngineOptions {
+        background_compaction: false,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    for i in 0..100 {
+        engine.set(format!("key_{}", i).as_bytes(), b"value".to_vec()).await.unwrap();
+    }
+    let full = Backup::create(&engine, &backup).unwrap();
+    assert_eq!(full.bytes_copied, full.total_bytes);
+    engine.set(b"key_0", b"updated".to_vec()).await.unwrap();
+    let incremental = Backup::create(&engine, &backup).unwrap();
+    assert!(incremental.bytes_copied > 0);
+    assert!(incremental.bytes_copied < full.bytes_copied);
+    assert_eq!(Backup::create(&engine, &backup).unwrap().bytes_copied, 0);
+    engine.del(b"key_1").await.unwrap();
+    engine.compact().await.unwrap();
+    Backup::create(&engine, &backup).unwrap();
+    engine.set(b"later", b"value".to_vec()).await.unwrap();
+
+    Backup::restore(&backup, &restored).unwrap();
+    let copy = Engine::open(restored.clone()).unwrap();
+    assert_eq!(copy.get(b"key_0").await.unwrap(), Some(Bytes::from_static(b"updated")));
+    assert_eq!(copy.get(b"key_1").await.unwrap(), None);
+    assert_eq!(copy.get(b"key_99").await.unwrap(), Some(Bytes::from_static(b"value")));
+    assert_eq!(copy.get(b"later").await.unwrap(), None);
+    drop(copy);
+    fs::remove_dir_all(&restored).unwrap();
+
+    // A damaged backup is detected before anything is restored.
+    let segment = fs::read_dir(&backup)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("log".as_ref()) && fs::metadata(path).unwrap().len() > 0)
+        .unwrap();
+    let mut bytes = fs::read(&segment).unwrap();
+    bytes[10] ^= 0xFF;
+    fs::write(&segment, bytes).unwrap();
+    assert!(matches!(Backup::restore(&backup, &restored), Err(Error::Corrupted(_))));
+    assert!(!restored.exists());
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_export_import() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("export_source.db");
+    let target = dir.path().join("import_target.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    engine.set(b"key", b"value".to_vec()).await.unwrap();
+    engine.set_with_ttl(b"ttl", b"value".to_vec(), Duration::from_secs(3600)).await.unwrap();
+    engine.set_with_ttl(b"expired", b"value".to_vec(), Duration::from_millis(1)).await.unwrap();
+    let tree = engine.open_tree("tree").unwrap();
+    tree.set(b"key", b"tree_value".to_vec()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let mut dump = Vec::new();
+    assert_eq!(engine.export(&mut dump).unwrap(), 3);
+    assert!(dump.starts_with(b"tegdb dump 1\n"));
+
+    let imported = Engine::open(target.clone()).unwrap();
+    assert_eq!(imported.import(dump.as_slice()).unwrap(), 3);
+    assert_eq!(imported.get(b"key").await.unwrap(), Some(Bytes::from_static(b"value")));
+    assert_eq!(imported.get(b"ttl").await.unwrap(), Some(Bytes::from_static(b"value")));
+    assert_eq!(imported.get(b"expired").await.unwrap(), None);
+    let tree = imported.open_tree("tree").unwrap();
+    assert_eq!(tree.get(b"key").await.unwrap(), Some(Bytes::from_static(b"tree_value")));
+    assert_eq!(imported.len(), 2);
+
+    assert!(matches!(imported.import(&dump[..dump.len() - 3]), Err(Error::Corrupted(_))));
+    assert!(matches!(imported.import(&b"not a dump"[..]), Err(Error::Corrupted(_))));
+    drop((engine, imported));
+}
+
+#[tokio::test]
+async fn test_ingest_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("ingest_source.db");
+    let target = dir.path().join("ingest_target.db");
+    let file = dir.path().join("ingest.dump");
+    let engine = Engine::open(path.clone()).unwrap();
+    for i in 0..1000 {
+        engine.set(format!("key_{:04}", i).as_bytes(), b"ingested".to_vec()).await.unwrap();
+    }
+    engine.set_with_ttl(b"ttl", b"value".to_vec(), Duration::from_secs(3600)).await.unwrap();
+    let tree = engine.open_tree("tree").unwrap();
+    tree.set(b"key", b"tree_value".to_vec()).await.unwrap();
+    let mut dump = Vec::new();
+    engine.export(&mut dump).unwrap();
+    fs::write(&file, &dump).unwrap();
+
+    let ingested = Engine::open(target.clone()).unwrap();
+    ingested.set(b"key_0000", b"old".to_vec()).await.unwrap();
+    ingested.set(b"other", b"kept".to_vec()).await.unwrap();
+    let mut watched = Box::pin(ingested.watch_prefix(b"key_0000"));
+    assert_eq!(ingested.ingest_file(&file).await.unwrap(), 1002);
+    assert_eq!(ingested.len(), 1002);
+    assert_eq!(ingested.get(b"key_0000").await.unwrap(), Some(Bytes::from_static(b"ingested")));
+    assert_eq!(ingested.get(b"other").await.unwrap(), Some(Bytes::from_static(b"kept")));
+    assert_eq!(ingested.tree_names(), vec!["tree".to_string()]);
+    let event = watched.next().await.unwrap();
+    assert_eq!(event.old_value, Some(Bytes::from_static(b"old")));
+    assert_eq!(event.new_value, Some(Bytes::from_static(b"ingested")));
+
+    // Invalid files are rejected without merging anything.
+    let mut unsorted = b"tegdb dump 1\n".to_vec();
+    for key in [b"b", b"a"] {
+        unsorted.push(2);
+        unsorted.extend_from_slice(&1u32.to_be_bytes());
+        unsorted.extend_from_slice(key);
+        unsorted.extend_from_slice(&1u32.to_be_bytes());
+        unsorted.extend_from_slice(b"v");
+        unsorted.extend_from_slice(&0u64.to_be_bytes());
+    }
+    fs::write(&file, &unsorted).unwrap();
+    assert!(matches!(ingested.ingest_file(&file).await, Err(Error::BulkLoad(_))));
+    fs::write(&file, &dump[..dump.len() - 3]).unwrap();
+    assert!(matches!(ingested.ingest_file(&file).await, Err(Error::Corrupted(_))));
+    assert_eq!(ingested.len(), 1002);
+    ingested.set(b"key_0001", b"updated".to_vec()).await.unwrap();
+    drop((engine, tree, ingested));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let ingested = Engine::open(target.clone()).unwrap();
+    assert_eq!(ingested.len(), 1002);
+    assert_eq!(ingested.get(b"key_0000").await.unwrap(), Some(Bytes::from_static(b"ingested")));
+    assert_eq!(ingested.get(b"key_0001").await.unwrap(), Some(Bytes::from_static(b"updated")));
+    assert!(ingested.get(b"ttl").await.unwrap().is_some());
+    let tree = ingested.open_tree("tree").unwrap();
+    assert_eq!(tree.get(b"key").await.unwrap(), Some(Bytes::from_static(b"tree_value")));
+    drop((ingested, tree));
+}
+
+#[cfg(all(feature = "lz4", feature = "zstd"))]
+#[tokio::test]
+async fn test_compression() {
+    use tegdb::Compression;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("compression.db");
+    let json = |i: usize| format!("{{\"id\": {}, \"tags\": [{}]}}", i, "\"tag\", ".repeat(50)).into_bytes();
+    let options = |compression| EngineOptions {
+        compression,
+        keep_values_in_memory: false,
+        value_cache_size: 0,
+        background_compaction: false,
+        ..Default::default()
+    };
+    // Entries written with every codec, and without one, are read back from the same log.
+    let codecs = [Compression::None, Compression::Lz4, Compression::Zstd(3)];
+    for (i, &compression) in codecs.iter().enumerate() {
+        let engine = Engine::open_with_options(path.clone(), options(compression)).unwrap();
+        engine.set(format!("key_{}", i).as_bytes(), json(i)).await.unwrap();
+        engine.set(b"short", b"x".to_vec()).await.unwrap();
+        assert_eq!(engine.get(format!("key_{}", i).as_bytes()).await.unwrap(), Some(Bytes::from(json(i))));
+        engine.close().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let engine = Engine::open_with_options(path.clone(), options(Compression::Zstd(3))).unwrap();
+    for i in 0..codecs.len() {
+        assert_eq!(engine.get(format!("key_{}", i).as_bytes()).await.unwrap(), Some(Bytes::from(json(i))));
+    }
+    engine.compact().await.unwrap();
+    for i in 0..codecs.len() {
+        assert_eq!(engine.get(format!("key_{}", i).as_bytes()).await.unwrap(), Some(Bytes::from(json(i))));
+    }
+    assert_eq!(engine.get(b"short").await.unwrap(), Some(Bytes::from_static(b"x")));
+    engine.close().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let engine = Engine::open(path.clone()).unwrap();
+    assert_eq!(engine.get(b"key_1").await.unwrap(), Some(Bytes::from(json(1))));
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_typed_row_encoding() {
+    use tegdb::types::{decode_row, encode_row, Value};
+
+    let rows = [
+        vec![Value::Null],
+        vec![Value::Bool(false)],
+        vec![Value::Bool(true)],
+        vec![Value::Int(i64::MIN)],
+        vec![Value::Int(-1)],
+        vec![Value::Int(0)],
+        vec![Value::Int(42), Value::Null],
+        vec![Value::Int(42), Value::Text("a".to_string())],
+        vec![Value::Int(i64::MAX)],
+        vec![Value::Real(f64::NEG_INFINITY)],
+        vec![Value::Real(-1.5)],
+        vec![Value::Real(0.0)],
+        vec![Value::Real(2.25)],
+        vec![Value::Text(String::new())],
+        vec![Value::Text("a".to_string())],
+        vec![Value::Text("a\0".to_string())],
+        vec![Value::Text("a\0b".to_string()), Value::Int(1)],
+        vec![Value::Text("ab".to_string())],
+        vec![Value::Blob(vec![0, 0, 0xFF])],
+        vec![Value::Blob(vec![1])],
+    ];
+    let encoded: Vec<Vec<u8>> = rows.iter().map(|row| encode_row(row)).collect();
+    for (row, bytes) in rows.iter().zip(&encoded) {
+        assert_eq!(&decode_row(bytes).unwrap(), row);
+    }
+    assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]), "Expected encoded rows to sort in row order");
+
+    // Encoded rows make composite keys that scan in row order.
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("typed_rows.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    for (i, bytes) in encoded.iter().enumerate().rev() {
+        engine.set(bytes, i.to_string().into_bytes()).await.unwrap();
+    }
+    let values: Vec<Bytes> = engine.scan(..).await.unwrap().map(|(_, value)| value).collect();
+    let expected: Vec<Bytes> = (0..rows.len()).map(|i| Bytes::from(i.to_string())).collect();
+    assert_eq!(values, expected);
+    assert!(matches!(decode_row(&[0x06, b'a']), Err(Error::Decode(_))));
+    engine.close().await.unwrap();
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_typed_tree() {
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct User {
+        name: String,
+        tags: Vec<String>,
+        age: Option<u8>,
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("typed_tree.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    let users = engine.open_tree("users").unwrap().typed::<u64, User>();
+    let user = |name: &str, age| User {
+        name: name.to_string(),
+        tags: vec!["admin".to_string()],
+        age,
+    };
+    users.put(&300, &user("carol", None)).await.unwrap();
+    users.put(&2, &user("alice", Some(30))).await.unwrap();
+    users.put(&10, &user("bob", Some(40))).await.unwrap();
+    assert_eq!(users.get(&2).await.unwrap(), Some(user("alice", Some(30))));
+    assert_eq!(users.get(&3).await.unwrap(), None);
+    assert!(users.contains_key(&2).await.unwrap());
+    assert!(!users.contains_key(&3).await.unwrap());
+
+    // Unsigned integer keys are scanned in numeric order.
+    let ids: Vec<u64> = users.scan(..).await.unwrap().into_iter().map(|(id, _)| id).collect();
+    assert_eq!(ids, vec![2, 10, 300]);
+    let names: Vec<String> = users.scan(5..).await.unwrap().into_iter().map(|(_, u)| u.name).collect();
+    assert_eq!(names, vec!["bob", "carol"]);
+
+    users.del(&10).await.unwrap();
+    assert_eq!(users.len(), 2);
+    let units = engine.typed::<String, ()>();
+    assert!(matches!(units.put(&"key".to_string(), &()).await, Err(Error::Encode(_))));
+    // Bytes that do not decode as the value type are reported as such.
+    engine.set(b"\x00\x00\x00\x00\x00\x00\x00\x01k", b"x".to_vec()).await.unwrap();
+    let mismatched = engine.typed::<String, User>();
+    assert!(matches!(mismatched.get(&"k".to_string()).await, Err(Error::Decode(_))));
+    drop((engine, users, units, mismatched));
+}
+
+#[tokio::test]
+async fn test_key_encoding() {
+    use tegdb::keyencoding::{decode, encode};
+
+    let keys = [
+        ("", 0u64, i64::MIN, false),
+        ("", 0, -1, true),
+        ("a", 0, 0, false),
+        ("a", 1, i64::MAX, false),
+        ("a", u64::MAX, 0, false),
+        ("a\0", 0, 0, false),
+        ("a\0b", 0, 0, false),
+        ("ab", 0, 0, false),
+        ("b", 0, -5, false),
+    ];
+    let encoded: Vec<Vec<u8>> = keys.iter().map(encode).collect();
+    for (key, bytes) in keys.iter().zip(&encoded) {
+        let (s, u, i, b): (String, u64, i64, bool) = decode(bytes).unwrap();
+        assert_eq!((s.as_str(), u, i, b), *key);
+    }
+    assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]), "Expected encoded keys to sort in tuple order");
+    assert!(encode(&(b"ab".as_slice(),)) < encode(&(b"ab".as_slice(), 1u64)));
+    assert!(matches!(decode::<(u64,)>(&[0; 9]), Err(Error::Decode(_))));
+    assert!(matches!(decode::<(String, u64)>(&encode(&("a",))), Err(Error::Decode(_))));
+
+    // Keys sharing a prefix are found by scanning the encoded prefix.
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("key_encoding.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    for (user, order) in [("bob", 2u64), ("alice", 7), ("bob", 1), ("bobby", 1)] {
+        engine.set(&encode(&(user, order)), b"order".to_vec()).await.unwrap();
+    }
+    let orders: Vec<(String, u64)> = engine
+        .scan_prefix(&encode("bob"))
+        .await
+        .unwrap()
+        .map(|(key, _)| decode(&key).unwrap())
+        .collect();
+    assert_eq!(orders, [("bob".to_string(), 1), ("bob".to_string(), 2)]);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_watch_prefix() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("watch.db");
+    let options = EngineOptions {
+        keep_values_in_memory: false,
+        value_cache_size: 0,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    engine.set(b"user:1", b"old".to_vec()).await.unwrap();
+    let mut users = Box::pin(engine.watch_prefix(b"user:"));
+    let mut all = Box::pin(engine.watch_prefix(b""));
+    let tree = engine.open_tree("other").unwrap();
+    tree.set(b"user:1", b"other tree".to_vec()).await.unwrap();
+    engine.set(b"order:1", b"value".to_vec()).await.unwrap();
+    engine.set(b"user:1", b"new".to_vec()).await.unwrap();
+    engine.del(b"user:1").await.unwrap();
+    engine.del(b"user:2").await.unwrap();
+    engine.set(b"user:2", b"value".to_vec()).await.unwrap();
+    engine.clear().await.unwrap();
+
+    let event = |key: &'static [u8], old: Option<&'static [u8]>, new: Option<&'static [u8]>, op| Event {
+        key: Bytes::from_static(key),
+        old_value: old.map(Bytes::from_static),
+        new_value: new.map(Bytes::from_static),
+        op,
+    };
+    let expected = [
+        event(b"user:1", Some(b"old"), Some(b"new"), Op::Set),
+        event(b"user:1", Some(b"new"), None, Op::Del),
+        event(b"user:2", None, Some(b"value"), Op::Set),
+        event(b"user:2", Some(b"value"), None, Op::Del),
+    ];
+    for expected in &expected {
+        assert_eq!(&users.next().await.unwrap(), expected);
+    }
+    assert_eq!(all.next().await.unwrap(), event(b"order:1", None, Some(b"value"), Op::Set));
+    // Clearing the tree also deleted the key outside the watched prefix.
+    assert_eq!(all.as_mut().take(expected.len() + 1).count().await, expected.len() + 1);
+
+    drop((engine, tree));
+    assert_eq!(users.next().await, None);
+    assert_eq!(all.next().await, None);
+}
+
+#[tokio::test]
+async fn test_watch_filters() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("watch_filters.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    let mut range = Box::pin(engine.watch(Filter::range(b"b".to_vec()..b"d".to_vec())));
+    let mut deletions = Box::pin(engine.watch(Filter::predicate(|event| event.op == Op::Del)));
+    for key in [b"a", b"b", b"c", b"d"] {
+        engine.set(key, b"value".to_vec()).await.unwrap();
+    }
+    engine.del(b"a").await.unwrap();
+    engine.del(b"c").await.unwrap();
+
+    let keys: Vec<Bytes> = range.as_mut().take(3).map(|event| event.key).collect().await;
+    assert_eq!(keys, [Bytes::from_static(b"b"), Bytes::from_static(b"c"), Bytes::from_static(b"c")]);
+    let keys: Vec<Bytes> = deletions.as_mut().take(2).map(|event| event.key).collect().await;
+    assert_eq!(keys, [Bytes::from_static(b"a"), Bytes::from_static(b"c")]);
+    drop(engine);
+    assert_eq!(range.next().await, None);
+    assert_eq!(deletions.next().await, None);
+}
+
+#[tokio::test]
+async fn test_pipeline() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("pipeline.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    let mut pipeline = engine.pipeline().max_bytes(100).max_delay(Duration::from_secs(3600));
+    for i in 0..9 {
+        pipeline.set(format!("key_{}", i).as_bytes(), vec![i; 5]).await.unwrap();
+    }
+    // Writes stay buffered until they add up to 100 bytes.
+    assert_eq!(pipeline.pending(), 9);
+    assert_eq!(engine.get(b"key_0").await.unwrap(), None);
+    pipeline.set(b"key_9", vec![9; 5]).await.unwrap();
+    assert_eq!(pipeline.pending(), 0);
+    assert_eq!(engine.len(), 10);
+    pipeline.set(b"key_0", Vec::new()).await.unwrap();
+    pipeline.set(b"key_10", vec![10; 5]).await.unwrap();
+    pipeline.flush().await.unwrap();
+    assert_eq!(engine.get(b"key_0").await.unwrap(), None);
+    assert_eq!(engine.get(b"key_10").await.unwrap(), Some(Bytes::from(vec![10; 5])));
+
+    let mut immediate = engine.pipeline().max_delay(Duration::ZERO);
+    immediate.set(b"now", b"value".to_vec()).await.unwrap();
+    assert_eq!(immediate.pending(), 0);
+    pipeline.set(b"cleared", b"value".to_vec()).await.unwrap();
+    pipeline.clear();
+    assert_eq!(pipeline.pending(), 0);
+    pipeline.set(b"dropped", b"value".to_vec()).await.unwrap();
+    drop(pipeline);
+    assert_eq!(engine.get(b"cleared").await.unwrap(), None);
+    assert_eq!(engine.get(b"dropped").await.unwrap(), Some(Bytes::from_static(b"value")));
+    drop((engine, immediate));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let engine = Engine::open(path.clone()).unwrap();
+    assert_eq!(engine.len(), 12);
+    drop(engine);
+}
+
+#[tokio::test]
+async fn test_bulk_load() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bulk_load.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    engine.set(b"before", b"value".to_vec()).await.unwrap();
+    let tree = engine.open_tree("bulk").unwrap();
+    let pairs = (0..10_000u32).map(|i| (format!("key_{:05}", i).into_bytes(), i.to_be_bytes().to_vec()));
+    assert_eq!(tree.bulk_load(pairs).await.unwrap(), 10_000);
+    assert_eq!(tree.len(), 10_000);
+    assert_eq!(tree.get(b"key_01234").await.unwrap(), Some(Bytes::copy_from_slice(&1234u32.to_be_bytes())));
+    let sequence = engine.last_sequence();
+    assert_eq!(tree.changes_since(sequence - 1).await.unwrap().len(), 1);
+
+    // Only empty trees are loaded, from keys in ascending order.
+    assert!(matches!(tree.bulk_load([(b"z".to_vec(), b"1".to_vec())]).await, Err(Error::BulkLoad(_))));
+    let other = engine.open_tree("other").unwrap();
+    let unsorted = [(b"b".to_vec(), b"1".to_vec()), (b"a".to_vec(), b"2".to_vec())];
+    assert!(matches!(other.bulk_load(unsorted).await, Err(Error::BulkLoad(_))));
+    assert!(other.is_empty());
+
+    // Later writes replay on top of the loaded keys.
+    tree.set(b"key_00000", b"updated".to_vec()).await.unwrap();
+    tree.del(b"key_00001").await.unwrap();
+    drop((engine, tree, other));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let engine = Engine::open(path.clone()).unwrap();
+    let tree = engine.open_tree("bulk").unwrap();
+    assert_eq!(tree.len(), 9_999);
+    assert_eq!(tree.get(b"key_00000").await.unwrap(), Some(Bytes::from_static(b"updated")));
+    assert_eq!(engine.get(b"before").await.unwrap(), Some(Bytes::from_static(b"value")));
+    engine.compact().await.unwrap();
+    assert_eq!(tree.get(b"key_09999").await.unwrap(), Some(Bytes::copy_from_slice(&9999u32.to_be_bytes())));
+    drop((engine, tree));
+}
+
+#[test]
+fn test_blocking_api() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("blocking.db");
+    let engine = blocking::Engine::open(&path).unwrap();
+    let mut watched = engine.watch_prefix(b"key");
+    engine.set(b"key1", b"value1".to_vec()).unwrap();
+    engine.set_with_ttl(b"key2", b"value2".to_vec(), Duration::from_secs(3600)).unwrap();
+    assert_eq!(engine.get(b"key1").unwrap(), Some(Bytes::from_static(b"value1")));
+    assert!(engine.compare_and_swap(b"key1", Some(b"value1"), Some(b"swapped".to_vec())).unwrap());
+    let pairs: Vec<_> = engine.scan_iter(..).map(Result::unwrap).collect();
+    assert_eq!(pairs.len(), 2);
+    assert_eq!(pairs[0], (Bytes::from_static(b"key1"), Bytes::from_static(b"swapped")));
+    assert_eq!(watched.next().unwrap().new_value, Some(Bytes::from_static(b"value1")));
+
+    let tree = engine.open_tree("tree").unwrap();
+    tree.set(b"a", b"1".to_vec()).unwrap();
+    assert!(tree.contains_key(b"a").unwrap());
+    let mut pipeline = tree.pipeline();
+    pipeline.set(b"b", b"2".to_vec()).unwrap();
+    pipeline.flush().unwrap();
+    assert_eq!(tree.len(), 2);
+    assert_eq!(tree.stats().keys, 2);
+    assert_eq!(engine.tree_names(), vec!["tree".to_string()]);
+    assert_eq!(engine.trees().unwrap()[0].1.len(), 2);
+    // Both APIs share the same engine.
+    let handle = engine.as_async().open_tree("tree").unwrap();
+    assert_eq!(futures::executor::block_on(handle.get(b"a")).unwrap(), Some(Bytes::from_static(b"1")));
+
+    // Iterating a watch blocks until another thread makes a change.
+    let writer = engine.clone();
+    let thread = std::thread::spawn(move || writer.del(b"key2").unwrap());
+    assert_eq!(watched.by_ref().take(2).count(), 2);
+    let event = watched.next().unwrap();
+    assert_eq!((event.key, event.op), (Bytes::from_static(b"key2"), Op::Del));
+    thread.join().unwrap();
+    assert!(engine.compact().is_ok());
+    engine.close().unwrap();
+    drop((engine, tree, handle, pipeline));
+    assert!(watched.next().is_none());
+}
+
+#[tokio::test]
+async fn test_changes_since() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("changes.db");
+    let engine = Engine::open(path.clone()).unwrap();
+    assert_eq!(engine.last_sequence(), 0);
+    engine.set(b"a", b"1".to_vec()).await.unwrap();
+    engine.set(b"b", b"2".to_vec()).await.unwrap();
+    engine.del(b"a").await.unwrap();
+    engine.set(b"b", b"3".to_vec()).await.unwrap();
+    let changes = engine.changes_since(0).await.unwrap();
+    assert_eq!(changes.iter().map(Change::sequence).collect::<Vec<_>>(), [1, 2, 3, 4]);
+    assert_eq!(
+        engine.changes_since(2).await.unwrap(),
+        [
+            Change::Del { sequence: 3, key: Bytes::from_static(b"a") },
+            Change::Set {
+                sequence: 4,
+                key: Bytes::from_static(b"b"),
+                value: Bytes::from_static(b"3"),
+                expires_at: None,
+            },
+        ]
+    );
+
+    // Other trees share the sequence but not the changes.
+    let tree = engine.open_tree("other").unwrap();
+    tree.set(b"x", b"value".to_vec()).await.unwrap();
+    assert!(engine.changes_since(4).await.unwrap().is_empty());
+    assert_eq!(tree.changes_since(4).await.unwrap().len(), 1);
+    engine.delete_range(b"a".to_vec()..b"c".to_vec()).await.unwrap();
+    let last = engine.last_sequence();
+    assert_eq!(
+        engine.changes_since(last - 1).await.unwrap(),
+        [Change::DelRange {
+            sequence: last,
+            start: Bytes::from_static(b"a"),
+            end: Some(Bytes::from_static(b"c")),
+        }]
+    );
+
+    drop((engine, tree));
+    let engine = Engine::open(path.clone()).unwrap();
+    assert_eq!(engine.last_sequence(), last);
+    engine.set(b"c", b"value".to_vec()).await.unwrap();
+    engine.del(b"c").await.unwrap();
+    assert_eq!(engine.last_sequence(), last + 2);
+    // Compaction drops every change to the default tree, but not the sequence numbers.
+    engine.compact().await.unwrap();
+    assert!(engine.changes_since(0).await.unwrap().is_empty());
+    engine.close().await.unwrap();
+    let engine = Engine::open(path.clone()).unwrap();
+    assert_eq!(engine.last_sequence(), last + 2);
+    engine.set(b"d", b"value".to_vec()).await.unwrap();
+    assert_eq!(engine.changes_since(0).await.unwrap()[0].sequence(), last + 3);
+    engine.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_retention() {
+    use tegdb::Retention;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("retention.db");
+    let sequences = |changes: Vec<Change>| changes.iter().map(Change::sequence).collect::<Vec<_>>();
+    let engine = Engine::open(path.clone()).unwrap();
+    assert_eq!(engine.gc_horizon(), u64::MAX);
+    engine.set(b"a", b"1".to_vec()).await.unwrap();
+    engine.set(b"a", b"2".to_vec()).await.unwrap();
+    engine.set(b"b", b"1".to_vec()).await.unwrap();
+    engine.del(b"b").await.unwrap();
+
+    // A consumer keeps the writes made after what it acknowledged, deletions included.
+    engine.acknowledge("reader", 2).await.unwrap();
+    engine.acknowledge("reader", 1).await.unwrap();
+    assert_eq!(engine.consumers(), [("reader".to_string(), 2)]);
+    assert_eq!(engine.gc_horizon(), 2);
+    engine.compact().await.unwrap();
+    assert_eq!(sequences(engine.changes_since(0).await.unwrap()), [2, 3, 4]);
+    assert_eq!(engine.get(b"b").await.unwrap(), None);
+    assert!(matches!(engine.acknowledge("two\nlines", 1).await, Err(Error::Io(_))));
+    engine.close().await.unwrap();
+
+    // Acknowledgements survive restarts, and compaction catches up as they move on.
+    let engine = Engine::open(path.clone()).unwrap();
+    assert_eq!(engine.gc_horizon(), 2);
+    assert_eq!(sequences(engine.changes_since(0).await.unwrap()), [2, 3, 4]);
+    engine.acknowledge("reader", 4).await.unwrap();
+    engine.compact().await.unwrap();
+    assert_eq!(sequences(engine.changes_since(0).await.unwrap()), [2]);
+    assert!(engine.remove_consumer("reader").await.unwrap());
+    assert!(!engine.remove_consumer("reader").await.unwrap());
+    assert_eq!(engine.gc_horizon(), u64::MAX);
+    engine.close().await.unwrap();
+    fs::remove_dir_all(&path).unwrap();
+
+    // Retention by sequence keeps the latest writes.
+    let options = EngineOptions {
+        retention: Retention::Sequences(2),
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    for i in 0..5 {
+        engine.set(b"counter", vec![i]).await.unwrap();
+    }
+    engine.compact().await.unwrap();
+    assert_eq!(engine.gc_horizon(), 3);
+    assert_eq!(sequences(engine.changes_since(0).await.unwrap()), [4, 5]);
+    engine.close().await.unwrap();
+    fs::remove_dir_all(&path).unwrap();
+
+    // Retention by time keeps the writes until they are old enough.
+    let options = EngineOptions {
+        retention: Retention::Time(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    engine.set(b"key", b"old".to_vec()).await.unwrap();
+    engine.set(b"key", b"new".to_vec()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(engine.gc_horizon(), 0);
+    engine.compact().await.unwrap();
+    assert_eq!(sequences(engine.changes_since(0).await.unwrap()), [1, 2]);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(engine.gc_horizon(), 2);
+    engine.compact().await.unwrap();
+    assert_eq!(sequences(engine.changes_since(0).await.unwrap()), [2]);
+    engine.close().await.unwrap();
+}
+
+#[cfg(feature = "replication")]
+#[tokio::test]
+async fn test_replication() {
+    use tegdb::{Primary, Replica};
+
+    async fn wait_for(replica: &Replica, sequence: u64) {
+        for _ in 0..200 {
+            if replica.applied_sequence() == sequence {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("replica did not reach sequence {}", sequence);
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let primary_path = dir.path().join("replication_primary.db");
+    let replica_path = dir.path().join("replication_replica.db");
+    let engine = Engine::open(primary_path.clone()).unwrap();
+    let standby = Engine::open(replica_path.clone()).unwrap();
+    standby.set(b"stale", b"value".to_vec()).await.unwrap();
+    let local = standby.open_tree("local").unwrap();
+    local.set(b"stale", b"value".to_vec()).await.unwrap();
+    engine.set(b"a", b"1".to_vec()).await.unwrap();
+    engine.open_tree("other").unwrap().set(b"x", b"1".to_vec()).await.unwrap();
+
+    // The replica starts from a snapshot, replacing whatever it held.
+    let primary = Primary::start(&engine, "127.0.0.1:0").unwrap();
+    let addr = primary.local_addr();
+    let replica = Replica::start(&standby, addr).unwrap();
+    wait_for(&replica, engine.last_sequence()).await;
+    assert_eq!(standby.get(b"a").await.unwrap(), Some(Bytes::from_static(b"1")));
+    assert_eq!(standby.get(b"stale").await.unwrap(), None);
+    // The keys of the replica's own trees are deleted, while the internal trees are left alone.
+    assert_eq!(local.get(b"stale").await.unwrap(), None);
+    assert!(standby.tree_names().contains(&"local".to_string()));
+    let other = standby.open_tree("other").unwrap();
+    assert_eq!(other.get(b"x").await.unwrap(), Some(Bytes::from_static(b"1")));
+
+    // Changes are streamed as they are committed.
+    engine.set(b"b", b"2".to_vec()).await.unwrap();
+    engine.del(b"a").await.unwrap();
+    engine.open_tree("third").unwrap().set(b"y", b"2".to_vec()).await.unwrap();
+    wait_for(&replica, engine.last_sequence()).await;
+    assert_eq!(standby.get(b"a").await.unwrap(), None);
+    assert_eq!(standby.get(b"b").await.unwrap(), Some(Bytes::from_static(b"2")));
+    let third = standby.open_tree("third").unwrap();
+    assert_eq!(third.get(b"y").await.unwrap(), Some(Bytes::from_static(b"2")));
+
+    // After losing its primary, the replica reconnects and catches up from the log.
+    drop(primary);
+    engine.delete_range(b"a".to_vec()..b"c".to_vec()).await.unwrap();
+    engine.set(b"c", b"3".to_vec()).await.unwrap();
+    let primary = Primary::start(&engine, addr).unwrap();
+    wait_for(&replica, engine.last_sequence()).await;
+    assert_eq!(standby.get(b"b").await.unwrap(), None);
+    assert_eq!(standby.get(b"c").await.unwrap(), Some(Bytes::from_static(b"3")));
+
+    // Dropping the replica stops it from using the engine, which can be reopened right away.
+    drop((replica, local, other, third, standby));
+    let standby = Engine::open(replica_path).unwrap();
+    assert_eq!(standby.get(b"c").await.unwrap(), Some(Bytes::from_static(b"3")));
+    drop((primary, engine, standby));
+    // The primary's threads let go of its engine shortly after being stopped.
+    tokio::time::sleep(Duration::from_secs(2)).await;
+}
+
+#[cfg(feature = "replication")]
+#[tokio::test]
+async fn test_replication_stalled_replica() {
+    use std::io::Write;
+    use tegdb::Primary;
+
+    let dir = tempfile::tempdir().unwrap();
+    let options = EngineOptions {
+        keep_values_in_memory: false,
+        background_compaction: false,
+        max_value_size: None,
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(dir.path(), options).unwrap();
+    for i in 0..2000 {
+        engine.set(format!("key_{:04}", i).as_bytes(), vec![1; 16 * 1024]).await.unwrap();
+    }
+    let primary = Primary::start(&engine, "127.0.0.1:0").unwrap();
+
+    // A replica asking for a snapshot but never reading it stalls the primary mid-stream.
+    let mut stalled = std::net::TcpStream::connect(primary.local_addr()).unwrap();
+    stalled.write_all(b"tegdb replica 1\n").unwrap();
+    stalled.write_all(&0u64.to_be_bytes()).unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // Compaction still runs, and deletes the segments the snapshot is read from.
+    for i in 0..2000 {
+        engine.set(format!("key_{:04}", i).as_bytes(), vec![2; 16]).await.unwrap();
+    }
+    let compacting = engine.clone();
+    let compacted = tokio::time::timeout(
+        Duration::from_secs(10),
+        tokio::task::spawn_blocking(move || futures::executor::block_on(compacting.compact())),
+    );
+    assert!(compacted.await.unwrap().unwrap().unwrap() > 0);
+
+    drop((stalled, primary));
+    engine.close().await.unwrap();
+}
+
+#[cfg(feature = "replication")]
+#[tokio::test]
+async fn test_replication_lagging_replica() {
+    use std::io::{Read, Write};
+    use tegdb::Primary;
+
+    let engine = Engine::open_temporary_with_options(EngineOptions {
+        background_compaction: false,
+        ..Default::default()
+    })
+    .unwrap();
+    engine.set(b"start", b"1".to_vec()).await.unwrap();
+    let primary = Primary::start(&engine, "127.0.0.1:0").unwrap();
+
+    // A replica that is up to date but stops reading falls behind the changes that follow.
+    let mut lagging = std::net::TcpStream::connect(primary.local_addr()).unwrap();
+    lagging.write_all(b"tegdb replica 1\n").unwrap();
+    lagging.write_all(&engine.last_sequence().to_be_bytes()).unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    for i in 0..10_000 {
+        engine.set(format!("key_{:05}", i).as_bytes(), vec![1; 4096]).await.unwrap();
+    }
+
+    // The primary only buffers so many changes for it, and then closes the connection, so
+    // that the replica catches up from the log once it reconnects.
+    lagging.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let (mut received, mut buf) = (0, vec![0; 64 * 1024]);
+    loop {
+        match lagging.read(&mut buf).unwrap() {
+            0 => break,
+            read => received += read,
+        }
+        assert!(received < 10_000 * 4096, "the lagging replica was sent every change");
+    }
+    assert!(received > 0);
+
+    drop(primary);
+    engine.close().await.unwrap();
+}
+
+#[cfg(feature = "sim")]
+#[tokio::test]
+async fn test_health() {
+    use tegdb::Simulation;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("health.db");
+    let simulation = Simulation::new();
+    let options = EngineOptions {
+        simulation: Some(simulation.clone()),
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    engine.set(b"key", b"value".to_vec()).await.unwrap();
+    engine.sync().await.unwrap();
+    let health = engine.health();
+    assert!(health.is_healthy());
+    assert!(health.write_error.is_none() && !health.disk_full && health.corruption.is_none());
+    assert_eq!((health.write_queue_depth, health.checksum_failures), (0, 0));
+    assert!(health.since_last_sync.is_some());
+
+    // Once the writer fails, the report says why until the database is reopened.
+    simulation.fail_writes(1);
+    engine.set(b"key", b"other".to_vec()).await.unwrap();
+    assert!(engine.flush().await.is_err());
+    let health = engine.health();
+    assert!(matches!(health.write_error, Some(Error::Io(_))));
+    assert_eq!(health.write_queue_depth, 0);
+    assert!(!health.is_healthy());
+    assert!(matches!(health.into_result(), Err(Error::Io(_))));
+    assert!(engine.close().await.is_err());
+    assert!(matches!(engine.health().write_error, Some(Error::Closed)));
+
+    // A database opened read-only has no writer to report on.
+    let read_only = EngineOptions {
+        read_only: true,
+        ..options
+    };
+    let engine = Engine::open_with_options(path.clone(), read_only).unwrap();
+    let health = engine.health();
+    assert!(health.is_healthy());
+    assert_eq!(health.write_queue_depth, 0);
+    assert!(health.since_last_sync.is_none());
+    assert!(matches!(engine.set(b"key", b"third".to_vec()).await, Err(Error::ReadOnly)));
+    assert!(engine.health().is_healthy());
+}
+
+#[cfg(feature = "sim")]
+#[tokio::test]
+async fn test_health_background_compaction() {
+    use tegdb::Simulation;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("health_compaction.db");
+    let simulation = Simulation::new();
+    let options = EngineOptions {
+        segment_size: 64,
+        compaction_min_size: 0,
+        compaction_interval: Duration::from_secs(10),
+        simulation: Some(simulation.clone()),
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    for fill in [b'a', b'b', b'c'] {
+        engine.set(b"key", vec![fill; 64]).await.unwrap();
+    }
+    engine.flush().await.unwrap();
+
+    // A background compaction failing is reported until one succeeds.
+    let segment = path.join("00000001.log");
+    let moved = dir.path().join("00000001.log");
+    fs::rename(&segment, &moved).unwrap();
+    simulation.advance(Duration::from_secs(10));
+    let health = engine.health();
+    assert!(matches!(health.compaction_error, Some(Error::Io(_))));
+    assert!(!health.is_healthy());
+    assert!(matches!(health.into_result(), Err(Error::Io(_))));
+
+    fs::rename(&moved, &segment).unwrap();
+    simulation.advance(Duration::from_secs(10));
+    let health = engine.health();
+    assert!(health.compaction_error.is_none());
+    assert!(health.is_healthy());
+    assert_eq!(engine.get(b"key").await.unwrap().as_deref(), Some(&[b'c'; 64][..]));
+}
+
+#[cfg(feature = "sim")]
+#[tokio::test]
+async fn test_health_index_snapshot() {
+    use tegdb::Simulation;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("health_snapshot.db");
+    let simulation = Simulation::new();
+    let options = EngineOptions {
+        background_compaction: false,
+        index_snapshot_interval: Some(Duration::from_secs(10)),
+        simulation: Some(simulation.clone()),
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    engine.set(b"key", b"value".to_vec()).await.unwrap();
+
+    // The snapshot cannot be written while a directory is in the way of its temporary file.
+    fs::create_dir(path.join("INDEX.tmp")).unwrap();
+    simulation.advance(Duration::from_secs(10));
+    let health = engine.health();
+    assert!(matches!(health.snapshot_error, Some(Error::Io(_))));
+    assert!(!health.is_healthy());
+    assert!(!path.join("INDEX").exists());
+
+    fs::remove_dir(path.join("INDEX.tmp")).unwrap();
+    simulation.advance(Duration::from_secs(10));
+    let health = engine.health();
+    assert!(health.snapshot_error.is_none());
+    assert!(health.is_healthy());
+    assert!(path.join("INDEX").exists());
+}
+
+#[cfg(feature = "sim")]
+#[tokio::test]
+async fn test_health_scrub() {
+    use tegdb::Simulation;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("health_scrub.db");
+    let simulation = Simulation::new();
+    let options = EngineOptions {
+        background_compaction: false,
+        segment_size: 64,
+        scrub_interval: Some(Duration::from_secs(10)),
+        simulation: Some(simulation.clone()),
+        ..Default::default()
+    };
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    engine.set(b"a", vec![b'a'; 64]).await.unwrap();
+    engine.set(b"b", vec![b'b'; 64]).await.unwrap();
+    engine.flush().await.unwrap();
+
+    // A sealed segment that cannot be read fails the scrub until it can be again.
+    let segment = path.join("00000001.log");
+    let moved = dir.path().join("00000001.log");
+    fs::rename(&segment, &moved).unwrap();
+    fs::create_dir(&segment).unwrap();
+    simulation.advance(Duration::from_secs(10));
+    let health = engine.health();
+    assert!(matches!(health.scrub_error, Some(Error::Io(_))));
+    assert!(health.corruption.is_none());
+    assert!(!health.is_healthy());
+    assert!(matches!(health.into_result(), Err(Error::Io(_))));
+
+    fs::remove_dir(&segment).unwrap();
+    fs::rename(&moved, &segment).unwrap();
+    simulation.advance(Duration::from_secs(10));
+    let health = engine.health();
+    assert!(health.scrub_error.is_none());
+    assert!(health.is_healthy());
+}
+
+#[cfg(feature = "sim")]
+#[tokio::test]
+async fn test_simulation() {
+    use tegdb::Simulation;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("simulation.db");
+    let simulation = Simulation::starting_at(Duration::from_secs(1_000_000));
+    let options = EngineOptions {
+        compaction_interval: Duration::from_secs(10),
+        simulation: Some(simulation.clone()),
+        ..Default::default()
+    };
+
+    // Keys expire, and are removed in the background, as the virtual clock advances.
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    engine.set_with_ttl(b"session", b"token".to_vec(), Duration::from_secs(60)).await.unwrap();
+    engine.set_with_ttl(b"cache", b"entry".to_vec(), Duration::from_secs(60)).await.unwrap();
+    engine.set(b"a", b"first".to_vec()).await.unwrap();
+    simulation.advance(Duration::from_secs(59));
+    assert!(engine.get(b"session").await.unwrap().is_some());
+    simulation.advance(Duration::from_secs(1));
+    assert_eq!(engine.get(b"session").await.unwrap(), None);
+    assert_eq!(engine.stats().live_keys, 2);
+    simulation.advance(Duration::from_secs(9));
+    assert_eq!(engine.stats().live_keys, 1);
+    engine.set(b"b", b"second".to_vec()).await.unwrap();
+    engine.close().await.unwrap();
+
+    // A failed write leaves nothing behind, and the engine refuses writes until reopened.
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    simulation.fail_writes(1);
+    engine.set(b"c", b"third".to_vec()).await.unwrap();
+    assert!(engine.flush().await.is_err());
+    assert!(engine.set(b"c", b"again".to_vec()).await.is_err());
+    assert!(engine.close().await.is_err());
+    let engine = Engine::open_with_options(path.clone(), options.clone()).unwrap();
+    assert_eq!(engine.get(b"c").await.unwrap(), None);
+
+    // A torn write leaves a partial entry at the end of the log, which repair removes.
+    simulation.tear_next_write(10);
+    engine.set(b"d", b"fourth".to_vec()).await.unwrap();
+    assert!(engine.sync().await.is_err());
+    assert!(engine.close().await.is_err());
+    assert!(matches!(Engine::open_with_options(path.clone(), options.clone()), Err(Error::Corrupted(_))));
+    assert!(Engine::repair(&path).unwrap().repaired);
+    let engine = Engine::open_with_options(path.clone(), options).unwrap();
+    assert_eq!(engine.get(b"a").await.unwrap().as_deref(), Some(&b"first"[..]));
+    assert_eq!(engine.get(b"b").await.unwrap().as_deref(), Some(&b"second"[..]));
+    assert_eq!(engine.get(b"d").await.unwrap(), None);
+    assert_eq!(engine.get(b"session").await.unwrap(), None);
+    engine.close().await.unwrap();
+}
+
+// Kills the server process when a test ends, even if it fails.
+#[cfg(feature = "server")]
+struct ServerProcess(std::process::Child);
+
+#[cfg(feature = "server")]
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_server() {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpStream;
+    use std::process::{Command, Stdio};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("server.db");
+    let server = Command::new(env!("CARGO_BIN_EXE_tegdb-server"))
+        .args(["--addr", "127.0.0.1:0"])
+        .arg(&path)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut server = ServerProcess(server);
+    let mut line = String::new();
+    BufReader::new(server.0.stdout.take().unwrap()).read_line(&mut line).unwrap();
+    let addr = line.trim().strip_prefix("Listening on ").unwrap().to_string();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut request = |command: &[u8], reply: &[u8]| {
+        stream.write_all(command).unwrap();
+        let mut buf = vec![0; reply.len()];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(String::from_utf8_lossy(&buf), String::from_utf8_lossy(reply));
+    };
+
+    request(b"*3\r\n$3\r\nSET\r\n$5\r\nuser1\r\n$5\r\nalice\r\n", b"+OK\r\n");
+    request(b"*2\r\n$3\r\nGET\r\n$5\r\nuser1\r\n", b"$5\r\nalice\r\n");
+    request(b"SET user2 bob EX 100\r\n", b"+OK\r\n");
+    request(b"SET order1 book\r\n", b"+OK\r\n");
+    // Patterns are matched against the keys visited, which are in key order.
+    request(b"SCAN 0 MATCH user* COUNT 2\r\n", b"*2\r\n$1\r\n2\r\n*1\r\n$5\r\nuser1\r\n");
+    request(b"SCAN 2 MATCH user* COUNT 2\r\n", b"*2\r\n$1\r\n0\r\n*1\r\n$5\r\nuser2\r\n");
+    request(b"EXPIRE user1 0\r\nEXPIRE missing 10\r\n", b":1\r\n:0\r\n");
+    request(b"DEL user1 user2 order1\r\n", b":2\r\n");
+    request(b"GET user2\r\n", b"$-1\r\n");
+    request(b"GET\r\n", b"-ERR wrong number of arguments for 'get' command\r\n");
+    request(b"FLUSHALL\r\n", b"-ERR unknown command 'flushall'\r\n");
+    request(b"QUIT\r\n", b"+OK\r\n");
+
+    drop(server);
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_http_server() {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpStream;
+    use std::process::{Command, Stdio};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("http_server.db");
+    let server = Command::new(env!("CARGO_BIN_EXE_tegdb-server"))
+        .args(["--addr", "127.0.0.1:0", "--http", "127.0.0.1:0"])
+        .arg(&path)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut server = ServerProcess(server);
+    let mut stdout = BufReader::new(server.0.stdout.take().unwrap());
+    let mut line = String::new();
+    while !line.starts_with("Listening for HTTP on ") {
+        line.clear();
+        stdout.read_line(&mut line).unwrap();
+    }
+    let addr = line.trim().strip_prefix("Listening for HTTP on ").unwrap().to_string();
+    let request = |method: &str, target: &str, body: &str| {
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            target,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, mut body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse::<u16>().unwrap();
+        let head = head.to_ascii_lowercase();
+        let mut content = String::new();
+        if head.contains("transfer-encoding: chunked") {
+            loop {
+                let (size, rest) = body.split_once("\r\n").unwrap();
+                let size = usize::from_str_radix(size, 16).unwrap();
+                if size == 0 {
+                    break;
+                }
+                content.push_str(&rest[..size]);
+                body = rest[size..].strip_prefix("\r\n").unwrap();
+            }
+        } else {
+            content.push_str(body);
+        }
+        (status, head, content)
+    };
+
+    assert_eq!(request("PUT", "/keys/user%3A1", "alice").0, 204);
+    assert_eq!(request("PUT", "/keys/user%3A2?ttl=100000", "bob").0, 204);
+    assert_eq!(request("GET", "/keys/user:1", "").2, "alice");
+    assert_eq!(request("GET", "/keys/missing", "").0, 404);
+    assert_eq!(request("POST", "/batch", "set order%3A1 a%20book\ndel user%3A2\n").0, 204);
+    assert_eq!(request("POST", "/batch", "put key value\n").0, 400);
+    let (status, head, body) = request("GET", "/scan", "");
+    assert_eq!(status, 200);
+    assert!(head.contains("transfer-encoding: chunked"));
+    assert_eq!(body, "order%3A1=a%20book\nuser%3A1=alice\n");
+    assert_eq!(request("GET", "/scan?prefix=user", "").2, "user%3A1=alice\n");
+    assert_eq!(request("GET", "/scan?limit=1", "").2, "order%3A1=a%20book\n");
+    assert_eq!(request("DELETE", "/keys/user:1", "").0, 204);
+    assert_eq!(request("GET", "/scan?start=p&limit=1", "").2, "");
+    assert_eq!(request("GET", "/scan?limit=many", "").0, 400);
+    assert_eq!(request("PUT", &format!("/keys/{}", "k".repeat(2000)), "value").0, 400);
+    let (status, _, body) = request("GET", "/metrics", "");
+    assert_eq!(status, 200);
+    assert!(body.contains("\ntegdb_live_keys 1\n"));
+    assert_eq!(request("GET", "/nowhere", "").0, 404);
+
+    drop(server);
+}
+
+#[test]
+fn test_cli() {
+    use std::process::Command;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("cli.db");
+    let dump = dir.path().join("cli.dump");
+    let restored = dir.path().join("cli_restored.db");
+    let cli = |path: &Path, args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_tegdb-cli")).arg(path).args(args).output().unwrap();
+        (output.status.success(), String::from_utf8(output.stdout).unwrap())
+    };
+
+    assert!(cli(&path, &["set", "a", "1"]).0);
+    assert!(cli(&path, &["set", "b", "2"]).0);
+    assert!(cli(&path, &["set", "c", "3", "--ttl", "60000"]).0);
+    assert!(cli(&path, &["--tree", "users", "set", "alice", "x\ty"]).0);
+    assert_eq!(cli(&path, &["get", "a"]), (true, "1\n".to_string()));
+    assert_eq!(cli(&path, &["--tree", "users", "get", "alice"]), (true, "x\\ty\n".to_string()));
+    assert!(!cli(&path, &["get", "missing"]).0);
+    assert!(!cli(&path, &["--tree", "missing", "get", "a"]).0);
+    assert_eq!(cli(&path, &["scan", "b"]), (true, "b\t2\nc\t3\n".to_string()));
+    assert_eq!(cli(&path, &["scan", "--limit", "1"]), (true, "a\t1\n".to_string()));
+    assert!(cli(&path, &["del", "b"]).0);
+    assert_eq!(cli(&path, &["scan", "a", "c"]), (true, "a\t1\n".to_string()));
+
+    let (ok, stats) = cli(&path, &["stats"]);
+    assert!(ok);
+    assert!(stats.contains("Keys in the default tree: 2\n"));
+    assert!(stats.contains("Keys in tree \"users\": 1\n"));
+    assert!(cli(&path, &["compact"]).0);
+    assert_eq!(cli(&path, &["verify"]), (true, "Read 3 values\n".to_string()));
+    assert!(cli(&path, &["fsck"]).0);
+
+    assert!(cli(&path, &["dump", dump.to_str().unwrap()]).0);
+    assert!(cli(&restored, &["restore", dump.to_str().unwrap()]).0);
+    assert_eq!(cli(&restored, &["scan"]), (true, "a\t1\nc\t3\n".to_string()));
+    assert_eq!(cli(&restored, &["--tree", "users", "get", "alice"]), (true, "x\\ty\n".to_string()));
+    assert!(!cli(&restored, &["frobnicate"]).0);
+
 }